@@ -0,0 +1,50 @@
+//! Compares the latency of starting a client handshake with and without a prewarmed
+//! ephemeral keypair, see `ClientHandshakerFactory::prewarm`.
+
+#[macro_use]
+extern crate criterion;
+extern crate sodiumoxide;
+extern crate secret_handshake;
+extern crate futures;
+
+use std::io::Cursor;
+
+use criterion::Criterion;
+use sodiumoxide::crypto::{sign, auth};
+use futures::io::AllowStdIo;
+
+use secret_handshake::*;
+
+// A stream to start handshakes over, which are never polled.
+fn stream() -> AllowStdIo<Cursor<Vec<u8>>> {
+    AllowStdIo::new(Cursor::new(Vec::new()))
+}
+
+fn bench_prewarm(c: &mut Criterion) {
+    sodiumoxide::init();
+    let (client_longterm_pk, client_longterm_sk) = sign::gen_keypair();
+    let (server_longterm_pk, _) = sign::gen_keypair();
+    let factory = move || {
+        ClientHandshakerFactory::new([0; auth::KEYBYTES],
+                                     client_longterm_pk.clone(),
+                                     client_longterm_sk.clone())
+    };
+
+    // Only the call to `start` is timed, creating and prewarming the factory is not.
+    let cold_factory = factory.clone();
+    let cold_server_pk = server_longterm_pk.clone();
+    c.bench_function("start without prewarming", move |b| {
+        b.iter_with_setup(&cold_factory, |mut f| f.start(stream(), cold_server_pk.clone()))
+    });
+    c.bench_function("start after prewarming", move |b| {
+        b.iter_with_setup(|| {
+                              let mut f = factory();
+                              f.prewarm();
+                              f
+                          },
+                          |mut f| f.start(stream(), server_longterm_pk.clone()))
+    });
+}
+
+criterion_group!(benches, bench_prewarm);
+criterion_main!(benches);
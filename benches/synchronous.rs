@@ -0,0 +1,165 @@
+//! Measures handshakes over transports that already buffer all messages of the peer,
+//! so that a single `poll` completes the whole handshake.
+//!
+//! The peer's messages are computed once up front via the `crypto` module, and each
+//! iteration replays them from memory.
+
+#[macro_use]
+extern crate criterion;
+extern crate sodiumoxide;
+extern crate secret_handshake;
+extern crate futures;
+
+use std::io::{self, Cursor, Read, Write};
+use std::pin::Pin;
+use std::task::Poll;
+
+use criterion::Criterion;
+use sodiumoxide::crypto::{box_, sign, auth};
+use futures::executor::block_on;
+use futures::future::{poll_fn, TryFuture};
+use futures::io::AllowStdIo;
+
+use secret_handshake::*;
+use secret_handshake::crypto::*;
+
+struct Keys {
+    network_identifier: [u8; auth::KEYBYTES],
+    client_longterm_pk: sign::PublicKey,
+    client_longterm_sk: sign::SecretKey,
+    client_ephemeral_pk: box_::PublicKey,
+    client_ephemeral_sk: box_::SecretKey,
+    server_longterm_pk: sign::PublicKey,
+    server_longterm_sk: sign::SecretKey,
+    server_ephemeral_pk: box_::PublicKey,
+    server_ephemeral_sk: box_::SecretKey,
+    client_msgs: Vec<u8>, // msg1 and msg3
+    server_msgs: Vec<u8>, // msg2 and msg4
+}
+
+impl Keys {
+    // Reusing ephemeral keys is fine for benchmarking, but must never be done
+    // for actual connections.
+    fn new() -> Keys {
+        let network_identifier = auth::gen_key().0;
+        let (client_longterm_pk, client_longterm_sk) = sign::gen_keypair();
+        let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+        let (server_longterm_pk, server_longterm_sk) = sign::gen_keypair();
+        let (server_ephemeral_pk, server_ephemeral_sk) = box_::gen_keypair();
+
+        let mut client = Client::from_keys(&network_identifier,
+                                           &client_longterm_pk,
+                                           &client_longterm_sk,
+                                           &client_ephemeral_pk,
+                                           &client_ephemeral_sk,
+                                           &server_longterm_pk);
+        let mut server = Server::from_keys(&network_identifier,
+                                           &server_longterm_pk,
+                                           &server_longterm_sk,
+                                           &server_ephemeral_pk,
+                                           &server_ephemeral_sk);
+
+        let mut msg1 = [0; MSG1_BYTES];
+        let mut msg2 = [0; MSG2_BYTES];
+        let mut msg3 = [0; MSG3_BYTES];
+        let mut msg4 = [0; MSG4_BYTES];
+        client.create_msg1(&mut msg1);
+        assert!(server.verify_msg1(&msg1));
+        server.create_msg2(&mut msg2);
+        assert!(client.verify_msg2(&msg2));
+        client.create_msg3(&mut msg3);
+        assert!(server.verify_msg3(&msg3));
+        server.create_msg4(&mut msg4);
+        assert!(client.verify_msg4(&msg4));
+
+        Keys {
+            network_identifier,
+            client_longterm_pk,
+            client_longterm_sk,
+            client_ephemeral_pk,
+            client_ephemeral_sk,
+            server_longterm_pk,
+            server_longterm_sk,
+            server_ephemeral_pk,
+            server_ephemeral_sk,
+            client_msgs: [&msg1[..], &msg3[..]].concat(),
+            server_msgs: [&msg2[..], &msg4[..]].concat(),
+        }
+    }
+}
+
+// Reads the buffered messages of the peer and collects everything written.
+struct Transport<'a> {
+    input: Cursor<&'a [u8]>,
+    output: Vec<u8>,
+}
+
+impl<'a> Transport<'a> {
+    fn new(input: &'a [u8], output_capacity: usize) -> AllowStdIo<Transport<'a>> {
+        AllowStdIo::new(Transport {
+                            input: Cursor::new(input),
+                            output: Vec::with_capacity(output_capacity),
+                        })
+    }
+}
+
+impl<'a> Read for Transport<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<'a> Write for Transport<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Polls the handshake exactly once, which must complete it.
+fn poll_once<F: TryFuture + Unpin>(mut handshake: F) {
+    block_on(poll_fn(|cx| match Pin::new(&mut handshake).try_poll(cx) {
+                         Poll::Ready(Ok(_)) => Poll::Ready(()),
+                         _ => panic!("handshake did not complete synchronously"),
+                     }));
+}
+
+fn client_handshake(keys: &Keys) {
+    let stream = Transport::new(&keys.server_msgs, MSG1_BYTES + MSG3_BYTES);
+    poll_once(ClientHandshaker::new(stream,
+                                    &keys.network_identifier,
+                                    &keys.client_longterm_pk,
+                                    &keys.client_longterm_sk,
+                                    &keys.client_ephemeral_pk,
+                                    &keys.client_ephemeral_sk,
+                                    &keys.server_longterm_pk));
+}
+
+fn server_handshake(keys: &Keys) {
+    let stream = Transport::new(&keys.client_msgs, MSG2_BYTES + MSG4_BYTES);
+    poll_once(ServerHandshaker::new(stream,
+                                    &keys.network_identifier,
+                                    &keys.server_longterm_pk,
+                                    &keys.server_longterm_sk,
+                                    &keys.server_ephemeral_pk,
+                                    &keys.server_ephemeral_sk));
+}
+
+fn bench_synchronous(c: &mut Criterion) {
+    sodiumoxide::init();
+
+    c.bench_function("synchronous client handshake", |b| {
+        let keys = Keys::new();
+        b.iter(|| client_handshake(&keys))
+    });
+    c.bench_function("synchronous server handshake", |b| {
+        let keys = Keys::new();
+        b.iter(|| server_handshake(&keys))
+    });
+}
+
+criterion_group!(benches, bench_synchronous);
+criterion_main!(benches);
@@ -0,0 +1,61 @@
+//! Accepts a tcp connection with async-std and performs a handshake over it, with a client
+//! connecting from the same process.
+//!
+//! The async-std streams implement the futures-io traits, so the handshakers work on them
+//! directly, and async-std runs the handshakers like any other future.
+//!
+//! Run with `cargo run --example async_std`.
+
+extern crate sodiumoxide;
+extern crate secret_handshake;
+extern crate async_std;
+extern crate futures;
+
+use sodiumoxide::crypto::{box_, sign};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use futures::prelude::*;
+use futures::future::try_join;
+
+use secret_handshake::*;
+
+fn main() {
+    sodiumoxide::init();
+
+    let network_identifier = [42; NETWORK_IDENTIFIER_BYTES];
+    let (server_longterm_pk, server_longterm_sk) = sign::gen_keypair();
+    let acceptor = Acceptor::new(network_identifier,
+                                 server_longterm_pk.clone(),
+                                 server_longterm_sk);
+
+    let listener = task::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    println!("listening on {}", addr);
+
+    let (client_longterm_pk, client_longterm_sk) = sign::gen_keypair();
+    let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+    let client_stream = task::block_on(TcpStream::connect(addr)).unwrap();
+    let client = OwningClientHandshaker::new(client_stream,
+                                             network_identifier,
+                                             client_longterm_pk,
+                                             client_longterm_sk,
+                                             client_ephemeral_pk,
+                                             client_ephemeral_sk,
+                                             server_longterm_pk);
+
+    // Accept the connection and start the server side of the handshake on it.
+    let (server_stream, peer) = task::block_on(listener.accept()).unwrap();
+    println!("accepted a connection from {}", peer);
+    let server = acceptor.accept(server_stream);
+
+    let client = client.map_ok(|(outcome, _stream)| outcome)
+        .map_err(|(err, _stream)| format!("client failed: {}", err));
+    let server = server.map_ok(|(outcome, _stream)| outcome)
+        .map_err(|(err, _stream)| format!("server failed: {}", err));
+    let (client_outcome, server_outcome) = task::block_on(try_join(client, server)).unwrap();
+
+    assert_eq!(client_outcome.encryption_key(), server_outcome.decryption_key());
+    assert_eq!(client_outcome.decryption_key(), server_outcome.encryption_key());
+    println!("handshake with {:?} completed",
+             server_outcome.peer_longterm_pk());
+}
@@ -0,0 +1,92 @@
+//! Performs a handshake without any executor, by calling `poll` on the client and the
+//! server in turns on a single thread.
+//!
+//! Both sides talk over in-memory channels that transfer at most 16 bytes per read or
+//! write, so each message takes several rounds: a side returns `Pending` whenever there is
+//! nothing to read yet, and continues where it left off on the next `poll`. The waker passed
+//! to `poll` does nothing, the loop simply polls both sides again.
+//!
+//! Run with `cargo run --example manual_poll`.
+
+extern crate sodiumoxide;
+extern crate secret_handshake;
+extern crate futures_test;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_test::io::AsyncReadTestExt;
+
+use secret_handshake::*;
+use secret_handshake::testutil::channel_pair;
+
+// A waker that ignores all wakeups.
+struct NoopWake;
+
+impl Wake for NoopWake {
+    fn wake(self: Arc<NoopWake>) {}
+}
+
+fn main() {
+    sodiumoxide::init();
+
+    let network_identifier = [42; NETWORK_IDENTIFIER_BYTES];
+    let (client_longterm_pk, client_longterm_sk) = sign::gen_keypair();
+    let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+    let (server_longterm_pk, server_longterm_sk) = sign::gen_keypair();
+    let (server_ephemeral_pk, server_ephemeral_sk) = box_::gen_keypair();
+
+    let (client_stream, server_stream) = channel_pair();
+
+    let mut client = OwningClientHandshaker::new(client_stream.limited(16),
+                                                 network_identifier,
+                                                 client_longterm_pk,
+                                                 client_longterm_sk,
+                                                 client_ephemeral_pk,
+                                                 client_ephemeral_sk,
+                                                 server_longterm_pk);
+    let mut server = OwningServerHandshaker::new(server_stream.limited(16),
+                                                 network_identifier,
+                                                 server_longterm_pk,
+                                                 server_longterm_sk,
+                                                 server_ephemeral_pk,
+                                                 server_ephemeral_sk);
+
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+
+    let mut client_outcome = None;
+    let mut server_outcome = None;
+    let mut round = 0;
+    while client_outcome.is_none() || server_outcome.is_none() {
+        round += 1;
+        if round > 1000 {
+            panic!("the handshake made no progress");
+        }
+
+        if client_outcome.is_none() {
+            match Pin::new(&mut client).poll(&mut cx) {
+                Poll::Ready(Ok((outcome, _stream))) => client_outcome = Some(outcome),
+                Poll::Ready(Err((err, _stream))) => panic!("client failed: {}", err),
+                Poll::Pending => println!("round {}: {}", round, client.log_summary()),
+            }
+        }
+
+        if server_outcome.is_none() {
+            match Pin::new(&mut server).poll(&mut cx) {
+                Poll::Ready(Ok((outcome, _stream))) => server_outcome = Some(outcome),
+                Poll::Ready(Err((err, _stream))) => panic!("server failed: {}", err),
+                Poll::Pending => println!("round {}: {}", round, server.log_summary()),
+            }
+        }
+    }
+
+    let client_outcome = client_outcome.unwrap();
+    let server_outcome = server_outcome.unwrap();
+    assert_eq!(client_outcome.encryption_key(), server_outcome.decryption_key());
+    assert_eq!(client_outcome.decryption_key(), server_outcome.encryption_key());
+    println!("handshake completed after {} rounds", round);
+}
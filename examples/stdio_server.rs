@@ -0,0 +1,79 @@
+//! Performs the server side of a handshake over stdin and stdout, the way a peer reachable
+//! as a child process does, see the `process` module.
+//!
+//! Run with `stdio_server <network identifier> <server key seed>`, both as 64 hex digits.
+//! Prints the longterm public key of the client to stderr once the handshake succeeded.
+
+extern crate sodiumoxide;
+extern crate secret_handshake;
+extern crate futures;
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::process;
+
+use sodiumoxide::crypto::sign;
+use futures::executor::block_on;
+use futures::io::AllowStdIo;
+
+use secret_handshake::*;
+
+// Reads from stdin and writes to stdout.
+struct Stdio {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+fn hex_arg(arg: Option<String>) -> [u8; 32] {
+    let arg = arg.unwrap_or_default();
+    let mut bytes = [0; 32];
+    if arg.len() != 64 {
+        eprintln!("usage: stdio_server <network identifier> <server key seed>");
+        process::exit(2);
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&arg[2 * i..2 * i + 2], 16).unwrap_or_else(|_| {
+            eprintln!("not a hex string: {}", arg);
+            process::exit(2)
+        });
+    }
+    bytes
+}
+
+fn main() {
+    sodiumoxide::init();
+
+    let mut args = env::args().skip(1);
+    let network_identifier = hex_arg(args.next());
+    let (server_longterm_pk, server_longterm_sk) =
+        sign::keypair_from_seed(&sign::Seed(hex_arg(args.next())));
+    let acceptor = Acceptor::new(network_identifier, server_longterm_pk, server_longterm_sk);
+
+    let stream = AllowStdIo::new(Stdio {
+                                     stdin: io::stdin(),
+                                     stdout: io::stdout(),
+                                 });
+    match block_on(acceptor.accept(stream)) {
+        Ok((outcome, _)) => eprintln!("handshake with {:?} completed", outcome.peer_longterm_pk()),
+        Err((err, _)) => {
+            eprintln!("handshake failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! Accept handshakes on streams obtained by the caller.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sodiumoxide::crypto::sign;
+#[cfg(feature = "insecure-ephemeral-audit")]
+use sodiumoxide::crypto::box_;
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::*;
+use {BoxedHandshake, Handshake, HandshakePhase};
+use errors::*;
+use options::HandshakeOptions;
+use server::{Compact, ConstFuture, UnsafeServerHandshakerWithFilter, const_async_true};
+use proxy::{ProxyHeader, ProxyHeaderReader};
+use ip_filter::IpFilter;
+use replay::ReplayCache;
+use throttle::Throttle;
+use listener::{Listener, PeerInfo};
+use connection::Secured;
+use observer::{HandshakeObserver, HandshakeResult, Observation, RejectionLog};
+#[cfg(all(unix, feature = "systemd"))]
+use systemd::{self, ActivatedListener, SystemdError};
+
+/// Accepts handshakes using a fixed server identity, generating fresh ephemeral
+/// keys for each connection.
+///
+/// An `Acceptor` does not own a listener, it only holds the keys. Cloning it is
+/// cheap, so it can be shared between all tasks that accept connections. Use `incoming`
+/// to accept the connections of a `Listener`.
+#[derive(Clone)]
+pub struct Acceptor {
+    keys: Arc<AcceptorKeys>,
+    options: HandshakeOptions,
+    expect_proxy_protocol: bool,
+    ip_filter: Option<Arc<IpFilter>>,
+    filtered: Arc<AtomicUsize>, // number of connections dropped by the ip filter
+    replay_cache: Option<Arc<ReplayCache>>,
+    replayed: Arc<AtomicUsize>, // number of handshakes failed with `ReplayedEphemeral`
+    throttle: Option<Arc<Throttle>>,
+    rejection_log: Option<Arc<RejectionLog + Send + Sync>>,
+    observer: Option<Arc<HandshakeObserver + Send + Sync>>,
+    rng: Option<EphemeralRng>,
+}
+
+struct AcceptorKeys {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    server_longterm_pk: sign::PublicKey,
+    server_longterm_sk: sign::SecretKey,
+}
+
+impl Acceptor {
+    /// Creates a new Acceptor for the server with the given longterm keys, accepting
+    /// clients which use the given network identifier.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               server_longterm_pk: sign::PublicKey,
+               server_longterm_sk: sign::SecretKey)
+               -> Acceptor {
+        Acceptor {
+            keys: Arc::new(AcceptorKeys {
+                               network_identifier,
+                               server_longterm_pk,
+                               server_longterm_sk,
+                           }),
+            options: HandshakeOptions::default(),
+            expect_proxy_protocol: false,
+            ip_filter: None,
+            filtered: Arc::new(AtomicUsize::new(0)),
+            replay_cache: None,
+            replayed: Arc::new(AtomicUsize::new(0)),
+            throttle: None,
+            rejection_log: None,
+            observer: None,
+            rng: None,
+        }
+    }
+
+    /// Sets the options for all handshakes accepted by this Acceptor.
+    pub fn options(mut self, options: HandshakeOptions) -> Acceptor {
+        self.options = options;
+        self
+    }
+
+    /// If `expect` is true, all connections must start with a PROXY protocol header (version
+    /// 1 or 2), as sent by load balancers like HAProxy. The header is read before the
+    /// handshake starts, see `Accept::proxy_header`. Connections without a valid header
+    /// fail with `HandshakeError::InvalidProxyHeader`.
+    ///
+    /// Only enable this if all connections come through such a proxy, otherwise clients
+    /// could claim arbitrary addresses.
+    pub fn expect_proxy_protocol(mut self, expect: bool) -> Acceptor {
+        self.expect_proxy_protocol = expect;
+        self
+    }
+
+    /// Sets the filter that `accept_from` applies to the addresses of peers.
+    pub fn ip_filter(mut self, filter: IpFilter) -> Acceptor {
+        self.ip_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Remembers the ephemeral keys of clients in `cache`, shared by all clones of this
+    /// Acceptor, and fails handshakes that reuse a remembered key with
+    /// `HandshakeError::ReplayedEphemeral`. See the `replay` module. Disabled by default.
+    pub fn replay_cache(mut self, cache: ReplayCache) -> Acceptor {
+        self.replay_cache = Some(Arc::new(cache));
+        self
+    }
+
+    /// Tracks the clients that authenticate with this Acceptor and its clones in `throttle`,
+    /// and fails the handshakes of clients that reconnect too often with
+    /// `HandshakeError::Throttled`. See the `throttle` module. Disabled by default.
+    pub fn throttle(mut self, throttle: Throttle) -> Acceptor {
+        self.throttle = Some(Arc::new(throttle));
+        self
+    }
+
+    /// Records the clients that handshakes accepted by this Acceptor and its clones reject
+    /// after verifying them in `log`, i.e. unexpected and throttled clients. See
+    /// `RejectionLog`.
+    pub fn rejection_log<L>(mut self, log: L) -> Acceptor
+        where L: RejectionLog + Send + Sync + 'static
+    {
+        self.rejection_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Reports the handshakes accepted by this Acceptor and its clones to `observer`.
+    /// Connections dropped by the ip filter are not reported, see `filtered_connections`.
+    pub fn observer<O>(mut self, observer: O) -> Acceptor
+        where O: HandshakeObserver + Send + Sync + 'static
+    {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Generates the ephemeral keys of all handshakes accepted by this Acceptor and its
+    /// clones with `rng` instead of the random number generator of the operating system.
+    pub fn with_rng(mut self, rng: EphemeralRng) -> Acceptor {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// The number of connections that `accept_from` dropped because of the ip filter,
+    /// counted across all clones of this Acceptor.
+    pub fn filtered_connections(&self) -> usize {
+        self.filtered.load(Ordering::Relaxed)
+    }
+
+    /// The number of handshakes that failed with `HandshakeError::ReplayedEphemeral`,
+    /// counted across all clones of this Acceptor.
+    pub fn replayed_handshakes(&self) -> usize {
+        self.replayed.load(Ordering::Relaxed)
+    }
+
+    /// Strikes `client` in the throttle, for clients that completed the handshake but were
+    /// rejected by the application afterwards. Returns how long the client is blocked, or
+    /// `None` if this Acceptor has no throttle.
+    pub fn reject_client(&self, client: &sign::PublicKey) -> Option<Duration> {
+        self.throttle.as_ref().map(|throttle| throttle.reject(client))
+    }
+
+    /// Like `accept`, but first checks the address of the peer against the ip filter (see
+    /// `ip_filter`), before any bytes are read from the stream. If the address is not
+    /// allowed, the stream is returned in an `Err` and should be closed.
+    pub fn accept_from<S: AsyncRead + AsyncWrite>(&self,
+                                                  stream: S,
+                                                  peer: IpAddr)
+                                                  -> Result<Accept<S>, S> {
+        if let Some(ref filter) = self.ip_filter {
+            if !filter.is_allowed(peer) {
+                self.filtered.fetch_add(1, Ordering::Relaxed);
+                return Err(stream);
+            }
+        }
+
+        Ok(self.accept(stream))
+    }
+
+    /// Returns a stream of handshakes on the connections accepted by `listener`, together
+    /// with metadata about their peers. Connections from ip addresses rejected by the ip
+    /// filter are dropped right away, see `accept_from`.
+    ///
+    /// The stream only fails if accepting a connection fails, the returned handshakes
+    /// still need to be driven to completion (or dropped) by the caller.
+    pub fn incoming<L: Listener>(&self, listener: L) -> Incoming<L> {
+        Incoming {
+            acceptor: self.clone(),
+            listener,
+        }
+    }
+
+    /// Takes the listeners passed by systemd socket activation and returns a stream of
+    /// handshakes for each of them, see the `systemd` module.
+    ///
+    /// Only available on unix, with the `systemd` feature.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn incoming_from_listen_fds(&self)
+                                    -> Result<Vec<Incoming<ActivatedListener>>, SystemdError> {
+        Ok(systemd::listen_fds()?
+               .into_iter()
+               .map(|listener| self.incoming(listener))
+               .collect())
+    }
+
+    /// Like `accept`, but the returned future yields a `SecuredConnection`.
+    pub fn accept_secured<S: AsyncRead + AsyncWrite>(&self, stream: S) -> Secured<Accept<S>> {
+        Secured::new(self.accept(stream))
+    }
+
+    /// Returns a future that performs the server side of a handshake over the
+    /// given `stream`, using a freshly generated ephemeral keypair.
+    pub fn accept<S: AsyncRead + AsyncWrite>(&self, stream: S) -> Accept<S> {
+        let keys = &self.keys;
+        let (server_ephemeral_pk, server_ephemeral_sk) =
+            generate_ephemeral_keypair(self.rng.as_ref());
+
+        let mut inner = UnsafeServerHandshakerWithFilter::new(stream,
+                                                              const_async_true as AcceptAll,
+                                                              &keys.network_identifier,
+                                                              &keys.server_longterm_pk,
+                                                              &keys.server_longterm_sk,
+                                                              &server_ephemeral_pk,
+                                                              &server_ephemeral_sk);
+        inner.driver.core.options = self.options;
+        inner.driver.replay_cache = self.replay_cache.clone();
+        inner.driver.throttle = self.throttle.clone();
+        inner.driver.rejection_log = self.rejection_log.clone();
+
+        Accept {
+            inner,
+            proxy: if self.expect_proxy_protocol {
+                Some(ProxyHeaderReader::new())
+            } else {
+                None
+            },
+            proxy_header: None,
+            observation: self.observer.clone().map(Observation::start),
+            replayed: self.replayed.clone(),
+            #[cfg(feature = "insecure-ephemeral-audit")]
+            server_ephemeral_sk,
+        }
+    }
+}
+
+/// A stream of handshakes on incoming connections, created via `Acceptor::incoming`.
+pub struct Incoming<L> {
+    acceptor: Acceptor,
+    listener: L,
+}
+
+impl<L> Incoming<L> {
+    /// Returns the underlying listener.
+    pub fn into_inner(self) -> L {
+        self.listener
+    }
+}
+
+impl<L: Listener + Unpin> Stream for Incoming<L> {
+    type Item = Result<(Accept<L::Stream>, PeerInfo), io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            let (stream, peer) = match self.listener.poll_accept(cx)? {
+                Ready(accepted) => accepted,
+                Pending => return Pending,
+            };
+
+            let accept = match peer.ip() {
+                Some(ip) => self.acceptor.accept_from(stream, ip),
+                None => Ok(self.acceptor.accept(stream)),
+            };
+            if let Ok(accept) = accept {
+                return Ready(Some(Ok((accept, peer))));
+            }
+        }
+    }
+}
+
+// The filter function of the inner handshaker, which accepts all clients.
+pub(crate) type AcceptAll = fn(&sign::PublicKey) -> ConstFuture;
+
+/// Future returned by `Acceptor::accept`, resolving to the outcome of the handshake.
+///
+/// To keep many pending handshakes cheap, the crypto state and the message buffer live in
+/// a single heap allocation, which is zeroed and freed as soon as the handshake completes.
+/// The server handshakers created directly keep them inline instead.
+pub struct Accept<S> {
+    inner: UnsafeServerHandshakerWithFilter<S, AcceptAll, ConstFuture, Compact>,
+    proxy: Option<ProxyHeaderReader>, // reads the proxy header before the handshake starts
+    proxy_header: Option<ProxyHeader>,
+    observation: Option<Observation>, // reports the end of the handshake to the observer
+    replayed: Arc<AtomicUsize>, // the `Acceptor::replayed_handshakes` counter
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    server_ephemeral_sk: box_::SecretKey,
+}
+
+impl<S> Accept<S> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details, the server reports a fingerprint of
+    /// the client's longterm public key once it has been verified.
+    pub fn log_summary(&self) -> String {
+        self.inner.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.inner.progress()
+    }
+
+    /// The PROXY protocol header of the connection, once it has been read. Always `None`
+    /// unless the `Acceptor` was configured with `expect_proxy_protocol(true)`.
+    ///
+    /// To access it after the handshake, poll the `Accept` by reference, or use
+    /// `Acceptor::accept_secured`, whose `SecuredConnection` carries the header.
+    pub fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.proxy_header
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize) -> Accept<S> {
+        self.inner.driver.core.options.zero_read_tolerance = n;
+        self
+    }
+
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    /// Returns the ephemeral secret key used by this handshake.
+    ///
+    /// **Insecure, for audits and tests only.** See
+    /// `OwningClientHandshaker::insecure_ephemeral_secret_key`.
+    pub fn insecure_ephemeral_secret_key(&self) -> &box_::SecretKey {
+        &self.server_ephemeral_sk
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> Accept<S> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> Accept<S> {
+        self.inner.driver.core.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions) -> Accept<S> {
+        self.inner.driver.core.options = options;
+        self
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Accept<S> {
+    /// Boxes this handshake into a `BoxedHandshake`. See `ClientHandshaker::boxed` for
+    /// details, including the cost of the allocation.
+    pub fn boxed<'a>(self) -> BoxedHandshake<'a, S>
+        where S: Unpin + 'a
+    {
+        Box::pin(self)
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Accept<S> {
+    type Output = Result<(Outcome, S), (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let result = self.poll_unobserved(cx);
+        let observed = match result {
+            Ready(Ok(_)) => HandshakeResult::Success,
+            Pending => return result,
+            Ready(Err((ref err, _))) => HandshakeResult::from(err),
+        };
+        if observed == HandshakeResult::ReplayedEphemeral {
+            self.replayed.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(observation) = self.observation.take() {
+            observation.finish(observed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake for Accept<S> {
+    type Stream = S;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        Pin::new(self).poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        self.inner.phase()
+    }
+
+    fn abort(&mut self) -> Option<S> {
+        self.inner.abort()
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        self.inner.peer_pk()
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.inner.rtt_estimate()
+    }
+
+    fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.proxy_header
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Accept<S> {
+    fn poll_unobserved(&mut self,
+                       cx: &mut Context)
+                       -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        if let Some(mut proxy) = self.proxy.take() {
+            match proxy.poll_header(cx, self.inner.stream_mut()) {
+                Ready(Ok(header)) => self.proxy_header = Some(header),
+                Pending => {
+                    self.proxy = Some(proxy);
+                    return Pending;
+                }
+                Ready(Err(err)) => return Ready(Err((err, self.inner.stream_take()))),
+            }
+        }
+
+        Pin::new(&mut self.inner)
+            .poll(cx)
+            .map_err(|(err, stream)| (accept_all_error(err), stream))
+    }
+}
+
+// Converts the errors of a handshaker whose filter function is `AcceptAll`.
+pub(crate) fn accept_all_error(err: FilteringHandshakeError<Infallible>) -> HandshakeError {
+    match err {
+        FilteringHandshakeError::IoError(io_err) => io_err.into(),
+        FilteringHandshakeError::FilterError(_) => unreachable!(),
+        FilteringHandshakeError::CryptoError => HandshakeError::CryptoError,
+        FilteringHandshakeError::Rejected(_) => unreachable!(),
+        FilteringHandshakeError::WeakSharedSecret => HandshakeError::WeakSharedSecret,
+        FilteringHandshakeError::AuthorizerTimeout => HandshakeError::AuthorizerTimeout,
+        FilteringHandshakeError::PreAuthFailed => HandshakeError::PreAuthFailed,
+        FilteringHandshakeError::ReplayedEphemeral => HandshakeError::ReplayedEphemeral,
+        FilteringHandshakeError::UnexpectedClient { expected, actual } => {
+            HandshakeError::UnexpectedClient { expected, actual }
+        }
+        FilteringHandshakeError::Throttled { retry_after } => {
+            HandshakeError::Throttled { retry_after }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Delegate the decisions of a filtering server to a central task.
+//!
+//! `authorizer_channel` returns a `ChannelAuthorizer`, which handshakes use to send
+//! authorization requests, and an `AuthorizerService`, a stream of these requests that the
+//! policy task drives:
+//!
+//! ```rust,ignore
+//! let (authorizer, service) = authorizer_channel();
+//!
+//! // in the policy task
+//! service.for_each(|request| {
+//!     let allowed = is_allowed(request.longterm_pk());
+//!     request.respond(allowed);
+//!     future::ready(())
+//! });
+//!
+//! // for each connection
+//! let server = OwningServerHandshakerWithFilter::new(stream,
+//!                                                    move |pk| authorizer.authorize(pk),
+//!                                                    ...)
+//!         .filter_timeout(Duration::from_secs(5));
+//! ```
+//!
+//! Use `authorize_from` instead of `authorize` to also tell the policy task the address of
+//! the client, e.g. the one from a PROXY protocol header (see the `proxy` module).
+//!
+//! If the policy task has shut down, handshakes fail closed with an
+//! `AuthorizerShutdown` error. To bound how long a handshake waits for a slow policy task,
+//! use `filter_timeout`.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::Ready;
+
+use sodiumoxide::crypto::sign;
+use futures_core::Stream;
+use futures_channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
+use futures_channel::oneshot;
+
+/// Creates a connected `ChannelAuthorizer` and `AuthorizerService`.
+pub fn authorizer_channel() -> (ChannelAuthorizer, AuthorizerService) {
+    let (sender, receiver) = unbounded();
+    (ChannelAuthorizer(sender), AuthorizerService(receiver))
+}
+
+/// Sends authorization requests to an `AuthorizerService`. Cloning it is cheap, all clones
+/// send to the same service.
+#[derive(Clone)]
+pub struct ChannelAuthorizer(UnboundedSender<AuthorizationRequest>);
+
+impl ChannelAuthorizer {
+    /// Asks the service whether the client with the given longterm public key should be
+    /// accepted. Suitable as the filter function of a filtering server handshaker.
+    pub fn authorize(&self, longterm_pk: &sign::PublicKey) -> Authorization {
+        self.authorize_from(longterm_pk, None)
+    }
+
+    /// Like `authorize`, but also tells the service the address of the client, see
+    /// `AuthorizationRequest::peer_addr`.
+    pub fn authorize_from(&self,
+                          longterm_pk: &sign::PublicKey,
+                          peer_addr: Option<SocketAddr>)
+                          -> Authorization {
+        let (reply, receiver) = oneshot::channel();
+        let request = AuthorizationRequest {
+            longterm_pk: longterm_pk.clone(),
+            peer_addr,
+            reply,
+        };
+
+        match self.0.unbounded_send(request) {
+            Ok(()) => Authorization(Some(receiver)),
+            Err(_) => Authorization(None),
+        }
+    }
+}
+
+/// Future returned by `ChannelAuthorizer::authorize`, resolving to the decision of the
+/// service.
+pub struct Authorization(Option<oneshot::Receiver<bool>>); // None if the request could not be sent
+
+impl Future for Authorization {
+    type Output = Result<bool, AuthorizerShutdown>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.0 {
+            None => Ready(Err(AuthorizerShutdown)),
+            Some(ref mut receiver) => {
+                Pin::new(receiver).poll(cx).map_err(|_| AuthorizerShutdown)
+            }
+        }
+    }
+}
+
+/// The stream of authorization requests sent by `ChannelAuthorizer`s. It ends once all
+/// authorizers have been dropped.
+pub struct AuthorizerService(UnboundedReceiver<AuthorizationRequest>);
+
+impl Stream for AuthorizerService {
+    type Item = AuthorizationRequest;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+/// A request to decide whether a client should be accepted.
+///
+/// Dropping a request without responding fails the handshake with `AuthorizerShutdown`.
+#[derive(Debug)]
+pub struct AuthorizationRequest {
+    longterm_pk: sign::PublicKey,
+    peer_addr: Option<SocketAddr>,
+    reply: oneshot::Sender<bool>,
+}
+
+impl AuthorizationRequest {
+    /// The verified longterm public key of the client.
+    pub fn longterm_pk(&self) -> &sign::PublicKey {
+        &self.longterm_pk
+    }
+
+    /// The address of the client, if the handshake was started with `authorize_from`.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Accepts the client if `allowed` is true, rejects it otherwise.
+    pub fn respond(self, allowed: bool) {
+        // If the handshake was abandoned (e.g. it timed out), nobody is interested in the
+        // decision anymore.
+        let _ = self.reply.send(allowed);
+    }
+}
+
+/// The `AuthorizerService` was dropped before deciding about a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthorizerShutdown;
+
+impl Display for AuthorizerShutdown {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Authorizer error: the authorizer service has shut down")
+    }
+}
+
+impl Error for AuthorizerShutdown {
+    fn description(&self) -> &str {
+        "the authorizer service has shut down"
+    }
+}
+
@@ -0,0 +1,77 @@
+//! Perform handshakes over owned blocking `std::io` streams, e.g. in a thread-per-connection
+//! server built on `std::net::TcpStream`.
+//!
+//! Like the functions of the `sync_io` module, `client_handshake_blocking` and
+//! `server_handshake_blocking` drive a `ClientHandshakeMachine` or `ServerHandshakeMachine`
+//! with blocking reads and writes on the calling thread. They take ownership of the stream
+//! and hand it back together with the outcome or the error, like the asynchronous
+//! handshakers:
+//!
+//! ```rust,ignore
+//! let (stream, _) = listener.accept()?;
+//! thread::spawn(move || {
+//!     match blocking::server_handshake_blocking(stream, &network_identifier, &pk, &sk,
+//!                                               &eph_pk, &eph_sk) {
+//!         Ok((outcome, stream)) => { /* start the box-stream */ }
+//!         Err((err, stream)) => { /* log the error, dropping closes the stream */ }
+//!     }
+//! });
+//! ```
+//!
+//! `Interrupted` errors are retried. A read of zero bytes fails with `UnexpectedEof` and a
+//! write of zero bytes with `WriteZero`, with the same messages as the asynchronous
+//! handshakers (e.g. `"failed to read msg2"`). Nothing is read past the last handshake
+//! message.
+
+use std::io::{Read, Write};
+
+use sodiumoxide::crypto::{box_, sign};
+
+use crypto::*;
+use errors::HandshakeError;
+use sync_io::drive;
+use {ClientHandshakeMachine, ServerHandshakeMachine};
+
+/// Performs the client side of a handshake over `stream`, and returns its outcome and the
+/// stream. See `ClientHandshaker::new` for the arguments.
+pub fn client_handshake_blocking<RW: Read + Write>
+    (mut stream: RW,
+     network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+     client_longterm_pk: &sign::PublicKey,
+     client_longterm_sk: &sign::SecretKey,
+     client_ephemeral_pk: &box_::PublicKey,
+     client_ephemeral_sk: &box_::SecretKey,
+     server_longterm_pk: &sign::PublicKey)
+     -> Result<(Outcome, RW), (HandshakeError, RW)> {
+    let mut machine = ClientHandshakeMachine::new(network_identifier,
+                                                  client_longterm_pk,
+                                                  client_longterm_sk,
+                                                  client_ephemeral_pk,
+                                                  client_ephemeral_sk,
+                                                  server_longterm_pk);
+    match drive(&mut stream, &mut machine) {
+        Ok(outcome) => Ok((outcome, stream)),
+        Err(err) => Err((err, stream)),
+    }
+}
+
+/// Performs the server side of a handshake over `stream`, accepting any client, and returns
+/// its outcome and the stream. See `ServerHandshaker::new` for the arguments.
+pub fn server_handshake_blocking<RW: Read + Write>
+    (mut stream: RW,
+     network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+     server_longterm_pk: &sign::PublicKey,
+     server_longterm_sk: &sign::SecretKey,
+     server_ephemeral_pk: &box_::PublicKey,
+     server_ephemeral_sk: &box_::SecretKey)
+     -> Result<(Outcome, RW), (HandshakeError, RW)> {
+    let mut machine = ServerHandshakeMachine::new(network_identifier,
+                                                  server_longterm_pk,
+                                                  server_longterm_sk,
+                                                  server_ephemeral_pk,
+                                                  server_ephemeral_sk);
+    match drive(&mut stream, &mut machine) {
+        Ok(outcome) => Ok((outcome, stream)),
+        Err(err) => Err((err, stream)),
+    }
+}
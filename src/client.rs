@@ -1,20 +1,38 @@
 //! Asynchronously initiate handshakes.
 
+use std::cmp::min;
+use std::fmt;
+use std::future::Future;
 use std::marker::PhantomData;
-use std::mem::uninitialized;
-use std::io::ErrorKind::{WriteZero, UnexpectedEof};
+#[cfg(feature = "crypto-pool")]
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant};
+use std::io::ErrorKind::{WriteZero, UnexpectedEof, Interrupted};
 
 use sodiumoxide::crypto::{box_, sign};
 use sodiumoxide::utils::memzero;
-use futures_core::{Poll, Future};
-use futures_core::Async::{Ready, Pending};
-use futures_core::task::Context;
 use futures_io::{AsyncRead, AsyncWrite, Error};
 
 use crypto::*;
-use errors::HandshakeError;
+use {BoxedHandshake, Handshake, HandshakePhase};
+use options::HandshakeOptions;
+use pre_auth::PRE_AUTH_BYTES;
+use errors::{HandshakeError, RETRY_LIMIT, is_retryable, overlong_read, overlong_write};
+#[cfg(feature = "crypto-pool")]
+use crypto_pool::{self, Job};
+#[cfg(feature = "insecure-key-schedule-trace")]
+use key_schedule;
 
 /// Performs the client side of a handshake.
+///
+/// Polling the handshaker again after it resolved or failed does not panic, it stays pending
+/// forever like a fused future, and `Handshake::phase` returns `HandshakePhase::Finished`.
+///
+/// The handshaker is a `Future` for `Unpin` streams only, since it resolves to the stream. For
+/// `!Unpin` streams, pin the handshaker and drive it with `poll_pinned` instead.
 pub struct ClientHandshaker<'a, S>(UnsafeClientHandshaker<S>, PhantomData<&'a u8>);
 
 impl<'a, S: AsyncRead + AsyncWrite> ClientHandshaker<'a, S> {
@@ -37,28 +55,193 @@ impl<'a, S: AsyncRead + AsyncWrite> ClientHandshaker<'a, S> {
                                                      server_longterm_pk),
                          PhantomData)
     }
+
+    /// Boxes this handshaker into a `BoxedHandshake`, erasing its concrete type so that it
+    /// can be stored alongside other client and server handshakes, e.g. in a `Vec` of
+    /// pending handshakes.
+    ///
+    /// This costs one heap allocation for the handshaker, and every `poll` becomes a
+    /// dynamically dispatched call. Prefer the concrete type where it can be named.
+    pub fn boxed(self) -> BoxedHandshake<'a, S>
+        where S: Unpin + 'a
+    {
+        Box::pin(self)
+    }
+
+    /// Drives the handshake like `Future::poll`, but with the stream pinned in place inside the
+    /// handshaker, so that it also works for `!Unpin` streams. Rather than boxing the stream,
+    /// pin the handshaker, e.g. with `std::pin::pin!`, and poll it with
+    /// `poll_fn(|cx| handshaker.as_mut().poll_pinned(cx))`.
+    ///
+    /// This resolves to the outcome or the error only, since a pinned stream can not be moved
+    /// out of the handshaker. The stream stays in place either way, and `get_pin_mut` gives
+    /// access to it afterwards, e.g. to shut the connection down after an error. Polling again
+    /// after the handshake resolved or failed stays pending forever.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, HandshakeError>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.poll_pinned(cx)
+    }
+
+    /// Returns the stream, pinned in place, see `poll_pinned`.
+    ///
+    /// Panics if the stream has already been handed out by polling the handshaker as a
+    /// `Future`.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.get_pin_mut()
+    }
+}
+
+impl<'a, S> ClientHandshaker<'a, S> {
+    /// Stops the handshake and returns the underlying stream.
+    ///
+    /// Handshakers are cancel-safe: whenever `poll` returns `Pending`, the stream is back
+    /// in the handshaker, so it can be dropped or taken apart at that point (e.g. when it
+    /// loses a select). Parts of the handshake may already have been written to or read
+    /// from the stream though, so it can only be reused for a new handshake if the peer
+    /// is known to not have sent or received anything yet.
+    ///
+    /// Panics if the handshake has already completed or failed.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. It contains
+    /// no secrets, only the current state, how many bytes of the current message have been
+    /// transmitted, and a fingerprint of the server's longterm public key, e.g.
+    /// `shs-client state=ReadMsg2 offset=12/64 server=4f4f4deefed781c5`.
+    pub fn log_summary(&self) -> String {
+        self.0.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0: the fraction of the
+    /// `HANDSHAKE_TOTAL_BYTES` bytes of all four messages that have been transmitted, e.g. for
+    /// a progress bar.
+    pub fn progress(&self) -> f32 {
+        self.0.progress()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream.
+    ///
+    /// A zero-length read signals that the peer closed the connection, so by default
+    /// (`n == 0`) the handshake fails with `UnexpectedEof` right away. Only raise this
+    /// for streams that are known to spuriously return zero-length reads: on an actually
+    /// closed connection, each retry just wakes the task and polls again, delaying the
+    /// error. The retries involve no backoff, so the task spins through all `n` of them.
+    pub fn zero_read_tolerance(mut self, n: usize) -> ClientHandshaker<'a, S> {
+        self.0.driver.machine.options.zero_read_tolerance = n;
+        self
+    }
+
+    /// Yield to other tasks during the handshake, by waking the task and returning
+    /// `Pending` after every `FAIR_BUDGET` state transitions within a single poll.
+    ///
+    /// Over fast transports (in-memory streams, loopback), a single poll may otherwise
+    /// run the whole handshake, including all of its crypto, without giving other
+    /// tasks on the same thread a chance to run. This costs some throughput, so it
+    /// is disabled by default.
+    pub fn fair(self) -> ClientHandshaker<'a, S> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> ClientHandshaker<'a, S> {
+        self.0.driver.machine.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions) -> ClientHandshaker<'a, S> {
+        self.0.driver.machine.options = options;
+        self
+    }
+
+    /// Writes the outcome of the handshake directly into `slot` instead of returning an
+    /// `Outcome`, so that the keys never materialize in any other memory. The slot can
+    /// live in memory the caller controls, see `SecureOutcomeSlot::from_bytes_mut`.
+    ///
+    /// The intermediate secrets of the handshake are zeroed once the returned future is
+    /// dropped.
+    pub fn with_outcome_sink(self,
+                             slot: &'a mut SecureOutcomeSlot)
+                             -> ClientHandshakerWithSink<'a, S> {
+        ClientHandshakerWithSink {
+            inner: self.0,
+            slot,
+        }
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
-impl<'a, S: AsyncRead + AsyncWrite> Future for ClientHandshaker<'a, S> {
-    type Item = (Outcome, S);
-    type Error = (HandshakeError, S);
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for ClientHandshaker<'a, S> {
+    type Output = Result<(Outcome, S), (HandshakeError, S)>;
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         self.0.poll(cx)
     }
 }
 
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Handshake for ClientHandshaker<'a, S> {
+    type Stream = S;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        self.0.poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        self.0.phase()
+    }
+
+    fn abort(&mut self) -> Option<S> {
+        self.0.stream.take()
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        Some(sign::PublicKey(self.0.driver.server_longterm_pk))
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.0.rtt_estimate()
+    }
+}
+
+/// Performs the client side of a handshake, writing the outcome into a
+/// `SecureOutcomeSlot` instead of returning it. Created via
+/// `ClientHandshaker::with_outcome_sink`.
+pub struct ClientHandshakerWithSink<'a, S> {
+    inner: UnsafeClientHandshaker<S>,
+    slot: &'a mut SecureOutcomeSlot,
+}
+
+/// Future implementation to asynchronously drive a handshake.
+///
+/// Resolves to the stream once the outcome has been written into the slot.
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for ClientHandshakerWithSink<'a, S> {
+    type Output = Result<S, (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        match this.inner.poll_verified(cx)? {
+            Ready(stream) => {
+                this.inner.driver.machine.write_outcome(this.slot.as_outcome_mut());
+                Ready(Ok(stream))
+            }
+            Pending => Pending,
+        }
+    }
+}
+
 /// Performs the client side of a handshake. This copies the keys so that it isn't constrainted by
 /// their lifetime.
 pub struct OwningClientHandshaker<S> {
-    network_identifier: Box<[u8; NETWORK_IDENTIFIER_BYTES]>,
-    client_longterm_pk: Box<sign::PublicKey>,
-    client_longterm_sk: Box<sign::SecretKey>,
-    client_ephemeral_pk: Box<box_::PublicKey>,
-    client_ephemeral_sk: Box<box_::SecretKey>,
-    server_longterm_pk: Box<sign::PublicKey>,
     inner: UnsafeClientHandshaker<S>,
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    client_ephemeral_sk: box_::SecretKey,
 }
 
 impl<S: AsyncRead + AsyncWrite> OwningClientHandshaker<S> {
@@ -72,250 +255,920 @@ impl<S: AsyncRead + AsyncWrite> OwningClientHandshaker<S> {
                client_ephemeral_sk: box_::SecretKey,
                server_longterm_pk: sign::PublicKey)
                -> OwningClientHandshaker<S> {
-        let network_identifier = Box::new(network_identifier.clone());
-        let client_longterm_pk = Box::new(client_longterm_pk.clone());
-        let client_longterm_sk = Box::new(client_longterm_sk.clone());
-        let client_ephemeral_pk = Box::new(client_ephemeral_pk.clone());
-        let client_ephemeral_sk = Box::new(client_ephemeral_sk.clone());
-        let server_longterm_pk = Box::new(server_longterm_pk.clone());
-
         OwningClientHandshaker {
             inner: UnsafeClientHandshaker::new(stream,
-                                               network_identifier.as_ref(),
-                                               client_longterm_pk.as_ref(),
-                                               client_longterm_sk.as_ref(),
-                                               client_ephemeral_pk.as_ref(),
-                                               client_ephemeral_sk.as_ref(),
-                                               server_longterm_pk.as_ref()),
-            network_identifier,
-            client_longterm_pk,
-            client_longterm_sk,
-            client_ephemeral_pk,
+                                               &network_identifier,
+                                               &client_longterm_pk,
+                                               &client_longterm_sk,
+                                               &client_ephemeral_pk,
+                                               &client_ephemeral_sk,
+                                               &server_longterm_pk),
+            #[cfg(feature = "insecure-ephemeral-audit")]
             client_ephemeral_sk,
-            server_longterm_pk,
         }
     }
 }
 
+impl<S: AsyncRead + AsyncWrite> OwningClientHandshaker<S> {
+    pub(crate) fn rejected_by_server(&self, err: &HandshakeError) -> bool {
+        self.inner.rejected_by_server(err)
+    }
+
+    /// Boxes this handshaker into a `BoxedHandshake`. See `ClientHandshaker::boxed` for
+    /// details, including the cost of the allocation.
+    pub fn boxed<'a>(self) -> BoxedHandshake<'a, S>
+        where S: Unpin + 'a
+    {
+        Box::pin(self)
+    }
+
+    /// Drives the handshake with the stream pinned in place, for `!Unpin` streams. See
+    /// `ClientHandshaker::poll_pinned` for details.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, HandshakeError>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.inner) }.poll_pinned(cx)
+    }
+
+    /// Returns the stream, pinned in place. See `ClientHandshaker::poll_pinned` for details.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.inner) }.get_pin_mut()
+    }
+}
+
+impl<S> OwningClientHandshaker<S> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details.
+    pub fn log_summary(&self) -> String {
+        self.inner.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.inner.progress()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize) -> OwningClientHandshaker<S> {
+        self.inner.driver.machine.options.zero_read_tolerance = n;
+        self
+    }
+
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    /// Returns the ephemeral secret key used by this handshake.
+    ///
+    /// **Insecure, for audits and tests only.** Anyone who learns this key can decrypt
+    /// everything sent over the resulting connection, which voids the forward secrecy of the
+    /// protocol. This is only available with the `insecure-ephemeral-audit` feature, never
+    /// enable it in production builds.
+    pub fn insecure_ephemeral_secret_key(&self) -> &box_::SecretKey {
+        &self.client_ephemeral_sk
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> OwningClientHandshaker<S> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> OwningClientHandshaker<S> {
+        self.inner.driver.machine.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions) -> OwningClientHandshaker<S> {
+        self.inner.driver.machine.options = options;
+        self
+    }
+}
+
 /// Future implementation to asynchronously drive a handshake.
-impl<S: AsyncRead + AsyncWrite> Future for OwningClientHandshaker<S> {
-    type Item = (Outcome, S);
-    type Error = (HandshakeError, S);
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for OwningClientHandshaker<S> {
+    type Output = Result<(Outcome, S), (HandshakeError, S)>;
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         self.inner.poll(cx)
     }
 }
 
-// Performs the client side of a handshake.
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake for OwningClientHandshaker<S> {
+    type Stream = S;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        self.inner.poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        self.inner.phase()
+    }
+
+    fn abort(&mut self) -> Option<S> {
+        self.inner.stream.take()
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        Some(sign::PublicKey(self.inner.driver.server_longterm_pk))
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.inner.rtt_estimate()
+    }
+}
+
+// Performs the client side of a handshake, by driving a `ClientHandshakeMachine` over the
+// stream.
+//
+// The stream is structurally pinned: `poll_pinned` and `get_pin_mut` hand it out as a
+// `Pin<&mut S>` once the handshaker is pinned. This is sound because nothing moves the stream
+// out of a pinned handshaker. The handshaker is only `Unpin` if `S` is, everything that takes
+// the stream out through `&mut self` (`poll`, `Handshake::abort`) requires `S: Unpin`,
+// `into_inner` consumes the handshaker, and no `Drop` impl touches the stream.
 struct UnsafeClientHandshaker<S> {
-    stream: Option<S>,
+    stream: Option<S>, // `None` once the stream has been handed out
+    finished: bool, // whether the handshake resolved or failed with the stream pinned in place
+    driver: ClientDriver,
+}
+
+// Everything of an `UnsafeClientHandshaker` but the stream, which it is handed on every poll.
+struct ClientDriver {
+    machine: ClientHandshakeMachine<'static>, // owns copies of the keys, so it borrows nothing
+    zero_reads: usize, // number of consecutive zero-length reads so far
+    retries: usize, // number of consecutive retryable errors so far
+    transitions: usize, // state transitions during the current poll
+    server_longterm_pk: [u8; sign::PUBLICKEYBYTES], // for logging and `peer_pk`
+    msg1_flushed_at: Option<Instant>, // for `rtt_estimate`
+    msg2_received_at: Option<Instant>, // when the first byte of msg2 was read
+    #[cfg(feature = "crypto-pool")]
+    job: Option<ClientJob>, // a crypto step running on the pool
+}
+
+// A cpu-bound step of the client, performed after reading msg2 or msg4.
+type CryptoStep = fn(&mut Client, &mut [u8; MSG3_BYTES], &HandshakeOptions)
+                     -> Result<(), HandshakeError>;
+
+#[cfg(feature = "crypto-pool")]
+type ClientJob = Job<(Box<Offloaded>, Result<(), HandshakeError>)>;
+
+// The state a crypto step works on while it runs on the pool.
+#[cfg(feature = "crypto-pool")]
+struct Offloaded {
     client: Client,
-    state: State,
-    data: [u8; MSG3_BYTES], // used to hold and cache the results of `client.create_client_challenge` and `client.create_client_auth`, and any data read from the server
-    offset: usize, // offset into the data array at which to read/write
+    data: [u8; MSG3_BYTES],
+}
+
+// Zero buffered handshake data on dropping, the client zeroes itself.
+#[cfg(feature = "crypto-pool")]
+impl Drop for Offloaded {
+    fn drop(&mut self) {
+        memzero(&mut self.data);
+    }
+}
+
+// Verifies msg2 and creates msg3.
+fn after_msg2(client: &mut Client,
+              data: &mut [u8; MSG3_BYTES],
+              _: &HandshakeOptions)
+              -> Result<(), HandshakeError> {
+    let msg2 = unsafe { &*(data as *const [u8; MSG3_BYTES] as *const [u8; MSG2_BYTES]) };
+    if !client.verify_msg2(msg2) {
+        #[cfg(feature = "insecure-key-schedule-trace")]
+        key_schedule::report(&client.trace_msg2(msg2));
+        return Err(HandshakeError::CryptoError);
+    }
+
+    if client.ephemeral_keys_match() {
+        return Err(HandshakeError::WeakSharedSecret);
+    }
+
+    client.create_msg3(data);
+    Ok(())
+}
+
+// Verifies msg4.
+fn after_msg4(client: &mut Client,
+              data: &mut [u8; MSG3_BYTES],
+              options: &HandshakeOptions)
+              -> Result<(), HandshakeError> {
+    let msg4 = unsafe { &*(data as *const [u8; MSG3_BYTES] as *const [u8; MSG4_BYTES]) };
+    let verified = match options.aad {
+        Some(ref aad) => client.verify_msg4_with_aad(msg4, aad),
+        None => client.verify_msg4(msg4),
+    };
+    if !verified {
+        #[cfg(feature = "insecure-key-schedule-trace")]
+        key_schedule::report(&client.trace_msg4(msg4, options.aad.as_ref()));
+        return Err(HandshakeError::CryptoError);
+    }
+    Ok(())
 }
 
 impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
     // Creates a new UnsafeClientHandshaker to connect to a server with known public key
-    // and app key over the given `stream`.
+    // and app key over the given `stream`. The client copies the keys.
     fn new(stream: S,
-           network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
-           client_longterm_pk: *const sign::PublicKey,
-           client_longterm_sk: *const sign::SecretKey,
-           client_ephemeral_pk: *const box_::PublicKey,
-           client_ephemeral_sk: *const box_::SecretKey,
-           server_longterm_pk: *const sign::PublicKey)
+           network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+           client_longterm_pk: &sign::PublicKey,
+           client_longterm_sk: &sign::SecretKey,
+           client_ephemeral_pk: &box_::PublicKey,
+           client_ephemeral_sk: &box_::SecretKey,
+           server_longterm_pk: &sign::PublicKey)
            -> UnsafeClientHandshaker<S> {
-        unsafe {
-            let mut ret = UnsafeClientHandshaker {
-                stream: Some(stream),
-                client: Client::new(network_identifier,
-                                    &(*client_longterm_pk).0,
-                                    &(*client_longterm_sk).0,
-                                    &(*client_ephemeral_pk).0,
-                                    &(*client_ephemeral_sk).0,
-                                    &(*server_longterm_pk).0),
-                state: WriteMsg1,
-                data: [0; MSG3_BYTES],
-                offset: 0,
-            };
-            ret.client
-                .create_msg1(&mut *(&mut ret.data as *mut [u8; MSG3_BYTES] as
-                                    *mut [u8; MSG1_BYTES]));
+        UnsafeClientHandshaker {
+            stream: Some(stream),
+            finished: false,
+            driver: ClientDriver {
+                machine: ClientHandshakeMachine::with_client(Client::from_keys(network_identifier,
+                                                                               client_longterm_pk,
+                                                                               client_longterm_sk,
+                                                                               client_ephemeral_pk,
+                                                                               client_ephemeral_sk,
+                                                                               server_longterm_pk)),
+                zero_reads: 0,
+                retries: 0,
+                transitions: 0,
+                server_longterm_pk: server_longterm_pk.0,
+                msg1_flushed_at: None,
+                msg2_received_at: None,
+                #[cfg(feature = "crypto-pool")]
+                job: None,
+            },
+        }
+    }
+}
 
-            ret
+impl<S: AsyncRead + AsyncWrite + Unpin> UnsafeClientHandshaker<S> {
+    // Drives the handshake, like `Future::poll`.
+    fn poll(&mut self, cx: &mut Context) -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        match self.poll_verified(cx)? {
+            Ready(stream) => {
+                let outcome = self.driver
+                    .machine
+                    .is_finished()
+                    .expect("Verified the server without an outcome");
+                Ready(Ok((outcome, stream)))
+            }
+            Pending => Pending,
         }
     }
+
+    // Drives the handshake until the server has been verified, without taking the outcome
+    // out of the machine. Once the handshake has resolved or failed, the stream has been
+    // handed out, and this stays pending forever instead.
+    fn poll_verified(&mut self, cx: &mut Context) -> Poll<Result<S, (HandshakeError, S)>> {
+        if self.finished {
+            return Pending;
+        }
+        let result = match self.stream {
+            Some(ref mut stream) => self.driver.poll(Pin::new(stream), cx),
+            None => return Pending,
+        };
+        match result {
+            Ready(Ok(())) => Ready(Ok(self.into_stream())),
+            Ready(Err(e)) => Ready(Err((e, self.into_stream()))),
+            Pending => Pending,
+        }
+    }
+
+    fn into_stream(&mut self) -> S {
+        self.stream.take().expect("Completed UnsafeClientHandshaker without a stream")
+    }
 }
 
-// Zero buffered handshake data on dropping.
-impl<S> Drop for UnsafeClientHandshaker<S> {
-    fn drop(&mut self) {
-        memzero(&mut self.data);
+impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
+    // Drives the handshake with the stream pinned in place, see `ClientHandshaker::poll_pinned`.
+    // Once the handshake has resolved or failed, this stays pending forever.
+    fn poll_pinned(self: Pin<&mut Self>,
+                   cx: &mut Context)
+                   -> Poll<Result<Outcome, HandshakeError>> {
+        // The stream is only ever accessed pinned from here on, see `UnsafeClientHandshaker`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.finished {
+            return Pending;
+        }
+        let result = match this.stream {
+            Some(ref mut stream) => this.driver.poll(unsafe { Pin::new_unchecked(stream) }, cx),
+            None => return Pending,
+        };
+        match result {
+            Ready(Ok(())) => {
+                this.finished = true;
+                Ready(Ok(this.driver
+                             .machine
+                             .is_finished()
+                             .expect("Verified the server without an outcome")))
+            }
+            Ready(Err(e)) => {
+                this.finished = true;
+                Ready(Err(e))
+            }
+            Pending => Pending,
+        }
+    }
+
+    // The stream, pinned in place, see `ClientHandshaker::get_pin_mut`.
+    fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The stream is structurally pinned, see `UnsafeClientHandshaker`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = this.stream
+            .as_mut()
+            .expect("Accessed the stream of ClientHandshaker after it was handed out");
+        unsafe { Pin::new_unchecked(stream) }
     }
 }
 
-// Future implementation to asynchronously drive a handshake.
-impl<S: AsyncRead + AsyncWrite> Future for UnsafeClientHandshaker<S> {
-    type Item = (Outcome, S);
-    type Error = (HandshakeError, S);
+impl<S> UnsafeClientHandshaker<S> {
+    pub(crate) fn into_inner(mut self) -> S {
+        self.stream.take().expect("Took the stream of UnsafeClientHandshaker after completion")
+    }
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        let mut stream = self.stream
-            .take()
-            .expect("Polled UnsafeClientHandshaker after completion");
+    fn log_summary(&self) -> String {
+        let len = match self.driver.machine.state {
+            WriteMsg1 => Some(MSG1_BYTES + self.driver.machine.options.pre_auth_bytes()),
+            ReadMsg2 => Some(MSG2_BYTES),
+            WriteMsg3 => Some(MSG3_BYTES),
+            ReadMsg4 => Some(MSG4_BYTES),
+            FlushMsg1 | FlushMsg3 => None,
+        };
+        let offset = match len {
+            Some(len) => format!(" offset={}/{}", self.driver.machine.offset, len),
+            None => String::new(),
+        };
 
-        match self.state {
-            WriteMsg1 => {
-                while self.offset < MSG1_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG1_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((Error::new(WriteZero, "failed to write msg1").into(),
-                                            stream));
-                            }
-                            self.offset += written;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
-                }
+        format!("shs-client state={:?}{} server={}",
+                self.driver.machine.state,
+                offset,
+                fingerprint(&self.driver.server_longterm_pk))
+    }
+}
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg1;
+// Shows only the progress and the server, never the keys or the buffered handshake data.
+impl<S> UnsafeClientHandshaker<S> {
+    fn fmt_redacted(&self, name: &str, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(name)
+            .field("state", &self.driver.machine.state)
+            .field("offset", &self.driver.machine.offset)
+            .field("options", &self.driver.machine.options)
+            .field("server", &fingerprint(&self.driver.server_longterm_pk))
+            .finish()
+    }
+}
 
-                return self.poll(cx);
+impl<'a, S> fmt::Debug for ClientHandshaker<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_redacted("ClientHandshaker", f)
+    }
+}
+
+impl<S> fmt::Debug for OwningClientHandshaker<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt_redacted("OwningClientHandshaker", f)
+    }
+}
+
+impl<S> UnsafeClientHandshaker<S> {
+    fn phase(&self) -> HandshakePhase {
+        if self.stream.is_none() || self.finished {
+            return HandshakePhase::Finished;
+        }
+        match self.driver.machine.state {
+            WriteMsg1 | FlushMsg1 => HandshakePhase::Msg1,
+            ReadMsg2 => HandshakePhase::Msg2,
+            WriteMsg3 | FlushMsg3 => HandshakePhase::Msg3,
+            ReadMsg4 => HandshakePhase::Msg4,
+        }
+    }
+
+    fn progress(&self) -> f32 {
+        // Verifying msg4 resets the offset, but the state stays `ReadMsg4`.
+        if self.driver.machine.verified {
+            return 1.0;
+        }
+        let offset = self.driver.machine.offset;
+        let done = match self.driver.machine.state {
+            WriteMsg1 => min(offset, MSG1_BYTES), // the offset includes any pre-authentication
+            FlushMsg1 => MSG1_BYTES,
+            ReadMsg2 => MSG1_BYTES + offset,
+            WriteMsg3 => MSG1_BYTES + MSG2_BYTES + offset,
+            FlushMsg3 => MSG1_BYTES + MSG2_BYTES + MSG3_BYTES,
+            ReadMsg4 => MSG1_BYTES + MSG2_BYTES + MSG3_BYTES + offset,
+        };
+        done as f32 / HANDSHAKE_TOTAL_BYTES as f32
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        match (self.driver.msg1_flushed_at, self.driver.msg2_received_at) {
+            (Some(flushed), Some(received)) => Some(received.duration_since(flushed)),
+            _ => None,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> UnsafeClientHandshaker<S> {
+    // Whether the handshake failed because the server closed the connection instead of
+    // sending msg4, which is how a server rejects a client it does not want to talk to.
+    pub(crate) fn rejected_by_server(&self, err: &HandshakeError) -> bool {
+        match (&self.driver.machine.state, err) {
+            (&ReadMsg4, &HandshakeError::IoError(ref e)) => {
+                self.driver.machine.offset == 0 && e.kind() == UnexpectedEof
             }
+            _ => false,
+        }
+    }
+}
 
-            FlushMsg1 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
-                    Ok(Pending) => {
-                        self.stream = Some(stream);
-                        return Ok(Pending);
-                    }
-                    Err(e) => return Err((e.into(), stream)),
-                }
+impl ClientDriver {
+    // Drives the handshake over `stream` until the server has been verified.
+    fn poll<S>(&mut self,
+               stream: Pin<&mut S>,
+               cx: &mut Context)
+               -> Poll<Result<(), HandshakeError>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.transitions = 0;
+        self.step(stream, cx)
+    }
 
-                self.stream = Some(stream);
-                self.state = ReadMsg2;
-                return self.poll(cx);
+    // Moves on to the next state, unless the fairness budget of this poll is used up, in
+    // which case the task is woken and yields.
+    fn transition<S>(&mut self,
+                     stream: Pin<&mut S>,
+                     cx: &mut Context)
+                     -> Poll<Result<(), HandshakeError>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.transitions += 1;
+        let fair_budget = self.machine.options.fair_budget;
+        if fair_budget != 0 && self.transitions >= fair_budget {
+            cx.waker().wake_by_ref();
+            return Pending;
+        }
+        self.step(stream, cx)
+    }
+
+    // Performs the crypto step of the machine, on the crypto pool if the options say so.
+    fn crypto_step<S>(&mut self,
+                      stream: Pin<&mut S>,
+                      cx: &mut Context)
+                      -> Poll<Result<(), HandshakeError>>
+        where S: AsyncRead + AsyncWrite
+    {
+        let step = self.machine.crypto_step();
+
+        #[cfg(feature = "crypto-pool")]
+        {
+            if self.machine.options.offload_crypto && self.machine.client.owns_keys() {
+                // The client and the data move to the pool, only a placeholder and zeroes stay
+                // behind until the job returns them.
+                let mut offloaded = Box::new(Offloaded {
+                                                 client: mem::replace(&mut self.machine.client,
+                                                                      Client::placeholder()),
+                                                 data: self.machine.data,
+                                             });
+                memzero(&mut self.machine.data);
+                let options = self.machine.options;
+                self.job = Some(crypto_pool::spawn(move || {
+                    let result = step(&mut offloaded.client, &mut offloaded.data, &options);
+                    (offloaded, result)
+                }));
+                return self.step(stream, cx);
             }
+        }
 
-            ReadMsg2 => {
-                while self.offset < MSG2_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG2_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((Error::new(UnexpectedEof, "failed to read msg2")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += read;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
-                }
+        let result = step(&mut self.machine.client, &mut self.machine.data, &self.machine.options);
+        self.finish_crypto(stream, cx, result)
+    }
 
-                if !self.client
-                        .verify_msg2(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG2_BYTES])
-                                     }) {
-                    return Err((HandshakeError::CryptoError, stream));
-                }
+    // Continues the handshake after a crypto step.
+    fn finish_crypto<S>(&mut self,
+                        stream: Pin<&mut S>,
+                        cx: &mut Context,
+                        result: Result<(), HandshakeError>)
+                        -> Poll<Result<(), HandshakeError>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.machine.finish_crypto(result)?;
+        if self.machine.verified {
+            return Ready(Ok(()));
+        }
+        self.transition(stream, cx)
+    }
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = WriteMsg3;
-                self.client.create_msg3(&mut self.data);
-                return self.poll(cx);
+    // Drives the state machine as far as possible.
+    fn step<S>(&mut self,
+               mut stream: Pin<&mut S>,
+               cx: &mut Context)
+               -> Poll<Result<(), HandshakeError>>
+        where S: AsyncRead + AsyncWrite
+    {
+        #[cfg(feature = "crypto-pool")]
+        {
+            if let Some(mut job) = self.job.take() {
+                match job.poll(cx) {
+                    Ready((mut offloaded, result)) => {
+                        mem::swap(&mut self.machine.client, &mut offloaded.client);
+                        self.machine.data = offloaded.data;
+                        return self.finish_crypto(stream, cx, result);
+                    }
+                    Pending => {
+                        self.job = Some(job);
+                        return Pending;
+                    }
+                }
             }
+        }
 
-            WriteMsg3 => {
-                while self.offset < MSG3_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG3_BYTES]) {
-                        Ok(Ready(written)) => {
+        match self.machine.state {
+            WriteMsg1 | WriteMsg3 => {
+                // The options may change until the first poll, so the pre-authentication is
+                // created here rather than together with msg1.
+                self.machine.create_pre_auth();
+
+                let what = match self.machine.state {
+                    WriteMsg1 => "failed to write msg1",
+                    _ => "failed to write msg3",
+                };
+                loop {
+                    let (len, result) = match self.machine.wants_write() {
+                        Some(bytes) => (bytes.len(), stream.as_mut().poll_write(cx, bytes)),
+                        None => break,
+                    };
+                    match result {
+                        Ready(Ok(written)) => {
                             if written == 0 {
-                                return Err((Error::new(WriteZero, "failed to write msg3").into(),
-                                            stream));
+                                return Ready(Err(Error::new(WriteZero, what).into()));
+                            }
+                            if written > len {
+                                return Ready(Err(overlong_write().into()));
                             }
-                            self.offset += written;
+                            self.retries = 0;
+                            self.machine.advance_write(written);
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
+                        Pending => return Pending,
+                        Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                            self.retries += 1;
+                            if e.kind() != Interrupted {
+                                cx.waker().wake_by_ref();
+                                return Pending;
+                            }
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        Ready(Err(e)) => return Ready(Err(e.into())),
                     }
                 }
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg3;
-                return self.poll(cx);
+                self.transition(stream, cx)
             }
 
-            FlushMsg3 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
-                    Ok(Pending) => {
-                        self.stream = Some(stream);
-                        return Ok(Pending);
+            FlushMsg1 | FlushMsg3 => {
+                match stream.as_mut().poll_flush(cx) {
+                    Ready(Ok(())) => self.retries = 0,
+                    Pending => return Pending,
+                    Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                        self.retries += 1;
+                        cx.waker().wake_by_ref();
+                        return Pending;
                     }
-                    Err(e) => return Err((e.into(), stream)),
+                    Ready(Err(e)) => return Ready(Err(e.into())),
                 }
 
-                self.stream = Some(stream);
-                self.state = ReadMsg4;
-                return self.poll(cx);
+                if let FlushMsg1 = self.machine.state {
+                    self.msg1_flushed_at = Some(Instant::now());
+                }
+                self.machine.flushed();
+                self.transition(stream, cx)
             }
 
-            ReadMsg4 => {
-                while self.offset < MSG4_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG4_BYTES]) {
-                        Ok(Ready(read)) => {
+            ReadMsg2 | ReadMsg4 => {
+                let what = match self.machine.state {
+                    ReadMsg2 => "failed to read msg2",
+                    _ => "failed to read msg4",
+                };
+                loop {
+                    let (len, result) = {
+                        let buf = self.machine.read_buf();
+                        if buf.is_empty() {
+                            break;
+                        }
+                        (buf.len(), stream.as_mut().poll_read(cx, buf))
+                    };
+                    match result {
+                        Ready(Ok(read)) => {
                             if read == 0 {
-                                return Err((Error::new(UnexpectedEof, "failed to read msg4")
-                                                .into(),
-                                            stream));
+                                if self.zero_reads < self.machine.options.zero_read_tolerance {
+                                    self.zero_reads += 1;
+                                    cx.waker().wake_by_ref();
+                                    return Pending;
+                                }
+                                return Ready(Err(Error::new(UnexpectedEof, what).into()));
+                            }
+                            self.zero_reads = 0;
+                            self.retries = 0;
+                            if read > len {
+                                return Ready(Err(overlong_read().into()));
                             }
-                            self.offset += read;
+                            if let (&ReadMsg2, 0) = (&self.machine.state, self.machine.offset) {
+                                self.msg2_received_at = Some(Instant::now());
+                            }
+                            self.machine.offset += read;
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
+                        Pending => return Pending,
+                        Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                            self.retries += 1;
+                            if e.kind() != Interrupted {
+                                cx.waker().wake_by_ref();
+                                return Pending;
+                            }
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        Ready(Err(e)) => return Ready(Err(e.into())),
                     }
                 }
 
-                if !self.client
-                        .verify_msg4(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG4_BYTES])
-                                     }) {
-                    return Err((HandshakeError::CryptoError, stream));
-                }
-
-                let mut outcome = unsafe { uninitialized() };
-                self.client.outcome(&mut outcome);
-                return Ok(Ready((outcome, stream)));
+                self.crypto_step(stream, cx)
             }
         }
     }
 }
 
-// State for the future state machine.
+/// The client side of a handshake as a state machine that performs no io, for transports
+/// that are not `AsyncRead + AsyncWrite`, e.g. blocking streams, `mio` or foreign event
+/// loops. The futures handshakers drive this same machine over their stream.
+///
+/// The driver of the machine writes the bytes returned by `wants_write` to the peer and
+/// reports how many of them were written with `advance_write`, flushing the transport once
+/// `wants_write` returns `None`. It then feeds the bytes received from the peer to
+/// `wants_read`, until `is_finished` hands over the outcome:
+///
+/// ```rust,ignore
+/// let mut machine = ClientHandshakeMachine::new(&network_identifier, &pk, &sk, &eph_pk,
+///                                               &eph_sk, &server_pk);
+/// let mut buf = [0; MSG3_BYTES];
+/// let outcome = loop {
+///     if let Some(outcome) = machine.is_finished() {
+///         break outcome;
+///     }
+///     while let Some(bytes) = machine.wants_write() {
+///         let written = transport.write(bytes)?;
+///         machine.advance_write(written);
+///     }
+///     transport.flush()?;
+///     let len = machine.wants_read_len();
+///     let read = transport.read(&mut buf[..len])?;
+///     machine.wants_read(&mut buf[..read])?;
+/// };
+/// ```
+///
+/// Only the `pre_auth` and `aad` options affect the machine. Timeouts, zero-length reads and
+/// retrying errors are up to the driver.
+pub struct ClientHandshakeMachine<'a> {
+    client: Client,
+    state: State,
+    data: [u8; MSG3_BYTES], // holds the message that is being written or read
+    offset: usize, // offset into the data array at which to write or read
+    options: HandshakeOptions,
+    pre_auth_created: bool, // whether the pre-authentication follows msg1 in `data`
+    failed: bool,
+    verified: bool, // whether msg4 has been verified
+    handed_over: bool, // whether the outcome has left the machine
+    _keys: PhantomData<&'a u8>,
+}
+
+impl<'a> ClientHandshakeMachine<'a> {
+    /// Creates a machine to connect to a server with known public key and app key. See
+    /// `ClientHandshaker::new` for the arguments.
+    pub fn new(network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: &'a sign::PublicKey,
+               client_longterm_sk: &'a sign::SecretKey,
+               client_ephemeral_pk: &'a box_::PublicKey,
+               client_ephemeral_sk: &'a box_::SecretKey,
+               server_longterm_pk: &'a sign::PublicKey)
+               -> ClientHandshakeMachine<'a> {
+        ClientHandshakeMachine::with_client(Client::from_keys(network_identifier,
+                                                              client_longterm_pk,
+                                                              client_longterm_sk,
+                                                              client_ephemeral_pk,
+                                                              client_ephemeral_sk,
+                                                              server_longterm_pk))
+    }
+
+    // Creates a machine around a client that owns its keys, and creates msg1.
+    fn with_client(client: Client) -> ClientHandshakeMachine<'a> {
+        let mut ret = ClientHandshakeMachine {
+            client,
+            state: WriteMsg1,
+            data: [0; MSG3_BYTES],
+            offset: 0,
+            options: HandshakeOptions::default(),
+            pre_auth_created: false,
+            failed: false,
+            verified: false,
+            handed_over: false,
+            _keys: PhantomData,
+        };
+        ret.client
+            .create_msg1(unsafe {
+                             &mut *(&mut ret.data as *mut [u8; MSG3_BYTES] as
+                                    *mut [u8; MSG1_BYTES])
+                         });
+        ret
+    }
+
+    /// Sets all options at once, replacing any previously set ones. Must be called before
+    /// anything has been written.
+    pub fn options(mut self, options: HandshakeOptions) -> ClientHandshakeMachine<'a> {
+        assert!(self.offset == 0,
+                "Set the options of a ClientHandshakeMachine after writing msg1");
+        self.options = options;
+        self.pre_auth_created = false;
+        self.create_pre_auth();
+        self
+    }
+
+    // Creates the pre-authentication after msg1, if the options ask for one and it has not
+    // been created yet.
+    fn create_pre_auth(&mut self) {
+        if let (Some(pre_auth), &WriteMsg1, false) =
+            (self.options.pre_auth, &self.state, self.pre_auth_created) {
+            let (msg1, rest) = self.data.split_at_mut(MSG1_BYTES);
+            pre_auth.create(unsafe { &*(msg1.as_ptr() as *const [u8; MSG1_BYTES]) },
+                            unsafe { &mut *(rest.as_mut_ptr() as *mut [u8; PRE_AUTH_BYTES]) });
+            self.pre_auth_created = true;
+        }
+    }
+
+    /// The bytes to send to the server next, or `None` if the machine waits for the server
+    /// (or has completed or failed).
+    pub fn wants_write(&self) -> Option<&[u8]> {
+        if self.failed {
+            return None;
+        }
+        match self.state {
+            WriteMsg1 => Some(&self.data[self.offset..MSG1_BYTES + self.options.pre_auth_bytes()]),
+            WriteMsg3 => Some(&self.data[self.offset..MSG3_BYTES]),
+            _ => None,
+        }
+    }
+
+    /// Marks the first `n` bytes returned by `wants_write` as written.
+    ///
+    /// Panics if `n` is larger than the number of bytes returned by `wants_write`.
+    pub fn advance_write(&mut self, n: usize) {
+        let len = self.wants_write().map_or(0, |bytes| bytes.len());
+        assert!(n <= len,
+                "Advanced a ClientHandshakeMachine past the bytes it wants to write");
+        self.offset += n;
+        if n == len && n != 0 {
+            self.offset = 0;
+            self.state = match self.state {
+                WriteMsg1 => FlushMsg1,
+                _ => FlushMsg3,
+            };
+        }
+    }
+
+    // Marks the message that has just been written as flushed.
+    fn flushed(&mut self) {
+        self.state = match self.state {
+            FlushMsg1 => ReadMsg2,
+            FlushMsg3 => ReadMsg4,
+            _ => return,
+        };
+    }
+
+    /// Feeds bytes received from the server to the machine, and returns how many of them it
+    /// consumed. The machine consumes at most the rest of the message it is reading, and
+    /// nothing while it wants to write, so any bytes the server sent after msg4 are left to
+    /// the caller. The consumed bytes are zeroed in `buf`.
+    ///
+    /// Returns an error if a message from the server can not be verified.
+    ///
+    /// Panics if the machine has already failed.
+    pub fn wants_read(&mut self, buf: &mut [u8]) -> Result<usize, HandshakeError> {
+        assert!(!self.failed, "Fed ClientHandshakeMachine after failure");
+        // The driver flushes before it reads.
+        self.flushed();
+        let consumed = min(buf.len(), self.wants_read_len());
+        self.read_buf()[..consumed].copy_from_slice(&buf[..consumed]);
+        memzero(&mut buf[..consumed]);
+        self.offset += consumed;
+        if consumed == 0 || self.wants_read_len() != 0 {
+            return Ok(consumed);
+        }
+
+        let step = self.crypto_step();
+        let result = step(&mut self.client, &mut self.data, &self.options);
+        self.finish_crypto(result)?;
+        Ok(consumed)
+    }
+
+    /// How many bytes `wants_read` consumes at most, i.e. the rest of the message the machine
+    /// is reading. Drivers that must not read past the handshake can limit their reads to
+    /// this.
+    pub fn wants_read_len(&self) -> usize {
+        if self.failed {
+            return 0;
+        }
+        match self.state {
+            FlushMsg1 | ReadMsg2 => MSG2_BYTES - self.offset,
+            FlushMsg3 | ReadMsg4 if !self.verified => MSG4_BYTES - self.offset,
+            _ => 0,
+        }
+    }
+
+    // The part of `data` that the rest of the message from the server is read into.
+    fn read_buf(&mut self) -> &mut [u8] {
+        let end = self.offset + self.wants_read_len();
+        &mut self.data[self.offset..end]
+    }
+
+    // The crypto step to perform once the message from the server is complete.
+    fn crypto_step(&self) -> CryptoStep {
+        match self.state {
+            ReadMsg2 => after_msg2,
+            _ => after_msg4,
+        }
+    }
+
+    // Moves on after the crypto step, or fails the machine if the step failed.
+    fn finish_crypto(&mut self, result: Result<(), HandshakeError>) -> Result<(), HandshakeError> {
+        if let Err(e) = result {
+            self.failed = true;
+            return Err(e);
+        }
+        self.offset = 0;
+        match self.state {
+            ReadMsg2 => self.state = WriteMsg3,
+            _ => self.verified = true,
+        }
+        Ok(())
+    }
+
+    /// Hands over the outcome of the handshake once the server has been verified. Returns
+    /// `None` before, and on every later call: the keys leave the machine exactly once.
+    pub fn is_finished(&mut self) -> Option<Outcome> {
+        if !self.verified || self.handed_over {
+            return None;
+        }
+        self.handed_over = true;
+        Some(self.client.outcome())
+    }
+
+    // Like `is_finished`, but writes the outcome into `outcome` instead of returning it.
+    fn write_outcome(&mut self, outcome: &mut Outcome) {
+        assert!(self.verified && !self.handed_over,
+                "Wrote the outcome of an unfinished ClientHandshakeMachine");
+        self.handed_over = true;
+        self.client.write_outcome(outcome);
+    }
+
+    /// How far the handshake has progressed. `Finished` once the machine has completed or
+    /// failed.
+    pub fn phase(&self) -> HandshakePhase {
+        if self.failed || self.verified {
+            return HandshakePhase::Finished;
+        }
+        match self.state {
+            WriteMsg1 => HandshakePhase::Msg1,
+            FlushMsg1 | ReadMsg2 => HandshakePhase::Msg2,
+            WriteMsg3 => HandshakePhase::Msg3,
+            FlushMsg3 | ReadMsg4 => HandshakePhase::Msg4,
+        }
+    }
+}
+
+// Zero buffered handshake data on dropping.
+impl<'a> Drop for ClientHandshakeMachine<'a> {
+    fn drop(&mut self) {
+        memzero(&mut self.data);
+    }
+}
+
+// Shows only the progress, never the keys or the buffered handshake data.
+impl<'a> fmt::Debug for ClientHandshakeMachine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientHandshakeMachine")
+            .field("state", &self.state)
+            .field("offset", &self.offset)
+            .field("phase", &self.phase())
+            .finish()
+    }
+}
+
+// State of a handshake. The futures handshakers poll the flush of the stream in the `Flush`
+// states. Other drivers flush on their own, so to them the machine is already reading in
+// these states, and leaves them on the next read.
+#[derive(Debug)]
 enum State {
     WriteMsg1,
     FlushMsg1,
@@ -324,4 +1177,5 @@ enum State {
     FlushMsg3,
     ReadMsg4,
 }
+
 use client::State::*;
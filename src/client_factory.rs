@@ -0,0 +1,194 @@
+//! Start client handshakes using a fixed client identity.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::*;
+use errors::HandshakeError;
+use options::HandshakeOptions;
+use client::OwningClientHandshaker;
+use connection::Secured;
+
+/// Starts client handshakes using a fixed client identity, with a fresh ephemeral keypair
+/// for each handshake.
+///
+/// Generating the ephemeral keypair is the only expensive part of starting a handshake. To
+/// keep it off the critical path of opening a connection, call `prewarm` ahead of time,
+/// the next `start` then uses the pregenerated keypair.
+///
+/// A factory is intentionally not `Clone`, a clone would share the prewarmed keypair.
+pub struct ClientHandshakerFactory {
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    client_longterm_pk: sign::PublicKey,
+    client_longterm_sk: sign::SecretKey,
+    options: HandshakeOptions,
+    prewarmed: Option<(box_::PublicKey, box_::SecretKey)>,
+    rng: Option<EphemeralRng>,
+}
+
+impl ClientHandshakerFactory {
+    /// Creates a new factory for the client with the given longterm keys, connecting to
+    /// servers which use the given network identifier.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               client_longterm_pk: sign::PublicKey,
+               client_longterm_sk: sign::SecretKey)
+               -> ClientHandshakerFactory {
+        ClientHandshakerFactory {
+            network_identifier,
+            client_longterm_pk,
+            client_longterm_sk,
+            options: HandshakeOptions::default(),
+            prewarmed: None,
+            rng: None,
+        }
+    }
+
+    /// Sets the options for all handshakes started by this factory.
+    pub fn options(mut self, options: HandshakeOptions) -> ClientHandshakerFactory {
+        self.options = options;
+        self
+    }
+
+    /// Generates the ephemeral keypairs with `rng` instead of the random number generator of
+    /// the operating system.
+    pub fn with_rng(mut self, rng: EphemeralRng) -> ClientHandshakerFactory {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Generates the ephemeral keypair for the next call to `start`, unless there already is
+    /// an unused one.
+    pub fn prewarm(&mut self) {
+        if self.prewarmed.is_none() {
+            self.prewarmed = Some(generate_ephemeral_keypair(self.rng.as_ref()));
+        }
+    }
+
+    /// Whether the next call to `start` uses a pregenerated ephemeral keypair.
+    pub fn is_prewarmed(&self) -> bool {
+        self.prewarmed.is_some()
+    }
+
+    /// Returns a future that performs the client side of a handshake with the server with
+    /// the given longterm public key over the given `stream`.
+    ///
+    /// Consumes the prewarmed ephemeral keypair if there is one, otherwise a new one is
+    /// generated. Either way, no ephemeral keypair is ever used for more than one handshake.
+    pub fn start<S: AsyncRead + AsyncWrite>(&mut self,
+                                            stream: S,
+                                            server_longterm_pk: sign::PublicKey)
+                                            -> OwningClientHandshaker<S> {
+        let (client_ephemeral_pk, client_ephemeral_sk) = match self.prewarmed.take() {
+            Some(keypair) => keypair,
+            None => generate_ephemeral_keypair(self.rng.as_ref()),
+        };
+
+        OwningClientHandshaker::new(stream,
+                                    self.network_identifier,
+                                    self.client_longterm_pk.clone(),
+                                    self.client_longterm_sk.clone(),
+                                    client_ephemeral_pk,
+                                    client_ephemeral_sk,
+                                    server_longterm_pk)
+                .options(self.options)
+    }
+
+    /// Like `start`, but the returned future yields a `SecuredConnection`.
+    pub fn start_secured<S: AsyncRead + AsyncWrite>(&mut self,
+                                                    stream: S,
+                                                    server_longterm_pk: sign::PublicKey)
+                                                    -> Secured<OwningClientHandshaker<S>> {
+        Secured::new(self.start(stream, server_longterm_pk))
+    }
+}
+
+/// Returns a future that performs client handshakes over all the given streams, each with the
+/// server with the paired longterm public key, and resolves to their results in the order of
+/// the streams.
+///
+/// At most `concurrency` handshakes are in progress at any time (at least one), the next
+/// stream is only taken from `streams` once a handshake has finished. Every handshake is
+/// started by `factory`, so each one uses a fresh ephemeral keypair. This is useful both for
+/// connecting to many servers at once and for generating load, see also the `loadtest`
+/// module.
+pub fn run_handshakes<I, S>(streams: I,
+                            factory: ClientHandshakerFactory,
+                            concurrency: usize)
+                            -> RunHandshakes<I::IntoIter, S>
+    where I: IntoIterator<Item = (S, sign::PublicKey)>,
+          S: AsyncRead + AsyncWrite
+{
+    RunHandshakes {
+        streams: streams.into_iter(),
+        factory,
+        concurrency: concurrency.max(1),
+        running: Vec::new(),
+        results: Vec::new(),
+    }
+}
+
+/// Future returned by `run_handshakes`.
+pub struct RunHandshakes<I, S> {
+    streams: I,
+    factory: ClientHandshakerFactory,
+    concurrency: usize,
+    running: Vec<(usize, OwningClientHandshaker<S>)>, // with the index of the stream
+    results: Vec<Option<Result<(Outcome, S), (HandshakeError, S)>>>, // `None` while running
+}
+
+// Neither the streams nor the handshakers are pinned.
+impl<I, S> Unpin for RunHandshakes<I, S> {}
+
+impl<I, S> Future for RunHandshakes<I, S>
+    where I: Iterator<Item = (S, sign::PublicKey)>,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    type Output = Vec<Result<(Outcome, S), (HandshakeError, S)>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            while this.running.len() < this.concurrency {
+                match this.streams.next() {
+                    Some((stream, server_longterm_pk)) => {
+                        let handshaker = this.factory.start(stream, server_longterm_pk);
+                        this.running.push((this.results.len(), handshaker));
+                        this.results.push(None);
+                    }
+                    None => break,
+                }
+            }
+
+            if this.running.is_empty() {
+                let results = mem::replace(&mut this.results, Vec::new());
+                return Ready(results.into_iter().map(Option::unwrap).collect());
+            }
+
+            let mut finished = false;
+            let mut i = 0;
+            while i < this.running.len() {
+                let result = match Pin::new(&mut this.running[i].1).poll(cx) {
+                    Pending => {
+                        i += 1;
+                        continue;
+                    }
+                    Ready(result) => result,
+                };
+                let (index, _) = this.running.swap_remove(i);
+                this.results[index] = Some(result);
+                finished = true;
+            }
+
+            // Start the next handshakes right away, they might complete without blocking.
+            if !finished {
+                return Pending;
+            }
+        }
+    }
+}
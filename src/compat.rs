@@ -0,0 +1,86 @@
+//! Conversions to and from the types of the [ssb-crypto](https://crates.io/crates/ssb-crypto)
+//! crate, used by other rust implementations of scuttlebutt.
+//!
+//! This module is only available with the `compat` feature.
+//!
+//! Both the keys of this crate (from sodiumoxide) and the keys of ssb-crypto are foreign to
+//! this crate, so they are converted with functions rather than `From` impls.
+
+use sodiumoxide::crypto::sign;
+use ssb_crypto;
+use ssb_crypto::secretbox;
+
+use crypto::{Outcome, NETWORK_IDENTIFIER_BYTES};
+
+/// Converts a network identifier into an ssb-crypto `NetworkKey`.
+pub fn network_key(network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES])
+                   -> ssb_crypto::NetworkKey {
+    ssb_crypto::NetworkKey(*network_identifier)
+}
+
+/// Converts an ssb-crypto `NetworkKey` into a network identifier.
+pub fn network_identifier(network_key: &ssb_crypto::NetworkKey)
+                          -> [u8; NETWORK_IDENTIFIER_BYTES] {
+    network_key.0
+}
+
+/// Converts a longterm public key into an ssb-crypto `PublicKey`.
+pub fn public_key(pk: &sign::PublicKey) -> ssb_crypto::PublicKey {
+    ssb_crypto::PublicKey(pk.0)
+}
+
+/// Converts an ssb-crypto `PublicKey` into a longterm public key.
+pub fn from_public_key(pk: &ssb_crypto::PublicKey) -> sign::PublicKey {
+    sign::PublicKey(pk.0)
+}
+
+/// Converts a longterm keypair into an ssb-crypto `Keypair`.
+///
+/// A libsodium secret key consists of the 32 byte seed followed by the public key, an
+/// ssb-crypto secret key is only the seed.
+pub fn keypair(pk: &sign::PublicKey, sk: &sign::SecretKey) -> ssb_crypto::Keypair {
+    let mut seed = [0; 32];
+    seed.copy_from_slice(&sk.0[..32]);
+
+    ssb_crypto::Keypair {
+        secret: ssb_crypto::SecretKey(seed),
+        public: public_key(pk),
+    }
+}
+
+/// Converts an ssb-crypto `Keypair` into a longterm keypair.
+pub fn from_keypair(keypair: &ssb_crypto::Keypair) -> (sign::PublicKey, sign::SecretKey) {
+    let mut sk = [0; sign::SECRETKEYBYTES];
+    sk[..32].copy_from_slice(&keypair.secret.0);
+    sk[32..].copy_from_slice(&keypair.public.0);
+
+    (from_public_key(&keypair.public), sign::SecretKey(sk))
+}
+
+/// The outcome of a handshake in the vocabulary of ssb-handshake and ssb-boxstream.
+///
+/// ssb-handshake does not export its own `HandshakeKeys` type, so this mirrors its fields.
+pub struct HandshakeKeys {
+    /// The key for decrypting messages from the peer.
+    pub read_key: secretbox::Key,
+    /// The initial nonce for decrypting messages from the peer.
+    pub read_starting_nonce: secretbox::Nonce,
+    /// The key for encrypting messages to the peer.
+    pub write_key: secretbox::Key,
+    /// The initial nonce for encrypting messages to the peer.
+    pub write_starting_nonce: secretbox::Nonce,
+    /// The longterm public key of the peer.
+    pub peer_key: ssb_crypto::PublicKey,
+}
+
+impl<'a> From<&'a Outcome> for HandshakeKeys {
+    fn from(outcome: &'a Outcome) -> HandshakeKeys {
+        HandshakeKeys {
+            read_key: secretbox::Key(outcome.decryption_key().0),
+            read_starting_nonce: secretbox::Nonce(outcome.decryption_nonce().0),
+            write_key: secretbox::Key(outcome.encryption_key().0),
+            write_starting_nonce: secretbox::Nonce(outcome.encryption_nonce().0),
+            peer_key: public_key(&outcome.peer_longterm_pk()),
+        }
+    }
+}
@@ -0,0 +1,714 @@
+//! Load keys, network identifier and addresses from a configuration file.
+//!
+//! This module is only available with the `config` feature.
+//!
+//! A `HandshakeConfig` can be deserialized with any serde format. Calling `materialize`
+//! on it loads and validates everything it refers to, and returns a `ClientConfig` and a
+//! `ServerConfig` that can be used to perform handshakes.
+//!
+//! The keyfile uses the format of the `~/.ssb/secret` file: Lines starting with `#` are
+//! comments, the remainder is a json object with `curve`, `public` and `private` fields,
+//! where the keys are base64 encoded and suffixed with `.ed25519`.
+//!
+//! Alternatively, `ClientConfig::from_env` reads a client configuration from environment
+//! variables, which suits containerized deployments:
+//!
+//! - `SHS_SECRET_KEY` (required): the longterm secret key of the client, either the 64 byte
+//!   libsodium secret key or its 32 byte seed.
+//! - `SHS_SERVER_KEY`: the longterm public key of the server to connect to.
+//! - `SHS_NETWORK_IDENTIFIER`: the network identifier, defaults to `MAIN_NET_IDENTIFIER`.
+//! - `SHS_DIAL`: the address to connect to, e.g. `127.0.0.1:8008`.
+//!
+//! Keys and the network identifier are hex or base64 encoded, keys may also take the form
+//! `@<base64>.ed25519` (with or without the `@`).
+//!
+//! A `ConnectionsSpec` is the `connections` section of an ssb-config file, which lists the
+//! transports to accept and dial connections on. `build_acceptors` turns its incoming `net`
+//! and `unix` entries into listeners with an `Acceptor` each:
+//!
+//! ```rust,ignore
+//! let spec: ConnectionsSpec = serde_json::from_value(config["connections"].take())?;
+//! for listener in spec.build_acceptors(&server_config)? {
+//!     // bind listener.addr, and accept connections with listener.acceptor
+//! }
+//! ```
+//!
+//! Keeping a secret key in the environment is risky: the environment of a process can be
+//! read by other processes of the same user (e.g. via `/proc/<pid>/environ`), is inherited
+//! by child processes, and tends to end up in logs and crash reports. `from_env` removes
+//! `SHS_SECRET_KEY` from the environment of the process after reading it, but prefer a
+//! keyfile or a secrets mechanism that mounts files where possible.
+
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use sodiumoxide::crypto::sign;
+use serde::{Deserialize, Deserializer};
+use serde_json;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::{NETWORK_IDENTIFIER_BYTES, EphemeralRng, generate_ephemeral_keypair, selftest_once};
+use errors::SelftestError;
+use client::OwningClientHandshaker;
+use acceptor::Acceptor;
+use options::HandshakeOptions;
+use keyfile::{decode_base64, decode_hex};
+
+/// The network identifier of the main scuttlebutt network.
+pub const MAIN_NET_IDENTIFIER: [u8; NETWORK_IDENTIFIER_BYTES] =
+    [212, 161, 203, 136, 166, 111, 2, 248, 219, 99, 92, 226, 100, 65, 204, 93, 172, 27, 8, 66,
+     12, 234, 172, 35, 8, 57, 183, 85, 132, 90, 159, 251];
+
+/// The unvalidated contents of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct HandshakeConfig {
+    /// Path to the file containing the longterm keypair.
+    pub keyfile: PathBuf,
+    /// The base64 encoded network identifier. Defaults to `MAIN_NET_IDENTIFIER`.
+    #[serde(default)]
+    pub caps: Option<String>,
+    /// The address on which to accept connections, e.g. `0.0.0.0:8008`.
+    #[serde(default)]
+    pub listen: Option<String>,
+    /// The address to connect to, e.g. `127.0.0.1:8008`.
+    #[serde(default)]
+    pub dial: Option<String>,
+    /// Whether `materialize` runs `crypto::selftest` (once per process) before the keys are
+    /// used. Defaults to false.
+    #[serde(default)]
+    pub selftest: bool,
+}
+
+/// Everything needed to initiate handshakes.
+pub struct ClientConfig {
+    /// The network identifier to use.
+    pub network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    /// The longterm public key of the client.
+    pub longterm_pk: sign::PublicKey,
+    /// The longterm secret key of the client.
+    pub longterm_sk: sign::SecretKey,
+    /// The longterm public key of the server to connect to, if configured. `handshake`
+    /// asserts that it connects to this server, see `expect_server`.
+    pub server_pk: Option<sign::PublicKey>,
+    /// The address to connect to, if configured.
+    pub dial: Option<SocketAddr>,
+    /// The source of the ephemeral keys, or `None` for the random number generator of the
+    /// operating system. See `with_rng`.
+    pub rng: Option<EphemeralRng>,
+}
+
+impl ClientConfig {
+    /// Reads the configuration from the environment variables listed in the module
+    /// documentation, and removes `SHS_SECRET_KEY` from the environment.
+    ///
+    /// See the module documentation for the security implications of keeping the secret
+    /// key in the environment.
+    pub fn from_env() -> Result<ClientConfig, ConfigError> {
+        let config = ClientConfig::from_vars(|name| env::var(name).ok());
+        env::remove_var("SHS_SECRET_KEY");
+        config
+    }
+
+    /// Only connect to the server with the longterm public key `pk`. This mirrors
+    /// `ServerConfig::expect_client`, but a client pins the key of its server anyway, so it
+    /// only adds an assertion: `handshake` panics if it is asked to connect to another
+    /// server.
+    pub fn expect_server(mut self, pk: sign::PublicKey) -> ClientConfig {
+        self.server_pk = Some(pk);
+        self
+    }
+
+    /// Generates the ephemeral keys of `handshake` with `rng` instead of the random number
+    /// generator of the operating system.
+    pub fn with_rng(mut self, rng: EphemeralRng) -> ClientConfig {
+        self.rng = Some(rng);
+        self
+    }
+
+    // Reads the configuration from the variables returned by `var`.
+    pub(crate) fn from_vars<F>(var: F) -> Result<ClientConfig, ConfigError>
+        where F: Fn(&'static str) -> Option<String>
+    {
+        let invalid = |name, reason| ConfigError::InvalidVariable { name, reason };
+
+        let network_identifier = match var("SHS_NETWORK_IDENTIFIER") {
+            Some(value) => {
+                let bytes = decode_env_bytes(&value)
+                    .ok_or_else(|| invalid("SHS_NETWORK_IDENTIFIER", "not valid hex or base64"))?;
+                if bytes.len() != NETWORK_IDENTIFIER_BYTES {
+                    return Err(invalid("SHS_NETWORK_IDENTIFIER", "must decode to 32 bytes"));
+                }
+                let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+                network_identifier.copy_from_slice(&bytes);
+                network_identifier
+            }
+            None => MAIN_NET_IDENTIFIER,
+        };
+
+        let secret = var("SHS_SECRET_KEY")
+            .ok_or(ConfigError::MissingVariable { name: "SHS_SECRET_KEY" })?;
+        let secret = decode_env_key(&secret)
+            .ok_or_else(|| invalid("SHS_SECRET_KEY", "not valid hex or base64"))?;
+        let (longterm_pk, longterm_sk) = match secret.len() {
+            sign::SEEDBYTES => {
+                let mut seed = [0; sign::SEEDBYTES];
+                seed.copy_from_slice(&secret);
+                sign::keypair_from_seed(&sign::Seed(seed))
+            }
+            sign::SECRETKEYBYTES => {
+                let mut seed = [0; sign::SEEDBYTES];
+                seed.copy_from_slice(&secret[..sign::SEEDBYTES]);
+                let (pk, sk) = sign::keypair_from_seed(&sign::Seed(seed));
+                if sk.0[..] != secret[..] {
+                    return Err(invalid("SHS_SECRET_KEY",
+                                       "the public key half does not match the seed"));
+                }
+                (pk, sk)
+            }
+            _ => return Err(invalid("SHS_SECRET_KEY", "must decode to 32 or 64 bytes")),
+        };
+
+        let server_pk = match var("SHS_SERVER_KEY") {
+            Some(value) => {
+                let bytes = decode_env_key(&value)
+                    .ok_or_else(|| invalid("SHS_SERVER_KEY", "not valid hex or base64"))?;
+                Some(sign::PublicKey::from_slice(&bytes)
+                         .ok_or_else(|| invalid("SHS_SERVER_KEY", "must decode to 32 bytes"))?)
+            }
+            None => None,
+        };
+
+        let dial = match var("SHS_DIAL") {
+            Some(value) => {
+                Some(value.parse()
+                         .map_err(|_| invalid("SHS_DIAL", "not a socket address"))?)
+            }
+            None => None,
+        };
+
+        Ok(ClientConfig {
+               network_identifier,
+               longterm_pk,
+               longterm_sk,
+               server_pk,
+               dial,
+               rng: None,
+           })
+    }
+
+    /// Returns a future that performs the client side of a handshake with the server with
+    /// the given longterm public key over the given `stream`, using a freshly generated
+    /// ephemeral keypair.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `server_pk` is set to a different key.
+    pub fn handshake<S: AsyncRead + AsyncWrite>(&self,
+                                                stream: S,
+                                                server_longterm_pk: sign::PublicKey)
+                                                -> OwningClientHandshaker<S> {
+        if let Some(ref expected) = self.server_pk {
+            assert!(expected == &server_longterm_pk,
+                    "ClientConfig::handshake called with a server other than the expected one");
+        }
+        let (client_ephemeral_pk, client_ephemeral_sk) =
+            generate_ephemeral_keypair(self.rng.as_ref());
+        OwningClientHandshaker::new(stream,
+                                    self.network_identifier,
+                                    self.longterm_pk.clone(),
+                                    self.longterm_sk.clone(),
+                                    client_ephemeral_pk,
+                                    client_ephemeral_sk,
+                                    server_longterm_pk)
+    }
+}
+
+/// Everything needed to accept handshakes.
+pub struct ServerConfig {
+    /// The network identifier to use.
+    pub network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    /// The longterm public key of the server.
+    pub longterm_pk: sign::PublicKey,
+    /// The longterm secret key of the server.
+    pub longterm_sk: sign::SecretKey,
+    /// The address on which to accept connections, if configured.
+    pub listen: Option<SocketAddr>,
+    /// The longterm public key of the only client to accept, if configured, see
+    /// `expect_client`.
+    pub expected_client: Option<sign::PublicKey>,
+}
+
+impl ServerConfig {
+    /// Only accept the client with the longterm public key `pk`, see
+    /// `HandshakeOptions::expect_client`. Any other client fails with
+    /// `HandshakeError::UnexpectedClient`.
+    pub fn expect_client(mut self, pk: sign::PublicKey) -> ServerConfig {
+        self.expected_client = Some(pk);
+        self
+    }
+
+    /// Returns an `Acceptor` for this server.
+    pub fn acceptor(&self) -> Acceptor {
+        let acceptor = Acceptor::new(self.network_identifier,
+                                     self.longterm_pk.clone(),
+                                     self.longterm_sk.clone());
+        match self.expected_client {
+            Some(pk) => acceptor.options(HandshakeOptions::new().expect_client(pk)),
+            None => acceptor,
+        }
+    }
+}
+
+impl HandshakeConfig {
+    /// Loads the keyfile, parses the network identifier and the addresses, and checks that
+    /// the keys in the keyfile belong together.
+    pub fn materialize(&self) -> Result<(ClientConfig, ServerConfig), ConfigError> {
+        if self.selftest {
+            selftest_once().map_err(ConfigError::Selftest)?;
+        }
+        let network_identifier = match self.caps {
+            Some(ref caps) => parse_caps(caps)?,
+            None => MAIN_NET_IDENTIFIER,
+        };
+        let listen = parse_addr("listen", &self.listen)?;
+        let dial = parse_addr("dial", &self.dial)?;
+        let (longterm_pk, longterm_sk) = load_keyfile(&self.keyfile)?;
+
+        Ok((ClientConfig {
+                network_identifier,
+                longterm_pk: longterm_pk.clone(),
+                longterm_sk: longterm_sk.clone(),
+                server_pk: None,
+                dial,
+                rng: None,
+            },
+            ServerConfig {
+                network_identifier,
+                longterm_pk,
+                longterm_sk,
+                listen,
+                expected_client: None,
+            }))
+    }
+}
+
+fn parse_caps(caps: &str) -> Result<[u8; NETWORK_IDENTIFIER_BYTES], ConfigError> {
+    let invalid = |reason| {
+        ConfigError::InvalidCaps {
+            value: caps.to_string(),
+            reason,
+        }
+    };
+
+    let bytes = decode_base64(caps).ok_or_else(|| invalid("not valid base64"))?;
+    if bytes.len() != NETWORK_IDENTIFIER_BYTES {
+        return Err(invalid("must decode to 32 bytes"));
+    }
+
+    let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+    network_identifier.copy_from_slice(&bytes);
+    Ok(network_identifier)
+}
+
+fn parse_addr(field: &'static str,
+              addr: &Option<String>)
+              -> Result<Option<SocketAddr>, ConfigError> {
+    match *addr {
+        None => Ok(None),
+        Some(ref addr) => {
+            addr.parse()
+                .map(Some)
+                .map_err(|_| {
+                             ConfigError::InvalidAddress {
+                                 field,
+                                 value: addr.clone(),
+                             }
+                         })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Keyfile {
+    curve: String,
+    public: String,
+    private: String,
+}
+
+fn load_keyfile(path: &Path) -> Result<(sign::PublicKey, sign::SecretKey), ConfigError> {
+    let invalid = |reason| {
+        ConfigError::InvalidKeyfile {
+            path: path.to_path_buf(),
+            reason,
+        }
+    };
+
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|err| {
+                     ConfigError::Keyfile {
+                         path: path.to_path_buf(),
+                         err,
+                     }
+                 })?;
+
+    let json: String = contents
+        .lines()
+        .filter(|line| !line.trim().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let keyfile: Keyfile = serde_json::from_str(&json)
+        .map_err(|_| invalid("not a json object with curve, public and private fields"))?;
+
+    if keyfile.curve != "ed25519" {
+        return Err(invalid("curve must be ed25519"));
+    }
+
+    let pk = decode_key(&keyfile.public)
+        .and_then(|bytes| sign::PublicKey::from_slice(&bytes))
+        .ok_or_else(|| invalid("malformed public key"))?;
+    let sk = decode_key(&keyfile.private)
+        .and_then(|bytes| sign::SecretKey::from_slice(&bytes))
+        .ok_or_else(|| invalid("malformed private key"))?;
+
+    let mut seed = [0; sign::SEEDBYTES];
+    seed.copy_from_slice(&sk.0[..sign::SEEDBYTES]);
+    let (derived_pk, _) = sign::keypair_from_seed(&sign::Seed(seed));
+    if derived_pk != pk || sk.0[sign::SEEDBYTES..] != pk.0[..] {
+        return Err(ConfigError::KeyMismatch { path: path.to_path_buf() });
+    }
+
+    Ok((pk, sk))
+}
+
+// Decodes a key of the form `<base64>.ed25519`.
+pub(crate) fn decode_key(key: &str) -> Option<Vec<u8>> {
+    if key.ends_with(".ed25519") {
+        decode_base64(&key[..key.len() - ".ed25519".len()])
+    } else {
+        None
+    }
+}
+
+// Decodes the value of an environment variable as hex or base64.
+fn decode_env_bytes(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    decode_hex(value).or_else(|| decode_base64(value))
+}
+
+// Decodes a key from an environment variable, which may also be of the form
+// `@<base64>.ed25519`.
+fn decode_env_key(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let value = if value.starts_with('@') { &value[1..] } else { value };
+    decode_key(value).or_else(|| decode_env_bytes(value))
+}
+
+/// The `connections` section of an ssb-config file, see the module documentation.
+///
+/// Only the `net` and `unix` transports are read, entries of other transports (e.g. `ws` or
+/// `onion`) are ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct ConnectionsSpec {
+    /// The transports on which to accept connections.
+    #[serde(default)]
+    pub incoming: TransportSpecs,
+    /// The transports on which to dial connections.
+    #[serde(default)]
+    pub outgoing: TransportSpecs,
+}
+
+/// The entries of the transports of one direction of a `ConnectionsSpec`.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct TransportSpecs {
+    /// The tcp entries.
+    #[serde(default)]
+    pub net: Vec<TransportSpec>,
+    /// The unix socket entries.
+    #[serde(default)]
+    pub unix: Vec<TransportSpec>,
+}
+
+/// One entry of a transport, e.g. `{ "scope": "public", "port": 8008, "transform": "shs" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TransportSpec {
+    /// The host to listen on, for incoming `net` entries. Defaults to `::`.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// The port to listen on, for incoming `net` entries. Defaults to 8008.
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// The path of the socket, for incoming `unix` entries.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// The scopes of the entry, e.g. `public` or `device`. A single string is read as one
+    /// scope.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub scope: Vec<String>,
+    /// How connections are secured. Only `shs` is supported.
+    pub transform: String,
+}
+
+/// The address of an incoming listener built from a `ConnectionsSpec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A tcp address.
+    Tcp(SocketAddr),
+    /// The path of a unix socket.
+    Unix(PathBuf),
+}
+
+/// A listener built from an incoming entry of a `ConnectionsSpec`.
+#[derive(Clone)]
+pub struct IncomingListener {
+    /// Where to listen.
+    pub addr: ListenAddr,
+    /// The scopes of the entry.
+    pub scope: Vec<String>,
+    /// The acceptor for the connections of this listener.
+    pub acceptor: Acceptor,
+}
+
+/// A transport to dial connections on, built from an outgoing entry of a `ConnectionsSpec`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialTarget {
+    /// The transport, `net` or `unix`.
+    pub transport: &'static str,
+    /// The scopes of the entry.
+    pub scope: Vec<String>,
+}
+
+/// The port of incoming `net` entries that do not specify one.
+pub const DEFAULT_PORT: u16 = 8008;
+
+impl ConnectionsSpec {
+    /// Returns a listener with an acceptor for `server` for every incoming `net` and `unix`
+    /// entry, in that order.
+    ///
+    /// Fails if an entry uses a transform other than `shs`, if the host of a `net` entry can
+    /// not be resolved, or if a `unix` entry has no path.
+    pub fn build_acceptors(&self,
+                           server: &ServerConfig)
+                           -> Result<Vec<IncomingListener>, ConfigError> {
+        let mut listeners = Vec::new();
+
+        for entry in &self.incoming.net {
+            entry.check_transform("net")?;
+            let host = entry.host.as_ref().map(|host| host.as_str()).unwrap_or("::");
+            let port = entry.port.unwrap_or(DEFAULT_PORT);
+            let addr = (host, port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| {
+                                ConfigError::InvalidAddress {
+                                    field: "connections.incoming.net",
+                                    value: format!("{}:{}", host, port),
+                                }
+                            })?;
+            listeners.push(IncomingListener {
+                               addr: ListenAddr::Tcp(addr),
+                               scope: entry.scope.clone(),
+                               acceptor: server.acceptor(),
+                           });
+        }
+
+        for entry in &self.incoming.unix {
+            entry.check_transform("unix")?;
+            let path = entry.path
+                .clone()
+                .ok_or(ConfigError::MissingField { field: "connections.incoming.unix.path" })?;
+            listeners.push(IncomingListener {
+                               addr: ListenAddr::Unix(path),
+                               scope: entry.scope.clone(),
+                               acceptor: server.acceptor(),
+                           });
+        }
+
+        Ok(listeners)
+    }
+
+    /// Returns the transports to dial connections on, one for every outgoing `net` and
+    /// `unix` entry, in that order. Fails if an entry uses a transform other than `shs`.
+    pub fn dial_targets(&self) -> Result<Vec<DialTarget>, ConfigError> {
+        let net = self.outgoing.net.iter().map(|entry| ("net", entry));
+        let unix = self.outgoing.unix.iter().map(|entry| ("unix", entry));
+
+        net.chain(unix)
+            .map(|(transport, entry)| {
+                     entry.check_transform(transport)?;
+                     Ok(DialTarget {
+                            transport,
+                            scope: entry.scope.clone(),
+                        })
+                 })
+            .collect()
+    }
+}
+
+impl TransportSpec {
+    fn check_transform(&self, transport: &'static str) -> Result<(), ConfigError> {
+        if self.transform == "shs" {
+            Ok(())
+        } else {
+            Err(ConfigError::UnsupportedTransform {
+                    transport,
+                    transform: self.transform.clone(),
+                })
+        }
+    }
+}
+
+// Deserializes a string or a sequence of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+           OneOrMany::One(scope) => vec![scope],
+           OneOrMany::Many(scopes) => scopes,
+       })
+}
+
+/// Errors that can occur when materializing a `HandshakeConfig`, when reading a
+/// `ClientConfig` from the environment, or when building from a `ConnectionsSpec`.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The keyfile could not be read.
+    Keyfile {
+        /// The configured path of the keyfile.
+        path: PathBuf,
+        /// The error that occured when reading it.
+        err: io::Error,
+    },
+    /// The keyfile does not contain a valid ed25519 keypair.
+    InvalidKeyfile {
+        /// The configured path of the keyfile.
+        path: PathBuf,
+        /// What is wrong with the keyfile.
+        reason: &'static str,
+    },
+    /// The public and the private key in the keyfile do not belong together.
+    KeyMismatch {
+        /// The configured path of the keyfile.
+        path: PathBuf,
+    },
+    /// The `caps` field is not a base64 encoded network identifier.
+    InvalidCaps {
+        /// The configured value.
+        value: String,
+        /// What is wrong with the value.
+        reason: &'static str,
+    },
+    /// The `listen` or `dial` field is not a socket address, or the host of a
+    /// `ConnectionsSpec` entry can not be resolved.
+    InvalidAddress {
+        /// The name of the field.
+        field: &'static str,
+        /// The configured value.
+        value: String,
+    },
+    /// An entry of a `ConnectionsSpec` secures connections with a transform other than
+    /// `shs`.
+    UnsupportedTransform {
+        /// The transport of the entry, `net` or `unix`.
+        transport: &'static str,
+        /// The configured transform.
+        transform: String,
+    },
+    /// An entry of a `ConnectionsSpec` lacks a field it needs.
+    MissingField {
+        /// The path of the field.
+        field: &'static str,
+    },
+    /// A required environment variable is not set.
+    MissingVariable {
+        /// The name of the variable.
+        name: &'static str,
+    },
+    /// An environment variable has an invalid value. The value is not included, since it
+    /// may be a secret key.
+    InvalidVariable {
+        /// The name of the variable.
+        name: &'static str,
+        /// What is wrong with the value.
+        reason: &'static str,
+    },
+    /// The crypto selftest failed, see `HandshakeConfig::selftest`.
+    Selftest(SelftestError),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::Keyfile { ref path, ref err } => {
+                write!(f, "Config error: keyfile {}: {}", path.display(), err)
+            }
+            ConfigError::InvalidKeyfile { ref path, reason } => {
+                write!(f, "Config error: keyfile {}: {}", path.display(), reason)
+            }
+            ConfigError::KeyMismatch { ref path } => {
+                write!(f,
+                       "Config error: keyfile {}: public and private key do not match",
+                       path.display())
+            }
+            ConfigError::InvalidCaps { ref value, reason } => {
+                write!(f, "Config error: caps {:?}: {}", value, reason)
+            }
+            ConfigError::InvalidAddress { field, ref value } => {
+                write!(f, "Config error: {} {:?}: not a socket address", field, value)
+            }
+            ConfigError::UnsupportedTransform { transport, ref transform } => {
+                write!(f,
+                       "Config error: {} transform {:?} is not supported, only \"shs\" is",
+                       transport,
+                       transform)
+            }
+            ConfigError::MissingField { field } => {
+                write!(f, "Config error: {} is missing", field)
+            }
+            ConfigError::MissingVariable { name } => {
+                write!(f, "Config error: environment variable {} is not set", name)
+            }
+            ConfigError::InvalidVariable { name, reason } => {
+                write!(f, "Config error: environment variable {}: {}", name, reason)
+            }
+            ConfigError::Selftest(ref err) => write!(f, "Config error: {}", err),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Keyfile { .. } => "could not read the keyfile",
+            ConfigError::InvalidKeyfile { reason, .. } => reason,
+            ConfigError::KeyMismatch { .. } => "public and private key do not match",
+            ConfigError::InvalidCaps { reason, .. } => reason,
+            ConfigError::InvalidAddress { .. } => "not a socket address",
+            ConfigError::UnsupportedTransform { .. } => "unsupported transform",
+            ConfigError::MissingField { .. } => "missing field",
+            ConfigError::MissingVariable { .. } => "environment variable is not set",
+            ConfigError::InvalidVariable { reason, .. } => reason,
+            ConfigError::Selftest(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ConfigError::Keyfile { ref err, .. } => Some(err),
+            ConfigError::Selftest(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Bundle the outcome of a handshake with the stream it was performed over, or reduce it to
+//! the verified identity of the peer.
+
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::Duration;
+
+use sodiumoxide::crypto::sign;
+use sodiumoxide::crypto::hash::sha256;
+
+use crypto::{Outcome, fingerprint};
+use errors::HandshakeError;
+use handshake::Handshake;
+use proxy::ProxyHeader;
+#[cfg(feature = "compat")]
+use compat::HandshakeKeys;
+
+/// A stream over which a handshake has been completed, together with the outcome of that
+/// handshake.
+///
+/// Returned by `Acceptor::accept_secured` and `ClientHandshakerFactory::start_secured`, or
+/// by wrapping any handshake with `Secured::new`.
+pub struct SecuredConnection<S> {
+    outcome: Outcome,
+    stream: S,
+    rtt_estimate: Option<Duration>,
+    proxy_header: Option<ProxyHeader>,
+}
+
+impl<S> SecuredConnection<S> {
+    /// Bundles the given outcome with the stream over which it was obtained.
+    pub fn new(outcome: Outcome, stream: S) -> SecuredConnection<S> {
+        SecuredConnection {
+            outcome,
+            stream,
+            rtt_estimate: None,
+            proxy_header: None,
+        }
+    }
+
+    /// Sets the estimate of the round trip time to the peer.
+    pub fn with_rtt_estimate(mut self, rtt_estimate: Option<Duration>) -> SecuredConnection<S> {
+        self.rtt_estimate = rtt_estimate;
+        self
+    }
+
+    /// Sets the PROXY protocol header that preceded the handshake.
+    pub fn with_proxy_header(mut self,
+                             proxy_header: Option<ProxyHeader>)
+                             -> SecuredConnection<S> {
+        self.proxy_header = proxy_header;
+        self
+    }
+
+    /// The outcome of the handshake.
+    pub fn outcome(&self) -> &Outcome {
+        &self.outcome
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_pk(&self) -> sign::PublicKey {
+        self.outcome.peer_longterm_pk()
+    }
+
+    /// An approximation of the round trip time to the peer, measured during the handshake,
+    /// see `Handshake::rtt_estimate`. `None` for connections created via `new` or `From`.
+    pub fn rtt_estimate(&self) -> Option<Duration> {
+        self.rtt_estimate
+    }
+
+    /// The PROXY protocol header that preceded the handshake, see `Handshake::proxy_header`.
+    pub fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.proxy_header
+    }
+
+    /// The address of the peer as reported by the PROXY protocol header, i.e. the one of the
+    /// actual client rather than the one of the proxy. `None` if there was no header, or it
+    /// did not convey an address.
+    pub fn proxy_peer_addr(&self) -> Option<SocketAddr> {
+        self.proxy_header.and_then(|header| header.source())
+    }
+
+    /// Gets a reference to the stream.
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+
+    /// Gets a mutable reference to the stream.
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Splits this connection into the outcome and the stream.
+    pub fn into_parts(self) -> (Outcome, S) {
+        (self.outcome, self.stream)
+    }
+
+    /// The keys for encrypting the stream with ssb-boxstream, see `compat::HandshakeKeys`.
+    ///
+    /// Only available with the `compat` feature.
+    #[cfg(feature = "compat")]
+    pub fn box_stream_keys(&self) -> HandshakeKeys {
+        HandshakeKeys::from(&self.outcome)
+    }
+}
+
+impl<S> From<(Outcome, S)> for SecuredConnection<S> {
+    fn from((outcome, stream): (Outcome, S)) -> SecuredConnection<S> {
+        SecuredConnection::new(outcome, stream)
+    }
+}
+
+// Leaves out the keys and nonces, and the stream.
+impl<S> fmt::Debug for SecuredConnection<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecuredConnection")
+            .field("peer", &fingerprint(&self.outcome.peer_longterm_pk().0))
+            .finish()
+    }
+}
+
+/// Wraps a handshake so that it yields a `SecuredConnection` instead of an
+/// `(Outcome, S)` tuple, including the `rtt_estimate` and the `proxy_header` of the
+/// handshake. Errors are passed through unchanged.
+#[derive(Debug)]
+pub struct Secured<F>(F);
+
+impl<F> Secured<F> {
+    /// Wraps the given handshake.
+    pub fn new(handshake: F) -> Secured<F> {
+        Secured(handshake)
+    }
+
+    /// Gets a mutable reference to the wrapped handshake.
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.0
+    }
+
+    /// Unwraps this `Secured`, returning the wrapped handshake.
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+
+// The handshake is polled via `Handshake::poll_handshake`, which does not pin it.
+impl<F> Unpin for Secured<F> {}
+
+impl<F: Handshake> Future for Secured<F> {
+    type Output = Result<SecuredConnection<F::Stream>, (HandshakeError, F::Stream)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.0.poll_handshake(cx)? {
+            Ready(parts) => {
+                Ready(Ok(SecuredConnection::from(parts)
+                             .with_rtt_estimate(self.0.rtt_estimate())
+                             .with_proxy_header(self.0.proxy_header())))
+            }
+            Pending => Pending,
+        }
+    }
+}
+
+/// The authenticated identity of the peer of a completed handshake, without any of the
+/// session keys. Created via `Handshake::verify_identity_only`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedIdentity {
+    peer_pk: sign::PublicKey,
+    session_id: [u8; sha256::DIGESTBYTES],
+}
+
+impl VerifiedIdentity {
+    /// The longterm public key of the peer, which the handshake has verified.
+    pub fn peer_pk(&self) -> sign::PublicKey {
+        self.peer_pk.clone()
+    }
+
+    /// Identifies the session, equal for client and server. It is a hash of the session keys
+    /// that does not reveal them, so it can be logged or compared out of band. This is not a
+    /// hash of the handshake messages.
+    pub fn session_id(&self) -> &[u8; sha256::DIGESTBYTES] {
+        &self.session_id
+    }
+}
+
+impl<'a> From<&'a Outcome> for VerifiedIdentity {
+    fn from(outcome: &'a Outcome) -> VerifiedIdentity {
+        VerifiedIdentity {
+            peer_pk: outcome.peer_longterm_pk(),
+            session_id: outcome.session_hash(),
+        }
+    }
+}
+
+/// Future returned by `Handshake::verify_identity_only`, resolving to the verified identity
+/// of the peer and the stream. The outcome of the handshake is zeroed right away.
+#[derive(Debug)]
+pub struct IdentityOnly<F>(F);
+
+impl<F> IdentityOnly<F> {
+    /// Wraps the given handshake.
+    pub fn new(handshake: F) -> IdentityOnly<F> {
+        IdentityOnly(handshake)
+    }
+
+    /// Unwraps this `IdentityOnly`, returning the wrapped handshake.
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+
+// The handshake is polled via `Handshake::poll_handshake`, which does not pin it.
+impl<F> Unpin for IdentityOnly<F> {}
+
+impl<F: Handshake> Future for IdentityOnly<F> {
+    type Output = Result<(VerifiedIdentity, F::Stream), (HandshakeError, F::Stream)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.0.poll_handshake(cx)? {
+            Ready((outcome, stream)) => {
+                let identity = VerifiedIdentity::from(&outcome);
+                outcome.zero();
+                Ready(Ok((identity, stream)))
+            }
+            Pending => Pending,
+        }
+    }
+}
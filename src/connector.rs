@@ -0,0 +1,605 @@
+//! Connect to a peer by its multiserver address in a single call, see `PeerConnector`.
+//!
+//! This module is only available with the `config` feature.
+//!
+//! ```rust,ignore
+//! let connector = PeerConnector::new(client_config)
+//!     .socket_options(SocketOptions::new().nodelay(true))
+//!     .timeout(Duration::from_secs(10))
+//!     .attempts(3);
+//! let peer = connector.connect("net:pub.example.com:8008~shs:<base64 key>")?;
+//! println!("connected to {}", peer.feed_id);
+//! ```
+//!
+//! A multiserver address is a `;`-separated list of alternatives of the form
+//! `<transport>~shs:<base64 longterm public key of the peer>`, where the transport is one of
+//!
+//! - `net:<host>:<port>`, dialed directly, or through the proxy if one is configured,
+//! - `onion:<host>.onion:<port>`, dialed through the proxy (e.g. the SOCKS5 port of tor),
+//! - `ws://<host>:<port>` or `wss://...`, which is recognized but not supported.
+//!
+//! The alternatives are tried in order, the first one that succeeds is used. Connecting
+//! blocks the calling thread.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::{TimedOut, WouldBlock, InvalidData};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use config::ClientConfig;
+use connection::{Secured, SecuredConnection};
+use crypto::selftest_once;
+use errors::{HandshakeError, SelftestError};
+use keyfile::{decode_base64, encode_base64};
+use listener::NonblockingStream;
+use socket::{connect_tcp, SocketOptions, SocketOptionError};
+
+/// The default of `PeerConnector::timeout`.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One alternative of a multiserver address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiserverAddress {
+    /// How to reach the peer.
+    pub transport: Transport,
+    /// The longterm public key of the peer.
+    pub server_pk: sign::PublicKey,
+}
+
+/// The transport part of a `MultiserverAddress`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// `net:<host>:<port>`.
+    Net {
+        /// The host name or ip address, without brackets for IPv6 addresses.
+        host: String,
+        /// The port.
+        port: u16,
+    },
+    /// `onion:<host>:<port>`.
+    Onion {
+        /// The onion host name.
+        host: String,
+        /// The port.
+        port: u16,
+    },
+    /// `ws://...` or `wss://...`, with the url as given.
+    Ws(String),
+}
+
+impl Transport {
+    /// The name of the transport, `net`, `onion` or `ws`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Net { .. } => "net",
+            Transport::Onion { .. } => "onion",
+            Transport::Ws(_) => "ws",
+        }
+    }
+}
+
+impl MultiserverAddress {
+    /// Parses all `;`-separated alternatives of a multiserver address.
+    pub fn parse_all(address: &str) -> Result<Vec<MultiserverAddress>, ConnectError> {
+        address.trim().split(';').map(MultiserverAddress::parse).collect()
+    }
+
+    /// Parses a single alternative of a multiserver address.
+    pub fn parse(address: &str) -> Result<MultiserverAddress, ConnectError> {
+        let invalid = |reason| {
+            ConnectError::InvalidAddress {
+                address: address.to_string(),
+                reason,
+            }
+        };
+
+        let address = address.trim();
+        let tilde = address.rfind('~').ok_or_else(|| invalid("missing ~shs:<key>"))?;
+        let (transport, transform) = (&address[..tilde], &address[tilde + 1..]);
+
+        if !transform.starts_with("shs:") {
+            return Err(invalid("the transform is not shs"));
+        }
+        let key = &transform["shs:".len()..];
+        let key = if key.starts_with('@') { &key[1..] } else { key };
+        let key = if key.ends_with(".ed25519") {
+            &key[..key.len() - ".ed25519".len()]
+        } else {
+            key
+        };
+        let server_pk = decode_base64(key)
+            .and_then(|bytes| sign::PublicKey::from_slice(&bytes))
+            .ok_or_else(|| invalid("the key is not a base64 encoded public key"))?;
+
+        let transport = if transport.starts_with("ws://") || transport.starts_with("wss://") {
+            Transport::Ws(transport.to_string())
+        } else if transport.starts_with("net:") {
+            let (host, port) = host_port(&transport["net:".len()..]).ok_or_else(|| {
+                invalid("not of the form net:<host>:<port>")
+            })?;
+            Transport::Net { host, port }
+        } else if transport.starts_with("onion:") {
+            let (host, port) = host_port(&transport["onion:".len()..]).ok_or_else(|| {
+                invalid("not of the form onion:<host>:<port>")
+            })?;
+            Transport::Onion { host, port }
+        } else {
+            return Err(ConnectError::UnsupportedTransport(transport.to_string()));
+        };
+
+        Ok(MultiserverAddress {
+               transport,
+               server_pk,
+           })
+    }
+}
+
+// Splits `host:port`, where the host may be an IPv6 address, with or without brackets.
+fn host_port(address: &str) -> Option<(String, u16)> {
+    let colon = address.rfind(':')?;
+    let (host, port) = (&address[..colon], &address[colon + 1..]);
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Connects to peers by their multiserver address: parses the address, resolves and dials
+/// it, applies the socket options, and performs the handshake as the client of a
+/// `ClientConfig`.
+pub struct PeerConnector {
+    config: ClientConfig,
+    socket_options: SocketOptions,
+    proxy: Option<SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+    selftest: bool,
+}
+
+impl PeerConnector {
+    /// Creates a connector that authenticates as the client of `config`, without socket
+    /// options or proxy, with a timeout of `DEFAULT_CONNECT_TIMEOUT` and a single attempt.
+    pub fn new(config: ClientConfig) -> PeerConnector {
+        PeerConnector {
+            config,
+            socket_options: SocketOptions::new(),
+            proxy: None,
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+            attempts: 1,
+            selftest: false,
+        }
+    }
+
+    /// Applies the `options` to every dialed socket, see `connect_tcp`. With a proxy, they
+    /// apply to the connection to the proxy.
+    pub fn socket_options(mut self, options: SocketOptions) -> PeerConnector {
+        self.socket_options = options;
+        self
+    }
+
+    /// Dials `net` and `onion` addresses through the SOCKS5 proxy at `addr` (e.g. tor),
+    /// which resolves the host names. `onion` addresses can not be dialed without one.
+    pub fn proxy(mut self, addr: SocketAddr) -> PeerConnector {
+        self.proxy = Some(addr);
+        self
+    }
+
+    /// Fails an attempt with `ConnectError::Timeout` if the proxy negotiation and the
+    /// handshake take longer than `timeout` together. Establishing the tcp connection itself
+    /// is bounded by the operating system.
+    pub fn timeout(mut self, timeout: Duration) -> PeerConnector {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Makes up to `n` attempts (at least one) to connect via each alternative of an
+    /// address. Failures to parse or resolve the address and the peer failing to
+    /// authenticate are not retried.
+    pub fn attempts(mut self, n: usize) -> PeerConnector {
+        self.attempts = n.max(1);
+        self
+    }
+
+    /// If `selftest` is true, runs `crypto::selftest` before the first connection attempt
+    /// of the process, and fails every attempt with `ConnectError::Selftest` if it did not
+    /// pass. The selftest runs at most once per process, no matter how many connectors
+    /// enable it.
+    pub fn selftest(mut self, selftest: bool) -> PeerConnector {
+        self.selftest = selftest;
+        self
+    }
+
+    /// Connects to the peer at the multiserver address `target`, trying its alternatives
+    /// in order, and returns the first connection over which the handshake succeeded. If
+    /// none does, the error of the last attempt is returned. Blocks the calling thread.
+    pub fn connect(&self, target: &str) -> Result<SecuredPeer, ConnectError> {
+        let addresses = MultiserverAddress::parse_all(target)?;
+
+        let mut last_err = None;
+        for address in &addresses {
+            for _ in 0..self.attempts {
+                match self.connect_to(address) {
+                    Ok(peer) => return Ok(peer),
+                    Err(err) => {
+                        let retry = err.is_transient();
+                        last_err = Some(err);
+                        if !retry {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        Err(last_err.expect("a multiserver address has at least one alternative"))
+    }
+
+    /// Connects to a single alternative of a multiserver address, in a single attempt.
+    pub fn connect_to(&self, address: &MultiserverAddress) -> Result<SecuredPeer, ConnectError> {
+        if self.selftest {
+            selftest_once().map_err(ConnectError::Selftest)?;
+        }
+        if let Some(ref expected) = self.config.server_pk {
+            if expected != &address.server_pk {
+                return Err(ConnectError::UnexpectedServer(address.server_pk));
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let stream = match (&address.transport, self.proxy) {
+            (&Transport::Ws(_), _) => {
+                return Err(ConnectError::UnsupportedTransport("ws".to_string()))
+            }
+            (&Transport::Onion { .. }, None) => return Err(ConnectError::MissingProxy),
+            (&Transport::Net { ref host, port }, Some(proxy)) |
+            (&Transport::Onion { ref host, port }, Some(proxy)) => {
+                let stream = self.dial(proxy)?;
+                socks5_connect(&stream, host, port, deadline).map_err(|err| {
+                    if err.kind() == TimedOut {
+                        ConnectError::Timeout
+                    } else {
+                        ConnectError::Proxy(err)
+                    }
+                })?;
+                stream
+            }
+            (&Transport::Net { ref host, port }, None) => {
+                let addr = (host.as_str(), port)
+                    .to_socket_addrs()
+                    .and_then(|mut addrs| {
+                        addrs.next()
+                            .ok_or_else(|| io::Error::new(InvalidData, "no addresses found"))
+                    })
+                    .map_err(|err| {
+                                 ConnectError::Resolve {
+                                     host: host.clone(),
+                                     err,
+                                 }
+                             })?;
+                self.dial(addr)?
+            }
+        };
+
+        let handshake = self.config
+            .handshake(BlockingUntil { stream, deadline }, address.server_pk);
+        let connection = match block_on_handshake(Secured::new(handshake)) {
+            Ok(connection) => connection,
+            Err((HandshakeError::IoError(ref err), _)) if err.kind() == TimedOut => {
+                return Err(ConnectError::Timeout)
+            }
+            Err((err, _)) => return Err(ConnectError::Handshake(err)),
+        };
+
+        let rtt_estimate = connection.rtt_estimate();
+        let (outcome, stream) = connection.into_parts();
+        let stream = stream.into_nonblocking().map_err(|err| {
+                          ConnectError::Connect(SocketOptionError {
+                                                    option: "nonblocking",
+                                                    err,
+                                                })
+                      })?;
+        let feed_id = format!("@{}.ed25519", encode_base64(&outcome.peer_longterm_pk().0));
+        Ok(SecuredPeer {
+               connection: SecuredConnection::new(outcome, stream).with_rtt_estimate(rtt_estimate),
+               feed_id,
+           })
+    }
+
+    // Dials `addr` and moves the socket back into blocking mode for the proxy negotiation
+    // and the handshake.
+    fn dial(&self, addr: SocketAddr) -> Result<TcpStream, ConnectError> {
+        let stream = connect_tcp(addr, &self.socket_options)
+            .map_err(ConnectError::Connect)?
+            .into_inner();
+        stream
+            .set_nonblocking(false)
+            .map_err(|err| {
+                         ConnectError::Connect(SocketOptionError {
+                                                   option: "nonblocking",
+                                                   err,
+                                               })
+                     })?;
+        Ok(stream)
+    }
+}
+
+// Leaves out the keys of the client.
+impl fmt::Debug for PeerConnector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("PeerConnector")
+            .field("socket_options", &self.socket_options)
+            .field("proxy", &self.proxy)
+            .field("timeout", &self.timeout)
+            .field("attempts", &self.attempts)
+            .finish()
+    }
+}
+
+/// A connection established by `PeerConnector::connect`.
+#[derive(Debug)]
+pub struct SecuredPeer {
+    /// The connection, with the outcome of the handshake. The stream is in nonblocking
+    /// mode. With the `compat` feature, `box_stream_keys` returns the keys for encrypting
+    /// it with ssb-boxstream.
+    pub connection: SecuredConnection<NonblockingStream<TcpStream>>,
+    /// The feed id of the peer, i.e. its longterm public key as `@<base64>.ed25519`.
+    pub feed_id: String,
+}
+
+// A blocking tcp stream whose reads and writes time out at the deadline, with a `TimedOut`
+// error.
+struct BlockingUntil {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl BlockingUntil {
+    fn remaining(&self) -> io::Result<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            Err(io::Error::new(TimedOut, "the connection attempt timed out"))
+        } else {
+            Ok(self.deadline - now)
+        }
+    }
+
+    fn into_nonblocking(self) -> io::Result<NonblockingStream<TcpStream>> {
+        self.stream.set_read_timeout(None)?;
+        self.stream.set_write_timeout(None)?;
+        NonblockingStream::new(self.stream)
+    }
+}
+
+// Sockets report an expired timeout as `WouldBlock` on some platforms.
+fn timed_out<T>(result: io::Result<T>) -> io::Result<T> {
+    result.map_err(|err| if err.kind() == WouldBlock {
+                       io::Error::new(TimedOut, "the connection attempt timed out")
+                   } else {
+                       err
+                   })
+}
+
+impl Read for BlockingUntil {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        timed_out(self.stream.read(buf))
+    }
+}
+
+impl Write for BlockingUntil {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        timed_out(self.stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl AsyncRead for BlockingUntil {
+    fn poll_read(self: Pin<&mut Self>,
+                 _: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<io::Result<usize>> {
+        Ready(self.get_mut().read(buf))
+    }
+}
+
+impl AsyncWrite for BlockingUntil {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Ready(self.get_mut().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Ready(self.get_mut().flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Ready(self.get_mut().flush())
+    }
+}
+
+// Drives a handshake over a blocking stream to completion on the current thread. It only
+// returns `Pending` when it yields, in which case it is polled again right away.
+fn block_on_handshake<F: Future + Unpin>(mut future: F) -> F::Output {
+    struct NoopWake;
+
+    impl Wake for NoopWake {
+        fn wake(self: Arc<NoopWake>) {}
+    }
+
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match Pin::new(&mut future).poll(&mut cx) {
+            Ready(output) => return output,
+            Pending => {}
+        }
+    }
+}
+
+// Asks the SOCKS5 proxy behind `stream` to connect to `host:port`, without authentication.
+fn socks5_connect(stream: &TcpStream, host: &str, port: u16, deadline: Instant) -> io::Result<()> {
+    let mut stream = BlockingUntil {
+        stream: stream.try_clone()?,
+        deadline,
+    };
+    let refused = |reason| io::Error::new(InvalidData, reason);
+
+    if host.len() > 255 {
+        return Err(refused("the host name is too long for SOCKS5"));
+    }
+
+    // Version 5, one method: no authentication.
+    stream.write_all(&[5, 1, 0])?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice)?;
+    if choice != [5, 0] {
+        return Err(refused("the proxy requires authentication"));
+    }
+
+    // Version 5, connect, reserved, domain name.
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 || reply[1] != 0 {
+        return Err(refused("the proxy could not connect to the peer"));
+    }
+    // The address the proxy bound to, followed by the port.
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(refused("the proxy replied with an invalid address type")),
+    };
+    stream.read_exact(&mut vec![0; bound + 2])
+}
+
+/// Errors of `PeerConnector::connect`, one kind per stage of connecting.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The multiserver address could not be parsed.
+    InvalidAddress {
+        /// The address, or the alternative of it that could not be parsed.
+        address: String,
+        /// What is wrong with it.
+        reason: &'static str,
+    },
+    /// The address uses a transport this connector does not support, e.g. `ws`.
+    UnsupportedTransport(String),
+    /// The address is an onion address, but no proxy is configured.
+    MissingProxy,
+    /// The address belongs to a server other than the one the `ClientConfig` expects.
+    UnexpectedServer(sign::PublicKey),
+    /// The host name could not be resolved.
+    Resolve {
+        /// The host name.
+        host: String,
+        /// The error that occured when resolving it.
+        err: io::Error,
+    },
+    /// Connecting to the peer or to the proxy, or applying the socket options failed.
+    Connect(SocketOptionError),
+    /// The proxy did not connect to the peer.
+    Proxy(io::Error),
+    /// The proxy negotiation and the handshake did not complete within the timeout.
+    Timeout,
+    /// The handshake failed.
+    Handshake(HandshakeError),
+    /// The crypto selftest failed, see `PeerConnector::selftest`.
+    Selftest(SelftestError),
+}
+
+impl ConnectError {
+    // Whether another attempt might succeed.
+    fn is_transient(&self) -> bool {
+        match *self {
+            ConnectError::Connect(_) |
+            ConnectError::Proxy(_) |
+            ConnectError::Timeout |
+            ConnectError::Handshake(HandshakeError::IoError(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConnectError::InvalidAddress { ref address, reason } => {
+                write!(f, "Connect error: address {:?}: {}", address, reason)
+            }
+            ConnectError::UnsupportedTransport(ref transport) => {
+                write!(f, "Connect error: transport {:?} is not supported", transport)
+            }
+            ConnectError::MissingProxy => {
+                write!(f, "Connect error: onion addresses require a proxy")
+            }
+            ConnectError::UnexpectedServer(ref pk) => {
+                write!(f,
+                       "Connect error: the address belongs to the unexpected server {}",
+                       encode_base64(&pk.0))
+            }
+            ConnectError::Resolve { ref host, ref err } => {
+                write!(f, "Connect error: resolving {}: {}", host, err)
+            }
+            ConnectError::Connect(ref err) => write!(f, "Connect error: {}", err),
+            ConnectError::Proxy(ref err) => write!(f, "Connect error: proxy: {}", err),
+            ConnectError::Timeout => write!(f, "Connect error: timed out"),
+            ConnectError::Handshake(ref err) => write!(f, "{}", err),
+            ConnectError::Selftest(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ConnectError {
+    fn description(&self) -> &str {
+        match *self {
+            ConnectError::InvalidAddress { reason, .. } => reason,
+            ConnectError::UnsupportedTransport(_) => "unsupported transport",
+            ConnectError::MissingProxy => "onion addresses require a proxy",
+            ConnectError::UnexpectedServer(_) => "the address belongs to an unexpected server",
+            ConnectError::Resolve { .. } => "could not resolve the host",
+            ConnectError::Connect(ref err) => err.description(),
+            ConnectError::Proxy(ref err) => err.description(),
+            ConnectError::Timeout => "timed out",
+            ConnectError::Handshake(ref err) => err.description(),
+            ConnectError::Selftest(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            ConnectError::Resolve { ref err, .. } => Some(err),
+            ConnectError::Connect(ref err) => Some(err),
+            ConnectError::Proxy(ref err) => Some(err),
+            ConnectError::Handshake(ref err) => Some(err),
+            ConnectError::Selftest(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
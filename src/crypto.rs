@@ -1,15 +1,64 @@
 //! Low-level bindings to shs1-c. You probably don't need to use this
 //! module directly.
 
-use std::mem::uninitialized;
+use std::fmt;
+use std::mem::{self, swap};
+use std::sync::{Arc, Mutex};
 
 use sodiumoxide::crypto::{box_, sign, scalarmult, secretbox, auth};
 use sodiumoxide::crypto::hash::sha256;
-use sodiumoxide::utils::memzero;
+use sodiumoxide::utils::{memzero, memcmp};
+use rand_core::CryptoRngCore;
+
+use errors::SelftestError;
+
+#[cfg(feature = "insecure-key-schedule-trace")]
+use key_schedule::KeyScheduleTrace;
 
 /// Length of a network identifier in bytes.
 pub const NETWORK_IDENTIFIER_BYTES: usize = 32;
 
+/// A network identifier, which all parties of a handshake must share.
+///
+/// The handshake uses it as the key of an HMAC, so while it is usually not secret, it is
+/// compared in constant time (both by `ct_eq` and by `==`), so that comparing it against
+/// a configured identifier does not leak how many leading bytes matched.
+#[derive(Clone, Copy)]
+pub struct NetworkIdentifier(pub [u8; NETWORK_IDENTIFIER_BYTES]);
+
+impl NetworkIdentifier {
+    /// Compares two network identifiers in constant time.
+    pub fn ct_eq(&self, other: &NetworkIdentifier) -> bool {
+        memcmp(&self.0, &other.0)
+    }
+}
+
+impl PartialEq for NetworkIdentifier {
+    fn eq(&self, other: &NetworkIdentifier) -> bool {
+        self.ct_eq(other)
+    }
+}
+
+impl Eq for NetworkIdentifier {}
+
+impl ::std::fmt::Debug for NetworkIdentifier {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "NetworkIdentifier({:?})", &self.0[..])
+    }
+}
+
+impl From<[u8; NETWORK_IDENTIFIER_BYTES]> for NetworkIdentifier {
+    fn from(bytes: [u8; NETWORK_IDENTIFIER_BYTES]) -> NetworkIdentifier {
+        NetworkIdentifier(bytes)
+    }
+}
+
+impl AsRef<[u8; NETWORK_IDENTIFIER_BYTES]> for NetworkIdentifier {
+    fn as_ref(&self) -> &[u8; NETWORK_IDENTIFIER_BYTES] {
+        &self.0
+    }
+}
+
 /// Length of msg1 in bytes.
 pub const MSG1_BYTES: usize = 64;
 /// Length of msg2 in bytes.
@@ -18,6 +67,105 @@ pub const MSG2_BYTES: usize = 64;
 pub const MSG3_BYTES: usize = 112;
 /// Length of msg4 in bytes.
 pub const MSG4_BYTES: usize = 80;
+/// Length of all four messages of a handshake together, in bytes.
+pub const HANDSHAKE_TOTAL_BYTES: usize = MSG1_BYTES + MSG2_BYTES + MSG3_BYTES + MSG4_BYTES;
+
+/// Number of state transitions a handshaker configured with `fair()` performs per
+/// poll before yielding.
+pub const FAIR_BUDGET: usize = 2;
+
+/// The cryptographic primitives used by the handshake, as returned by `crypto_info`. The
+/// names are those of libsodium, which implements all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptoInfo {
+    /// The protocol, `"shs1"`.
+    pub protocol: &'static str,
+    /// Signatures with the longterm keys (msg3 and msg4), `"ed25519"`.
+    pub signature: &'static str,
+    /// Diffie-Hellman key agreement between the ephemeral and longterm keys,
+    /// `"curve25519"`. The longterm ed25519 keys are converted to curve25519 keys for this.
+    pub key_agreement: &'static str,
+    /// Authentication of the ephemeral keys with the network identifier (msg1 and msg2),
+    /// `"hmacsha512256"`.
+    pub authenticator: &'static str,
+    /// Hashing of shared secrets and of the derived keys, `"sha256"`.
+    pub hash: &'static str,
+    /// Encryption of msg3 and msg4, `"xsalsa20poly1305"`. The keys and nonces of the
+    /// `Outcome` are meant for the same cipher, as used by box-stream.
+    pub encryption: &'static str,
+}
+
+/// Describes the cryptographic primitives used by the handshake. This is a static
+/// description, it does not depend on the configuration of any handshake.
+pub fn crypto_info() -> CryptoInfo {
+    CryptoInfo {
+        protocol: "shs1",
+        signature: "ed25519",
+        key_agreement: "curve25519",
+        authenticator: "hmacsha512256",
+        hash: "sha256",
+        encryption: "xsalsa20poly1305",
+    }
+}
+
+/// A caller-supplied source of randomness for ephemeral keys, for environments that must not
+/// use the random number generator of the operating system (e.g. certified DRBGs on
+/// embedded targets). See `generate_ephemeral_keypair` and the `with_rng` methods of
+/// `Acceptor`, `ClientHandshakerFactory` and `config::ClientConfig`.
+///
+/// Clones share the same generator, which is locked for generating each keypair.
+#[derive(Clone)]
+pub struct EphemeralRng(Arc<Mutex<Box<CryptoRngCore + Send>>>);
+
+impl EphemeralRng {
+    /// Generates ephemeral keys with `rng`.
+    pub fn new<R: CryptoRngCore + Send + 'static>(rng: R) -> EphemeralRng {
+        EphemeralRng(Arc::new(Mutex::new(Box::new(rng))))
+    }
+}
+
+impl fmt::Debug for EphemeralRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EphemeralRng").finish()
+    }
+}
+
+/// Generates an ephemeral keypair for a handshake, with 32 bytes of randomness from `rng`,
+/// or with the random number generator of the operating system (via libsodium) if `rng` is
+/// `None`.
+pub fn generate_ephemeral_keypair(rng: Option<&EphemeralRng>)
+                                  -> (box_::PublicKey, box_::SecretKey) {
+    let rng = match rng {
+        Some(rng) => rng,
+        None => return box_::gen_keypair(),
+    };
+
+    let mut sk = [0; box_::SECRETKEYBYTES];
+    // Generating a keypair can not panic while holding the lock.
+    rng.0
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .fill_bytes(&mut sk);
+    // Like `crypto_box_keypair`, which also takes the secret key as is.
+    let pk = scalarmult::scalarmult_base(&scalarmult::Scalar(sk));
+    let keypair = (box_::PublicKey(pk.0), box_::SecretKey(sk));
+    memzero(&mut sk);
+    keypair
+}
+
+// A short identifier of a longterm public key for logs, the hex encoding of its first 8 bytes.
+pub(crate) fn fingerprint(pk: &[u8; sign::PUBLICKEYBYTES]) -> String {
+    pk[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Whether `pk` is a point of the ed25519 curve that can take part in a handshake, i.e. one
+// that libsodium converts to curve25519.
+pub(crate) fn is_valid_longterm_pk(pk: &sign::PublicKey) -> bool {
+    let mut curve_pub = [0u8; scalarmult::GROUPELEMENTBYTES];
+    let valid = unsafe { crypto_sign_ed25519_pk_to_curve25519(&mut curve_pub, &pk.0) } == 0;
+    memzero(&mut curve_pub);
+    valid
+}
 
 /// The data resulting from a handshake: Keys and nonces suitable for encrypted
 /// two-way communication with the peer via box-stream-rs, and the longterm
@@ -34,6 +182,111 @@ pub struct Outcome {
     peer_longterm_pk: [u8; sign::PUBLICKEYBYTES],
 }
 
+/// Length of the memory layout of an `Outcome` in bytes, see `SecureOutcomeSlot`.
+pub const OUTCOME_BYTES: usize = 160;
+
+/// Caller-provided memory into which the outcome of a handshake can be written directly,
+/// see `ClientHandshaker::with_outcome_sink`.
+///
+/// The layout of the `OUTCOME_BYTES` bytes is:
+///
+/// - bytes 0 to 31: the encryption key
+/// - bytes 32 to 55: the encryption nonce
+/// - bytes 56 to 63: unspecified
+/// - bytes 64 to 95: the decryption key
+/// - bytes 96 to 119: the decryption nonce
+/// - bytes 120 to 127: unspecified
+/// - bytes 128 to 159: the longterm public key of the peer
+///
+/// Unlike an `Outcome`, a slot is not zeroed when dropped, the memory belongs to the caller.
+#[repr(transparent)]
+pub struct SecureOutcomeSlot([u8; OUTCOME_BYTES]);
+
+impl SecureOutcomeSlot {
+    /// Uses the given bytes as a slot. The bytes may live anywhere, e.g. in locked memory.
+    pub fn from_bytes_mut(bytes: &mut [u8; OUTCOME_BYTES]) -> &mut SecureOutcomeSlot {
+        // The slot is `repr(transparent)`, so it has the layout of its only field.
+        unsafe { &mut *(bytes as *mut [u8; OUTCOME_BYTES] as *mut SecureOutcomeSlot) }
+    }
+
+    /// The key that should be used to encrypt messages to the peer.
+    pub fn encryption_key(&self) -> &[u8; secretbox::KEYBYTES] {
+        &self.as_outcome().encryption_key
+    }
+
+    /// The initial nonce that should be used to encrypt messages to the peer.
+    pub fn encryption_nonce(&self) -> &[u8; secretbox::NONCEBYTES] {
+        &self.as_outcome().encryption_nonce
+    }
+
+    /// The key that should be used to decrypt messages from the peer.
+    pub fn decryption_key(&self) -> &[u8; secretbox::KEYBYTES] {
+        &self.as_outcome().decryption_key
+    }
+
+    /// The initial nonce that should be used to decrypt messages from the peer.
+    pub fn decryption_nonce(&self) -> &[u8; secretbox::NONCEBYTES] {
+        &self.as_outcome().decryption_nonce
+    }
+
+    /// The longterm public key of the peer.
+    pub fn peer_longterm_pk(&self) -> &[u8; sign::PUBLICKEYBYTES] {
+        &self.as_outcome().peer_longterm_pk
+    }
+
+    /// Zeroes out the whole slot.
+    pub fn zero(&mut self) {
+        memzero(&mut self.0);
+    }
+
+    // `Outcome` only consists of byte arrays, so it has no alignment requirements and its
+    // `repr(C)` layout is exactly the one documented above. The assertions below the impl
+    // check this at compile time.
+    fn as_outcome(&self) -> &Outcome {
+        unsafe { &*(self as *const SecureOutcomeSlot as *const Outcome) }
+    }
+
+    pub(crate) fn as_outcome_mut(&mut self) -> &mut Outcome {
+        unsafe { &mut *(self as *mut SecureOutcomeSlot as *mut Outcome) }
+    }
+}
+
+// The casts between slots and outcomes rely on these, the array lengths only match if they hold.
+const _: [(); OUTCOME_BYTES] = [(); mem::size_of::<Outcome>()];
+const _: [(); OUTCOME_BYTES] = [(); mem::size_of::<SecureOutcomeSlot>()];
+const _: [(); 1] = [(); mem::align_of::<Outcome>()];
+
+/// The parameters of a box-stream with the peer of a handshake, see
+/// `Outcome::into_box_stream_params`.
+pub struct BoxStreamParams {
+    /// The key for encrypting messages to the peer.
+    pub encryption_key: secretbox::Key,
+    /// The initial nonce for encrypting messages to the peer.
+    pub encryption_nonce: secretbox::Nonce,
+    /// The key for decrypting messages from the peer.
+    pub decryption_key: secretbox::Key,
+    /// The initial nonce for decrypting messages from the peer.
+    pub decryption_nonce: secretbox::Nonce,
+    /// The longterm public key of the peer.
+    pub peer_longterm_pk: sign::PublicKey,
+}
+
+/// Which of the derived keys and nonces are used for sending and which for receiving, see
+/// `Outcome::with_direction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectionOrder {
+    /// The assignment mandated by the protocol.
+    Standard,
+    /// The encryption and decryption keys and nonces are exchanged.
+    Swapped,
+}
+
+impl Default for DirectionOrder {
+    fn default() -> DirectionOrder {
+        DirectionOrder::Standard
+    }
+}
+
 /// Zero out all sensitive data when going out of scope
 impl Drop for Outcome {
     fn drop(&mut self) {
@@ -45,6 +298,37 @@ impl Drop for Outcome {
 }
 
 impl Outcome {
+    /// Length of the encryption and decryption keys in bytes.
+    pub const KEY_BYTES: usize = secretbox::KEYBYTES;
+    /// Length of the encryption and decryption nonces in bytes.
+    pub const NONCE_BYTES: usize = secretbox::NONCEBYTES;
+    /// Length of the longterm public key of the peer in bytes.
+    pub const PEER_PK_BYTES: usize = sign::PUBLICKEYBYTES;
+
+    // An outcome of all zeroes, to be overwritten by the C code.
+    fn zeroed() -> Outcome {
+        Outcome {
+            encryption_key: [0; secretbox::KEYBYTES],
+            encryption_nonce: [0; secretbox::NONCEBYTES],
+            padding_encryption: [0; 8],
+            decryption_key: [0; secretbox::KEYBYTES],
+            decryption_nonce: [0; secretbox::NONCEBYTES],
+            padding_decryption: [0; 8],
+            peer_longterm_pk: [0; sign::PUBLICKEYBYTES],
+        }
+    }
+
+    /// Length of the encryption and decryption keys in bytes, same as `Outcome::KEY_BYTES`.
+    pub fn key_len(&self) -> usize {
+        Outcome::KEY_BYTES
+    }
+
+    /// Length of the encryption and decryption nonces in bytes, same as
+    /// `Outcome::NONCE_BYTES`.
+    pub fn nonce_len(&self) -> usize {
+        Outcome::NONCE_BYTES
+    }
+
     /// The negotiated key that should be used to encrypt messages to the peer.
     pub fn encryption_key(&self) -> secretbox::Key {
         secretbox::Key(self.encryption_key)
@@ -69,9 +353,148 @@ impl Outcome {
     pub fn peer_longterm_pk(&self) -> sign::PublicKey {
         sign::PublicKey(self.peer_longterm_pk)
     }
+
+    /// Converts the outcome into the parameters of a box-stream with the peer. The
+    /// `Outcome` is zeroed, the returned keys are zeroed when they are dropped.
+    pub fn into_box_stream_params(self) -> BoxStreamParams {
+        BoxStreamParams {
+            encryption_key: self.encryption_key(),
+            encryption_nonce: self.encryption_nonce(),
+            decryption_key: self.decryption_key(),
+            decryption_nonce: self.decryption_nonce(),
+            peer_longterm_pk: self.peer_longterm_pk(),
+        }
+    }
+
+    /// Assigns the derived keys and nonces to the directions in the given `order`.
+    ///
+    /// `DirectionOrder::Standard` keeps the assignment mandated by the protocol and returns
+    /// the outcome unchanged. `DirectionOrder::Swapped` exchanges the encryption key and
+    /// nonce with the decryption key and nonce, as an escape hatch for peers that assign
+    /// them the other way around. Swapping must be coordinated with the peer: if only one
+    /// side swaps, neither can decrypt what the other sends.
+    pub fn with_direction(mut self, order: DirectionOrder) -> Outcome {
+        if order == DirectionOrder::Swapped {
+            swap(&mut self.encryption_key, &mut self.decryption_key);
+            swap(&mut self.encryption_nonce, &mut self.decryption_nonce);
+        }
+        self
+    }
+
+    /// Zeroes out all sensitive data right away, rather than when the `Outcome`
+    /// goes out of scope. This consumes the `Outcome`, so it can not be used
+    /// afterwards. Keys and nonces previously obtained from it are copies and
+    /// are not affected.
+    pub fn zero(self) {
+        // Dropping does the zeroing.
+    }
+
+    /// Computes a message authentication code for `data` with a key that only the two
+    /// peers of the handshake know, e.g. to authenticate a small token as issued within this
+    /// session without sending it through the encrypted stream. Both peers compute the same
+    /// code, different `context`s result in unrelated codes.
+    ///
+    /// The construction is `crypto_auth` (HMAC-SHA-512-256) with the key
+    /// `sha256("secret-handshake mac key" || k1 || k2)`, where `k1` and `k2` are the two
+    /// session keys in lexicographic order, over the message
+    /// `len(context) || context || data`, with the length as a big-endian u64.
+    pub fn mac(&self, context: &[u8], data: &[u8]) -> [u8; auth::TAGBYTES] {
+        let auth::Tag(tag) = auth::authenticate(&mac_message(context, data), &self.mac_key());
+        tag
+    }
+
+    /// Checks in constant time whether `tag` is the code computed by `mac` for the given
+    /// `context` and `data`.
+    pub fn verify_mac(&self, context: &[u8], data: &[u8], tag: &[u8; auth::TAGBYTES]) -> bool {
+        auth::verify(&auth::Tag(*tag), &mac_message(context, data), &self.mac_key())
+    }
+
+    fn mac_key(&self) -> auth::Key {
+        auth::Key(self.derive(b"secret-handshake mac key"))
+    }
+
+    // A hash of the two session keys, in an order that does not depend on the direction, so
+    // that client and server compute the same value. Does not reveal the keys.
+    pub(crate) fn session_hash(&self) -> [u8; sha256::DIGESTBYTES] {
+        self.derive(b"secret-handshake session hash")
+    }
+
+    // Hashes the label followed by the two session keys in lexicographic order. The keys are
+    // secret, so they are ordered without branching on their bytes.
+    fn derive(&self, label: &[u8]) -> [u8; sha256::DIGESTBYTES] {
+        const K: usize = secretbox::KEYBYTES;
+        let mut input = vec![0u8; label.len() + 2 * K];
+        input[..label.len()].copy_from_slice(label);
+        {
+            let (first, second) = input[label.len()..].split_at_mut(K);
+            // 0xff if the decryption key comes first, 0 otherwise
+            let mask = 0u8.wrapping_sub(ct_less(&self.decryption_key, &self.encryption_key));
+            for i in 0..K {
+                let (enc, dec) = (self.encryption_key[i], self.decryption_key[i]);
+                first[i] = enc ^ (mask & (enc ^ dec));
+                second[i] = dec ^ (mask & (enc ^ dec));
+            }
+        }
+        let sha256::Digest(hash) = sha256::hash(&input);
+        memzero(&mut input);
+        hash
+    }
+
+    // Increments the encryption nonce the way box-stream does (as a big-endian number). Used by
+    // extensions which encrypt messages before the box-stream is started.
+    pub(crate) fn increment_encryption_nonce(&mut self) {
+        increment_be(&mut self.encryption_nonce);
+    }
+
+    // Increments the decryption nonce the way box-stream does (as a big-endian number). Used by
+    // extensions which decrypt messages before the box-stream is started.
+    pub(crate) fn increment_decryption_nonce(&mut self) {
+        increment_be(&mut self.decryption_nonce);
+    }
+}
+
+// The message authenticated by `Outcome::mac`.
+fn mac_message(context: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + context.len() + data.len());
+    let len = context.len() as u64;
+    for i in 0..8 {
+        message.push((len >> (56 - 8 * i)) as u8);
+    }
+    message.extend_from_slice(context);
+    message.extend_from_slice(data);
+    message
+}
+
+// 1 if `a` is lexicographically smaller than `b`, 0 otherwise, in constant time.
+fn ct_less(a: &[u8; secretbox::KEYBYTES], b: &[u8; secretbox::KEYBYTES]) -> u8 {
+    let mut less = 0u8;
+    let mut decided = 0u8;
+    for i in 0..secretbox::KEYBYTES {
+        // the borrow of the subtraction, 1 if the first byte is smaller
+        let lt = ((a[i] as u16).wrapping_sub(b[i] as u16) >> 8) as u8 & 1;
+        let gt = ((b[i] as u16).wrapping_sub(a[i] as u16) >> 8) as u8 & 1;
+        less |= lt & !decided;
+        decided |= lt | gt;
+    }
+    less
+}
+
+fn increment_be(nonce: &mut [u8; secretbox::NONCEBYTES]) {
+    for byte in nonce.iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
 }
 
 /// The struct used in the C code to perform the client side of a handshake.
+///
+/// This is the building block of the handshakers, and can drive a handshake over a custom
+/// transport without any `unsafe` code: create it with `from_keys`, then send the result of
+/// `create_msg1`, check the server's msg2 with `verify_msg2` (and `ephemeral_keys_match`),
+/// send the result of `create_msg3`, check the server's msg4 with `verify_msg4`, and finally
+/// obtain the keys with `outcome`. Abort the handshake as soon as a check fails.
 #[repr(C)]
 // #[derive(Debug)]
 pub struct Client {
@@ -88,17 +511,50 @@ pub struct Client {
     hello: [u8; sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES],
     shared_hash: [u8; sha256::DIGESTBYTES],
     server_eph_pub: [u8; box_::PUBLICKEYBYTES],
+    // the keys the inputs point to if created by `from_keys`, never accessed by the C code
+    keys: Option<Box<OwnedKeys>>,
+}
+
+// Copies of the keys of a `Client` or `Server` created by `from_keys`. They live on the heap,
+// so that the pointers to them stay valid when the `Client` or `Server` moves.
+struct OwnedKeys {
+    app: [u8; auth::KEYBYTES],
+    pub_: [u8; sign::PUBLICKEYBYTES],
+    sec: [u8; sign::SECRETKEYBYTES],
+    eph_pub: [u8; box_::PUBLICKEYBYTES],
+    eph_sec: [u8; box_::SECRETKEYBYTES],
+    peer_pub: [u8; sign::PUBLICKEYBYTES], // the server's longterm key, unused by a `Server`
 }
 
+impl Drop for OwnedKeys {
+    fn drop(&mut self) {
+        memzero(&mut self.sec);
+        memzero(&mut self.eph_sec);
+    }
+}
+
+// What the pointers of a placeholder `Client` or `Server` point to.
+#[cfg(feature = "crypto-pool")]
+static NO_PUBLIC_KEY: [u8; sign::PUBLICKEYBYTES] = [0; sign::PUBLICKEYBYTES];
+#[cfg(feature = "crypto-pool")]
+static NO_SECRET_KEY: [u8; sign::SECRETKEYBYTES] = [0; sign::SECRETKEYBYTES];
+
 impl Client {
-    /// Creates and initializes a new `Client`.
-    pub fn new(app: *const [u8; auth::KEYBYTES],
-               pub_: *const [u8; sign::PUBLICKEYBYTES],
-               sec: *const [u8; sign::SECRETKEYBYTES],
-               eph_pub: *const [u8; box_::PUBLICKEYBYTES],
-               eph_sec: *const [u8; box_::SECRETKEYBYTES],
-               server_pub: *const [u8; sign::PUBLICKEYBYTES])
-               -> Client {
+    /// Creates and initializes a new `Client` that points to the given keys. Prefer
+    /// `from_keys`, which copies the keys and needs no `unsafe`.
+    ///
+    /// # Safety
+    ///
+    /// Only the pointers are stored: all of them must point to valid keys that stay
+    /// unchanged and outlive every later use of the client, on any thread, i.e. until it is
+    /// dropped.
+    pub unsafe fn new(app: *const [u8; auth::KEYBYTES],
+                      pub_: *const [u8; sign::PUBLICKEYBYTES],
+                      sec: *const [u8; sign::SECRETKEYBYTES],
+                      eph_pub: *const [u8; box_::PUBLICKEYBYTES],
+                      eph_sec: *const [u8; box_::SECRETKEYBYTES],
+                      server_pub: *const [u8; sign::PUBLICKEYBYTES])
+                      -> Client {
         Client {
             app,
             pub_,
@@ -106,14 +562,68 @@ impl Client {
             eph_pub,
             eph_sec,
             server_pub,
-            shared_secret: unsafe { uninitialized() },
-            server_lterm_shared: unsafe { uninitialized() },
-            hello: unsafe { uninitialized() },
-            shared_hash: unsafe { uninitialized() },
-            server_eph_pub: unsafe { uninitialized() },
+            shared_secret: [0; scalarmult::GROUPELEMENTBYTES],
+            server_lterm_shared: [0; scalarmult::GROUPELEMENTBYTES],
+            hello: [0; sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES],
+            shared_hash: [0; sha256::DIGESTBYTES],
+            server_eph_pub: [0; box_::PUBLICKEYBYTES],
+            keys: None,
+        }
+    }
+
+    /// Creates and initializes a new `Client` with copies of the given keys, so that it is
+    /// not constrained by their lifetime. The copies of the secret keys are zeroed when the
+    /// `Client` is dropped.
+    pub fn from_keys(network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                     client_longterm_pk: &sign::PublicKey,
+                     client_longterm_sk: &sign::SecretKey,
+                     client_ephemeral_pk: &box_::PublicKey,
+                     client_ephemeral_sk: &box_::SecretKey,
+                     server_longterm_pk: &sign::PublicKey)
+                     -> Client {
+        let keys = Box::new(OwnedKeys {
+                                app: *network_identifier,
+                                pub_: client_longterm_pk.0,
+                                sec: client_longterm_sk.0,
+                                eph_pub: client_ephemeral_pk.0,
+                                eph_sec: client_ephemeral_sk.0,
+                                peer_pub: server_longterm_pk.0,
+                            });
+        // The keys live on the heap and are owned by the client, so they stay valid when it
+        // moves, and are dropped after its last use.
+        let mut client = unsafe {
+            Client::new(&keys.app,
+                        &keys.pub_,
+                        &keys.sec,
+                        &keys.eph_pub,
+                        &keys.eph_sec,
+                        &keys.peer_pub)
+        };
+        client.keys = Some(keys);
+        client
+    }
+
+    // A client without keys that stands in for one whose state has been moved elsewhere for
+    // a while. It must not be used for a handshake.
+    #[cfg(feature = "crypto-pool")]
+    pub(crate) fn placeholder() -> Client {
+        // The statics are valid forever and never change.
+        unsafe {
+            Client::new(&NO_PUBLIC_KEY,
+                        &NO_PUBLIC_KEY,
+                        &NO_SECRET_KEY,
+                        &NO_PUBLIC_KEY,
+                        &NO_PUBLIC_KEY,
+                        &NO_PUBLIC_KEY)
         }
     }
 
+    // Whether the client was created by `from_keys`, i.e. does not point to keys it borrows.
+    #[cfg(feature = "crypto-pool")]
+    pub(crate) fn owns_keys(&self) -> bool {
+        self.keys.is_some()
+    }
+
     /// Writes the client challenge into `challenge` and updates the client state.
     pub fn create_msg1(&mut self, challenge: &mut [u8; MSG1_BYTES]) {
         unsafe { shs1_create_client_challenge(challenge, self) }
@@ -134,8 +644,96 @@ impl Client {
         unsafe { shs1_verify_server_ack(ack, self) }
     }
 
-    /// Computes the outcome of the handshake and writes it into `outcome`.
-    pub fn outcome(&mut self, outcome: &mut Outcome) {
+    /// Like `verify_msg4`, but requires the server to have signed the digest `aad` of the
+    /// associated data as well, see `HandshakeOptions::aad`.
+    ///
+    /// This mirrors `shs1_verify_server_ack` and leaves the `Client` in the same state, so
+    /// the outcome is computed by the C code as usual.
+    pub fn verify_msg4_with_aad(&mut self,
+                                ack: &[u8; MSG4_BYTES],
+                                aad: &[u8; sha256::DIGESTBYTES])
+                                -> bool {
+        // K | a_s * b_p | a_s * B_p | A_s * b_p
+        let mut tmp = [0u8; auth::KEYBYTES + 3 * scalarmult::GROUPELEMENTBYTES];
+        let verified = unsafe { self.verify_msg4_into(ack, aad, &mut tmp) };
+        memzero(&mut tmp);
+        verified
+    }
+
+    unsafe fn verify_msg4_into(&mut self,
+                               ack: &[u8; MSG4_BYTES],
+                               aad: &[u8; sha256::DIGESTBYTES],
+                               tmp: &mut [u8; auth::KEYBYTES + 3 * scalarmult::GROUPELEMENTBYTES])
+                               -> bool {
+        const K: usize = auth::KEYBYTES;
+        const G: usize = scalarmult::GROUPELEMENTBYTES;
+        const H: usize = sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES;
+
+        tmp[..K].copy_from_slice(&*self.app);
+        tmp[K..K + G].copy_from_slice(&self.shared_secret);
+        tmp[K + G..K + 2 * G].copy_from_slice(&self.server_lterm_shared);
+
+        // A_s * b_p
+        let mut curve_sec = [0u8; G];
+        if crypto_sign_ed25519_sk_to_curve25519(&mut curve_sec, &*self.sec) != 0 {
+            return false;
+        }
+        let shared = scalarmult::scalarmult(&scalarmult::Scalar(curve_sec),
+                                            &scalarmult::GroupElement(self.server_eph_pub));
+        memzero(&mut curve_sec);
+        let mut shared = match shared {
+            Ok(shared) => shared,
+            Err(()) => return false,
+        };
+        tmp[K + 2 * G..].copy_from_slice(&shared.0);
+        memzero(&mut shared.0);
+
+        // hash(K | a_s * b_p | a_s * B_p | A_s * b_p), stored where the C code stores it
+        self.shared_secret = sha256::hash(&tmp[..]).0;
+
+        // K | H | hash(a_s * b_p) | aad
+        let mut expected = [0u8; K + H + 2 * sha256::DIGESTBYTES];
+        expected[..K].copy_from_slice(&*self.app);
+        expected[K..K + H].copy_from_slice(&self.hello);
+        expected[K + H..K + H + sha256::DIGESTBYTES].copy_from_slice(&self.shared_hash);
+        expected[K + H + sha256::DIGESTBYTES..].copy_from_slice(aad);
+
+        let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+        let verified = match secretbox::open(ack, &nonce, &secretbox::Key(self.shared_secret)) {
+            Ok(signature) => {
+                match sign::Signature::from_slice(&signature) {
+                    Some(signature) => {
+                        sign::verify_detached(&signature,
+                                              &expected,
+                                              &sign::PublicKey(*self.server_pub))
+                    }
+                    None => false,
+                }
+            }
+            Err(()) => false,
+        };
+        memzero(&mut expected);
+        verified
+    }
+
+    /// Returns whether the server used the same ephemeral public key as the
+    /// client, which indicates a broken source of randomness. Must only be
+    /// called after the client verified msg2.
+    pub fn ephemeral_keys_match(&self) -> bool {
+        unsafe { *self.eph_pub == self.server_eph_pub }
+    }
+
+    /// Computes and returns the outcome of the handshake. Must only be called after the
+    /// client verified msg4.
+    pub fn outcome(&mut self) -> Outcome {
+        let mut outcome = Outcome::zeroed();
+        self.write_outcome(&mut outcome);
+        outcome
+    }
+
+    /// Computes the outcome of the handshake and writes it into `outcome`, overwriting all
+    /// of its keys and nonces. Must only be called after the client verified msg4.
+    pub fn write_outcome(&mut self, outcome: &mut Outcome) {
         unsafe { shs1_client_outcome(outcome, self) }
     }
 
@@ -152,7 +750,66 @@ impl Drop for Client {
     }
 }
 
+// The keys a `Client` points to are never mutated through it, and are either owned by it or
+// kept valid on every thread by the caller of `Client::new`.
+unsafe impl Send for Client {}
+
+/// Performs the Diffie-Hellman key agreements involving the server's ephemeral secret key.
+///
+/// Implement this to keep the ephemeral secret key inside secure hardware (e.g. an HSM)
+/// which only exposes the public key and performs the scalar multiplications itself. See
+/// `OwningServerHandshaker::with_key_agreement`.
+pub trait EphemeralKeyAgreement {
+    /// The ephemeral public key of the server.
+    fn public_key(&self) -> box_::PublicKey;
+
+    /// Multiplies the curve25519 point `point` with the ephemeral secret key, as
+    /// `crypto_scalarmult` does. Returns `None` if the result is all zeros, i.e. if `point`
+    /// has small order, or if the operation failed.
+    fn scalarmult(&self, point: &[u8; scalarmult::GROUPELEMENTBYTES])
+                  -> Option<[u8; scalarmult::GROUPELEMENTBYTES]>;
+}
+
+/// The default `EphemeralKeyAgreement`, which holds the ephemeral secret key in memory.
+pub struct SoftwareKeyAgreement {
+    pk: box_::PublicKey,
+    sk: box_::SecretKey,
+}
+
+impl SoftwareKeyAgreement {
+    /// Performs the key agreements with the given ephemeral keypair.
+    pub fn new(pk: box_::PublicKey, sk: box_::SecretKey) -> SoftwareKeyAgreement {
+        SoftwareKeyAgreement { pk, sk }
+    }
+
+    /// Performs the key agreements with a freshly generated ephemeral keypair.
+    pub fn generate() -> SoftwareKeyAgreement {
+        let (pk, sk) = box_::gen_keypair();
+        SoftwareKeyAgreement::new(pk, sk)
+    }
+}
+
+impl EphemeralKeyAgreement for SoftwareKeyAgreement {
+    fn public_key(&self) -> box_::PublicKey {
+        self.pk
+    }
+
+    fn scalarmult(&self, point: &[u8; scalarmult::GROUPELEMENTBYTES])
+                  -> Option<[u8; scalarmult::GROUPELEMENTBYTES]> {
+        scalarmult::scalarmult(&scalarmult::Scalar(self.sk.0),
+                               &scalarmult::GroupElement(*point))
+                .ok()
+                .map(|shared| shared.0)
+    }
+}
+
 /// The struct used in the C code to perform the server side of a handshake.
+///
+/// Like a `Client`, this can drive a handshake over a custom transport without any `unsafe`
+/// code: create it with `from_keys`, check the client's msg1 with `verify_msg1` (and
+/// `ephemeral_keys_match`), send the result of `create_msg2`, check the client's msg3 with
+/// `verify_msg3`, send the result of `create_msg4`, and obtain the keys and the client's
+/// identity with `outcome`. Abort the handshake as soon as a check fails.
 #[repr(C)]
 // #[derive(Debug)]
 pub struct Server {
@@ -167,27 +824,122 @@ pub struct Server {
     client_eph_pub: [u8; box_::PUBLICKEYBYTES],
     client_pub: [u8; sign::PUBLICKEYBYTES],
     box_sec: [u8; sha256::DIGESTBYTES],
+    // the keys the inputs point to if created by `from_keys`, never accessed by the C code
+    keys: Option<Box<OwnedKeys>>,
 }
 
 impl Server {
-    /// Creates and initializes a new `Server`.
-    pub fn new(app: *const [u8; auth::KEYBYTES],
-               pub_: *const [u8; sign::PUBLICKEYBYTES],
-               sec: *const [u8; sign::SECRETKEYBYTES],
-               eph_pub: *const [u8; box_::PUBLICKEYBYTES],
-               eph_sec: *const [u8; box_::SECRETKEYBYTES])
-               -> Server {
+    /// Creates and initializes a new `Server` that points to the given keys. Prefer
+    /// `from_keys`, which copies the keys and needs no `unsafe`.
+    ///
+    /// # Safety
+    ///
+    /// Only the pointers are stored: all of them must point to valid keys that stay
+    /// unchanged and outlive every later use of the server, on any thread, i.e. until it is
+    /// dropped.
+    pub unsafe fn new(app: *const [u8; auth::KEYBYTES],
+                      pub_: *const [u8; sign::PUBLICKEYBYTES],
+                      sec: *const [u8; sign::SECRETKEYBYTES],
+                      eph_pub: *const [u8; box_::PUBLICKEYBYTES],
+                      eph_sec: *const [u8; box_::SECRETKEYBYTES])
+                      -> Server {
         Server {
             app,
             pub_,
             sec,
             eph_pub,
             eph_sec,
-            client_hello: unsafe { uninitialized() },
-            shared_hash: unsafe { uninitialized() },
-            client_eph_pub: unsafe { uninitialized() },
-            client_pub: unsafe { uninitialized() },
-            box_sec: unsafe { uninitialized() },
+            client_hello: [0; sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES],
+            shared_hash: [0; sha256::DIGESTBYTES],
+            client_eph_pub: [0; box_::PUBLICKEYBYTES],
+            client_pub: [0; sign::PUBLICKEYBYTES],
+            box_sec: [0; sha256::DIGESTBYTES],
+            keys: None,
+        }
+    }
+
+    /// Creates and initializes a new `Server` with copies of the given keys, so that it is
+    /// not constrained by their lifetime. The copies of the secret keys are zeroed when the
+    /// `Server` is dropped.
+    pub fn from_keys(network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                     server_longterm_pk: &sign::PublicKey,
+                     server_longterm_sk: &sign::SecretKey,
+                     server_ephemeral_pk: &box_::PublicKey,
+                     server_ephemeral_sk: &box_::SecretKey)
+                     -> Server {
+        let keys = Box::new(OwnedKeys {
+                                app: *network_identifier,
+                                pub_: server_longterm_pk.0,
+                                sec: server_longterm_sk.0,
+                                eph_pub: server_ephemeral_pk.0,
+                                eph_sec: server_ephemeral_sk.0,
+                                peer_pub: [0; sign::PUBLICKEYBYTES],
+                            });
+        // Same as for `Client::from_keys`.
+        let mut server = unsafe {
+            Server::new(&keys.app,
+                        &keys.pub_,
+                        &keys.sec,
+                        &keys.eph_pub,
+                        &keys.eph_sec)
+        };
+        server.keys = Some(keys);
+        server
+    }
+
+    /// Replaces the longterm keys of the server. Only valid before msg3 is verified, msg1 and
+    /// msg2 do not depend on them.
+    ///
+    /// # Safety
+    ///
+    /// Only the pointers are stored: `pub_` and `sec` must point to valid keys that outlive
+    /// every later use of the server, i.e. until it is dropped.
+    pub unsafe fn set_longterm_keys(&mut self,
+                                    pub_: *const [u8; sign::PUBLICKEYBYTES],
+                                    sec: *const [u8; sign::SECRETKEYBYTES]) {
+        self.pub_ = pub_;
+        self.sec = sec;
+    }
+
+    /// Replaces the longterm keys of the server with copies of the given keys, see
+    /// `set_longterm_keys`. The copies of the secret key are zeroed when the `Server` is
+    /// dropped.
+    ///
+    /// Panics if the server was not created by `from_keys`.
+    pub fn replace_longterm_keys(&mut self,
+                                 server_longterm_pk: &sign::PublicKey,
+                                 server_longterm_sk: &sign::SecretKey) {
+        let keys = self.keys
+            .as_mut()
+            .expect("Replaced the longterm keys of a Server that was not created by from_keys");
+        keys.pub_ = server_longterm_pk.0;
+        keys.sec = server_longterm_sk.0;
+        self.pub_ = &keys.pub_;
+        self.sec = &keys.sec;
+    }
+
+    // A server without keys, see `Client::placeholder`.
+    #[cfg(feature = "crypto-pool")]
+    pub(crate) fn placeholder() -> Server {
+        // The statics are valid forever and never change.
+        unsafe {
+            Server::new(&NO_PUBLIC_KEY,
+                        &NO_PUBLIC_KEY,
+                        &NO_SECRET_KEY,
+                        &NO_PUBLIC_KEY,
+                        &NO_PUBLIC_KEY)
+        }
+    }
+
+    // Whether the server was created by `from_keys` and only points to its own copies of the
+    // keys, also after `replace_longterm_keys`.
+    #[cfg(feature = "crypto-pool")]
+    pub(crate) fn owns_keys(&self) -> bool {
+        match self.keys {
+            Some(ref keys) => {
+                self.pub_ == &keys.pub_ as *const _ && self.sec == &keys.sec as *const _
+            }
+            None => false,
         }
     }
 
@@ -206,13 +958,143 @@ impl Server {
         unsafe { shs1_verify_client_auth(auth, self) }
     }
 
+    /// Like `verify_msg3`, but performs the key agreements involving the ephemeral secret key
+    /// via `agreement` rather than with the ephemeral secret key of the `Server`, which is
+    /// never accessed.
+    ///
+    /// This mirrors `shs1_verify_client_auth` and leaves the `Server` in the same state, so
+    /// the remaining steps are performed by the C code as usual.
+    pub fn verify_msg3_with(&mut self,
+                            auth: &[u8; MSG3_BYTES],
+                            agreement: &EphemeralKeyAgreement)
+                            -> bool {
+        // K | b_s * a_p | B_s * a_p | b_s * A_p
+        let mut tmp = [0u8; auth::KEYBYTES + 3 * scalarmult::GROUPELEMENTBYTES];
+        let verified = unsafe { self.verify_msg3_into(auth, agreement, &mut tmp) };
+        memzero(&mut tmp);
+        verified
+    }
+
+    unsafe fn verify_msg3_into(&mut self,
+                               auth: &[u8; MSG3_BYTES],
+                               agreement: &EphemeralKeyAgreement,
+                               tmp: &mut [u8; auth::KEYBYTES + 3 * scalarmult::GROUPELEMENTBYTES])
+                               -> bool {
+        const K: usize = auth::KEYBYTES;
+        const G: usize = scalarmult::GROUPELEMENTBYTES;
+
+        tmp[..K].copy_from_slice(&*self.app);
+
+        // b_s * a_p
+        match agreement.scalarmult(&self.client_eph_pub) {
+            Some(shared) => tmp[K..K + G].copy_from_slice(&shared),
+            None => return false,
+        }
+
+        // B_s * a_p
+        let mut curve_sec = [0u8; G];
+        if crypto_sign_ed25519_sk_to_curve25519(&mut curve_sec, &*self.sec) != 0 {
+            return false;
+        }
+        let shared = scalarmult::scalarmult(&scalarmult::Scalar(curve_sec),
+                                            &scalarmult::GroupElement(self.client_eph_pub));
+        memzero(&mut curve_sec);
+        match shared {
+            Ok(shared) => tmp[K + G..K + 2 * G].copy_from_slice(&shared.0),
+            Err(()) => return false,
+        }
+
+        // H = sign_{A_s}(K | B_p | hash(a_s * b_p)) | A_p
+        let key = secretbox::Key(sha256::hash(&tmp[..K + 2 * G]).0);
+        let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+        let hello = match secretbox::open(auth, &nonce, &key) {
+            Ok(hello) => hello,
+            Err(()) => return false,
+        };
+        self.client_hello.copy_from_slice(&hello);
+        self.client_pub.copy_from_slice(&hello[sign::SIGNATUREBYTES..]);
+
+        // b_s * A_p
+        let mut curve_client_pub = [0u8; G];
+        if crypto_sign_ed25519_pk_to_curve25519(&mut curve_client_pub, &self.client_pub) != 0 {
+            return false;
+        }
+        match agreement.scalarmult(&curve_client_pub) {
+            Some(shared) => tmp[K + 2 * G..].copy_from_slice(&shared),
+            None => return false,
+        }
+
+        // hash(b_s * a_p)
+        self.shared_hash = sha256::hash(&tmp[K..K + G]).0;
+
+        // K | B_p | hash(a_s * b_p)
+        let mut expected = [0u8; auth::KEYBYTES + sign::PUBLICKEYBYTES + sha256::DIGESTBYTES];
+        expected[..K].copy_from_slice(&*self.app);
+        expected[K..K + sign::PUBLICKEYBYTES].copy_from_slice(&*self.pub_);
+        expected[K + sign::PUBLICKEYBYTES..].copy_from_slice(&self.shared_hash);
+
+        let mut signature = [0u8; sign::SIGNATUREBYTES];
+        signature.copy_from_slice(&self.client_hello[..sign::SIGNATUREBYTES]);
+        if !sign::verify_detached(&sign::Signature(signature),
+                                  &expected,
+                                  &sign::PublicKey(self.client_pub)) {
+            return false;
+        }
+
+        // hash(K | b_s * a_p | B_s * a_p | b_s * A_p)
+        self.box_sec = sha256::hash(&tmp[..]).0;
+        true
+    }
+
     /// Writes the server acknowledgement into `ack` and updates the server state.
-    pub fn create_msg4(&mut self, ack: *mut [u8; MSG4_BYTES]) {
+    pub fn create_msg4(&mut self, ack: &mut [u8; MSG4_BYTES]) {
         unsafe { shs1_create_server_ack(ack, self) }
     }
 
-    /// Computes the outcome of the handshake and writes it into `outcome`.
-    pub fn outcome(&mut self, outcome: &mut Outcome) {
+    /// Like `create_msg4`, but signs the digest `aad` of the associated data as well, see
+    /// `HandshakeOptions::aad`.
+    ///
+    /// This mirrors `shs1_create_server_ack`, the outcome is computed by the C code as
+    /// usual.
+    pub fn create_msg4_with_aad(&mut self,
+                                ack: &mut [u8; MSG4_BYTES],
+                                aad: &[u8; sha256::DIGESTBYTES]) {
+        const K: usize = auth::KEYBYTES;
+        const H: usize = sign::SIGNATUREBYTES + sign::PUBLICKEYBYTES;
+
+        // K | H | hash(b_s * a_p) | aad
+        let mut to_sign = [0u8; K + H + 2 * sha256::DIGESTBYTES];
+        to_sign[..K].copy_from_slice(unsafe { &*self.app });
+        to_sign[K..K + H].copy_from_slice(&self.client_hello);
+        to_sign[K + H..K + H + sha256::DIGESTBYTES].copy_from_slice(&self.shared_hash);
+        to_sign[K + H + sha256::DIGESTBYTES..].copy_from_slice(aad);
+
+        // box_{box_sec}(sign_{B_s}(K | H | hash(b_s * a_p) | aad))
+        let mut signature = sign::sign_detached(&to_sign, &sign::SecretKey(unsafe { *self.sec }));
+        memzero(&mut to_sign);
+        let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+        ack.copy_from_slice(&secretbox::seal(&signature.0, &nonce, &secretbox::Key(self.box_sec)));
+        memzero(&mut signature.0);
+    }
+
+    /// Returns whether the client used the same ephemeral public key as the
+    /// server, which indicates a broken source of randomness. Must only be
+    /// called after the server verified msg1.
+    pub fn ephemeral_keys_match(&self) -> bool {
+        unsafe { *self.eph_pub == self.client_eph_pub }
+    }
+
+    /// Computes and returns the outcome of the handshake, which includes the longterm public
+    /// key of the client. Must only be called after the server created msg4.
+    pub fn outcome(&mut self) -> Outcome {
+        let mut outcome = Outcome::zeroed();
+        self.write_outcome(&mut outcome);
+        outcome
+    }
+
+    /// Computes the outcome of the handshake and writes it into `outcome`, overwriting all
+    /// of its keys and nonces. Must only be called after the server created msg4.
+    pub fn write_outcome(&mut self, outcome: &mut Outcome) {
         unsafe { shs1_server_outcome(outcome, self) }
     }
 
@@ -222,10 +1104,16 @@ impl Server {
     }
 
     /// Returns the longterm public key of the client. This will return
-    /// uninitialized memory if called before the server verified msg3.
+    /// zeroes if called before the server verified msg3.
     pub unsafe fn client_longterm_pub(&self) -> [u8; sign::PUBLICKEYBYTES] {
         self.client_pub
     }
+
+    /// Returns the ephemeral public key of the client. This will return
+    /// zeroes if called before the server verified msg1.
+    pub unsafe fn client_ephemeral_pub(&self) -> [u8; box_::PUBLICKEYBYTES] {
+        self.client_eph_pub
+    }
 }
 
 /// Zero out all sensitive data when going out of scope.
@@ -235,6 +1123,452 @@ impl Drop for Server {
     }
 }
 
+// Same as for `Client`.
+unsafe impl Send for Server {}
+
+/// The fixed keys of a handshake, and the messages and outcome it must produce, see
+/// `selftest_with`.
+#[derive(Clone)]
+pub struct KnownAnswers {
+    /// The network identifier.
+    pub network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    /// The longterm public key of the client.
+    pub client_pk: sign::PublicKey,
+    /// The longterm secret key of the client.
+    pub client_sk: sign::SecretKey,
+    /// The ephemeral public key of the client.
+    pub client_eph_pk: box_::PublicKey,
+    /// The ephemeral secret key of the client.
+    pub client_eph_sk: box_::SecretKey,
+    /// The longterm public key of the server.
+    pub server_pk: sign::PublicKey,
+    /// The longterm secret key of the server.
+    pub server_sk: sign::SecretKey,
+    /// The ephemeral public key of the server.
+    pub server_eph_pk: box_::PublicKey,
+    /// The ephemeral secret key of the server.
+    pub server_eph_sk: box_::SecretKey,
+    /// The expected msg1.
+    pub msg1: [u8; MSG1_BYTES],
+    /// The expected msg2.
+    pub msg2: [u8; MSG2_BYTES],
+    /// The expected msg3.
+    pub msg3: [u8; MSG3_BYTES],
+    /// The expected msg4.
+    pub msg4: [u8; MSG4_BYTES],
+    /// The expected encryption key of the client, which is the decryption key of the server.
+    pub client_encryption_key: [u8; secretbox::KEYBYTES],
+    /// The expected encryption nonce of the client.
+    pub client_encryption_nonce: [u8; secretbox::NONCEBYTES],
+    /// The expected decryption key of the client, which is the encryption key of the server.
+    pub client_decryption_key: [u8; secretbox::KEYBYTES],
+    /// The expected decryption nonce of the client.
+    pub client_decryption_nonce: [u8; secretbox::NONCEBYTES],
+}
+
+/// The vectors `selftest` checks against, a handshake recorded with the reference
+/// implementation.
+pub static KNOWN_ANSWERS: KnownAnswers = KnownAnswers {
+    network_identifier: [111, 97, 159, 86, 19, 13, 53, 115, 66, 209, 32, 84, 255, 140, 143, 85,
+                         157, 74, 32, 154, 156, 90, 29, 185, 141, 19, 184, 255, 104, 107, 124,
+                         198],
+    client_pk: sign::PublicKey([225, 162, 73, 136, 73, 119, 94, 84, 208, 102, 233, 120, 23, 46,
+                                225, 245, 198, 79, 176, 0, 151, 208, 70, 146, 111, 23, 94, 101,
+                                25, 192, 30, 35]),
+    client_sk: sign::SecretKey([243, 168, 6, 50, 44, 78, 192, 183, 210, 241, 189, 36, 183, 154,
+                                132, 119, 115, 84, 47, 151, 32, 32, 26, 237, 64, 180, 69, 20, 95,
+                                133, 92, 176, 225, 162, 73, 136, 73, 119, 94, 84, 208, 102, 233,
+                                120, 23, 46, 225, 245, 198, 79, 176, 0, 151, 208, 70, 146, 111,
+                                23, 94, 101, 25, 192, 30, 35]),
+    client_eph_pk: box_::PublicKey([79, 79, 77, 238, 254, 215, 129, 197, 235, 41, 185, 208, 47,
+                                    32, 146, 37, 255, 237, 208, 215, 182, 92, 201, 106, 85, 86,
+                                    157, 41, 53, 165, 177, 32]),
+    client_eph_sk: box_::SecretKey([80, 169, 55, 157, 134, 142, 219, 152, 125, 240, 174, 209,
+                                    225, 109, 46, 188, 97, 224, 193, 187, 198, 58, 226, 193, 24,
+                                    235, 213, 214, 49, 55, 213, 104]),
+    server_pk: sign::PublicKey([42, 190, 113, 153, 16, 248, 187, 195, 163, 201, 187, 204, 86,
+                                238, 66, 151, 52, 115, 160, 4, 244, 1, 12, 76, 170, 129, 66, 12,
+                                202, 54, 1, 70]),
+    server_sk: sign::SecretKey([118, 98, 17, 77, 86, 116, 58, 146, 99, 84, 198, 164, 35, 220, 73,
+                                213, 246, 224, 242, 230, 175, 116, 71, 218, 56, 37, 212, 66, 163,
+                                14, 74, 209, 42, 190, 113, 153, 16, 248, 187, 195, 163, 201, 187,
+                                204, 86, 238, 66, 151, 52, 115, 160, 4, 244, 1, 12, 76, 170, 129,
+                                66, 12, 202, 54, 1, 70]),
+    server_eph_pk: box_::PublicKey([166, 12, 63, 218, 235, 136, 61, 99, 232, 142, 165, 147, 88,
+                                    93, 79, 177, 23, 148, 129, 57, 179, 24, 192, 174, 90, 62, 40,
+                                    83, 51, 9, 97, 82]),
+    server_eph_sk: box_::SecretKey([176, 248, 210, 185, 226, 76, 162, 153, 239, 144, 57, 206,
+                                    218, 97, 2, 215, 155, 5, 223, 189, 22, 28, 137, 85, 228, 233,
+                                    93, 79, 217, 203, 63, 125]),
+    msg1: [211, 6, 20, 155, 178, 209, 30, 107, 1, 3, 140, 242, 73, 101, 116, 234, 249, 127, 131,
+           227, 142, 66, 240, 195, 13, 50, 38, 96, 7, 208, 124, 180, 79, 79, 77, 238, 254, 215,
+           129, 197, 235, 41, 185, 208, 47, 32, 146, 37, 255, 237, 208, 215, 182, 92, 201, 106,
+           85, 86, 157, 41, 53, 165, 177, 32],
+    msg2: [44, 140, 79, 227, 23, 153, 202, 203, 81, 40, 114, 59, 56, 167, 63, 166, 201, 9, 50,
+           152, 0, 255, 226, 147, 22, 43, 84, 99, 107, 198, 198, 219, 166, 12, 63, 218, 235, 136,
+           61, 99, 232, 142, 165, 147, 88, 93, 79, 177, 23, 148, 129, 57, 179, 24, 192, 174, 90,
+           62, 40, 83, 51, 9, 97, 82],
+    msg3: [80, 34, 24, 195, 46, 211, 235, 66, 91, 89, 65, 98, 137, 26, 86, 197, 32, 4, 153, 142,
+           160, 18, 56, 180, 12, 171, 127, 38, 44, 53, 74, 64, 55, 188, 22, 25, 161, 25, 7, 243,
+           200, 196, 145, 249, 207, 211, 88, 178, 0, 206, 173, 234, 188, 20, 251, 240, 199, 169,
+           94, 180, 212, 32, 150, 226, 138, 44, 141, 235, 33, 152, 91, 215, 31, 126, 48, 48, 220,
+           239, 97, 225, 103, 79, 190, 56, 227, 103, 142, 195, 124, 10, 21, 76, 66, 11, 194, 11,
+           220, 15, 163, 66, 138, 232, 228, 12, 130, 172, 4, 137, 52, 159, 64, 98],
+    msg4: [72, 114, 92, 105, 109, 48, 17, 14, 25, 150, 242, 50, 148, 70, 49, 25, 222, 254, 255,
+           124, 194, 144, 84, 114, 190, 148, 252, 189, 159, 132, 157, 173, 92, 14, 247, 198, 87,
+           232, 141, 83, 84, 79, 226, 43, 194, 95, 14, 8, 138, 233, 96, 40, 126, 153, 205, 36,
+           95, 203, 200, 202, 221, 118, 126, 99, 47, 216, 209, 219, 3, 133, 240, 216, 166, 182,
+           182, 226, 215, 116, 177, 66],
+    client_encryption_key: [162, 29, 153, 150, 123, 225, 10, 173, 175, 201, 160, 34, 190, 179,
+                            158, 14, 176, 105, 232, 238, 97, 66, 133, 194, 250, 148, 199, 7, 34,
+                            157, 174, 24],
+    client_encryption_nonce: [44, 140, 79, 227, 23, 153, 202, 203, 81, 40, 114, 59, 56, 167, 63,
+                              166, 201, 9, 50, 152, 0, 255, 226, 147],
+    client_decryption_key: [125, 136, 153, 7, 109, 241, 239, 84, 228, 176, 141, 23, 58, 129, 90,
+                            228, 188, 93, 191, 224, 209, 67, 147, 187, 45, 204, 178, 17, 77, 225,
+                            117, 98],
+    client_decryption_nonce: [211, 6, 20, 155, 178, 209, 30, 107, 1, 3, 140, 242, 73, 101, 116,
+                              234, 249, 127, 131, 227, 142, 66, 240, 195],
+};
+
+/// Runs a handshake between a `Client` and a `Server` with the fixed keys of
+/// `KNOWN_ANSWERS` in memory, and checks that every message and both outcomes match the
+/// recorded ones. Call this at startup, before any real keys are used, to detect a
+/// miscompiled or tampered libsodium.
+///
+/// All messages and outcomes are zeroed afterwards. Takes about as long as two handshakes.
+pub fn selftest() -> Result<(), SelftestError> {
+    selftest_with(&KNOWN_ANSWERS)
+}
+
+/// Like `selftest`, but checks against the given vectors.
+pub fn selftest_with(vectors: &KnownAnswers) -> Result<(), SelftestError> {
+    let mut msg1 = [0; MSG1_BYTES];
+    let mut msg2 = [0; MSG2_BYTES];
+    let mut msg3 = [0; MSG3_BYTES];
+    let mut msg4 = [0; MSG4_BYTES];
+    let result = run_selftest(vectors, &mut msg1, &mut msg2, &mut msg3, &mut msg4);
+    memzero(&mut msg1);
+    memzero(&mut msg2);
+    memzero(&mut msg3);
+    memzero(&mut msg4);
+    result
+}
+
+// Runs the known-answer handshake, writing the messages into the given buffers. The
+// `Client`, the `Server` and the outcomes zero themselves when dropped.
+fn run_selftest(v: &KnownAnswers,
+                msg1: &mut [u8; MSG1_BYTES],
+                msg2: &mut [u8; MSG2_BYTES],
+                msg3: &mut [u8; MSG3_BYTES],
+                msg4: &mut [u8; MSG4_BYTES])
+                -> Result<(), SelftestError> {
+    let mut client = Client::from_keys(&v.network_identifier,
+                                       &v.client_pk,
+                                       &v.client_sk,
+                                       &v.client_eph_pk,
+                                       &v.client_eph_sk,
+                                       &v.server_pk);
+    let mut server = Server::from_keys(&v.network_identifier,
+                                       &v.server_pk,
+                                       &v.server_sk,
+                                       &v.server_eph_pk,
+                                       &v.server_eph_sk);
+
+    client.create_msg1(msg1);
+    expect("msg1", &msg1[..], &v.msg1[..])?;
+    if !server.verify_msg1(msg1) {
+        return Err(SelftestError::Rejected("msg1"));
+    }
+
+    server.create_msg2(msg2);
+    expect("msg2", &msg2[..], &v.msg2[..])?;
+    if !client.verify_msg2(msg2) {
+        return Err(SelftestError::Rejected("msg2"));
+    }
+
+    if client.create_msg3(msg3) != 0 {
+        return Err(SelftestError::Rejected("msg3"));
+    }
+    expect("msg3", &msg3[..], &v.msg3[..])?;
+    if !server.verify_msg3(msg3) {
+        return Err(SelftestError::Rejected("msg3"));
+    }
+
+    server.create_msg4(msg4);
+    expect("msg4", &msg4[..], &v.msg4[..])?;
+    if !client.verify_msg4(msg4) {
+        return Err(SelftestError::Rejected("msg4"));
+    }
+
+    let client_outcome = client.outcome();
+    let server_outcome = server.outcome();
+
+    expect("client encryption key",
+           &client_outcome.encryption_key,
+           &v.client_encryption_key)?;
+    expect("client encryption nonce",
+           &client_outcome.encryption_nonce,
+           &v.client_encryption_nonce)?;
+    expect("client decryption key",
+           &client_outcome.decryption_key,
+           &v.client_decryption_key)?;
+    expect("client decryption nonce",
+           &client_outcome.decryption_nonce,
+           &v.client_decryption_nonce)?;
+    expect("client peer key", &client_outcome.peer_longterm_pk, &v.server_pk.0)?;
+    expect("server encryption key",
+           &server_outcome.encryption_key,
+           &v.client_decryption_key)?;
+    expect("server encryption nonce",
+           &server_outcome.encryption_nonce,
+           &v.client_decryption_nonce)?;
+    expect("server decryption key",
+           &server_outcome.decryption_key,
+           &v.client_encryption_key)?;
+    expect("server decryption nonce",
+           &server_outcome.decryption_nonce,
+           &v.client_encryption_nonce)?;
+    expect("server peer key", &server_outcome.peer_longterm_pk, &v.client_pk.0)
+}
+
+fn expect(step: &'static str, actual: &[u8], expected: &[u8]) -> Result<(), SelftestError> {
+    if actual.len() == expected.len() && memcmp(actual, expected) {
+        Ok(())
+    } else {
+        Err(SelftestError::Mismatch(step))
+    }
+}
+
+// Runs `selftest` on the first call only, later calls return the same result.
+pub(crate) fn selftest_once() -> Result<(), SelftestError> {
+    static RESULT: Mutex<Option<Result<(), SelftestError>>> = Mutex::new(None);
+    // The selftest does not panic while holding the lock.
+    *RESULT
+         .lock()
+         .unwrap_or_else(|poisoned| poisoned.into_inner())
+         .get_or_insert_with(selftest)
+}
+
+#[cfg(feature = "insecure-key-schedule-trace")]
+impl Client {
+    /// Recomputes the steps of verifying `msg2`, see the `key_schedule` module.
+    pub fn trace_msg2(&self, msg2: &[u8; MSG2_BYTES]) -> KeyScheduleTrace {
+        let mut trace = KeyScheduleTrace::new("client", "msg2");
+        trace_challenge(&mut trace, unsafe { &*self.app }, msg2, "b_p");
+        trace
+    }
+
+    /// Recomputes the steps of verifying `msg4`, see the `key_schedule` module. Must only be
+    /// called after the client created msg3.
+    pub fn trace_msg4(&self,
+                      msg4: &[u8; MSG4_BYTES],
+                      aad: Option<&[u8; sha256::DIGESTBYTES]>)
+                      -> KeyScheduleTrace {
+        let mut trace = KeyScheduleTrace::new("client", "msg4");
+        let app = unsafe { &*self.app };
+        let server_pub = unsafe { &*self.server_pub };
+        trace.step("K", app);
+        trace.step("b_p", &self.server_eph_pub);
+        trace.step("B_p", server_pub);
+
+        let eph_sec = scalarmult::Scalar(unsafe { *self.eph_sec });
+        let a_b = match scalarmult::scalarmult(&eph_sec,
+                                               &scalarmult::GroupElement(self.server_eph_pub)) {
+            Ok(shared) => shared,
+            Err(()) => return trace.diverge("a_s * b_p is zero, b_p has small order"),
+        };
+        trace.step("a_s * b_p", &a_b.0);
+        let shared_hash = sha256::hash(&a_b.0);
+        trace.step("hash(a_s * b_p)", &shared_hash.0);
+
+        let mut curve_server_pub = [0u8; scalarmult::GROUPELEMENTBYTES];
+        if unsafe { crypto_sign_ed25519_pk_to_curve25519(&mut curve_server_pub, server_pub) } !=
+           0 {
+            return trace.diverge("B_p is not a valid ed25519 public key");
+        }
+        let a_bb = match scalarmult::scalarmult(&eph_sec,
+                                                &scalarmult::GroupElement(curve_server_pub)) {
+            Ok(shared) => shared,
+            Err(()) => return trace.diverge("a_s * B_p is zero"),
+        };
+        trace.step("a_s * B_p", &a_bb.0);
+
+        let mut curve_sec = [0u8; scalarmult::SCALARBYTES];
+        if unsafe { crypto_sign_ed25519_sk_to_curve25519(&mut curve_sec, &*self.sec) } != 0 {
+            return trace.diverge("A_s is not a valid ed25519 secret key");
+        }
+        let aa_b = scalarmult::scalarmult(&scalarmult::Scalar(curve_sec),
+                                          &scalarmult::GroupElement(self.server_eph_pub));
+        memzero(&mut curve_sec);
+        let aa_b = match aa_b {
+            Ok(shared) => shared,
+            Err(()) => return trace.diverge("A_s * b_p is zero, b_p has small order"),
+        };
+        trace.step("A_s * b_p", &aa_b.0);
+        trace.step("H", &self.hello);
+
+        let mut input = Vec::new();
+        input.extend_from_slice(app);
+        input.extend_from_slice(&a_b.0);
+        input.extend_from_slice(&a_bb.0);
+        input.extend_from_slice(&aa_b.0);
+        let key = sha256::hash(&input);
+        memzero(&mut input);
+        trace.step("hash(K | a_s * b_p | a_s * B_p | A_s * b_p)", &key.0);
+
+        trace.step("msg4", msg4);
+        let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+        let signature = match secretbox::open(msg4, &nonce, &secretbox::Key(key.0)) {
+            Ok(signature) => signature,
+            Err(()) => return trace.diverge("msg4 does not open with the derived box key"),
+        };
+        trace.step("opened msg4", &signature);
+
+        let mut signed = Vec::new();
+        signed.extend_from_slice(app);
+        signed.extend_from_slice(&self.hello);
+        signed.extend_from_slice(&shared_hash.0);
+        if let Some(aad) = aad {
+            signed.extend_from_slice(aad);
+            trace.step("K | H | hash(a_s * b_p) | hash(aad)", &signed);
+        } else {
+            trace.step("K | H | hash(a_s * b_p)", &signed);
+        }
+        match sign::Signature::from_slice(&signature) {
+            Some(ref signature) if sign::verify_detached(signature,
+                                                         &signed,
+                                                         &sign::PublicKey(*server_pub)) => trace,
+            _ => trace.diverge("the opened msg4 is not a signature of the expected data by B_s"),
+        }
+    }
+}
+
+#[cfg(feature = "insecure-key-schedule-trace")]
+impl Server {
+    /// Recomputes the steps of verifying `msg1`, see the `key_schedule` module.
+    pub fn trace_msg1(&self, msg1: &[u8; MSG1_BYTES]) -> KeyScheduleTrace {
+        let mut trace = KeyScheduleTrace::new("server", "msg1");
+        trace_challenge(&mut trace, unsafe { &*self.app }, msg1, "a_p");
+        trace
+    }
+
+    /// Recomputes the steps of verifying `msg3`, see the `key_schedule` module. Must only be
+    /// called after the server verified msg1. Performs the key agreements involving the
+    /// ephemeral secret key via `agreement` if given, as `verify_msg3_with` does.
+    pub fn trace_msg3(&self,
+                      msg3: &[u8; MSG3_BYTES],
+                      agreement: Option<&EphemeralKeyAgreement>)
+                      -> KeyScheduleTrace {
+        let mut trace = KeyScheduleTrace::new("server", "msg3");
+        let app = unsafe { &*self.app };
+        trace.step("K", app);
+        trace.step("a_p", &self.client_eph_pub);
+        trace.step("B_p", unsafe { &*self.pub_ });
+
+        let ephemeral_mult = |point: &[u8; scalarmult::GROUPELEMENTBYTES]| match agreement {
+            Some(agreement) => agreement.scalarmult(point),
+            None => {
+                scalarmult::scalarmult(&scalarmult::Scalar(unsafe { *self.eph_sec }),
+                                       &scalarmult::GroupElement(*point))
+                        .ok()
+                        .map(|shared| shared.0)
+            }
+        };
+
+        let b_a = match ephemeral_mult(&self.client_eph_pub) {
+            Some(shared) => shared,
+            None => return trace.diverge("b_s * a_p is zero, a_p has small order"),
+        };
+        trace.step("b_s * a_p", &b_a);
+        let shared_hash = sha256::hash(&b_a);
+        trace.step("hash(b_s * a_p)", &shared_hash.0);
+
+        let mut curve_sec = [0u8; scalarmult::SCALARBYTES];
+        if unsafe { crypto_sign_ed25519_sk_to_curve25519(&mut curve_sec, &*self.sec) } != 0 {
+            return trace.diverge("B_s is not a valid ed25519 secret key");
+        }
+        let bb_a = scalarmult::scalarmult(&scalarmult::Scalar(curve_sec),
+                                          &scalarmult::GroupElement(self.client_eph_pub));
+        memzero(&mut curve_sec);
+        let bb_a = match bb_a {
+            Ok(shared) => shared,
+            Err(()) => return trace.diverge("B_s * a_p is zero, a_p has small order"),
+        };
+        trace.step("B_s * a_p", &bb_a.0);
+
+        let mut input = Vec::new();
+        input.extend_from_slice(app);
+        input.extend_from_slice(&b_a);
+        input.extend_from_slice(&bb_a.0);
+        let key = sha256::hash(&input);
+        trace.step("hash(K | b_s * a_p | B_s * a_p)", &key.0);
+
+        trace.step("msg3", msg3);
+        let nonce = secretbox::Nonce([0; secretbox::NONCEBYTES]);
+        let hello = match secretbox::open(msg3, &nonce, &secretbox::Key(key.0)) {
+            Ok(hello) => hello,
+            Err(()) => {
+                memzero(&mut input);
+                return trace.diverge("msg3 does not open with the derived box key");
+            }
+        };
+        trace.step("H", &hello);
+        let mut client_pub = [0u8; sign::PUBLICKEYBYTES];
+        client_pub.copy_from_slice(&hello[sign::SIGNATUREBYTES..]);
+        trace.step("A_p", &client_pub);
+
+        let mut signed = Vec::new();
+        signed.extend_from_slice(app);
+        signed.extend_from_slice(unsafe { &*self.pub_ });
+        signed.extend_from_slice(&shared_hash.0);
+        trace.step("K | B_p | hash(b_s * a_p)", &signed);
+        let mut signature = [0u8; sign::SIGNATUREBYTES];
+        signature.copy_from_slice(&hello[..sign::SIGNATUREBYTES]);
+        if !sign::verify_detached(&sign::Signature(signature),
+                                  &signed,
+                                  &sign::PublicKey(client_pub)) {
+            memzero(&mut input);
+            return trace.diverge("H does not contain a signature of the expected data by A_s");
+        }
+
+        let mut curve_client_pub = [0u8; scalarmult::GROUPELEMENTBYTES];
+        if unsafe { crypto_sign_ed25519_pk_to_curve25519(&mut curve_client_pub, &client_pub) } !=
+           0 {
+            memzero(&mut input);
+            return trace.diverge("A_p is not a valid ed25519 public key");
+        }
+        let b_aa = match ephemeral_mult(&curve_client_pub) {
+            Some(shared) => shared,
+            None => {
+                memzero(&mut input);
+                return trace.diverge("b_s * A_p is zero");
+            }
+        };
+        trace.step("b_s * A_p", &b_aa);
+        input.extend_from_slice(&b_aa);
+        trace.step("hash(K | b_s * a_p | B_s * a_p | b_s * A_p)", &sha256::hash(&input).0);
+        memzero(&mut input);
+        trace
+    }
+}
+
+// Recomputes the steps of verifying msg1 or msg2: `hmac_K(eph_pub) | eph_pub`.
+#[cfg(feature = "insecure-key-schedule-trace")]
+fn trace_challenge(trace: &mut KeyScheduleTrace,
+                   app: &[u8; auth::KEYBYTES],
+                   challenge: &[u8; MSG1_BYTES],
+                   eph_pub_label: &'static str) {
+    trace.step("K", app);
+    trace.step(eph_pub_label, &challenge[auth::TAGBYTES..]);
+    trace.step("received hmac", &challenge[..auth::TAGBYTES]);
+    let expected = auth::authenticate(&challenge[auth::TAGBYTES..], &auth::Key(*app));
+    trace.step("expected hmac", &expected.0);
+    if !memcmp(&expected.0, &challenge[..auth::TAGBYTES]) {
+        trace.divergence = Some("the hmac does not match, the peer uses a different K");
+    }
+}
+
 extern "C" {
     // client side
     fn shs1_create_client_challenge(challenge: *mut [u8; MSG1_BYTES], client: *mut Client);
@@ -254,4 +1588,12 @@ extern "C" {
     fn shs1_create_server_ack(ack: *mut [u8; MSG4_BYTES], server: *mut Server);
     fn shs1_server_outcome(outcome: *mut Outcome, server: *mut Server);
     fn shs1_server_clean(server: *mut Server);
+    // libsodium
+    fn crypto_sign_ed25519_sk_to_curve25519(curve25519_sk: *mut [u8; scalarmult::SCALARBYTES],
+                                            ed25519_sk: *const [u8; sign::SECRETKEYBYTES])
+                                            -> i32;
+    fn crypto_sign_ed25519_pk_to_curve25519(curve25519_pk: *mut [u8;
+                                                                 scalarmult::GROUPELEMENTBYTES],
+                                            ed25519_pk: *const [u8; sign::PUBLICKEYBYTES])
+                                            -> i32;
 }
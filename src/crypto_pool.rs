@@ -0,0 +1,126 @@
+//! A pool of threads for the cpu-bound steps of handshakes, see
+//! `HandshakeOptions::offload_crypto`.
+//!
+//! This module is only available with the `crypto-pool` feature.
+//!
+//! Verifying msg2 and creating msg3 on the client, and verifying msg3 and creating msg4 on
+//! the server, involve scalar multiplications and signatures, which take tens of
+//! microseconds each. Performed inline, they block the executor thread that polls the
+//! handshake. With `offload_crypto`, they run on this pool instead, and the handshake is
+//! woken once they are done.
+//!
+//! This trades latency for responsiveness: each offloaded step costs a round trip through
+//! the pool (two context switches and an allocation), so a single handshake completes
+//! more slowly, but the executor can poll other tasks meanwhile. The total throughput is
+//! bounded by the `POOL_THREADS` threads of the pool, which are shared by all handshakes.
+//! Offloading pays off for servers under high handshake rates on executors with few
+//! threads, not for clients performing the occasional handshake.
+//!
+//! Only handshakes whose keys are owned by their `Client` or `Server` offload their steps,
+//! others perform them inline. A step moves the state of the handshake to the pool and back,
+//! the handshake keeps no copy of it meanwhile. Dropping a handshake while one of its steps
+//! is running does not wait for the step, its state is zeroed once the step is done.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::task::{Context, Poll, Waker};
+use std::task::Poll::{Ready, Pending};
+use std::thread;
+
+/// The number of threads of the pool, which are started when the first step is offloaded.
+pub const POOL_THREADS: usize = 4;
+
+type Task = Box<FnOnce() + Send>;
+
+// Sends tasks to the threads of the pool, once they have been started.
+static TASKS: Mutex<Option<Sender<Task>>> = Mutex::new(None);
+
+fn submit(task: Task) {
+    let mut tasks = lock(&TASKS);
+    let sender = tasks.get_or_insert_with(|| {
+        let (sender, receiver) = channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..POOL_THREADS {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("shs-crypto-{}", i))
+                .spawn(move || work(&receiver))
+                .expect("failed to start a crypto pool thread");
+        }
+        sender
+    });
+    sender.send(task).expect("the crypto pool has stopped");
+}
+
+fn work(receiver: &Mutex<Receiver<Task>>) {
+    loop {
+        let task = match lock(receiver).recv() {
+            Ok(task) => task,
+            Err(_) => return,
+        };
+        task();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // Tasks catch their panics, so the mutexes are never poisoned while inconsistent.
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// A step running on the pool. The step owns everything it accesses, so dropping the `Job`
+// before the step is done only discards the result, which is dropped on the pool then.
+pub(crate) struct Job<T> {
+    state: Arc<Mutex<JobState<T>>>,
+}
+
+struct JobState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+// Runs `step` on the pool.
+pub(crate) fn spawn<T, F>(step: F) -> Job<T>
+    where T: Send + 'static,
+          F: FnOnce() -> T + Send + 'static
+{
+    let state = Arc::new(Mutex::new(JobState {
+                                        result: None,
+                                        waker: None,
+                                    }));
+
+    let task_state = state.clone();
+    let task: Task = Box::new(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(step));
+        let waker = {
+            let mut state = lock(&task_state);
+            state.result = Some(result);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    submit(task);
+
+    Job { state }
+}
+
+impl<T> Job<T> {
+    // Returns the result of the step once it is done, and wakes the task then otherwise. A
+    // panic of the step is resumed on the polling thread.
+    pub(crate) fn poll(&mut self, cx: &mut Context) -> Poll<T> {
+        let mut state = lock(&self.state);
+        match state.result.take() {
+            Some(Ok(result)) => Ready(result),
+            Some(Err(panic)) => {
+                drop(state);
+                panic::resume_unwind(panic)
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Bound how long a handshake may take, and resume it if the caller grants more time.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+
+use crypto::Outcome;
+use errors::HandshakeError;
+use handshake::{Handshake, HandshakePhase};
+use proxy::ProxyHeader;
+use timer;
+use {OwningClientHandshaker, OwningServerHandshaker};
+
+/// The source of time for deadlines. `SystemClock` is the real time, tests can substitute a
+/// virtual clock (e.g. `testutil::VirtualClock`) via `WithDeadline::with_clock`.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Wakes `waker` once `now` has reached `at`.
+    fn wake_at(&self, at: Instant, waker: Waker);
+}
+
+/// The real time, which wakes tasks via a single timer thread shared by all handshakes, which
+/// sleeps until the earliest requested instant.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wake_at(&self, at: Instant, waker: Waker) {
+        if at <= Instant::now() {
+            waker.wake();
+        } else {
+            // `Clock` has no way to cancel a wakeup, so the deadline stays until it passes.
+            timer::wake_at(at, waker).detach();
+        }
+    }
+}
+
+/// Future returned by `Handshake::with_deadline`, which fails with `DeadlineError::Expired`
+/// if the handshake has not completed by the deadline.
+///
+/// The handshake is only ever stopped between two polls, never within a read or write, so
+/// no bytes are lost and an expired handshake can be resumed via `Expired::resume`. The
+/// future wakes itself at the deadline via its `Clock`, by default the timer thread of
+/// `SystemClock`, so it does not depend on the timer of any runtime.
+///
+/// Polling it again after it resolved or failed does not panic, it stays pending forever.
+pub struct WithDeadline<H> {
+    handshake: Option<H>, // `None` once the future has completed
+    deadline: Instant,
+    clock: Arc<Clock>,
+    timer: bool, // whether the clock has been asked to wake the task at the deadline
+}
+
+impl<H> WithDeadline<H> {
+    /// Wraps the given handshake.
+    pub fn new(handshake: H, deadline: Instant) -> WithDeadline<H> {
+        WithDeadline::with_clock(handshake, deadline, Arc::new(SystemClock))
+    }
+
+    /// Wraps the given handshake, measuring the deadline with `clock` instead of the real
+    /// time.
+    pub fn with_clock(handshake: H, deadline: Instant, clock: Arc<Clock>) -> WithDeadline<H> {
+        WithDeadline {
+            handshake: Some(handshake),
+            deadline,
+            clock,
+            timer: false,
+        }
+    }
+
+    /// The deadline by which the handshake must complete.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+// The handshake is polled via `Handshake::poll_handshake`, which does not pin it.
+impl<H> Unpin for WithDeadline<H> {}
+
+impl<H: Handshake> Future for WithDeadline<H> {
+    type Output = Result<(Outcome, H::Stream), DeadlineError<H>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Once the future has resolved or failed, the handshake has been handed out, and this
+        // stays pending forever instead.
+        let mut handshake = match this.handshake.take() {
+            Some(handshake) => handshake,
+            None => return Pending,
+        };
+
+        if this.clock.now() >= this.deadline {
+            return Ready(Err(DeadlineError::Expired(Expired {
+                                                        handshake,
+                                                        clock: this.clock.clone(),
+                                                    })));
+        }
+
+        match handshake.poll_handshake(cx) {
+            Ready(Ok(done)) => Ready(Ok(done)),
+            Pending => {
+                if !this.timer {
+                    this.timer = true;
+                    this.clock.wake_at(this.deadline, cx.waker().clone());
+                }
+                this.handshake = Some(handshake);
+                Pending
+            }
+            Ready(Err((err, stream))) => Ready(Err(DeadlineError::Failed(err, stream))),
+        }
+    }
+}
+
+/// Error of a `WithDeadline` future.
+pub enum DeadlineError<H: Handshake> {
+    /// The handshake failed before the deadline, with the given error.
+    Failed(HandshakeError, H::Stream),
+    /// The deadline passed before the handshake completed.
+    Expired(Expired<H>),
+}
+
+// Leaves out the stream.
+impl<H: Handshake> fmt::Debug for DeadlineError<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeadlineError::Failed(ref err, _) => f.debug_tuple("Failed").field(err).finish(),
+            DeadlineError::Expired(ref expired) => {
+                f.debug_tuple("Expired").field(expired).finish()
+            }
+        }
+    }
+}
+
+/// A handshake whose deadline has passed, stopped exactly where it left off.
+pub struct Expired<H> {
+    handshake: H,
+    clock: Arc<Clock>, // the clock of the expired `WithDeadline`, kept when resuming
+}
+
+impl<H: Handshake> Expired<H> {
+    /// How far the handshake has progressed.
+    pub fn phase(&self) -> HandshakePhase {
+        self.handshake.phase()
+    }
+
+    /// Continues the handshake from where it left off, now with the given deadline. The
+    /// ephemeral keys and the bytes exchanged so far are kept.
+    ///
+    /// Fails and returns the expired handshake if the new deadline has already passed, or if
+    /// the handshake has been aborted via `get_mut` in the meantime.
+    pub fn resume(self, new_deadline: Instant) -> Result<WithDeadline<H>, Expired<H>> {
+        if new_deadline <= self.clock.now() || self.phase() == HandshakePhase::Finished {
+            Err(self)
+        } else {
+            Ok(WithDeadline::with_clock(self.handshake, new_deadline, self.clock))
+        }
+    }
+
+    /// Gets a mutable reference to the handshake.
+    pub fn get_mut(&mut self) -> &mut H {
+        &mut self.handshake
+    }
+
+    /// Gives up on the handshake and returns the stream, see `Handshake::abort`.
+    pub fn abort(mut self) -> Option<H::Stream> {
+        self.handshake.abort()
+    }
+}
+
+impl<H: Handshake> fmt::Debug for Expired<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Expired").field("phase", &self.phase()).finish()
+    }
+}
+
+/// Future returned by `Handshake::with_timeout`, which fails with `HandshakeError::TimedOut`
+/// if the handshake has not completed within its timeout.
+///
+/// The timeout starts with the first poll, not when the future is created. Unlike an
+/// expired `WithDeadline`, a timed out handshake can not be resumed: it is dropped, which
+/// zeroes its buffered handshake data, and only the stream is handed back so that it can
+/// be closed (or reused, see `ClientHandshaker::into_inner`).
+///
+/// Polling it again after it resolved or failed does not panic, it stays pending forever.
+pub struct WithTimeout<H> {
+    handshake: Option<H>, // `None` once the future has completed
+    timeout: Duration,
+    clock: Arc<Clock>,
+    deadline: Option<Instant>, // set on the first poll
+}
+
+/// A client handshake that fails with `HandshakeError::TimedOut` after a timeout.
+pub type TimedClientHandshaker<S> = WithTimeout<OwningClientHandshaker<S>>;
+
+/// A server handshake that fails with `HandshakeError::TimedOut` after a timeout.
+pub type TimedServerHandshaker<S> = WithTimeout<OwningServerHandshaker<S>>;
+
+impl<H> WithTimeout<H> {
+    /// Wraps the given handshake.
+    pub fn new(handshake: H, timeout: Duration) -> WithTimeout<H> {
+        WithTimeout::with_clock(handshake, timeout, Arc::new(SystemClock))
+    }
+
+    /// Wraps the given handshake, measuring the timeout with `clock` instead of the real
+    /// time.
+    pub fn with_clock(handshake: H, timeout: Duration, clock: Arc<Clock>) -> WithTimeout<H> {
+        WithTimeout {
+            handshake: Some(handshake),
+            timeout,
+            clock,
+            deadline: None,
+        }
+    }
+
+    /// How long the handshake may take.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+// The handshake is polled via `Handshake::poll_handshake`, which does not pin it.
+impl<H> Unpin for WithTimeout<H> {}
+
+impl<H: Handshake> Future for WithTimeout<H> {
+    type Output = Result<(Outcome, H::Stream), (HandshakeError, H::Stream)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Once the future has resolved or failed, the handshake has been handed out, and this
+        // stays pending forever instead.
+        let mut handshake = match this.handshake.take() {
+            Some(handshake) => handshake,
+            None => return Pending,
+        };
+
+        let now = this.clock.now();
+        let deadline = match this.deadline {
+            Some(deadline) => deadline,
+            None => {
+                let deadline = now + this.timeout;
+                this.deadline = Some(deadline);
+                this.clock.wake_at(deadline, cx.waker().clone());
+                deadline
+            }
+        };
+
+        if now >= deadline {
+            let stream = handshake.abort().expect("Timed out a finished handshake");
+            return Ready(Err((HandshakeError::TimedOut, stream)));
+        }
+
+        match handshake.poll_handshake(cx) {
+            Pending => {
+                this.handshake = Some(handshake);
+                Pending
+            }
+            done => done,
+        }
+    }
+}
+
+impl<H: Handshake> Handshake for WithTimeout<H> {
+    type Stream = H::Stream;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, H::Stream), (HandshakeError, H::Stream)>> {
+        Pin::new(self).poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        self.handshake
+            .as_ref()
+            .map_or(HandshakePhase::Finished, |handshake| handshake.phase())
+    }
+
+    fn abort(&mut self) -> Option<H::Stream> {
+        self.handshake.take().and_then(|mut handshake| handshake.abort())
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        self.handshake.as_ref().and_then(|handshake| handshake.peer_pk())
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.handshake.as_ref().and_then(|handshake| handshake.rtt_estimate())
+    }
+
+    fn proxy_header(&self) -> Option<ProxyHeader> {
+        self.handshake.as_ref().and_then(|handshake| handshake.proxy_header())
+    }
+}
+
+impl<H: Handshake> fmt::Debug for WithTimeout<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WithTimeout")
+            .field("phase", &self.phase())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
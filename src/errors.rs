@@ -1,19 +1,84 @@
 //! The errors that an be emitted when performing handshakes.
 
 use std::error::Error;
+use std::io::ErrorKind::{WouldBlock, Interrupted, InvalidData};
 use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
 
 use futures_io;
+use sodiumoxide::crypto::{box_, sign};
+
+use crypto::fingerprint;
 
 /// Errors that can occur during a handshake.
+///
+/// This enum is non-exhaustive: new kinds of failures may be added as variants in minor
+/// releases, so matches on it outside of this crate need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum HandshakeError {
     /// An io error occured during the handshake.
+    ///
+    /// Errors of kind `WouldBlock` and `Interrupted` are not fatal: `WouldBlock` means the
+    /// operation could not complete yet, and `Interrupted` means it should simply be
+    /// attempted again. The handshaker retries `Interrupted` right away, and for
+    /// `WouldBlock` it wakes its task and returns `Pending`, so the operation is retried on
+    /// the next poll. Neither involves any backoff, so a stream that keeps returning them
+    /// would keep the task spinning: after `RETRY_LIMIT` of them in a row without any
+    /// progress, the handshake fails with the last one.
     IoError(futures_io::Error),
     /// The peer did not provide correct authentication.
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
     CryptoError,
+    /// The peer used the same ephemeral public key as this side, which indicates that
+    /// (at least) one of the two sides uses a broken source of randomness.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    WeakSharedSecret,
+    /// The filter function of a server did not decide within the configured `filter_timeout`.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    AuthorizerTimeout,
+    /// The connection did not start with a valid PROXY protocol header, although one was
+    /// expected (see `Acceptor::expect_proxy_protocol`).
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    InvalidProxyHeader,
+    /// The client did not send a valid pre-authentication after msg1, see the `pre_auth`
+    /// module. Only emitted by servers.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    PreAuthFailed,
+    /// The client sent a msg1 with an ephemeral key that the replay cache of the server has
+    /// already seen, i.e. the msg1 was most likely replayed, see `Acceptor::replay_cache`.
+    /// Only emitted by servers.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    ReplayedEphemeral,
+    /// The client authenticated successfully, but not as the client the server expects, see
+    /// `HandshakeOptions::expect_client`. Only emitted by servers.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    UnexpectedClient {
+        /// The longterm public key of the expected client.
+        expected: sign::PublicKey,
+        /// The verified longterm public key of the client that connected instead.
+        actual: sign::PublicKey,
+    },
+    /// The handshake did not complete within its timeout, see `Handshake::with_timeout`.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    TimedOut,
+    /// The client authenticated successfully, but connected again before its backoff window
+    /// ended, see `Acceptor::throttle`. No msg4 is sent. Only emitted by servers.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    Throttled {
+        /// How long the client stays blocked. Connecting again before that only extends the
+        /// window.
+        retry_after: Duration,
+    },
 }
 
 impl Display for HandshakeError {
@@ -21,6 +86,22 @@ impl Display for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => write!(f, "Handshake error: {}", err),
             HandshakeError::CryptoError => write!(f, "Handshake error: crypto error"),
+            HandshakeError::WeakSharedSecret => write!(f, "Handshake error: weak shared secret"),
+            HandshakeError::AuthorizerTimeout => write!(f, "Handshake error: authorizer timeout"),
+            HandshakeError::InvalidProxyHeader => {
+                write!(f, "Handshake error: invalid proxy protocol header")
+            }
+            HandshakeError::PreAuthFailed => {
+                write!(f, "Handshake error: pre-authentication failed")
+            }
+            HandshakeError::ReplayedEphemeral => {
+                write!(f, "Handshake error: replayed ephemeral key")
+            }
+            HandshakeError::UnexpectedClient { ref expected, ref actual } => {
+                write_unexpected_client(f, expected, actual)
+            }
+            HandshakeError::TimedOut => write!(f, "Handshake error: timed out"),
+            HandshakeError::Throttled { retry_after } => write_throttled(f, retry_after),
         }
     }
 }
@@ -30,6 +111,16 @@ impl Error for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => err.description(),
             HandshakeError::CryptoError => "the peer did not provide valid authentication",
+            HandshakeError::WeakSharedSecret => "the peer used the same ephemeral key as this side",
+            HandshakeError::AuthorizerTimeout => "the filter function did not decide in time",
+            HandshakeError::InvalidProxyHeader => "the proxy protocol header was invalid",
+            HandshakeError::PreAuthFailed => "the client did not provide a valid pre-authentication",
+            HandshakeError::ReplayedEphemeral => "the client reused an ephemeral key",
+            HandshakeError::UnexpectedClient { .. } => {
+                "a client other than the expected one connected"
+            }
+            HandshakeError::TimedOut => "the handshake did not complete in time",
+            HandshakeError::Throttled { .. } => "the client connected again too soon",
         }
     }
 
@@ -37,6 +128,14 @@ impl Error for HandshakeError {
         match *self {
             HandshakeError::IoError(ref err) => Some(err),
             HandshakeError::CryptoError => None,
+            HandshakeError::WeakSharedSecret => None,
+            HandshakeError::AuthorizerTimeout => None,
+            HandshakeError::InvalidProxyHeader => None,
+            HandshakeError::PreAuthFailed => None,
+            HandshakeError::ReplayedEphemeral => None,
+            HandshakeError::UnexpectedClient { .. } => None,
+            HandshakeError::TimedOut => None,
+            HandshakeError::Throttled { .. } => None,
         }
     }
 }
@@ -48,9 +147,13 @@ impl From<futures_io::Error> for HandshakeError {
 }
 
 /// Errors that can occur during a filtering handshake.
+///
+/// Like `HandshakeError`, this enum is non-exhaustive.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum FilteringHandshakeError<FnErr> {
-    /// An io error occured during the handshake.
+    /// An io error occured during the handshake. Only of kind `WouldBlock` or
+    /// `Interrupted` after `RETRY_LIMIT` of them in a row, see `HandshakeError::IoError`.
     IoError(futures_io::Error),
     /// The filter function errored.
     ///
@@ -63,7 +166,76 @@ pub enum FilteringHandshakeError<FnErr> {
     /// The peer was rejected by the filter function.
     ///
     /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
-    Rejected,
+    Rejected(RejectedClient),
+    /// The peer used the same ephemeral public key as this side, which indicates that
+    /// (at least) one of the two sides uses a broken source of randomness.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    WeakSharedSecret,
+    /// The filter function did not decide within the configured `filter_timeout`. The filter
+    /// future is dropped without being polled again.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    AuthorizerTimeout,
+    /// The client did not send a valid pre-authentication after msg1, see the `pre_auth`
+    /// module.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    PreAuthFailed,
+    /// The client sent a msg1 with an ephemeral key that the replay cache has already seen,
+    /// see `HandshakeError::ReplayedEphemeral`.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    ReplayedEphemeral,
+    /// The client authenticated successfully, but not as the client the server expects, see
+    /// `HandshakeOptions::expect_client`. The filter function is not invoked.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    UnexpectedClient {
+        /// The longterm public key of the expected client.
+        expected: sign::PublicKey,
+        /// The verified longterm public key of the client that connected instead.
+        actual: sign::PublicKey,
+    },
+    /// The client connected again before its backoff window ended, see
+    /// `HandshakeError::Throttled`. The filter function is not invoked.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    Throttled {
+        /// How long the client stays blocked.
+        retry_after: Duration,
+    },
+}
+
+/// Information about a client that was rejected after its authentication (msg3) had been
+/// verified, so the longterm public key is proven to belong to the rejected peer. No msg4 is
+/// sent to rejected clients.
+///
+/// Carried by `FilteringHandshakeError::Rejected` for clients rejected by the filter function,
+/// and handed to the `RejectionLog` of the server for all rejections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedClient {
+    /// The verified longterm public key of the client.
+    pub longterm_pk: sign::PublicKey,
+    /// The ephemeral public key the client used for this handshake.
+    pub ephemeral_pk: box_::PublicKey,
+    /// When the server verified the client's authentication.
+    pub verified_at: SystemTime,
+    /// When the server rejected the client.
+    pub rejected_at: SystemTime,
+    /// Why the server rejected the client.
+    pub reason: RejectionReason,
+}
+
+/// Why a server rejected a client whose authentication it had verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionReason {
+    /// The filter function (or the `ChannelAuthorizer`) resolved to `false`.
+    Filtered,
+    /// The client is not the one the server expects, see `HandshakeOptions::expect_client`.
+    UnexpectedClient,
+    /// The client connected again before its backoff window ended, see `Acceptor::throttle`.
+    Throttled,
 }
 
 impl<FnErr: Display> Display for FilteringHandshakeError<FnErr> {
@@ -72,7 +244,25 @@ impl<FnErr: Display> Display for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::IoError(ref err) => write!(f, "Handshake error: {}", err),
             FilteringHandshakeError::FilterError(ref err) => write!(f, "Handshake error: {}", err),
             FilteringHandshakeError::CryptoError => write!(f, "Handshake error: crypto error"),
-            FilteringHandshakeError::Rejected => write!(f, "Handshake error: peer rejected"),
+            FilteringHandshakeError::Rejected(_) => write!(f, "Handshake error: peer rejected"),
+            FilteringHandshakeError::WeakSharedSecret => {
+                write!(f, "Handshake error: weak shared secret")
+            }
+            FilteringHandshakeError::AuthorizerTimeout => {
+                write!(f, "Handshake error: authorizer timeout")
+            }
+            FilteringHandshakeError::PreAuthFailed => {
+                write!(f, "Handshake error: pre-authentication failed")
+            }
+            FilteringHandshakeError::ReplayedEphemeral => {
+                write!(f, "Handshake error: replayed ephemeral key")
+            }
+            FilteringHandshakeError::UnexpectedClient { ref expected, ref actual } => {
+                write_unexpected_client(f, expected, actual)
+            }
+            FilteringHandshakeError::Throttled { retry_after } => {
+                write_throttled(f, retry_after)
+            }
         }
     }
 }
@@ -83,7 +273,21 @@ impl<FnErr: Error> Error for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::IoError(ref err) => err.description(),
             FilteringHandshakeError::FilterError(ref err) => err.description(),
             FilteringHandshakeError::CryptoError => "the peer did not provide valid authentication",
-            FilteringHandshakeError::Rejected => "the peer was rejected by the filter function",
+            FilteringHandshakeError::Rejected(_) => "the peer was rejected by the filter function",
+            FilteringHandshakeError::WeakSharedSecret => {
+                "the peer used the same ephemeral key as this side"
+            }
+            FilteringHandshakeError::AuthorizerTimeout => {
+                "the filter function did not decide in time"
+            }
+            FilteringHandshakeError::PreAuthFailed => {
+                "the client did not provide a valid pre-authentication"
+            }
+            FilteringHandshakeError::ReplayedEphemeral => "the client reused an ephemeral key",
+            FilteringHandshakeError::UnexpectedClient { .. } => {
+                "a client other than the expected one connected"
+            }
+            FilteringHandshakeError::Throttled { .. } => "the client connected again too soon",
         }
     }
 
@@ -92,13 +296,182 @@ impl<FnErr: Error> Error for FilteringHandshakeError<FnErr> {
             FilteringHandshakeError::IoError(ref err) => Some(err),
             FilteringHandshakeError::FilterError(ref err) => Some(err),
             FilteringHandshakeError::CryptoError => None,
-            FilteringHandshakeError::Rejected => None,
+            FilteringHandshakeError::Rejected(_) => None,
+            FilteringHandshakeError::WeakSharedSecret => None,
+            FilteringHandshakeError::AuthorizerTimeout => None,
+            FilteringHandshakeError::PreAuthFailed => None,
+            FilteringHandshakeError::ReplayedEphemeral => None,
+            FilteringHandshakeError::UnexpectedClient { .. } => None,
+            FilteringHandshakeError::Throttled { .. } => None,
         }
     }
 }
 
+fn write_unexpected_client(f: &mut Formatter,
+                           expected: &sign::PublicKey,
+                           actual: &sign::PublicKey)
+                           -> Result<(), fmt::Error> {
+    write!(f,
+           "Handshake error: expected client {}, but {} connected",
+           fingerprint(&expected.0),
+           fingerprint(&actual.0))
+}
+
+fn write_throttled(f: &mut Formatter, retry_after: Duration) -> Result<(), fmt::Error> {
+    write!(f,
+           "Handshake error: throttled, retry after {}s",
+           retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 })
+}
+
 impl<FnErr> From<futures_io::Error> for FilteringHandshakeError<FnErr> {
     fn from(err: futures_io::Error) -> FilteringHandshakeError<FnErr> {
         FilteringHandshakeError::IoError(err)
     }
 }
+
+/// Errors that can occur when handshaking with several client identities, see
+/// `handshake_with_identities`.
+#[derive(Debug)]
+pub enum IdentityFallbackError {
+    /// Opening a connection failed. No further identities are attempted.
+    ConnectError(futures_io::Error),
+    /// A handshake failed for a reason other than the server rejecting the identity, e.g.
+    /// the connection broke before msg3 was sent. No further identities are attempted.
+    HandshakeError(HandshakeError),
+    /// The server rejected all identities.
+    AllRejected,
+}
+
+impl Display for IdentityFallbackError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            IdentityFallbackError::ConnectError(ref err) => write!(f, "Connect error: {}", err),
+            IdentityFallbackError::HandshakeError(ref err) => write!(f, "{}", err),
+            IdentityFallbackError::AllRejected => {
+                write!(f, "Handshake error: all identities rejected")
+            }
+        }
+    }
+}
+
+impl Error for IdentityFallbackError {
+    fn description(&self) -> &str {
+        match *self {
+            IdentityFallbackError::ConnectError(ref err) => err.description(),
+            IdentityFallbackError::HandshakeError(ref err) => err.description(),
+            IdentityFallbackError::AllRejected => "the server rejected all identities",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            IdentityFallbackError::ConnectError(ref err) => Some(err),
+            IdentityFallbackError::HandshakeError(ref err) => Some(err),
+            IdentityFallbackError::AllRejected => None,
+        }
+    }
+}
+
+/// Errors that can occur during a handshake whose server keys are looked up per connection,
+/// see `key_source::KeyedAcceptor`.
+#[derive(Debug)]
+pub enum KeyedHandshakeError<E> {
+    /// The handshake itself failed.
+    HandshakeError(HandshakeError),
+    /// The key source failed to provide keys for the connection. Happens after msg1 has been
+    /// received, before anything is sent to the client.
+    ///
+    /// This error is non-fatal, and the underyling connection should be closed when it is emitted.
+    KeyLookupError(E),
+}
+
+impl<E: Display> Display for KeyedHandshakeError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            KeyedHandshakeError::HandshakeError(ref err) => write!(f, "{}", err),
+            KeyedHandshakeError::KeyLookupError(ref err) => {
+                write!(f, "Key lookup error: {}", err)
+            }
+        }
+    }
+}
+
+impl<E: Error> Error for KeyedHandshakeError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            KeyedHandshakeError::HandshakeError(ref err) => err.description(),
+            KeyedHandshakeError::KeyLookupError(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            KeyedHandshakeError::HandshakeError(ref err) => Some(err),
+            KeyedHandshakeError::KeyLookupError(ref err) => Some(err),
+        }
+    }
+}
+
+impl<E> From<HandshakeError> for KeyedHandshakeError<E> {
+    fn from(err: HandshakeError) -> KeyedHandshakeError<E> {
+        KeyedHandshakeError::HandshakeError(err)
+    }
+}
+
+/// The error of `crypto::selftest`: the crypto core does not produce the known answers, so
+/// it must not be used for handshakes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelftestError {
+    /// The named message or outcome value differs from the known answer.
+    Mismatch(&'static str),
+    /// The named known-good message was rejected by the receiving side.
+    Rejected(&'static str),
+}
+
+impl Display for SelftestError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SelftestError::Mismatch(step) => {
+                write!(f, "Selftest error: {} differs from the known answer", step)
+            }
+            SelftestError::Rejected(step) => write!(f, "Selftest error: {} was rejected", step),
+        }
+    }
+}
+
+impl Error for SelftestError {
+    fn description(&self) -> &str {
+        match *self {
+            SelftestError::Mismatch(_) => "the crypto core produced a wrong result",
+            SelftestError::Rejected(_) => "the crypto core rejected a valid message",
+        }
+    }
+}
+
+// The error for a transport whose `poll_read` claims to have read more bytes than fit into
+// the buffer.
+pub(crate) fn overlong_read() -> futures_io::Error {
+    futures_io::Error::new(InvalidData, "the transport read more bytes than requested")
+}
+
+// The error for a transport whose `poll_write` claims to have written more bytes than it was
+// given.
+pub(crate) fn overlong_write() -> futures_io::Error {
+    futures_io::Error::new(InvalidData, "the transport wrote more bytes than requested")
+}
+
+/// How many io errors of kind `WouldBlock` or `Interrupted` in a row the handshakers retry
+/// before failing with the last of them, see `HandshakeError::IoError`. Any progress on the
+/// stream starts the count over.
+pub const RETRY_LIMIT: usize = 64;
+
+// Whether an io error only means that the operation should be retried later.
+//
+// The read and write loops of the handshakers retry `Interrupted` right away, as is the std
+// convention, so this only sees it for flushes and for the other callers.
+pub(crate) fn is_retryable(err: &futures_io::Error) -> bool {
+    match err.kind() {
+        WouldBlock | Interrupted => true,
+        _ => false,
+    }
+}
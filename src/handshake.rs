@@ -0,0 +1,146 @@
+//! A common interface of client and server handshakes.
+
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+
+use crypto::{Outcome, MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES, HANDSHAKE_TOTAL_BYTES};
+use errors::HandshakeError;
+use listener::Reset;
+use connection::IdentityOnly;
+use deadline::{WithDeadline, WithTimeout};
+use proxy::ProxyHeader;
+
+/// How far a handshake has progressed, named after the message that is being sent or
+/// received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakePhase {
+    /// The client's ephemeral public key is being exchanged, including any
+    /// pre-authentication.
+    Msg1,
+    /// The server's ephemeral public key is being exchanged.
+    Msg2,
+    /// The client's longterm public key and signature are being exchanged.
+    Msg3,
+    /// The server decides whether to accept the client, which it has just verified.
+    ///
+    /// In this phase the handshake waits on the application (the filter function of a
+    /// filtering handshaker, or a `ChannelAuthorizer`) rather than on the network, so the time
+    /// spent here is the latency of the authorization logic. Handshakes that accept all
+    /// clients pass through it without waiting.
+    Filter,
+    /// The server's signature is being exchanged.
+    Msg4,
+    /// The handshake has succeeded, failed or been aborted.
+    Finished,
+}
+
+/// The interface shared by client and server handshakes, for code that manages pending
+/// handshakes regardless of which side initiated them.
+///
+/// The trait is object safe, so handshakes of different types can be stored together, e.g.
+/// as `Box<Handshake<Stream = TcpStream> + Send>`. Of the handshakers, only the owning ones
+/// and `Accept` are `Send`.
+pub trait Handshake {
+    /// The stream over which the handshake is performed.
+    type Stream;
+
+    /// Drives the handshake, exactly like `Future::poll`, but without pinning it: the
+    /// handshakers only implement this for `Unpin` streams.
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, Self::Stream), (HandshakeError, Self::Stream)>>;
+
+    /// How far the handshake has progressed.
+    fn phase(&self) -> HandshakePhase;
+
+    /// Stops the handshake and returns the stream, or `None` if the handshake has already
+    /// finished. The handshake must not be polled afterwards. See
+    /// `ClientHandshaker::into_inner` for what the stream can still be used for.
+    fn abort(&mut self) -> Option<Self::Stream>;
+
+    /// Stops the handshake and drops the stream such that the connection is reset instead of
+    /// closed gracefully, e.g. to shed an abusive peer. Does nothing if the handshake has
+    /// already finished. The handshake must not be polled afterwards.
+    ///
+    /// This is best effort: transports that can not reset a connection (see `Reset`) and
+    /// failures to set up the reset result in a normal close. The secret key material of the
+    /// handshake is zeroed when the handshake is dropped, as always.
+    fn abort_reset(&mut self)
+        where Self::Stream: Reset
+    {
+        if let Some(stream) = self.abort() {
+            let _ = stream.reset_on_drop();
+        }
+    }
+
+    /// The longterm public key of the peer, once it is known. A client knows it from the
+    /// start, a server once it has verified msg3.
+    fn peer_pk(&self) -> Option<sign::PublicKey>;
+
+    /// An estimate of the round trip time to the peer, available once the peer has started
+    /// to answer the second message of the handshake.
+    ///
+    /// A server measures from flushing msg2 to reading the first byte of msg3, a client from
+    /// flushing msg1 to reading the first byte of msg2. This approximates one round trip
+    /// plus the time the peer spends on computing its answer, and is only as precise as
+    /// the polling of the handshake. Handshakes that do not measure return `None`.
+    fn rtt_estimate(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The PROXY protocol header that preceded the handshake, once it has been read. Only
+    /// `Accept`s of an `Acceptor` that expects the PROXY protocol read one, all other
+    /// handshakes return `None`.
+    fn proxy_header(&self) -> Option<ProxyHeader> {
+        None
+    }
+
+    /// Turns this handshake into one that only yields the verified identity of the peer,
+    /// for callers that authenticate the peer but do not talk to it over an encrypted
+    /// channel. The handshake is performed in full, but the session keys are zeroed as
+    /// soon as it completes and never handed out.
+    fn verify_identity_only(self) -> IdentityOnly<Self>
+        where Self: Sized
+    {
+        IdentityOnly::new(self)
+    }
+
+    /// Fails the handshake with `DeadlineError::Expired` if it has not completed by the
+    /// given `deadline`. The expired handshake can be resumed with a later deadline, see
+    /// `WithDeadline`.
+    fn with_deadline(self, deadline: Instant) -> WithDeadline<Self>
+        where Self: Sized
+    {
+        WithDeadline::new(self, deadline)
+    }
+
+    /// Fails the handshake with `HandshakeError::TimedOut` if it has not completed within
+    /// `timeout` of its first poll, see `WithTimeout`.
+    fn with_timeout(self, timeout: Duration) -> WithTimeout<Self>
+        where Self: Sized
+    {
+        WithTimeout::new(self, timeout)
+    }
+}
+
+/// The lengths of the handshake messages, as associated constants of every `Handshake`,
+/// e.g. `ClientHandshaker::<S>::MSG1_LEN` or `H::MSG3_LEN` in code generic over `H`.
+///
+/// These are the same as the free `MSG1_BYTES` etc. constants. They live in a trait of
+/// their own because associated constants would keep `Handshake` from being object safe.
+pub trait MessageLengths {
+    /// Length of msg1 (client to server) in bytes.
+    const MSG1_LEN: usize = MSG1_BYTES;
+    /// Length of msg2 (server to client) in bytes.
+    const MSG2_LEN: usize = MSG2_BYTES;
+    /// Length of msg3 (client to server) in bytes.
+    const MSG3_LEN: usize = MSG3_BYTES;
+    /// Length of msg4 (server to client) in bytes.
+    const MSG4_LEN: usize = MSG4_BYTES;
+    /// Length of all four messages together in bytes.
+    const TOTAL_LEN: usize = HANDSHAKE_TOTAL_BYTES;
+}
+
+impl<H: Handshake> MessageLengths for H {}
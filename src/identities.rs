@@ -0,0 +1,142 @@
+//! Perform a client handshake with the first of several identities the server accepts.
+
+use std::future::Future;
+use std::mem::replace;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{self, AsyncRead, AsyncWrite};
+
+use crypto::*;
+use errors::*;
+use options::HandshakeOptions;
+use client::OwningClientHandshaker;
+
+/// Returns a future that performs client handshakes with the server with the given
+/// longterm public key, using each of the given longterm `identities` in order until the
+/// server accepts one of them.
+///
+/// `connect` is called to open a new connection for each attempt. The future resolves to
+/// the index of the accepted identity, the outcome and the stream of the successful
+/// handshake.
+///
+/// A server rejects a client by closing the connection instead of sending msg4, only this
+/// leads to the next identity being attempted. Any other failure (failing to connect, the
+/// connection breaking at some other point, invalid messages from the server) aborts the
+/// whole sequence.
+pub fn handshake_with_identities<'a, F, C, S>(connect: F,
+                                              network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                                              identities: &'a [(sign::PublicKey, sign::SecretKey)],
+                                              server_longterm_pk: sign::PublicKey)
+                                              -> IdentityFallback<'a, F, C, S>
+    where F: FnMut() -> C,
+          C: Future<Output = Result<S, futures_io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    IdentityFallback {
+        connect,
+        network_identifier,
+        identities,
+        server_longterm_pk,
+        options: HandshakeOptions::default(),
+        index: 0,
+        attempt: Attempt::Idle,
+    }
+}
+
+/// Future returned by `handshake_with_identities`.
+pub struct IdentityFallback<'a, F, C, S> {
+    connect: F,
+    network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    identities: &'a [(sign::PublicKey, sign::SecretKey)],
+    server_longterm_pk: sign::PublicKey,
+    options: HandshakeOptions,
+    index: usize, // the identity of the current attempt
+    attempt: Attempt<C, S>,
+}
+
+enum Attempt<C, S> {
+    Idle,
+    Connecting(C),
+    Handshaking(OwningClientHandshaker<S>),
+}
+
+impl<'a, F, C, S> IdentityFallback<'a, F, C, S> {
+    /// Sets the options for all handshakes performed by this future.
+    pub fn options(mut self, options: HandshakeOptions) -> IdentityFallback<'a, F, C, S> {
+        self.options = options;
+        self
+    }
+}
+
+// The connection attempts and handshakes are moved between polls, so they are never pinned.
+impl<'a, F, C, S> Unpin for IdentityFallback<'a, F, C, S> {}
+
+impl<'a, F, C, S> Future for IdentityFallback<'a, F, C, S>
+    where F: FnMut() -> C,
+          C: Future<Output = Result<S, futures_io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    type Output = Result<(usize, Outcome, S), IdentityFallbackError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            this.attempt = match replace(&mut this.attempt, Attempt::Idle) {
+                Attempt::Idle => {
+                    if this.index >= this.identities.len() {
+                        return Ready(Err(IdentityFallbackError::AllRejected));
+                    }
+                    Attempt::Connecting((this.connect)())
+                }
+
+                Attempt::Connecting(mut connecting) => {
+                    match Pin::new(&mut connecting).poll(cx) {
+                        Ready(Ok(stream)) => {
+                            let (ref longterm_pk, ref longterm_sk) = this.identities[this.index];
+                            let (ephemeral_pk, ephemeral_sk) = box_::gen_keypair();
+                            let handshaker =
+                                OwningClientHandshaker::new(stream,
+                                                            this.network_identifier,
+                                                            longterm_pk.clone(),
+                                                            longterm_sk.clone(),
+                                                            ephemeral_pk,
+                                                            ephemeral_sk,
+                                                            this.server_longterm_pk.clone())
+                                        .options(this.options);
+                            Attempt::Handshaking(handshaker)
+                        }
+                        Pending => {
+                            this.attempt = Attempt::Connecting(connecting);
+                            return Pending;
+                        }
+                        Ready(Err(err)) => {
+                            return Ready(Err(IdentityFallbackError::ConnectError(err)))
+                        }
+                    }
+                }
+
+                Attempt::Handshaking(mut handshaker) => {
+                    match Pin::new(&mut handshaker).poll(cx) {
+                        Ready(Ok((outcome, stream))) => {
+                            return Ready(Ok((this.index, outcome, stream)))
+                        }
+                        Pending => {
+                            this.attempt = Attempt::Handshaking(handshaker);
+                            return Pending;
+                        }
+                        Ready(Err((err, _))) => {
+                            if !handshaker.rejected_by_server(&err) {
+                                return Ready(Err(IdentityFallbackError::HandshakeError(err)));
+                            }
+                            this.index += 1;
+                            Attempt::Idle
+                        }
+                    }
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Redeem scuttlebutt invite codes.
+//!
+//! A pub hands out invites of the form `host:port:@<base64>.ed25519~<base64 seed>`: the
+//! address and longterm public key of the pub, followed by the seed of a temporary identity.
+//! Redeeming an invite means performing a handshake with the pub as that temporary identity,
+//! which the pub accepts because it generated the seed. The longterm keys of the user are
+//! not involved.
+//!
+//! ```rust,ignore
+//! let invite = Invite::parse(code)?;
+//! let stream = TcpStream::connect((invite.host.as_str(), invite.port))?;
+//! let (outcome, stream) = block_on(invite.handshake(stream, MAIN_NET_IDENTIFIER))?;
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::NETWORK_IDENTIFIER_BYTES;
+use client::OwningClientHandshaker;
+use keyfile::decode_base64;
+
+/// A parsed invite code.
+#[derive(Clone)]
+pub struct Invite {
+    /// The host name or ip address of the pub.
+    pub host: String,
+    /// The port of the pub.
+    pub port: u16,
+    /// The longterm public key of the pub.
+    pub server_pk: sign::PublicKey,
+    /// The public key of the temporary identity derived from the seed.
+    pub invite_pk: sign::PublicKey,
+    invite_sk: sign::SecretKey,
+}
+
+impl Invite {
+    /// Parses an invite of the form `host:port:@<base64>.ed25519~<base64 seed>`, deriving
+    /// the temporary identity from the seed.
+    pub fn parse(invite: &str) -> Result<Invite, InviteError> {
+        let invite = invite.trim();
+        let tilde = invite.rfind('~').ok_or(InviteError::MissingSeed)?;
+        let (address, seed) = (&invite[..tilde], &invite[tilde + 1..]);
+
+        // The host may be an IPv6 address, which contains colons, the key and port do not.
+        let mut parts = address.rsplitn(3, ':');
+        let key = parts.next().unwrap_or("");
+        let port = parts.next().ok_or(InviteError::MissingAddress)?;
+        let host = parts.next().ok_or(InviteError::MissingAddress)?;
+        if host.is_empty() {
+            return Err(InviteError::MissingAddress);
+        }
+        let port = port.parse().map_err(|_| InviteError::InvalidPort(port.to_string()))?;
+
+        if !key.starts_with('@') || !key.ends_with(".ed25519") {
+            return Err(InviteError::InvalidKeySuffix(key.to_string()));
+        }
+        let key_bytes = decode_base64(&key[1..key.len() - ".ed25519".len()])
+            .ok_or(InviteError::InvalidServerKey("not valid base64"))?;
+        let server_pk = sign::PublicKey::from_slice(&key_bytes)
+            .ok_or(InviteError::InvalidServerKey("not 32 bytes long"))?;
+
+        let seed_bytes = decode_base64(seed).ok_or(InviteError::InvalidSeed)?;
+        if seed_bytes.len() != sign::SEEDBYTES {
+            return Err(InviteError::WrongSeedLength(seed_bytes.len()));
+        }
+        let mut seed = [0; sign::SEEDBYTES];
+        seed.copy_from_slice(&seed_bytes);
+        let (invite_pk, invite_sk) = sign::keypair_from_seed(&sign::Seed(seed));
+
+        Ok(Invite {
+               host: host.to_string(),
+               port,
+               server_pk,
+               invite_pk,
+               invite_sk,
+           })
+    }
+
+    /// Returns a future that performs the client side of a handshake with the pub over the
+    /// given `stream`, authenticating as the temporary identity of the invite.
+    pub fn handshake<S: AsyncRead + AsyncWrite>(&self,
+                                                stream: S,
+                                                network_identifier: [u8;
+                                                                        NETWORK_IDENTIFIER_BYTES])
+                                                -> OwningClientHandshaker<S> {
+        let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+        OwningClientHandshaker::new(stream,
+                                    network_identifier,
+                                    self.invite_pk.clone(),
+                                    self.invite_sk.clone(),
+                                    client_ephemeral_pk,
+                                    client_ephemeral_sk,
+                                    self.server_pk.clone())
+    }
+}
+
+// Leaves out the secret key of the temporary identity.
+impl fmt::Debug for Invite {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Invite")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("server_pk", &self.server_pk)
+            .field("invite_pk", &self.invite_pk)
+            .finish()
+    }
+}
+
+/// Errors that can occur when parsing an invite.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InviteError {
+    /// The invite does not contain a `~` followed by the seed.
+    MissingSeed,
+    /// The invite does not start with `host:port:`.
+    MissingAddress,
+    /// The port is not a number from 0 to 65535.
+    InvalidPort(String),
+    /// The key of the pub does not start with `@` and end with `.ed25519`.
+    InvalidKeySuffix(String),
+    /// The key of the pub is not a valid public key.
+    InvalidServerKey(&'static str),
+    /// The seed is not valid base64.
+    InvalidSeed,
+    /// The seed does not decode to 32 bytes, but to the given number of bytes.
+    WrongSeedLength(usize),
+}
+
+impl Display for InviteError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            InviteError::MissingSeed => write!(f, "Invite error: missing seed"),
+            InviteError::MissingAddress => write!(f, "Invite error: missing host or port"),
+            InviteError::InvalidPort(ref port) => {
+                write!(f, "Invite error: invalid port {:?}", port)
+            }
+            InviteError::InvalidKeySuffix(ref key) => {
+                write!(f, "Invite error: key {:?} is not of the form @<base64>.ed25519", key)
+            }
+            InviteError::InvalidServerKey(reason) => write!(f, "Invite error: key {}", reason),
+            InviteError::InvalidSeed => write!(f, "Invite error: seed is not valid base64"),
+            InviteError::WrongSeedLength(len) => {
+                write!(f,
+                       "Invite error: seed has {} bytes instead of {}",
+                       len,
+                       sign::SEEDBYTES)
+            }
+        }
+    }
+}
+
+impl Error for InviteError {
+    fn description(&self) -> &str {
+        match *self {
+            InviteError::MissingSeed => "missing seed",
+            InviteError::MissingAddress => "missing host or port",
+            InviteError::InvalidPort(_) => "invalid port",
+            InviteError::InvalidKeySuffix(_) => "the key is not of the form @<base64>.ed25519",
+            InviteError::InvalidServerKey(reason) => reason,
+            InviteError::InvalidSeed => "the seed is not valid base64",
+            InviteError::WrongSeedLength(_) => "the seed has the wrong length",
+        }
+    }
+}
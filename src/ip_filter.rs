@@ -0,0 +1,191 @@
+//! Allow or deny connections based on the ip address of the peer.
+//!
+//! An `IpFilter` consists of allow and deny rules, each an IPv4 or IPv6 prefix in CIDR
+//! notation (e.g. `10.0.0.0/8` or `fd00::/8`). Deny rules override allow rules, addresses
+//! matching no rule are handled according to the `DefaultPolicy`:
+//!
+//! ```rust,ignore
+//! // only accept connections from the local network, except from a single host
+//! let filter = IpFilter::new(DefaultPolicy::Deny)
+//!     .allow("192.168.0.0/16".parse()?)
+//!     .deny("192.168.0.13".parse()?);
+//! let acceptor = Acceptor::new(...).ip_filter(filter);
+//! ```
+//!
+//! See `Acceptor::accept_from` for applying a filter to incoming connections.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 prefix, e.g. `10.0.0.0/8` or `fd00::/8`.
+///
+/// Parsed from an address followed by `/` and the prefix length. An address without a
+/// prefix length denotes just that single address. An IPv4-mapped IPv6 prefix such as
+/// `::ffff:10.0.0.0/104` is equivalent to the IPv4 prefix `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Whether the given address lies within this prefix. IPv4 addresses mapped to IPv6
+    /// (`::ffff:a.b.c.d`) are treated as the corresponding IPv4 address.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, unmap(addr)) {
+            (IpAddr::V4(prefix), IpAddr::V4(addr)) => {
+                let mask = mask_u32(self.prefix_len);
+                u32::from(prefix) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(prefix), IpAddr::V6(addr)) => {
+                let mask = mask_u128(self.prefix_len);
+                u128::from(prefix) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// The mask selecting the first `prefix_len` bits.
+fn mask_u32(prefix_len: u8) -> u32 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (32 - prefix_len as u32)
+    }
+}
+
+fn mask_u128(prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        !0 << (128 - prefix_len as u32)
+    }
+}
+
+// Converts IPv4-mapped IPv6 addresses to IPv4.
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+            } else {
+                addr
+            }
+        }
+        IpAddr::V4(_) => addr,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Cidr, InvalidCidr> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or("");
+        let prefix_len = parts.next();
+
+        let addr: IpAddr = match addr.parse::<Ipv4Addr>() {
+            Ok(v4) => IpAddr::V4(v4),
+            Err(_) => IpAddr::V6(addr.parse::<Ipv6Addr>().map_err(|_| InvalidCidr)?),
+        };
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().map_err(|_| InvalidCidr)?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(InvalidCidr);
+        }
+
+        // Checked addresses are unmapped before matching, so rules for IPv4-mapped IPv6
+        // prefixes are stored as the corresponding IPv4 prefix. Shorter prefixes would also
+        // cover addresses outside of the mapped range, they are rejected.
+        match (addr, unmap(addr)) {
+            (IpAddr::V6(_), IpAddr::V4(v4)) => {
+                if prefix_len < 96 {
+                    return Err(InvalidCidr);
+                }
+                Ok(Cidr {
+                       addr: IpAddr::V4(v4),
+                       prefix_len: prefix_len - 96,
+                   })
+            }
+            _ => Ok(Cidr { addr, prefix_len }),
+        }
+    }
+}
+
+/// A string could not be parsed as a `Cidr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCidr;
+
+impl Display for InvalidCidr {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Invalid cidr: expected an ip address with an optional /prefix length")
+    }
+}
+
+impl Error for InvalidCidr {
+    fn description(&self) -> &str {
+        "invalid cidr"
+    }
+}
+
+/// How an `IpFilter` handles addresses that match none of its rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultPolicy {
+    /// Accept connections from such addresses.
+    Allow,
+    /// Drop connections from such addresses.
+    Deny,
+}
+
+/// Allow and deny rules for the addresses of peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    default: DefaultPolicy,
+}
+
+impl IpFilter {
+    /// Creates a filter without any rules, handling all addresses according to `default`.
+    pub fn new(default: DefaultPolicy) -> IpFilter {
+        IpFilter {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            default,
+        }
+    }
+
+    /// Accepts connections from the given prefix, unless a deny rule matches as well.
+    pub fn allow(mut self, cidr: Cidr) -> IpFilter {
+        self.allow.push(cidr);
+        self
+    }
+
+    /// Drops connections from the given prefix, regardless of any allow rules.
+    pub fn deny(mut self, cidr: Cidr) -> IpFilter {
+        self.deny.push(cidr);
+        self
+    }
+
+    /// Whether connections from the given address should be accepted.
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|cidr| cidr.contains(addr)) {
+            return true;
+        }
+        self.default == DefaultPolicy::Allow
+    }
+}
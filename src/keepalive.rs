@@ -0,0 +1,220 @@
+//! Optional negotiation of a keepalive interval after a completed handshake.
+//!
+//! **This is not part of the secret-handshake protocol.** Both peers must opt in,
+//! since each side sends one additional message and waits for the peer's message.
+//! A peer that does not perform the negotiation will receive bytes it can not make
+//! sense of, and a peer waiting for the negotiation message of a peer that did not
+//! opt in will wait until the connection is closed. Both peers send their message
+//! before reading the one of the peer, so the underlying transport must be able to
+//! buffer `KEEPALIVE_MSG_BYTES` bytes in each direction (any socket can).
+//!
+//! Each side sends its proposed interval in milliseconds as a big-endian `u32`,
+//! encrypted with the encryption key and nonce of the handshake's `Outcome`. The
+//! negotiated interval is the smaller of the two proposals. Since the message
+//! consumes a nonce in each direction, the `Outcome` returned by the negotiation
+//! has its nonces incremented the way box-stream increments them, so it can be
+//! used to start the box-stream afterwards.
+
+use std::cmp::min;
+use std::future::Future;
+use std::io::ErrorKind::{WriteZero, UnexpectedEof};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::Duration;
+
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+use futures_io::{AsyncRead, AsyncWrite, Error};
+
+use crypto::Outcome;
+use errors::{HandshakeError, RETRY_LIMIT, is_retryable, overlong_read, overlong_write};
+
+/// Length of the message carrying a keepalive proposal in bytes.
+pub const KEEPALIVE_MSG_BYTES: usize = 4 + secretbox::MACBYTES;
+
+/// Negotiates a keepalive interval with the peer of a completed handshake.
+///
+/// Polling it again after it resolved or failed does not panic, it stays pending forever.
+pub struct KeepaliveNegotiation<S> {
+    stream: Option<S>,
+    outcome: Option<Outcome>,
+    proposal: u32,
+    state: State,
+    data: [u8; KEEPALIVE_MSG_BYTES], // the encrypted proposal, later the encrypted proposal of the peer
+    offset: usize, // offset into the data array at which to read/write
+    retries: usize, // number of consecutive retryable errors so far
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> KeepaliveNegotiation<S> {
+    /// Creates a new KeepaliveNegotiation, proposing the given `interval` to the
+    /// peer over `stream`. Intervals are transmitted in milliseconds, longer
+    /// intervals than `u32::MAX` milliseconds are truncated to that value.
+    pub fn new(stream: S, outcome: Outcome, interval: Duration) -> KeepaliveNegotiation<S> {
+        let millis = interval.as_secs()
+            .saturating_mul(1000)
+            .saturating_add(interval.subsec_nanos() as u64 / 1_000_000);
+        let proposal = min(millis, u32::max_value() as u64) as u32;
+
+        let mut plain = [0; 4];
+        for i in 0..4 {
+            plain[i] = (proposal >> (8 * (3 - i))) as u8;
+        }
+
+        let mut data = [0; KEEPALIVE_MSG_BYTES];
+        data.copy_from_slice(&secretbox::seal(&plain,
+                                              &outcome.encryption_nonce(),
+                                              &outcome.encryption_key()));
+
+        KeepaliveNegotiation {
+            stream: Some(stream),
+            outcome: Some(outcome),
+            proposal,
+            state: WriteProposal,
+            data,
+            offset: 0,
+            retries: 0,
+        }
+    }
+}
+
+// Zero buffered data on dropping.
+impl<S> Drop for KeepaliveNegotiation<S> {
+    fn drop(&mut self) {
+        memzero(&mut self.data);
+    }
+}
+
+/// Future implementation to asynchronously negotiate the keepalive interval.
+///
+/// Resolves to the `Outcome` with updated nonces, the negotiated interval and the stream.
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for KeepaliveNegotiation<S> {
+    type Output = Result<(Outcome, Duration, S), (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Once the negotiation has resolved or failed, the stream has been handed out, and
+        // this stays pending forever instead.
+        let mut stream = match this.stream.take() {
+            Some(stream) => stream,
+            None => return Pending,
+        };
+
+        match this.state {
+            WriteProposal => {
+                while this.offset < KEEPALIVE_MSG_BYTES {
+                    match Pin::new(&mut stream).poll_write(cx, &this.data[this.offset..]) {
+                        Ready(Ok(written)) => {
+                            if written == 0 {
+                                return Ready(Err((Error::new(WriteZero,
+                                                             "failed to write keepalive proposal")
+                                                          .into(),
+                                                  stream)));
+                            }
+                            if written > KEEPALIVE_MSG_BYTES - this.offset {
+                                return Ready(Err((overlong_write().into(), stream)));
+                            }
+                            this.retries = 0;
+                            this.offset += written;
+                        }
+                        Pending => {
+                            this.stream = Some(stream);
+                            return Pending;
+                        }
+                        Ready(Err(ref e)) if is_retryable(e) && this.retries < RETRY_LIMIT => {
+                            this.retries += 1;
+                            this.stream = Some(stream);
+                            cx.waker().wake_by_ref();
+                            return Pending;
+                        }
+                        Ready(Err(e)) => return Ready(Err((e.into(), stream))),
+                    }
+                }
+
+                this.stream = Some(stream);
+                this.offset = 0;
+                this.state = FlushProposal;
+                return self.poll(cx);
+            }
+
+            FlushProposal => {
+                match Pin::new(&mut stream).poll_flush(cx) {
+                    Ready(Ok(())) => this.retries = 0,
+                    Pending => {
+                        this.stream = Some(stream);
+                        return Pending;
+                    }
+                    Ready(Err(ref e)) if is_retryable(e) && this.retries < RETRY_LIMIT => {
+                        this.retries += 1;
+                        this.stream = Some(stream);
+                        cx.waker().wake_by_ref();
+                        return Pending;
+                    }
+                    Ready(Err(e)) => return Ready(Err((e.into(), stream))),
+                }
+
+                this.stream = Some(stream);
+                this.state = ReadProposal;
+                return self.poll(cx);
+            }
+
+            ReadProposal => {
+                while this.offset < KEEPALIVE_MSG_BYTES {
+                    match Pin::new(&mut stream).poll_read(cx, &mut this.data[this.offset..]) {
+                        Ready(Ok(read)) => {
+                            if read == 0 {
+                                return Ready(Err((Error::new(UnexpectedEof,
+                                                             "failed to read keepalive proposal")
+                                                          .into(),
+                                                  stream)));
+                            }
+                            if read > KEEPALIVE_MSG_BYTES - this.offset {
+                                return Ready(Err((overlong_read().into(), stream)));
+                            }
+                            this.retries = 0;
+                            this.offset += read;
+                        }
+                        Pending => {
+                            this.stream = Some(stream);
+                            return Pending;
+                        }
+                        Ready(Err(ref e)) if is_retryable(e) && this.retries < RETRY_LIMIT => {
+                            this.retries += 1;
+                            this.stream = Some(stream);
+                            cx.waker().wake_by_ref();
+                            return Pending;
+                        }
+                        Ready(Err(e)) => return Ready(Err((e.into(), stream))),
+                    }
+                }
+
+                // only taken here, right before the stream is handed out
+                let mut outcome = this.outcome.take().unwrap();
+
+                let plain = match secretbox::open(&this.data,
+                                                  &outcome.decryption_nonce(),
+                                                  &outcome.decryption_key()) {
+                    Ok(plain) => plain,
+                    Err(()) => return Ready(Err((HandshakeError::CryptoError, stream))),
+                };
+                let peer_proposal = plain.iter().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+
+                outcome.increment_encryption_nonce();
+                outcome.increment_decryption_nonce();
+
+                let interval = min(this.proposal, peer_proposal) as u64;
+                return Ready(Ok((outcome,
+                                 Duration::from_millis(interval),
+                                 stream)));
+            }
+        }
+    }
+}
+
+// State for the future state machine.
+enum State {
+    WriteProposal,
+    FlushProposal,
+    ReadProposal,
+}
+use keepalive::State::*;
@@ -0,0 +1,103 @@
+//! Dump the key schedule of failed handshakes, for debugging interoperability with other
+//! implementations.
+//!
+//! **Insecure, for debugging only.** With the `insecure-key-schedule-trace` feature, every
+//! handshake that fails with a crypto error recomputes the derivation steps of the message
+//! it could not verify and writes them to stderr: the inputs, the Diffie-Hellman shared
+//! secrets, the hashes and keys derived from them, and the first check that failed. The
+//! trace contains the ephemeral shared secrets and the keys derived from them, so anyone who
+//! sees it can decrypt the connection. Never enable the feature in production builds.
+//!
+//! Without the feature, this module does not exist and the handshakers do not record
+//! anything.
+
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::utils::memzero;
+
+/// The derivation steps of one message of a handshake, as recomputed after the message
+/// could not be verified.
+#[derive(Debug)]
+pub struct KeyScheduleTrace {
+    /// `"client"` or `"server"`, the side that failed to verify the message.
+    pub side: &'static str,
+    /// The message that could not be verified, e.g. `"msg4"`.
+    pub message: &'static str,
+    /// The named inputs and intermediate results, in the order in which they are derived.
+    pub steps: Vec<TraceStep>,
+    /// The first check that fails when recomputing the steps, or `None` if all of them pass
+    /// (which indicates a bug in this crate rather than in the peer).
+    pub divergence: Option<&'static str>,
+}
+
+/// A named input or intermediate result of the key schedule. Zeroed when dropped.
+#[derive(Debug)]
+pub struct TraceStep {
+    /// What the bytes are, in the notation of the protocol paper, e.g. `"hash(a_s * b_p)"`.
+    pub label: &'static str,
+    /// The bytes themselves.
+    pub bytes: Vec<u8>,
+}
+
+impl Drop for TraceStep {
+    fn drop(&mut self) {
+        memzero(&mut self.bytes);
+    }
+}
+
+impl KeyScheduleTrace {
+    pub(crate) fn new(side: &'static str, message: &'static str) -> KeyScheduleTrace {
+        KeyScheduleTrace {
+            side,
+            message,
+            steps: Vec::new(),
+            divergence: None,
+        }
+    }
+
+    pub(crate) fn step(&mut self, label: &'static str, bytes: &[u8]) {
+        self.steps.push(TraceStep {
+                            label,
+                            bytes: bytes.to_vec(),
+                        });
+    }
+
+    // Records the first failing check, the trace stops there.
+    pub(crate) fn diverge(mut self, check: &'static str) -> KeyScheduleTrace {
+        self.divergence = Some(check);
+        self
+    }
+
+    /// The bytes of the step with the given `label`, if the trace got that far.
+    pub fn get(&self, label: &str) -> Option<&[u8]> {
+        self.steps
+            .iter()
+            .find(|step| step.label == label)
+            .map(|step| &step.bytes[..])
+    }
+}
+
+impl Display for KeyScheduleTrace {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f,
+                 "shs key schedule trace (INSECURE): {} failed to verify {}",
+                 self.side,
+                 self.message)?;
+        for step in &self.steps {
+            write!(f, "  {} = ", step.label)?;
+            for byte in &step.bytes {
+                write!(f, "{:02x}", byte)?;
+            }
+            writeln!(f)?;
+        }
+        match self.divergence {
+            Some(check) => write!(f, "  diverged: {}", check),
+            None => write!(f, "  diverged: nowhere, all checks pass"),
+        }
+    }
+}
+
+// Writes the trace of a failed message to stderr.
+pub(crate) fn report(trace: &KeyScheduleTrace) {
+    eprintln!("{}", trace);
+}
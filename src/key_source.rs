@@ -0,0 +1,285 @@
+//! Look up the longterm keys of the server per connection, for servers that host several
+//! identities behind one listener.
+//!
+//! A `KeySource` maps information about a connection to the keys of the identity that
+//! should answer it, possibly asynchronously, e.g. by querying a database:
+//!
+//! ```rust,ignore
+//! let acceptor = KeyedAcceptor::new(network_identifier, PubsByPort::new(database));
+//!
+//! // for each connection
+//! let info = ConnectionInfo::new().local_addr(stream.local_addr()?);
+//! let handshake = acceptor.accept(stream, info);
+//! ```
+//!
+//! The lookup starts once the client's msg1 has been verified, so clients that do not know
+//! the network identifier never cause lookups. The handshake waits for the lookup before
+//! sending msg2.
+
+use std::future::{self, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use sodiumoxide::crypto::sign;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::*;
+use errors::*;
+use options::HandshakeOptions;
+use server::{Compact, ConstFuture, UnsafeServerHandshakerWithFilter, const_async_true};
+use acceptor::{AcceptAll, accept_all_error};
+use listener::PeerInfo;
+
+/// The longterm keys of a server identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerKeys {
+    /// The longterm public key, which the client expects.
+    pub longterm_pk: sign::PublicKey,
+    /// The longterm secret key belonging to `longterm_pk`.
+    pub longterm_sk: sign::SecretKey,
+}
+
+/// What is known about a connection when its keys are looked up. All fields are optional,
+/// they are filled in by the caller of `KeyedAcceptor::accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ConnectionInfo {
+    /// The local address the client connected to.
+    pub local_addr: Option<SocketAddr>,
+    /// Metadata about the peer, as reported by a `Listener`.
+    pub peer: Option<PeerInfo>,
+}
+
+impl ConnectionInfo {
+    /// Creates a `ConnectionInfo` without any information.
+    pub fn new() -> ConnectionInfo {
+        ConnectionInfo::default()
+    }
+
+    /// Sets the local address.
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> ConnectionInfo {
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Sets the peer metadata.
+    pub fn peer(mut self, peer: PeerInfo) -> ConnectionInfo {
+        self.peer = Some(peer);
+        self
+    }
+}
+
+/// Provides the longterm keys of the server for each connection.
+pub trait KeySource {
+    /// The error of a failed lookup, e.g. because there is no identity for the connection.
+    type Error;
+    /// The future resolving to the keys.
+    type Future: Future<Output = Result<ServerKeys, Self::Error>>;
+
+    /// Looks up the keys for the connection described by `info`.
+    fn server_keys(&self, info: &ConnectionInfo) -> Self::Future;
+}
+
+/// Serves a fixed set of identities, selected by the local port of the connection.
+///
+/// Lookups for connections without a local address or on an unknown port fail with
+/// `UnknownPort`.
+impl KeySource for Vec<(u16, ServerKeys)> {
+    type Error = UnknownPort;
+    type Future = future::Ready<Result<ServerKeys, UnknownPort>>;
+
+    fn server_keys(&self, info: &ConnectionInfo) -> Self::Future {
+        let port = info.local_addr.map(|addr| addr.port());
+        match self.iter().find(|&&(p, _)| Some(p) == port) {
+            Some(&(_, ref keys)) => future::ready(Ok(keys.clone())),
+            None => future::ready(Err(UnknownPort(port))),
+        }
+    }
+}
+
+/// Error of the `KeySource` implementation of `Vec<(u16, ServerKeys)>`: no identity is
+/// configured for the local port, or the port is not known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownPort(pub Option<u16>);
+
+impl ::std::fmt::Display for UnknownPort {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        match self.0 {
+            Some(port) => write!(f, "no server keys for port {}", port),
+            None => write!(f, "no server keys for connections without a local port"),
+        }
+    }
+}
+
+impl ::std::error::Error for UnknownPort {
+    fn description(&self) -> &str {
+        "no server keys for the local port"
+    }
+}
+
+// Passed to the handshaker until the lookup has completed, never accessed.
+static NO_LONGTERM_PK: sign::PublicKey = sign::PublicKey([0; sign::PUBLICKEYBYTES]);
+static NO_LONGTERM_SK: sign::SecretKey = sign::SecretKey([0; sign::SECRETKEYBYTES]);
+
+/// Like an `Acceptor`, but looks up the longterm keys of the server for each connection via
+/// a `KeySource`. Cloning it is cheap.
+pub struct KeyedAcceptor<K> {
+    network_identifier: Arc<[u8; NETWORK_IDENTIFIER_BYTES]>,
+    key_source: Arc<K>,
+    options: HandshakeOptions,
+    lookup_failures: Arc<AtomicUsize>, // number of handshakes whose key lookup failed
+    rng: Option<EphemeralRng>,
+}
+
+impl<K> Clone for KeyedAcceptor<K> {
+    fn clone(&self) -> KeyedAcceptor<K> {
+        KeyedAcceptor {
+            network_identifier: self.network_identifier.clone(),
+            key_source: self.key_source.clone(),
+            options: self.options,
+            lookup_failures: self.lookup_failures.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+}
+
+impl<K: KeySource> KeyedAcceptor<K> {
+    /// Creates a new KeyedAcceptor accepting clients which use the given network identifier,
+    /// with keys provided by `key_source`.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               key_source: K)
+               -> KeyedAcceptor<K> {
+        KeyedAcceptor {
+            network_identifier: Arc::new(network_identifier),
+            key_source: Arc::new(key_source),
+            options: HandshakeOptions::default(),
+            lookup_failures: Arc::new(AtomicUsize::new(0)),
+            rng: None,
+        }
+    }
+
+    /// Sets the options for all handshakes accepted by this KeyedAcceptor.
+    pub fn options(mut self, options: HandshakeOptions) -> KeyedAcceptor<K> {
+        self.options = options;
+        self
+    }
+
+    /// Generates the ephemeral keys with `rng` instead of the random number generator of the
+    /// operating system, see `Acceptor::with_rng`.
+    pub fn with_rng(mut self, rng: EphemeralRng) -> KeyedAcceptor<K> {
+        self.rng = Some(rng);
+        self
+    }
+
+    /// The number of handshakes that failed because the key source returned an error,
+    /// counted across all clones of this KeyedAcceptor. Other failures are not counted.
+    pub fn lookup_failures(&self) -> usize {
+        self.lookup_failures.load(Ordering::Relaxed)
+    }
+
+    /// Returns a future that performs the server side of a handshake over the given
+    /// `stream`, using the keys that the key source provides for `info` and a freshly
+    /// generated ephemeral keypair.
+    pub fn accept<S: AsyncRead + AsyncWrite>(&self,
+                                             stream: S,
+                                             info: ConnectionInfo)
+                                             -> KeyedAccept<S, K> {
+        let (server_ephemeral_pk, server_ephemeral_sk) =
+            generate_ephemeral_keypair(self.rng.as_ref());
+
+        let mut inner = UnsafeServerHandshakerWithFilter::new(stream,
+                                                              const_async_true as AcceptAll,
+                                                              &*self.network_identifier,
+                                                              &NO_LONGTERM_PK,
+                                                              &NO_LONGTERM_SK,
+                                                              &server_ephemeral_pk,
+                                                              &server_ephemeral_sk);
+        inner.driver.core.options = self.options;
+        inner.defer_longterm_keys();
+
+        KeyedAccept {
+            inner,
+            info,
+            lookup: None,
+            acceptor: self.clone(),
+            keys: None,
+        }
+    }
+}
+
+/// Future returned by `KeyedAcceptor::accept`, resolving to the outcome of the handshake.
+pub struct KeyedAccept<S, K: KeySource> {
+    inner: UnsafeServerHandshakerWithFilter<S, AcceptAll, ConstFuture, Compact>,
+    info: ConnectionInfo,
+    lookup: Option<K::Future>,
+    acceptor: KeyedAcceptor<K>,
+    keys: Option<ServerKeys>,
+}
+
+impl<S, K: KeySource> KeyedAccept<S, K> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `Accept::log_summary` for details.
+    pub fn log_summary(&self) -> String {
+        self.inner.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.inner.progress()
+    }
+
+    /// The keys used by this handshake, once the lookup has completed.
+    pub fn server_keys(&self) -> Option<&ServerKeys> {
+        self.keys.as_ref()
+    }
+}
+
+/// Future implementation to asynchronously drive a handshake.
+impl<S: AsyncRead + AsyncWrite + Unpin, K: KeySource> Future for KeyedAccept<S, K> {
+    type Output = Result<(Outcome, S), (KeyedHandshakeError<K::Error>, S)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The lookup future is polled in place and only ever dropped in place, everything else
+        // is `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        loop {
+            if let Some(ref mut lookup) = this.lookup {
+                match unsafe { Pin::new_unchecked(lookup) }.poll(cx) {
+                    Ready(Ok(keys)) => {
+                        this.lookup = None;
+                        this.inner.provide_longterm_keys(&keys.longterm_pk, &keys.longterm_sk);
+                        this.keys = Some(keys);
+                    }
+                    Pending => return Pending,
+                    Ready(Err(err)) => {
+                        this.lookup = None;
+                        this.acceptor.lookup_failures.fetch_add(1, Ordering::Relaxed);
+                        return Ready(Err((KeyedHandshakeError::KeyLookupError(err),
+                                          this.inner.stream_take())));
+                    }
+                }
+            }
+
+            match Pin::new(&mut this.inner).poll(cx) {
+                Pending if this.inner.awaiting_longterm_keys() => {
+                    this.lookup = Some(this.acceptor.key_source.server_keys(&this.info));
+                }
+                Pending => return Pending,
+                Ready(Ok(done)) => return Ready(Ok(done)),
+                Ready(Err((err, stream))) => {
+                    return Ready(Err((accept_all_error(err).into(), stream)))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Remember the addresses of servers across runs.
+//!
+//! This module is only available with the `config` feature.
+//!
+//! A `KnownPeers` store maps longterm public keys of servers to the addresses they have
+//! been reached at, together with the time of the last successful connection to each
+//! address. `connect_any` uses it to pick the addresses to try, and records which one
+//! worked:
+//!
+//! ```rust,ignore
+//! let known = KnownPeers::load(&path)?;
+//! let (addr, stream) = await!(connect_any(&known, &server_pk, |addr| TcpStream::connect(&addr)))?;
+//! known.save(&path)?;
+//! ```
+//!
+//! To apply `SocketOptions` to the dialed sockets, connect via `socket::connect_tcp`, e.g.
+//! `|addr| future::result(connect_tcp(addr, &options).map_err(io::Error::from))`.
+//!
+//! The store is a json file, which `save` replaces atomically. A `KnownPeers` can be shared
+//! between tasks, all methods take `&self`.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::sign;
+use serde_json;
+use futures_core::future::TryFuture;
+use futures_io;
+
+use config::decode_key;
+use keyfile::encode_base64;
+
+/// The addresses of servers, keyed by their longterm public keys.
+#[derive(Debug, Default)]
+pub struct KnownPeers {
+    peers: Mutex<HashMap<[u8; sign::PUBLICKEYBYTES], Vec<KnownAddress>>>,
+}
+
+/// An address at which a server has been reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KnownAddress {
+    /// The address.
+    pub addr: SocketAddr,
+    /// When a connection to this address last succeeded, if ever.
+    pub last_success: Option<SystemTime>,
+}
+
+impl KnownPeers {
+    /// Creates an empty store.
+    pub fn new() -> KnownPeers {
+        KnownPeers::default()
+    }
+
+    /// Loads a store from the file at `path`. A missing file results in an empty store.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<KnownPeers, KnownPeersError> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(KnownPeers::new())
+            }
+            Err(err) => {
+                return Err(KnownPeersError::Io {
+                               path: path.to_path_buf(),
+                               err,
+                           })
+            }
+        }
+
+        let corrupt = |reason| {
+            KnownPeersError::Corrupt {
+                path: path.to_path_buf(),
+                reason,
+            }
+        };
+
+        let file: StoreFile = serde_json::from_str(&contents)
+            .map_err(|_| corrupt("not a json object with a peers field"))?;
+
+        let mut peers = HashMap::new();
+        for peer in file.peers {
+            let key = decode_key(&peer.key)
+                .and_then(|bytes| sign::PublicKey::from_slice(&bytes))
+                .ok_or_else(|| corrupt("malformed public key"))?;
+
+            let mut addresses = Vec::with_capacity(peer.addresses.len());
+            for address in peer.addresses {
+                addresses.push(KnownAddress {
+                                   addr: address
+                                       .addr
+                                       .parse()
+                                       .map_err(|_| corrupt("malformed address"))?,
+                                   last_success: address
+                                       .last_success
+                                       .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+                               });
+            }
+            peers.insert(key.0, addresses);
+        }
+
+        Ok(KnownPeers { peers: Mutex::new(peers) })
+    }
+
+    /// Writes the store to the file at `path`. The file is replaced atomically, so it is never
+    /// left partially written, and concurrent saves result in one of the saved states.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), KnownPeersError> {
+        static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+        let path = path.as_ref();
+        let file = StoreFile {
+            peers: self.lock()
+                .iter()
+                .map(|(key, addresses)| {
+                    StorePeer {
+                        key: format!("{}.ed25519", encode_base64(key)),
+                        addresses: addresses
+                            .iter()
+                            .map(|address| {
+                                     StoreAddress {
+                                         addr: address.addr.to_string(),
+                                         last_success: address.last_success.map(to_millis),
+                                     }
+                                 })
+                            .collect(),
+                    }
+                })
+                .collect(),
+        };
+        let contents = serde_json::to_string_pretty(&file).expect("failed to serialize");
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(".{}.{}.tmp",
+                              ::std::process::id(),
+                              SAVES.fetch_add(1, Ordering::Relaxed)));
+        let tmp_path = path.with_file_name(tmp_name);
+
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                          file.write_all(contents.as_bytes())?;
+                          file.sync_all()
+                      })
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|err| {
+                         let _ = fs::remove_file(&tmp_path);
+                         KnownPeersError::Io {
+                             path: path.to_path_buf(),
+                             err,
+                         }
+                     })
+    }
+
+    /// Adds the given addresses for the server with the longterm public key `pk`. Addresses
+    /// that are already known keep their last success.
+    pub fn insert(&self, pk: &sign::PublicKey, addresses: &[SocketAddr]) {
+        let mut peers = self.lock();
+        let known = peers.entry(pk.0).or_insert_with(Vec::new);
+        for &addr in addresses {
+            if !known.iter().any(|address| address.addr == addr) {
+                known.push(KnownAddress {
+                               addr,
+                               last_success: None,
+                           });
+            }
+        }
+    }
+
+    /// The known addresses of the server with the longterm public key `pk`, the most recently
+    /// successful ones first, followed by the ones that never succeeded in the order they were
+    /// inserted.
+    pub fn addresses_for(&self, pk: &sign::PublicKey) -> Vec<SocketAddr> {
+        let mut addresses = self.known_addresses(pk);
+        // stable, so addresses without successes keep their order
+        addresses.sort_by(|a, b| b.last_success.cmp(&a.last_success));
+        addresses.into_iter().map(|address| address.addr).collect()
+    }
+
+    /// The known addresses of the server with the longterm public key `pk`, in the order they
+    /// were inserted.
+    pub fn known_addresses(&self, pk: &sign::PublicKey) -> Vec<KnownAddress> {
+        self.lock().get(&pk.0).cloned().unwrap_or_default()
+    }
+
+    /// When a connection to `addr` of the server with the longterm public key `pk` last
+    /// succeeded.
+    pub fn last_success(&self, pk: &sign::PublicKey, addr: SocketAddr) -> Option<SystemTime> {
+        self.known_addresses(pk)
+            .into_iter()
+            .find(|address| address.addr == addr)
+            .and_then(|address| address.last_success)
+    }
+
+    /// Records that a connection to `addr` of the server with the longterm public key `pk`
+    /// succeeded just now, adding the address if it is not known yet.
+    pub fn record_success(&self, pk: &sign::PublicKey, addr: SocketAddr) {
+        let now = SystemTime::now();
+        let mut peers = self.lock();
+        let known = peers.entry(pk.0).or_insert_with(Vec::new);
+        match known.iter_mut().find(|address| address.addr == addr) {
+            Some(address) => address.last_success = Some(now),
+            None => {
+                known.push(KnownAddress {
+                               addr,
+                               last_success: Some(now),
+                           })
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<[u8; sign::PUBLICKEYBYTES], Vec<KnownAddress>>> {
+        // The map is consistent after every operation, so it can be used after a panic.
+        self.peers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    peers: Vec<StorePeer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StorePeer {
+    key: String,
+    addresses: Vec<StoreAddress>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreAddress {
+    addr: String,
+    #[serde(default)]
+    last_success: Option<u64>, // milliseconds since the unix epoch
+}
+
+/// Returns a future that connects to the server with the longterm public key `pk`, trying
+/// the addresses from `known` in the order of `KnownPeers::addresses_for` until `connect`
+/// succeeds for one of them.
+///
+/// The future resolves to the address and the connection, and records the success in
+/// `known`. If all addresses fail, it fails with the error of the last attempt, or with an
+/// error of kind `AddrNotAvailable` if no addresses are known.
+pub fn connect_any<'a, F, C>(known: &'a KnownPeers,
+                             pk: &sign::PublicKey,
+                             connect: F)
+                             -> ConnectAny<'a, F, C>
+    where F: FnMut(SocketAddr) -> C,
+          C: TryFuture<Error = futures_io::Error> + Unpin
+{
+    let mut candidates = known.addresses_for(pk);
+    candidates.reverse();
+
+    ConnectAny {
+        known,
+        pk: pk.clone(),
+        connect,
+        candidates,
+        attempt: None,
+    }
+}
+
+/// Future returned by `connect_any`.
+pub struct ConnectAny<'a, F, C> {
+    known: &'a KnownPeers,
+    pk: sign::PublicKey,
+    connect: F,
+    candidates: Vec<SocketAddr>, // in reverse order, the next one is popped
+    attempt: Option<(SocketAddr, C)>,
+}
+
+// The connection attempts are moved between polls, so they are never pinned.
+impl<'a, F, C> Unpin for ConnectAny<'a, F, C> {}
+
+impl<'a, F, C> Future for ConnectAny<'a, F, C>
+    where F: FnMut(SocketAddr) -> C,
+          C: TryFuture<Error = futures_io::Error> + Unpin
+{
+    type Output = Result<(SocketAddr, C::Ok), futures_io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            let (addr, mut attempt) = match this.attempt.take() {
+                Some(attempt) => attempt,
+                None => {
+                    match this.candidates.pop() {
+                        Some(addr) => (addr, (this.connect)(addr)),
+                        None => {
+                            return Ready(Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                                                            "no known addresses")))
+                        }
+                    }
+                }
+            };
+
+            match Pin::new(&mut attempt).try_poll(cx) {
+                Ready(Ok(stream)) => {
+                    this.known.record_success(&this.pk, addr);
+                    return Ready(Ok((addr, stream)));
+                }
+                Pending => {
+                    this.attempt = Some((addr, attempt));
+                    return Pending;
+                }
+                Ready(Err(err)) => {
+                    if this.candidates.is_empty() {
+                        return Ready(Err(err));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Errors that can occur when loading or saving a `KnownPeers` store.
+#[derive(Debug)]
+pub enum KnownPeersError {
+    /// The file could not be read or written.
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The error that occured.
+        err: io::Error,
+    },
+    /// The file does not contain a valid store.
+    Corrupt {
+        /// The path of the file.
+        path: PathBuf,
+        /// What is wrong with the file.
+        reason: &'static str,
+    },
+}
+
+impl Display for KnownPeersError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            KnownPeersError::Io { ref path, ref err } => {
+                write!(f, "Known peers error: {}: {}", path.display(), err)
+            }
+            KnownPeersError::Corrupt { ref path, reason } => {
+                write!(f, "Known peers error: {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl Error for KnownPeersError {
+    fn description(&self) -> &str {
+        match *self {
+            KnownPeersError::Io { .. } => "could not access the known peers file",
+            KnownPeersError::Corrupt { reason, .. } => reason,
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            KnownPeersError::Io { ref err, .. } => Some(err),
+            KnownPeersError::Corrupt { .. } => None,
+        }
+    }
+}
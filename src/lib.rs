@@ -4,28 +4,141 @@
 //! This library uses libsodium internally. In application code, call
 //! [`sodiumoxide::init()`](https://dnaq.github.io/sodiumoxide/sodiumoxide/fn.init.html)
 //! before performing any handshakes.
+//!
+//! The handshakers are `std::future::Future`s over streams that implement the futures-io 0.3
+//! `AsyncRead` and `AsyncWrite` traits, so they can be awaited on any runtime, e.g.
+//! async-std or tokio via its compat layer. A handshaker resolves to
+//! `Result<(Outcome, S), (HandshakeError, S)>`, handing back the stream either way. The
+//! stream must be `Unpin` for this. Handshakers over `!Unpin` streams are driven in place
+//! with `poll_pinned` instead, which keeps the stream pinned inside the handshaker, see
+//! `ClientHandshaker::poll_pinned`.
+//!
+//! All handshakers are cancel-safe: whenever `poll` returns `Pending`, the handshaker is in
+//! a consistent state, so it can be dropped at any such point, or the stream can be
+//! recovered with `into_inner`. If the stream panics during a `poll`, the handshaker must not
+//! be polled again, but the stream can still be recovered with `into_inner`.
+//!
+//! Handshakers write every message with a single `poll_write` of the whole message (msg1
+//! together with any pre-authentication), followed by a single `poll_flush`. Further writes
+//! only happen if the stream accepts just a part of the message or is not ready, so streams
+//! with expensive writes do not need to buffer the handshake.
+//!
+//! All handshakers (and `Accept`, `KeyedAccept` and `KeepaliveNegotiation`) are `Send` if
+//! the stream is `Send`, as are the filter function and its future for the filtering
+//! handshakers. A handshaker that has already made progress can thus be moved to another
+//! task, e.g. on a multithreaded executor. They are not `Sync`, which a future does not
+//! need to be.
 
 #![deny(missing_docs)]
 extern crate sodiumoxide;
 extern crate libc;
 extern crate futures_core;
 extern crate futures_io;
+extern crate futures_channel;
+extern crate rand_core;
+#[cfg(feature = "config")]
+extern crate serde;
+#[cfg(feature = "config")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "config")]
+extern crate serde_json;
+#[cfg(feature = "compat")]
+extern crate ssb_crypto;
+#[cfg(feature = "prometheus")]
+extern crate prometheus;
 
 pub mod crypto;
 pub mod errors;
+pub mod keepalive;
+pub mod sniff;
+pub mod testutil;
+pub mod authorizer;
+pub mod proxy;
+pub mod ip_filter;
+pub mod keyfile;
+pub mod listener;
+pub mod key_source;
+pub mod pre_auth;
+pub mod wire;
+pub mod observer;
+pub mod invite;
+pub mod replay;
+pub mod throttle;
+pub mod socket;
+pub mod push;
+pub mod sync_io;
+pub mod blocking;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "config")]
+pub mod known_peers;
+#[cfg(feature = "config")]
+pub mod connector;
+#[cfg(feature = "compat")]
+pub mod compat;
+#[cfg(feature = "test-util")]
+pub mod transcript;
+#[cfg(feature = "test-util")]
+pub mod simnet;
+#[cfg(feature = "insecure-key-schedule-trace")]
+pub mod key_schedule;
+#[cfg(feature = "loadtest")]
+pub mod loadtest;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "crypto-pool")]
+pub mod crypto_pool;
+#[cfg(all(unix, feature = "systemd"))]
+pub mod systemd;
+#[cfg(unix)]
+pub mod process;
 mod client;
 mod server;
+mod acceptor;
+mod options;
+mod identities;
+mod client_factory;
+mod handshake;
+mod connection;
+mod deadline;
+mod sync;
+mod timer;
 
 pub use client::*;
 pub use server::*;
-pub use crypto::{Outcome, NETWORK_IDENTIFIER_BYTES};
+pub use acceptor::*;
+pub use options::*;
+pub use identities::*;
+pub use client_factory::*;
+pub use handshake::*;
+pub use connection::*;
+pub use deadline::*;
+pub use crypto::{Outcome, BoxStreamParams, DirectionOrder, SecureOutcomeSlot, OUTCOME_BYTES,
+                 NETWORK_IDENTIFIER_BYTES, NetworkIdentifier, EphemeralKeyAgreement,
+                 SoftwareKeyAgreement, CryptoInfo, crypto_info, EphemeralRng,
+                 generate_ephemeral_keypair};
+
+/// A handshake of any kind, with its concrete type erased. Created via the `boxed` method
+/// of the client and server handshakers.
+pub type BoxedHandshake<'a, S> =
+    std::pin::Pin<Box<std::future::Future<Output = Result<(Outcome, S),
+                                                          (errors::HandshakeError, S)>> + 'a>>;
 
 #[cfg(test)]
-extern crate async_ringbuffer;
+extern crate futures;
 #[cfg(test)]
-extern crate atm_io_utils;
+extern crate futures_test;
 #[cfg(test)]
-extern crate futures;
-
+extern crate quickcheck;
 #[cfg(test)]
+extern crate async_std;
+#[cfg(all(test, feature = "compat"))]
+extern crate ssb_boxstream;
+#[cfg(all(test, loom))]
+extern crate loom;
+
+#[cfg(all(test, not(loom)))]
 mod test;
+#[cfg(all(test, loom))]
+mod loom_test;
@@ -0,0 +1,327 @@
+//! Accept connections from listeners, see `Acceptor::incoming`.
+//!
+//! A `Listener` produces streams together with metadata about the peer. This module
+//! implements it for the tcp and unix socket listeners of std, wrapped in a
+//! `NonblockingListener`:
+//!
+//! ```rust,ignore
+//! let listener = NonblockingListener::new(UnixListener::bind("/run/ssb/socket")?)?;
+//! let handshakes = acceptor.incoming(listener);
+//! ```
+//!
+//! These listeners and their streams are not registered with any reactor. Whenever an
+//! operation would block, they wake the task right away, so a task driving them is polled
+//! continuously until it makes progress.
+
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::WouldBlock;
+use std::net::{self, IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use futures_io::{AsyncRead, AsyncWrite, Error};
+
+use socket::SocketOptions;
+
+/// Metadata about the peer of an accepted connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerInfo {
+    /// The peer connected via tcp from the given address.
+    Tcp(SocketAddr),
+    /// The peer connected via a unix socket. Contains its credentials where the platform
+    /// provides them.
+    Unix(Option<UnixCredentials>),
+}
+
+impl PeerInfo {
+    /// The ip address of the peer, if it connected via ip.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match *self {
+            PeerInfo::Tcp(addr) => Some(addr.ip()),
+            PeerInfo::Unix(_) => None,
+        }
+    }
+}
+
+/// The credentials of the process on the other end of a unix socket, at the time it
+/// connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixCredentials {
+    /// The process id.
+    pub pid: u32,
+    /// The user id.
+    pub uid: u32,
+    /// The group id.
+    pub gid: u32,
+}
+
+/// A source of incoming connections.
+pub trait Listener {
+    /// The type of the accepted streams.
+    type Stream: AsyncRead + AsyncWrite + Unpin;
+
+    /// Attempts to accept a new connection.
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<(Self::Stream, PeerInfo), Error>>;
+}
+
+/// Std sockets that can be put into nonblocking mode.
+pub trait SetNonblocking {
+    /// Moves the socket into or out of nonblocking mode.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl SetNonblocking for net::TcpListener {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        net::TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl SetNonblocking for net::TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        net::TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl SetNonblocking for UnixListener {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl SetNonblocking for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Streams whose connection can be reset rather than closed gracefully, see
+/// `Handshake::abort_reset`.
+///
+/// Resetting is best effort. Transports that have no notion of a reset implement this as a
+/// no-op, so that dropping the stream closes it normally.
+pub trait Reset {
+    /// Arranges for the connection to be reset once the stream is dropped, discarding any
+    /// unsent data.
+    fn reset_on_drop(&self) -> io::Result<()>;
+}
+
+// A zero linger timeout makes closing the socket send a RST.
+#[cfg(unix)]
+impl Reset for net::TcpStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+        use libc;
+
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let result = unsafe {
+            libc::setsockopt(self.as_raw_fd(),
+                             libc::SOL_SOCKET,
+                             libc::SO_LINGER,
+                             &linger as *const libc::linger as *const libc::c_void,
+                             mem::size_of::<libc::linger>() as libc::socklen_t)
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl Reset for net::TcpStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Unix sockets are always closed gracefully.
+#[cfg(unix)]
+impl Reset for UnixStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps a std `TcpListener` or `UnixListener` and puts it into nonblocking mode, so that
+/// it can be used as a `Listener`.
+pub struct NonblockingListener<L> {
+    listener: L,
+    options: Option<SocketOptions>,
+}
+
+impl<L: SetNonblocking> NonblockingListener<L> {
+    /// Puts the listener into nonblocking mode.
+    pub fn new(listener: L) -> io::Result<NonblockingListener<L>> {
+        listener.set_nonblocking(true)?;
+        Ok(NonblockingListener {
+               listener,
+               options: None,
+           })
+    }
+}
+
+impl NonblockingListener<net::TcpListener> {
+    /// Applies the given options to every accepted socket, see the `socket` module.
+    ///
+    /// If the options are strict, connections on which they can not be applied are dropped
+    /// right away, otherwise such failures are ignored.
+    pub fn socket_options(mut self,
+                          options: SocketOptions)
+                          -> NonblockingListener<net::TcpListener> {
+        self.options = Some(options);
+        self
+    }
+}
+
+impl Listener for NonblockingListener<net::TcpListener> {
+    type Stream = NonblockingStream<net::TcpStream>;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<(Self::Stream, PeerInfo), Error>> {
+        match self.listener.accept() {
+            Ok((stream, addr)) => {
+                if let Some(ref options) = self.options {
+                    if options.apply_checked(&stream).is_err() {
+                        // Drop the connection and look for the next one.
+                        cx.waker().wake_by_ref();
+                        return Pending;
+                    }
+                }
+                Ready(NonblockingStream::new(stream).map(|stream| (stream, PeerInfo::Tcp(addr))))
+            }
+            Err(ref e) if e.kind() == WouldBlock => {
+                cx.waker().wake_by_ref();
+                Pending
+            }
+            Err(e) => Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Listener for NonblockingListener<UnixListener> {
+    type Stream = NonblockingStream<UnixStream>;
+
+    fn poll_accept(&mut self, cx: &mut Context) -> Poll<Result<(Self::Stream, PeerInfo), Error>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                let credentials = peer_credentials(&stream);
+                Ready(NonblockingStream::new(stream)
+                          .map(|stream| (stream, PeerInfo::Unix(credentials))))
+            }
+            Err(ref e) if e.kind() == WouldBlock => {
+                cx.waker().wake_by_ref();
+                Pending
+            }
+            Err(e) => Ready(Err(e)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(stream: &UnixStream) -> Option<UnixCredentials> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+
+    unsafe {
+        let mut ucred: libc::ucred = mem::zeroed();
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        if libc::getsockopt(stream.as_raw_fd(),
+                            libc::SOL_SOCKET,
+                            libc::SO_PEERCRED,
+                            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+                            &mut len) != 0 {
+            return None;
+        }
+
+        Some(UnixCredentials {
+                 pid: ucred.pid as u32,
+                 uid: ucred.uid,
+                 gid: ucred.gid,
+             })
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_credentials(_: &UnixStream) -> Option<UnixCredentials> {
+    None
+}
+
+/// A std stream in nonblocking mode, accepted by a `NonblockingListener`.
+pub struct NonblockingStream<S>(S);
+
+impl<S: SetNonblocking> NonblockingStream<S> {
+    /// Puts the stream into nonblocking mode, e.g. for a client connection.
+    pub fn new(stream: S) -> io::Result<NonblockingStream<S>> {
+        stream.set_nonblocking(true)?;
+        Ok(NonblockingStream(stream))
+    }
+}
+
+impl<S> NonblockingStream<S> {
+    /// Gets a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.0
+    }
+
+    /// Returns the wrapped stream, which is still in nonblocking mode.
+    pub fn into_inner(self) -> S {
+        self.0
+    }
+}
+
+impl<S: Reset> Reset for NonblockingStream<S> {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        self.0.reset_on_drop()
+    }
+}
+
+// Turns `WouldBlock` into waking the task and returning `Pending`.
+fn nonblocking<T>(cx: &mut Context, result: io::Result<T>) -> Poll<Result<T, Error>> {
+    match result {
+        Err(ref e) if e.kind() == WouldBlock => {
+            cx.waker().wake_by_ref();
+            Pending
+        }
+        result => Ready(result),
+    }
+}
+
+impl<S: Read + Unpin> AsyncRead for NonblockingStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let result = self.0.read(buf);
+        nonblocking(cx, result)
+    }
+}
+
+impl<S: Write + Unpin> AsyncWrite for NonblockingStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        let result = self.0.write(buf);
+        nonblocking(cx, result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let result = self.0.flush();
+        nonblocking(cx, result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,335 @@
+//! Soak-test a server with many concurrent client handshakes.
+//!
+//! This module is only available with the `loadtest` feature.
+//!
+//! A `LoadTest` opens connections via a `target` function, performs a full client handshake
+//! on each of them, and reports the latencies and failures:
+//!
+//! ```rust,ignore
+//! let config = LoadTestConfig::new(network_identifier, server_longterm_pk).identities(10);
+//! let report = block_on(LoadTest::new(connect, config).clients(1000).rate(200.0).run())?;
+//! println!("p99: {:?}, failures: {:?}", report.p99, report.failures_by_kind);
+//! ```
+//!
+//! The clients use the deterministic identities of `identity`, so that the server can be
+//! configured to accept them.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::{box_, sign};
+use sodiumoxide::crypto::hash::sha256;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crypto::NETWORK_IDENTIFIER_BYTES;
+use errors::HandshakeError;
+use client::OwningClientHandshaker;
+use timer::{self, Timer};
+
+/// The longterm keypair of the `index`-th synthetic client identity. The same index always
+/// results in the same keypair.
+pub fn identity(index: u64) -> (sign::PublicKey, sign::SecretKey) {
+    let mut input = b"secret-handshake loadtest identity ".to_vec();
+    for i in 0..8 {
+        input.push((index >> (56 - 8 * i)) as u8);
+    }
+    let sha256::Digest(seed) = sha256::hash(&input);
+    sign::keypair_from_seed(&sign::Seed(seed))
+}
+
+/// The server to test and the identities to test it with.
+#[derive(Debug, Clone)]
+pub struct LoadTestConfig {
+    /// The network identifier of the server.
+    pub network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    /// The longterm public key of the server.
+    pub server_longterm_pk: sign::PublicKey,
+    /// The number of distinct client identities, the clients use `identity(0)` up to
+    /// `identity(identities - 1)` in turn. Defaults to 1.
+    pub identities: u64,
+}
+
+impl LoadTestConfig {
+    /// Creates a config for the server with the given network identifier and longterm public
+    /// key, using a single client identity.
+    pub fn new(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+               server_longterm_pk: sign::PublicKey)
+               -> LoadTestConfig {
+        LoadTestConfig {
+            network_identifier,
+            server_longterm_pk,
+            identities: 1,
+        }
+    }
+
+    /// Sets the number of distinct client identities.
+    pub fn identities(mut self, identities: u64) -> LoadTestConfig {
+        self.identities = identities;
+        self
+    }
+}
+
+/// A load test, see the module documentation.
+pub struct LoadTest<F> {
+    target: F,
+    config: LoadTestConfig,
+    clients: usize,
+    rate: Option<f64>,
+}
+
+impl<F, C, S> LoadTest<F>
+    where F: FnMut() -> C,
+          C: Future<Output = Result<S, io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    /// Creates a load test that opens connections to the server by calling `target`. By
+    /// default, it performs a single handshake.
+    pub fn new(target: F, config: LoadTestConfig) -> LoadTest<F> {
+        LoadTest {
+            target,
+            config,
+            clients: 1,
+            rate: None,
+        }
+    }
+
+    /// Sets the total number of handshakes.
+    pub fn clients(mut self, clients: usize) -> LoadTest<F> {
+        self.clients = clients;
+        self
+    }
+
+    /// Sets the rate at which connections are opened, in connections per second. By default,
+    /// all connections are opened at once.
+    pub fn rate(mut self, connections_per_second: f64) -> LoadTest<F> {
+        self.rate = Some(connections_per_second);
+        self
+    }
+
+    /// Returns a future that performs the load test and resolves to its report.
+    ///
+    /// The future wakes itself via the timer thread shared by all handshakes while it waits to
+    /// open the next connection, it does not depend on the timer of any runtime.
+    pub fn run(self) -> LoadTestRun<F, C, S> {
+        let identities = (0..self.config.identities.max(1)).map(identity).collect();
+
+        LoadTestRun {
+            target: self.target,
+            config: self.config,
+            identities,
+            clients: self.clients,
+            interval: self.rate
+                .map(|rate| Duration::from_nanos((1_000_000_000.0 / rate) as u64)),
+            started_at: None,
+            started: 0,
+            timer: None,
+            attempts: Vec::new(),
+            latencies: Vec::new(),
+            failures_by_kind: HashMap::new(),
+        }
+    }
+}
+
+/// Future returned by `LoadTest::run`.
+pub struct LoadTestRun<F, C, S> {
+    target: F,
+    config: LoadTestConfig,
+    identities: Vec<(sign::PublicKey, sign::SecretKey)>,
+    clients: usize,
+    interval: Option<Duration>, // between opening two connections
+    started_at: Option<Instant>,
+    started: usize, // number of connections opened so far
+    timer: Option<Timer>, // wakes the task to open the next connection
+    attempts: Vec<Attempt<C, S>>,
+    latencies: Vec<Duration>,
+    failures_by_kind: HashMap<FailureKind, usize>,
+}
+
+struct Attempt<C, S> {
+    started_at: Instant,
+    state: AttemptState<C, S>,
+}
+
+enum AttemptState<C, S> {
+    Connecting(C, usize), // with the index of the identity to use
+    Handshaking(OwningClientHandshaker<S>),
+}
+
+// Drives an attempt, resolving once the handshake has completed.
+fn poll_attempt<C, S>(attempt: &mut Attempt<C, S>,
+                      config: &LoadTestConfig,
+                      identities: &[(sign::PublicKey, sign::SecretKey)],
+                      cx: &mut Context)
+                      -> Poll<Result<(), FailureKind>>
+    where C: Future<Output = Result<S, io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    loop {
+        let handshaker = match attempt.state {
+            AttemptState::Connecting(ref mut connect, identity) => {
+                let stream = match Pin::new(connect).poll(cx) {
+                    Ready(Ok(stream)) => stream,
+                    Ready(Err(err)) => return Ready(Err(FailureKind::Connect(err.kind()))),
+                    Pending => return Pending,
+                };
+
+                let (client_longterm_pk, client_longterm_sk) = identities[identity].clone();
+                let (client_ephemeral_pk, client_ephemeral_sk) = box_::gen_keypair();
+                OwningClientHandshaker::new(stream,
+                                            config.network_identifier,
+                                            client_longterm_pk,
+                                            client_longterm_sk,
+                                            client_ephemeral_pk,
+                                            client_ephemeral_sk,
+                                            config.server_longterm_pk.clone())
+            }
+            AttemptState::Handshaking(ref mut handshaker) => {
+                return match Pin::new(handshaker).poll(cx) {
+                           Ready(Ok(_)) => Ready(Ok(())),
+                           Ready(Err((err, _))) => Ready(Err(FailureKind::from(&err))),
+                           Pending => Pending,
+                       };
+            }
+        };
+
+        attempt.state = AttemptState::Handshaking(handshaker);
+    }
+}
+
+impl<F, C, S> LoadTestRun<F, C, S>
+    where F: FnMut() -> C,
+          C: Future<Output = Result<S, io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    fn report(&mut self) -> LoadReport {
+        self.latencies.sort();
+        let percentile = |latencies: &[Duration], p: usize| if latencies.is_empty() {
+            None
+        } else {
+            // nearest rank
+            let rank = (latencies.len() * p + 99) / 100;
+            Some(latencies[rank.max(1) - 1])
+        };
+
+        LoadReport {
+            successes: self.latencies.len(),
+            p50: percentile(&self.latencies, 50),
+            p95: percentile(&self.latencies, 95),
+            p99: percentile(&self.latencies, 99),
+            failures_by_kind: self.failures_by_kind.clone(),
+        }
+    }
+}
+
+// The attempts are moved around in their vector, so they are never pinned.
+impl<F, C, S> Unpin for LoadTestRun<F, C, S> {}
+
+impl<F, C, S> Future for LoadTestRun<F, C, S>
+    where F: FnMut() -> C,
+          C: Future<Output = Result<S, io::Error>> + Unpin,
+          S: AsyncRead + AsyncWrite + Unpin
+{
+    type Output = LoadReport;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let now = Instant::now();
+        let started_at = *this.started_at.get_or_insert(now);
+
+        while this.started < this.clients {
+            if let Some(interval) = this.interval {
+                let due = started_at + interval * this.started as u32;
+                if due > now {
+                    if this.timer.as_ref().map(Timer::deadline) != Some(due) {
+                        this.timer = Some(timer::wake_at(due, cx.waker().clone()));
+                    }
+                    break;
+                }
+            }
+
+            let identity = this.started % this.identities.len();
+            this.attempts
+                .push(Attempt {
+                          started_at: now,
+                          state: AttemptState::Connecting((this.target)(), identity),
+                      });
+            this.started += 1;
+        }
+
+        let mut i = 0;
+        while i < this.attempts.len() {
+            match poll_attempt(&mut this.attempts[i], &this.config, &this.identities, cx) {
+                Pending => i += 1,
+                Ready(Ok(())) => {
+                    let attempt = this.attempts.swap_remove(i);
+                    this.latencies.push(attempt.started_at.elapsed());
+                }
+                Ready(Err(kind)) => {
+                    this.attempts.swap_remove(i);
+                    *this.failures_by_kind.entry(kind).or_insert(0) += 1;
+                }
+            }
+        }
+
+        if this.started == this.clients && this.attempts.is_empty() {
+            Ready(this.report())
+        } else {
+            Pending
+        }
+    }
+}
+
+/// The results of a load test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadReport {
+    /// The number of successful handshakes.
+    pub successes: usize,
+    /// The median latency of the successful handshakes, from opening the connection to
+    /// receiving msg4. `None` if no handshake succeeded.
+    pub p50: Option<Duration>,
+    /// The 95th percentile of the latencies.
+    pub p95: Option<Duration>,
+    /// The 99th percentile of the latencies.
+    pub p99: Option<Duration>,
+    /// The number of failed attempts, by the reason they failed.
+    pub failures_by_kind: HashMap<FailureKind, usize>,
+}
+
+impl LoadReport {
+    /// The total number of failed attempts.
+    pub fn failures(&self) -> usize {
+        self.failures_by_kind.values().sum()
+    }
+}
+
+/// Why an attempt of a load test failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureKind {
+    /// Opening the connection failed with an error of the given kind.
+    Connect(io::ErrorKind),
+    /// The handshake failed with an io error of the given kind, e.g. because the server closed
+    /// the connection.
+    Io(io::ErrorKind),
+    /// The server did not provide valid authentication.
+    Crypto,
+    /// The server used the same ephemeral key as the client.
+    WeakSharedSecret,
+    /// Any other handshake error.
+    Other,
+}
+
+impl<'a> From<&'a HandshakeError> for FailureKind {
+    fn from(err: &'a HandshakeError) -> FailureKind {
+        match *err {
+            HandshakeError::IoError(ref err) => FailureKind::Io(err.kind()),
+            HandshakeError::CryptoError => FailureKind::Crypto,
+            HandshakeError::WeakSharedSecret => FailureKind::WeakSharedSecret,
+            _ => FailureKind::Other,
+        }
+    }
+}
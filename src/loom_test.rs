@@ -0,0 +1,98 @@
+//! Model checks of the state that handshakes on different tasks share, run with
+//! `RUSTFLAGS="--cfg loom" cargo test --lib --release`. Loom runs each test for every
+//! interleaving of its threads, the normal tests are not compiled in this configuration.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use loom;
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::thread;
+
+use observer::{HandshakeObserver, HandshakeResult, Observation};
+use replay::ReplayCache;
+
+#[test]
+// Of two handshakes that present the same ephemeral key at the same time, exactly one is
+// detected as a replay.
+fn replay_cache_concurrent_check() {
+    loom::model(|| {
+        let cache = Arc::new(ReplayCache::new(2, Duration::from_secs(60)));
+
+        let other = cache.clone();
+        let handle = thread::spawn(move || other.check(&[1; 32]));
+        let replayed = cache.check(&[1; 32]);
+
+        assert!(replayed != handle.join().unwrap());
+        assert_eq!(cache.len(), 1);
+    });
+}
+
+#[test]
+// Concurrent sightings of different keys never exceed the capacity, and the most recently
+// seen key is always remembered, even if a task took the time before another task updated
+// the cache.
+fn replay_cache_concurrent_eviction() {
+    loom::model(|| {
+        let cache = Arc::new(ReplayCache::new(1, Duration::from_secs(60)));
+        let start = Instant::now();
+
+        let other = cache.clone();
+        let handle = thread::spawn(move || {
+                                       other.check_at(&[1; 32], start + Duration::from_secs(1))
+                                   });
+        let replayed = cache.check_at(&[2; 32], start);
+
+        assert!(!replayed);
+        assert!(!handle.join().unwrap());
+        assert_eq!(cache.len(), 1);
+        // Whichever key was seen last is remembered, and the other one is not.
+        let one = cache.check_at(&[1; 32], start + Duration::from_secs(2));
+        let two = cache.check_at(&[2; 32], start + Duration::from_secs(2));
+        assert!(!(one && two));
+    });
+}
+
+// Counts started and finished handshakes, like a gauge of handshakes in flight.
+struct InFlight {
+    in_flight: AtomicUsize,
+    finished: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl HandshakeObserver for InFlight {
+    fn handshake_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn handshake_finished(&self, result: HandshakeResult, _: Duration) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        if result == HandshakeResult::Dropped {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[test]
+// Every started handshake is reported as finished exactly once, whether it completes or is
+// dropped on another task, so a gauge of handshakes in flight returns to zero.
+fn observation_counts_cancelled_handshakes() {
+    loom::model(|| {
+        let observer = Arc::new(InFlight {
+                                    in_flight: AtomicUsize::new(0),
+                                    finished: AtomicUsize::new(0),
+                                    dropped: AtomicUsize::new(0),
+                                });
+
+        let completed = Observation::start(observer.clone());
+        let cancelled = Observation::start(observer.clone());
+        let handle = thread::spawn(move || drop(cancelled));
+        completed.finish(HandshakeResult::Success);
+        handle.join().unwrap();
+
+        assert_eq!(observer.in_flight.load(Ordering::SeqCst), 0);
+        assert_eq!(observer.finished.load(Ordering::SeqCst), 2);
+        assert_eq!(observer.dropped.load(Ordering::SeqCst), 1);
+    });
+}
@@ -0,0 +1,90 @@
+//! Export metrics about the handshakes of an `Acceptor` to prometheus.
+//!
+//! This module is only available with the `prometheus` feature.
+//!
+//! ```rust,ignore
+//! let observer = PrometheusObserver::register(&registry)?;
+//! let acceptor = Acceptor::new(network_identifier, pk, sk).observer(observer);
+//! ```
+//!
+//! The following metrics are registered, none of them has per-peer labels:
+//!
+//! - `handshakes_total` (counter): finished handshakes, labeled with `result`, which is
+//!   `success`, `rejected` or `dropped`
+//! - `handshake_rejections_total` (counter): failed handshakes, labeled with `reason`, the
+//!   name of their `HandshakeResult`, e.g. `crypto_error` or `timeout`
+//! - `handshakes_in_flight` (gauge): handshakes that have started but not finished
+//! - `handshake_duration_seconds` (histogram): the durations of all finished handshakes
+//!
+//! To prefix the names, register into a registry created via `Registry::new_custom`.
+
+use std::time::Duration;
+
+use prometheus::{Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry};
+use prometheus::Result;
+
+use observer::{HandshakeObserver, HandshakeResult};
+
+/// A `HandshakeObserver` that records the handshakes in prometheus metrics. Cloning it is
+/// cheap, clones update the same metrics.
+#[derive(Clone)]
+pub struct PrometheusObserver {
+    handshakes: IntCounterVec,
+    rejections: IntCounterVec,
+    in_flight: IntGauge,
+    duration: Histogram,
+}
+
+impl PrometheusObserver {
+    /// Creates the metrics without registering them anywhere.
+    pub fn new() -> Result<PrometheusObserver> {
+        Ok(PrometheusObserver {
+               handshakes: IntCounterVec::new(Opts::new("handshakes_total",
+                                                        "Number of finished handshakes"),
+                                              &["result"])?,
+               rejections: IntCounterVec::new(Opts::new("handshake_rejections_total",
+                                                        "Number of failed handshakes"),
+                                              &["reason"])?,
+               in_flight: IntGauge::new("handshakes_in_flight",
+                                        "Number of handshakes in progress")?,
+               duration: Histogram::with_opts(HistogramOpts::new("handshake_duration_seconds",
+                                                                 "Duration of handshakes"))?,
+           })
+    }
+
+    /// Creates the metrics and registers them into `registry`.
+    pub fn register(registry: &Registry) -> Result<PrometheusObserver> {
+        let observer = PrometheusObserver::new()?;
+        observer.register_into(registry)?;
+        Ok(observer)
+    }
+
+    /// Registers the metrics into `registry`, e.g. into a second one.
+    pub fn register_into(&self, registry: &Registry) -> Result<()> {
+        registry.register(Box::new(self.handshakes.clone()))?;
+        registry.register(Box::new(self.rejections.clone()))?;
+        registry.register(Box::new(self.in_flight.clone()))?;
+        registry.register(Box::new(self.duration.clone()))
+    }
+}
+
+impl HandshakeObserver for PrometheusObserver {
+    fn handshake_started(&self) {
+        self.in_flight.inc();
+    }
+
+    fn handshake_finished(&self, result: HandshakeResult, duration: Duration) {
+        self.in_flight.dec();
+        self.duration
+            .observe(duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9);
+
+        match result {
+            HandshakeResult::Success => self.handshakes.with_label_values(&["success"]).inc(),
+            HandshakeResult::Dropped => self.handshakes.with_label_values(&["dropped"]).inc(),
+            rejected => {
+                self.handshakes.with_label_values(&["rejected"]).inc();
+                self.rejections.with_label_values(&[rejected.name()]).inc();
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Observe the handshakes of an `Acceptor`, e.g. to export metrics, see
+//! `Acceptor::observer`.
+//!
+//! A `HandshakeObserver` is told when a handshake starts and how and when it ends. Every
+//! started handshake ends exactly once, handshakes that are dropped before completing end
+//! as `HandshakeResult::Dropped`.
+//!
+//! A `RejectionLog` records the verified identities of the clients that a server rejected,
+//! e.g. for an audit trail or a honeypot. Unlike the coarse `HandshakeResult`, each record
+//! names the client, see `RejectedClient`. Filtering server handshakers accept one as well.
+
+use std::io::ErrorKind::TimedOut;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_channel::mpsc::UnboundedSender;
+
+use errors::{HandshakeError, RejectedClient};
+
+/// How a handshake ended.
+///
+/// This is deliberately coarse, so that it can be used as a metric label without
+/// unbounded cardinality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandshakeResult {
+    /// The handshake succeeded.
+    Success,
+    /// The handshake failed with an io error other than a timeout.
+    IoError,
+    /// The handshake timed out, with an io error of kind `TimedOut`,
+    /// `HandshakeError::AuthorizerTimeout` or `HandshakeError::TimedOut`.
+    Timeout,
+    /// The client did not provide valid authentication.
+    CryptoError,
+    /// The client used the same ephemeral key as the server.
+    WeakSharedSecret,
+    /// The connection did not start with a valid PROXY protocol header.
+    InvalidProxyHeader,
+    /// The client did not provide a valid pre-authentication.
+    PreAuthFailed,
+    /// The client reused an ephemeral key, see `Acceptor::replay_cache`.
+    ReplayedEphemeral,
+    /// A client other than the expected one connected.
+    UnexpectedClient,
+    /// The client connected again before its backoff window ended, see `Acceptor::throttle`.
+    Throttled,
+    /// The handshake was dropped before it completed.
+    Dropped,
+}
+
+impl HandshakeResult {
+    /// A short name of the result, e.g. `"crypto_error"`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HandshakeResult::Success => "success",
+            HandshakeResult::IoError => "io_error",
+            HandshakeResult::Timeout => "timeout",
+            HandshakeResult::CryptoError => "crypto_error",
+            HandshakeResult::WeakSharedSecret => "weak_shared_secret",
+            HandshakeResult::InvalidProxyHeader => "invalid_proxy_header",
+            HandshakeResult::PreAuthFailed => "pre_auth_failed",
+            HandshakeResult::ReplayedEphemeral => "replayed_ephemeral",
+            HandshakeResult::UnexpectedClient => "unexpected_client",
+            HandshakeResult::Throttled => "throttled",
+            HandshakeResult::Dropped => "dropped",
+        }
+    }
+}
+
+impl<'a> From<&'a HandshakeError> for HandshakeResult {
+    fn from(err: &'a HandshakeError) -> HandshakeResult {
+        match *err {
+            HandshakeError::IoError(ref err) if err.kind() == TimedOut => HandshakeResult::Timeout,
+            HandshakeError::IoError(_) => HandshakeResult::IoError,
+            HandshakeError::CryptoError => HandshakeResult::CryptoError,
+            HandshakeError::WeakSharedSecret => HandshakeResult::WeakSharedSecret,
+            HandshakeError::AuthorizerTimeout => HandshakeResult::Timeout,
+            HandshakeError::InvalidProxyHeader => HandshakeResult::InvalidProxyHeader,
+            HandshakeError::PreAuthFailed => HandshakeResult::PreAuthFailed,
+            HandshakeError::ReplayedEphemeral => HandshakeResult::ReplayedEphemeral,
+            HandshakeError::UnexpectedClient { .. } => HandshakeResult::UnexpectedClient,
+            HandshakeError::TimedOut => HandshakeResult::Timeout,
+            HandshakeError::Throttled { .. } => HandshakeResult::Throttled,
+        }
+    }
+}
+
+/// Receives events about the handshakes of an `Acceptor`. The methods are called from
+/// whichever task polls or drops the handshake, so they should be cheap.
+pub trait HandshakeObserver {
+    /// A handshake has started, i.e. `Acceptor::accept` has been called.
+    fn handshake_started(&self);
+
+    /// A handshake has ended with the given `result`, `duration` after it started.
+    fn handshake_finished(&self, result: HandshakeResult, duration: Duration);
+}
+
+/// Records the clients that a server rejected after verifying their authentication, see
+/// `Acceptor::rejection_log` and `ServerHandshakerWithFilter::rejection_log`.
+///
+/// `record` is called from the task that polls the handshake, right before the handshake
+/// fails, so it should be cheap. Closures taking a `&RejectedClient` are rejection logs, and
+/// so is an `UnboundedSender<RejectedClient>`, which forwards the records to the task that
+/// holds the receiver.
+pub trait RejectionLog {
+    /// The verified `client` has been rejected, for `client.reason`.
+    fn record(&self, client: &RejectedClient);
+}
+
+impl<F: Fn(&RejectedClient)> RejectionLog for F {
+    fn record(&self, client: &RejectedClient) {
+        self(client)
+    }
+}
+
+impl RejectionLog for UnboundedSender<RejectedClient> {
+    fn record(&self, client: &RejectedClient) {
+        // Nobody is interested in the records once the receiver has been dropped.
+        let _ = self.unbounded_send(client.clone());
+    }
+}
+
+// Reports the end of a handshake to an observer, as `Dropped` if it is dropped without
+// calling `finish`.
+pub(crate) struct Observation {
+    observer: Arc<HandshakeObserver + Send + Sync>,
+    started_at: Instant,
+    finished: bool,
+}
+
+impl Observation {
+    pub(crate) fn start(observer: Arc<HandshakeObserver + Send + Sync>) -> Observation {
+        observer.handshake_started();
+        Observation {
+            observer,
+            started_at: Instant::now(),
+            finished: false,
+        }
+    }
+
+    pub(crate) fn finish(mut self, result: HandshakeResult) {
+        self.finished = true;
+        self.observer.handshake_finished(result, self.started_at.elapsed());
+    }
+}
+
+impl Drop for Observation {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.observer
+                .handshake_finished(HandshakeResult::Dropped, self.started_at.elapsed());
+        }
+    }
+}
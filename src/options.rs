@@ -0,0 +1,142 @@
+//! Options shared by all handshakers.
+
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use sodiumoxide::crypto::sign;
+use sodiumoxide::crypto::hash::sha256;
+
+use crypto::{FAIR_BUDGET, is_valid_longterm_pk};
+use errors::HandshakeError;
+use pre_auth::{PreAuth, PRE_AUTH_BYTES};
+
+/// Options for a handshake, to be passed to the `options` method of a handshaker, or to
+/// `Acceptor::options`. The defaults match the behavior of a handshaker on which no
+/// options were set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HandshakeOptions {
+    pub(crate) zero_read_tolerance: usize,
+    pub(crate) fair_budget: usize,
+    pub(crate) filter_timeout: Option<Duration>,
+    pub(crate) pre_auth: Option<PreAuth>,
+    pub(crate) offload_crypto: bool,
+    pub(crate) expected_client: Option<sign::PublicKey>,
+    pub(crate) aad: Option<[u8; sha256::DIGESTBYTES]>, // the digest of the associated data
+}
+
+impl HandshakeOptions {
+    /// Creates new HandshakeOptions with default values.
+    pub fn new() -> HandshakeOptions {
+        HandshakeOptions::default()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize) -> HandshakeOptions {
+        self.zero_read_tolerance = n;
+        self
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> HandshakeOptions {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> HandshakeOptions {
+        self.fair_budget = n;
+        self
+    }
+
+    /// Fail the handshake with `AuthorizerTimeout` if the filter function of a server does
+    /// not decide within `timeout`. See `ServerHandshakerWithFilter::filter_timeout` for
+    /// details.
+    pub fn filter_timeout(mut self, timeout: Duration) -> HandshakeOptions {
+        self.filter_timeout = Some(timeout);
+        self
+    }
+
+    /// Requires clients to send a pre-authentication after msg1, or sends one as a client.
+    /// **This deviates from the secret-handshake protocol**, both sides must use the same
+    /// `pre_auth`. See the `pre_auth` module for details.
+    pub fn pre_auth(mut self, pre_auth: PreAuth) -> HandshakeOptions {
+        self.pre_auth = Some(pre_auth);
+        self
+    }
+
+    /// Performs the cpu-bound steps of the handshake on a thread pool instead of the task
+    /// that polls it. This adds latency to every handshake, see the `crypto_pool` module
+    /// for when it pays off.
+    ///
+    /// This is only available with the `crypto-pool` feature.
+    #[cfg(feature = "crypto-pool")]
+    pub fn offload_crypto(mut self) -> HandshakeOptions {
+        self.offload_crypto = true;
+        self
+    }
+
+    /// Only accept the client with the longterm public key `pk`, for point-to-point links
+    /// where the server knows which client connects. Any other client fails the handshake
+    /// with `UnexpectedClient` as soon as its authentication has been verified, without
+    /// invoking a filter function and without sending msg4. Ignored by clients, which pin
+    /// the server key anyway.
+    pub fn expect_client(mut self, pk: sign::PublicKey) -> HandshakeOptions {
+        self.expected_client = Some(pk);
+        self
+    }
+
+    /// Binds the application data `aad` (e.g. a negotiated feature set) into the handshake,
+    /// so that it only succeeds if both peers supplied the same data. The server signs the
+    /// sha256 hash of `aad` along with the usual contents of msg4, and the client checks it.
+    ///
+    /// **This changes the authenticated material of the secret-handshake protocol**, both
+    /// peers must opt in with matching data. If only one of them does or the data differs,
+    /// the client fails with `CryptoError` upon receiving msg4, and the server succeeds
+    /// but the client closes the connection. The session keys are not affected.
+    pub fn aad(mut self, aad: &[u8]) -> HandshakeOptions {
+        self.aad = Some(sha256::hash(aad).0);
+        self
+    }
+
+    /// Checks that the options are consistent, to catch misconfigurations before the first
+    /// connection instead of as failing handshakes. Returns the first problem found, as an
+    /// `IoError` of kind `InvalidInput` describing it:
+    ///
+    /// - a `filter_timeout` of zero, which times out every filter function
+    /// - an `expect_client` key that is not a valid ed25519 public key, so no client could
+    ///   ever authenticate as it
+    /// - a `PreAuth::Token` of all zeros, which is most likely an unset token
+    ///
+    /// The network identifier and the keypairs are not part of the options, their lengths
+    /// are enforced by their types.
+    pub fn validate(&self) -> Result<(), HandshakeError> {
+        let invalid = |reason: &str| {
+            Err(HandshakeError::IoError(io::Error::new(ErrorKind::InvalidInput,
+                                                       format!("invalid handshake options: {}",
+                                                               reason))))
+        };
+
+        if self.filter_timeout == Some(Duration::from_secs(0)) {
+            return invalid("filter_timeout must not be zero");
+        }
+        if let Some(ref pk) = self.expected_client {
+            if !is_valid_longterm_pk(pk) {
+                return invalid("expect_client is not a valid ed25519 public key");
+            }
+        }
+        if let Some(PreAuth::Token(ref token)) = self.pre_auth {
+            if token.iter().all(|byte| *byte == 0) {
+                return invalid("the pre_auth token is all zeros");
+            }
+        }
+        Ok(())
+    }
+
+    // The number of bytes sent after msg1.
+    pub(crate) fn pre_auth_bytes(&self) -> usize {
+        match self.pre_auth {
+            Some(_) => PRE_AUTH_BYTES,
+            None => 0,
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! An optional pre-authentication of clients, checked by the server before any expensive
+//! cryptography. **This is an extension of the secret-handshake protocol**, both sides must
+//! enable it via `HandshakeOptions::pre_auth` with the same `PreAuth`, or the handshake
+//! fails.
+//!
+//! # Threat model
+//!
+//! The network identifier of a public network is not a secret, so anyone can send a valid
+//! msg1. After receiving msg1, a server sends msg2 and then verifies msg3, which takes
+//! several scalar multiplications and a signature verification. An attacker can make the
+//! server perform this work for free by opening many connections with garbage msg3s. A
+//! pre-authentication makes such connections cost the client something (`ProofOfWork`), or
+//! restricts them to clients that know a secret shared out of band (`Token`). It does not
+//! protect against attackers who can afford the proof of work or know the token, and it
+//! does not replace the authentication of the handshake itself.
+//!
+//! # Wire format
+//!
+//! The client sends a block of `PRE_AUTH_BYTES` bytes directly after msg1. The server reads
+//! it together with msg1 and checks it before verifying msg1. If the check fails, the
+//! server closes the connection without sending msg2 and the handshake fails with
+//! `PreAuthFailed`. The block is bound to msg1, which contains the fresh ephemeral key of
+//! the client, so it can not be reused for other handshakes.
+//!
+//! - `Token`: the block is the HMAC-SHA-512-256 of msg1, keyed with the token.
+//! - `ProofOfWork`: the block is a nonce such that the sha256 hash of msg1 followed by the
+//!   nonce starts with the required number of zero bits.
+
+use std::fmt;
+
+use sodiumoxide::crypto::auth;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes_into;
+
+use crypto::MSG1_BYTES;
+
+/// Length of the pre-authentication block the client sends after msg1, in bytes.
+pub const PRE_AUTH_BYTES: usize = 32;
+
+/// The kind of pre-authentication, see the module documentation.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PreAuth {
+    /// Clients prove knowledge of a token shared out of band. Costs the server a single HMAC
+    /// per connection.
+    Token([u8; auth::KEYBYTES]),
+    /// Clients solve a proof of work with the given difficulty in bits, which takes `2^n`
+    /// hash computations on average. Costs the server a single hash per connection.
+    ProofOfWork(u8),
+}
+
+impl fmt::Debug for PreAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreAuth::Token(_) => write!(f, "Token(..)"),
+            PreAuth::ProofOfWork(difficulty) => write!(f, "ProofOfWork({})", difficulty),
+        }
+    }
+}
+
+impl PreAuth {
+    // Computes the block the client sends after `msg1`.
+    pub(crate) fn create(&self, msg1: &[u8; MSG1_BYTES], block: &mut [u8; PRE_AUTH_BYTES]) {
+        match *self {
+            PreAuth::Token(ref token) => {
+                let auth::Tag(tag) = auth::authenticate(msg1, &auth::Key(*token));
+                *block = tag;
+            }
+            PreAuth::ProofOfWork(difficulty) => {
+                randombytes_into(block);
+                while !has_proof_of_work(msg1, block, difficulty) {
+                    increment(block);
+                }
+            }
+        }
+    }
+
+    // Checks the block the client sent after `msg1`.
+    pub(crate) fn verify(&self, msg1: &[u8; MSG1_BYTES], block: &[u8; PRE_AUTH_BYTES]) -> bool {
+        match *self {
+            PreAuth::Token(ref token) => {
+                auth::verify(&auth::Tag(*block), msg1, &auth::Key(*token))
+            }
+            PreAuth::ProofOfWork(difficulty) => has_proof_of_work(msg1, block, difficulty),
+        }
+    }
+}
+
+fn has_proof_of_work(msg1: &[u8; MSG1_BYTES], nonce: &[u8; PRE_AUTH_BYTES], difficulty: u8) -> bool {
+    let mut state = sha256::State::new();
+    state.update(msg1);
+    state.update(nonce);
+    let sha256::Digest(hash) = state.finalize();
+
+    let mut zeros = 0;
+    for byte in hash.iter() {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros >= difficulty as u32
+}
+
+fn increment(nonce: &mut [u8; PRE_AUTH_BYTES]) {
+    for byte in nonce.iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Perform handshakes with peers that run as child processes and speak the protocol over
+//! their stdin and stdout, see `connect_process`.
+//!
+//! This module is only available on unix.
+//!
+//! ```rust,ignore
+//! let mut command = Command::new("ssb-server");
+//! command.arg("--stdio");
+//! let (connection, child) = block_on(connect_process(&mut command, factory, server_pk)?)?;
+//! ```
+//!
+//! The pipes to the child are put into nonblocking mode and, like the streams of the
+//! `listener` module, are not registered with any reactor: a task driving them is polled
+//! continuously until it makes progress.
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use libc;
+use sodiumoxide::crypto::sign;
+use futures_io::{AsyncRead, AsyncWrite, Error};
+
+use client::OwningClientHandshaker;
+use client_factory::ClientHandshakerFactory;
+use connection::SecuredConnection;
+use errors::HandshakeError;
+use listener::{NonblockingStream, SetNonblocking};
+
+impl SetNonblocking for ChildStdout {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd(), nonblocking)
+    }
+}
+
+impl SetNonblocking for ChildStdin {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd(), nonblocking)
+    }
+}
+
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if libc::fcntl(fd, libc::F_SETFL, flags) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The stdout (read from) and stdin (written to) of a child process, in nonblocking mode.
+pub struct ProcessStream {
+    stdout: NonblockingStream<ChildStdout>,
+    stdin: NonblockingStream<ChildStdin>,
+}
+
+impl ProcessStream {
+    /// Puts the given pipes into nonblocking mode and combines them into one stream.
+    pub fn new(stdout: ChildStdout, stdin: ChildStdin) -> io::Result<ProcessStream> {
+        Ok(ProcessStream {
+               stdout: NonblockingStream::new(stdout)?,
+               stdin: NonblockingStream::new(stdin)?,
+           })
+    }
+
+    /// Splits this stream into the pipes, which are still in nonblocking mode.
+    pub fn into_inner(self) -> (ChildStdout, ChildStdin) {
+        (self.stdout.into_inner(), self.stdin.into_inner())
+    }
+}
+
+impl AsyncRead for ProcessStream {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProcessStream {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stdin).poll_close(cx)
+    }
+}
+
+/// Spawns `command` with piped stdin and stdout, and returns a future that performs the
+/// client side of a handshake with the server with the given longterm public key over
+/// them, using a fresh ephemeral keypair from `factory`.
+///
+/// The future resolves to the secured connection together with the `Child`, whose lifetime
+/// is then up to the caller. If the handshake fails, or the future is dropped before it
+/// completes, the child is killed and waited for. Fails right away if the child can not
+/// be spawned.
+pub fn connect_process(command: &mut Command,
+                       factory: &mut ClientHandshakerFactory,
+                       server_longterm_pk: sign::PublicKey)
+                       -> io::Result<ConnectProcess> {
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+    let stream = match (child.stdout.take(), child.stdin.take()) {
+        (Some(stdout), Some(stdin)) => ProcessStream::new(stdout, stdin),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "the child has no piped stdio")),
+    };
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(err) => {
+            reap(child);
+            return Err(err);
+        }
+    };
+
+    Ok(ConnectProcess {
+           handshaker: factory.start(stream, server_longterm_pk),
+           child: Some(child),
+       })
+}
+
+/// Future returned by `connect_process`.
+///
+/// Polling it again after it resolved or failed does not panic, it stays pending forever.
+pub struct ConnectProcess {
+    handshaker: OwningClientHandshaker<ProcessStream>,
+    child: Option<Child>, // `None` once the future has completed
+}
+
+impl Future for ConnectProcess {
+    type Output = Result<(SecuredConnection<ProcessStream>, Child), HandshakeError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Once the future has resolved or failed, the child has been handed out or reaped,
+        // and this stays pending forever instead.
+        if this.child.is_none() {
+            return Pending;
+        }
+        match Pin::new(&mut this.handshaker).poll(cx) {
+            Ready(Ok(parts)) => {
+                let child = this.child.take().unwrap();
+                Ready(Ok((SecuredConnection::from(parts), child)))
+            }
+            Pending => Pending,
+            Ready(Err((err, _))) => {
+                if let Some(child) = this.child.take() {
+                    reap(child);
+                }
+                Ready(Err(err))
+            }
+        }
+    }
+}
+
+impl Drop for ConnectProcess {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            reap(child);
+        }
+    }
+}
+
+// Kills the child and waits for it, so that it does not linger as a zombie.
+fn reap(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
@@ -0,0 +1,306 @@
+//! Parse the [PROXY protocol](https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt)
+//! header that load balancers like HAProxy send before the first byte of a connection.
+//!
+//! Use `Acceptor::expect_proxy_protocol` to make an `Acceptor` read the header before
+//! starting the handshake. Both the human-readable version 1 and the binary version 2 of
+//! the header are supported. The TLVs of a version 2 header are skipped.
+//!
+//! Filtering server handshakers do not read the header themselves. Read it with
+//! `read_proxy_header` first, and hand the address of the client to the filter function:
+//!
+//! ```rust,ignore
+//! // once `read_proxy_header(stream)` has resolved to `(header, stream)`
+//! let client_addr = header.source();
+//! let server = OwningServerHandshakerWithFilter::new(stream,
+//!                                                    move |pk| {
+//!                                                        authorizer.authorize_from(pk, client_addr)
+//!                                                    },
+//!                                                    ...);
+//! ```
+
+use std::io::ErrorKind::UnexpectedEof;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::future::Future;
+use std::pin::Pin;
+use std::str;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use futures_io::{AsyncRead, Error};
+
+use errors::{HandshakeError, RETRY_LIMIT, is_retryable, overlong_read};
+
+/// The addresses conveyed by a PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// The proxy forwarded a tcp connection.
+    Tcp {
+        /// The address of the actual client.
+        source: SocketAddr,
+        /// The address to which the client connected, i.e. the one of the proxy.
+        destination: SocketAddr,
+    },
+    /// The header did not convey any tcp addresses. This is the case for connections the
+    /// proxy made on its own (e.g. health checks), and for protocols other than tcp.
+    Unknown,
+}
+
+impl ProxyHeader {
+    /// The address of the actual client, `None` for `Unknown` headers.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match *self {
+            ProxyHeader::Tcp { source, .. } => Some(source),
+            ProxyHeader::Unknown => None,
+        }
+    }
+}
+
+/// Reads a PROXY protocol header from `stream`, without reading any byte that follows it.
+///
+/// Connections without a valid header fail with `HandshakeError::InvalidProxyHeader`.
+pub fn read_proxy_header<S: AsyncRead>(stream: S) -> ReadProxyHeader<S> {
+    ReadProxyHeader {
+        reader: ProxyHeaderReader::new(),
+        stream: Some(stream),
+    }
+}
+
+/// Future returned by `read_proxy_header`, resolving to the header and the stream.
+pub struct ReadProxyHeader<S> {
+    reader: ProxyHeaderReader,
+    stream: Option<S>,
+}
+
+impl<S: AsyncRead + Unpin> Future for ReadProxyHeader<S> {
+    type Output = Result<(ProxyHeader, S), (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        // Once the header has been read or reading it failed, the stream has been handed out,
+        // and this stays pending forever instead.
+        let result = match this.stream {
+            Some(ref mut stream) => this.reader.poll_header(cx, stream),
+            None => return Pending,
+        };
+        match result {
+            Ready(Ok(header)) => Ready(Ok((header, this.stream.take().unwrap()))),
+            Pending => Pending,
+            Ready(Err(err)) => Ready(Err((err, this.stream.take().unwrap()))),
+        }
+    }
+}
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_BYTES: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49,
+                                0x54, 0x0A];
+const V2_HEADER_BYTES: usize = 16;
+const V2_TCP4_BYTES: usize = 12;
+const V2_TCP6_BYTES: usize = 36;
+
+// Enough to tell the two versions apart, and shorter than the shortest v1 header.
+const PEEK_BYTES: usize = 8;
+
+// Reads a PROXY header from a stream, without reading any byte that follows it.
+pub(crate) struct ProxyHeaderReader {
+    buf: [u8; V1_MAX_BYTES],
+    len: usize,
+    header: Option<ProxyHeader>, // set once parsed, while the v2 TLVs are skipped
+    skip: usize, // bytes of v2 TLVs left to skip
+    retries: usize, // number of consecutive retryable errors so far
+}
+
+// What to do after some bytes of a header have been read.
+enum Next {
+    // Fill the buffer up to the given length.
+    Read(usize),
+    // The header is complete, with the given number of bytes left to skip.
+    Done(ProxyHeader, usize),
+}
+
+impl ProxyHeaderReader {
+    pub(crate) fn new() -> ProxyHeaderReader {
+        ProxyHeaderReader {
+            buf: [0; V1_MAX_BYTES],
+            len: 0,
+            header: None,
+            skip: 0,
+            retries: 0,
+        }
+    }
+
+    pub(crate) fn poll_header<S: AsyncRead + Unpin>(&mut self,
+                                                    cx: &mut Context,
+                                                    stream: &mut S)
+                                                    -> Poll<Result<ProxyHeader, HandshakeError>> {
+        loop {
+            if let Some(header) = self.header {
+                if self.skip == 0 {
+                    return Ready(Ok(header));
+                }
+
+                let mut scratch = [0; 64];
+                let len = if self.skip < scratch.len() {
+                    self.skip
+                } else {
+                    scratch.len()
+                };
+                match read(cx, stream, &mut self.retries, &mut scratch[..len])? {
+                    Ready(read) => self.skip -= read,
+                    Pending => return Pending,
+                }
+                continue;
+            }
+
+            match self.next()? {
+                Next::Read(len) => {
+                    match read(cx, stream, &mut self.retries, &mut self.buf[self.len..len])? {
+                        Ready(read) => self.len += read,
+                        Pending => return Pending,
+                    }
+                }
+                Next::Done(header, skip) => {
+                    self.header = Some(header);
+                    self.skip = skip;
+                }
+            }
+        }
+    }
+
+    fn next(&self) -> Result<Next, HandshakeError> {
+        if self.len < PEEK_BYTES {
+            return Ok(Next::Read(PEEK_BYTES));
+        }
+
+        if self.buf[..PEEK_BYTES] == V2_SIGNATURE[..PEEK_BYTES] {
+            self.next_v2()
+        } else if &self.buf[..V1_PREFIX.len()] == V1_PREFIX {
+            self.next_v1()
+        } else {
+            Err(HandshakeError::InvalidProxyHeader)
+        }
+    }
+
+    fn next_v1(&self) -> Result<Next, HandshakeError> {
+        // The header is a single line, which is read byte by byte to not read past it.
+        if !self.buf[..self.len].ends_with(b"\r\n") {
+            if self.len == V1_MAX_BYTES {
+                return Err(HandshakeError::InvalidProxyHeader);
+            }
+            return Ok(Next::Read(self.len + 1));
+        }
+
+        let line = str::from_utf8(&self.buf[V1_PREFIX.len()..self.len - 2])
+            .map_err(|_| HandshakeError::InvalidProxyHeader)?;
+        parse_v1(line)
+            .map(|header| Next::Done(header, 0))
+            .ok_or(HandshakeError::InvalidProxyHeader)
+    }
+
+    fn next_v2(&self) -> Result<Next, HandshakeError> {
+        if self.len < V2_HEADER_BYTES {
+            return Ok(Next::Read(V2_HEADER_BYTES));
+        }
+
+        if self.buf[..V2_SIGNATURE.len()] != V2_SIGNATURE {
+            return Err(HandshakeError::InvalidProxyHeader);
+        }
+
+        let command = self.buf[12];
+        let family = self.buf[13];
+        let remaining = (self.buf[14] as usize) << 8 | self.buf[15] as usize;
+
+        let local = match command {
+            0x20 => true,
+            0x21 => false,
+            _ => return Err(HandshakeError::InvalidProxyHeader),
+        };
+
+        let address_bytes = match family {
+            0x11 => V2_TCP4_BYTES,
+            0x21 => V2_TCP6_BYTES,
+            _ => 0,
+        };
+        if local || address_bytes == 0 {
+            return Ok(Next::Done(ProxyHeader::Unknown, remaining));
+        }
+        if remaining < address_bytes {
+            return Err(HandshakeError::InvalidProxyHeader);
+        }
+        if self.len < V2_HEADER_BYTES + address_bytes {
+            return Ok(Next::Read(V2_HEADER_BYTES + address_bytes));
+        }
+
+        let addresses = &self.buf[V2_HEADER_BYTES..V2_HEADER_BYTES + address_bytes];
+        let header = if address_bytes == V2_TCP4_BYTES {
+            let ip = |bytes: &[u8]| {
+                IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+            };
+            tcp_header(ip(&addresses[0..4]), ip(&addresses[4..8]), &addresses[8..12])
+        } else {
+            let ip = |bytes: &[u8]| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            tcp_header(ip(&addresses[0..16]), ip(&addresses[16..32]), &addresses[32..36])
+        };
+
+        Ok(Next::Done(header, remaining - address_bytes))
+    }
+}
+
+fn tcp_header(source_ip: IpAddr, destination_ip: IpAddr, ports: &[u8]) -> ProxyHeader {
+    ProxyHeader::Tcp {
+        source: SocketAddr::new(source_ip, (ports[0] as u16) << 8 | ports[1] as u16),
+        destination: SocketAddr::new(destination_ip, (ports[2] as u16) << 8 | ports[3] as u16),
+    }
+}
+
+// Parses the part of a v1 header between "PROXY " and "\r\n".
+fn parse_v1(line: &str) -> Option<ProxyHeader> {
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[0] {
+        "UNKNOWN" => Some(ProxyHeader::Unknown),
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let (source_ip, destination_ip): (IpAddr, IpAddr) = if fields[0] == "TCP4" {
+                (IpAddr::V4(fields[1].parse().ok()?), IpAddr::V4(fields[2].parse().ok()?))
+            } else {
+                (IpAddr::V6(fields[1].parse().ok()?), IpAddr::V6(fields[2].parse().ok()?))
+            };
+
+            Some(ProxyHeader::Tcp {
+                     source: SocketAddr::new(source_ip, fields[3].parse().ok()?),
+                     destination: SocketAddr::new(destination_ip, fields[4].parse().ok()?),
+                 })
+        }
+        _ => None,
+    }
+}
+
+// Reads into a nonempty `buf`, treating a zero-length read as the end of the stream.
+// `retries` counts the consecutive retryable errors, see `RETRY_LIMIT`.
+fn read<S: AsyncRead + Unpin>(cx: &mut Context,
+                              stream: &mut S,
+                              retries: &mut usize,
+                              buf: &mut [u8])
+                              -> Poll<Result<usize, HandshakeError>> {
+    match Pin::new(stream).poll_read(cx, buf) {
+        Ready(Ok(0)) => {
+            Ready(Err(Error::new(UnexpectedEof, "failed to read the proxy header").into()))
+        }
+        Ready(Ok(read)) if read > buf.len() => Ready(Err(overlong_read().into())),
+        Ready(Ok(read)) => {
+            *retries = 0;
+            Ready(Ok(read))
+        }
+        Pending => Pending,
+        Ready(Err(ref e)) if is_retryable(e) && *retries < RETRY_LIMIT => {
+            *retries += 1;
+            cx.waker().wake_by_ref();
+            Pending
+        }
+        Ready(Err(e)) => Ready(Err(e.into())),
+    }
+}
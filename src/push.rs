@@ -0,0 +1,215 @@
+//! Drive a handshake by pushing bytes into it, for transports that deliver data via
+//! callbacks rather than as a pollable stream (e.g. the event loop of a C library).
+//!
+//! A `PushDriver` runs a handshake over a `PushStream`, an in-memory stream that is only
+//! filled by `PushDriver::feed`. Each call to `feed` hands the received bytes to the
+//! handshake, drives it as far as they allow, and returns the bytes the handshake wants to
+//! send in return. The bytes may arrive in chunks of any size, the handshake simply waits
+//! for more if a message is incomplete.
+//!
+//! ```rust,ignore
+//! let mut driver = PushDriver::new(|stream| {
+//!     OwningClientHandshaker::new(stream, network_identifier, pk, sk, eph_pk, eph_sk, server_pk)
+//! });
+//! // Nothing has been received yet, this returns msg1.
+//! transport.send(&driver.feed(&[]).send);
+//!
+//! // In the receive callback of the transport:
+//! let output = driver.feed(received);
+//! transport.send(&output.send);
+//! match output.status {
+//!     DriverStatus::Pending => {}
+//!     DriverStatus::Done { outcome, leftover } => { /* start the box-stream */ }
+//!     DriverStatus::Failed(err) => transport.close(),
+//! }
+//! ```
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::task::Poll::{Ready, Pending};
+
+use futures_io::{AsyncRead, AsyncWrite, Error};
+
+use crypto::Outcome;
+use errors::HandshakeError;
+use handshake::Handshake;
+
+/// The bytes to send to the peer after a call to `PushDriver::feed`, and the state of the
+/// handshake.
+#[derive(Debug)]
+pub struct DriverOutput {
+    /// The bytes the handshake wrote, to be sent to the peer in this order. May be empty.
+    pub send: Vec<u8>,
+    /// Whether the handshake has completed.
+    pub status: DriverStatus,
+}
+
+/// The state of a handshake driven by a `PushDriver`.
+#[derive(Debug)]
+pub enum DriverStatus {
+    /// The handshake needs more bytes from the peer.
+    Pending,
+    /// The handshake succeeded.
+    Done {
+        /// The outcome of the handshake.
+        outcome: Outcome,
+        /// The bytes that were fed after the last handshake message, i.e. the start of
+        /// whatever the peer sends over the encrypted channel.
+        leftover: Vec<u8>,
+    },
+    /// The handshake failed.
+    Failed(HandshakeError),
+}
+
+/// Drives a handshake over a `PushStream` with the bytes passed to `feed`.
+pub struct PushDriver<H> {
+    handshake: Option<H>,
+    buffers: Arc<Mutex<Buffers>>,
+}
+
+impl<H: Handshake<Stream = PushStream>> PushDriver<H> {
+    /// Creates a driver for the handshake that `create` constructs over the given stream,
+    /// e.g. an `OwningClientHandshaker` or an `Accept`.
+    pub fn new<F: FnOnce(PushStream) -> H>(create: F) -> PushDriver<H> {
+        let buffers = Arc::new(Mutex::new(Buffers::default()));
+        PushDriver {
+            handshake: Some(create(PushStream(buffers.clone()))),
+            buffers,
+        }
+    }
+
+    /// Hands the `bytes` received from the peer to the handshake and drives it as far as
+    /// possible. Call this with no bytes to obtain the first message of a client.
+    ///
+    /// Panics if the handshake has already completed or failed.
+    pub fn feed(&mut self, bytes: &[u8]) -> DriverOutput {
+        lock(&self.buffers).incoming.extend(bytes);
+
+        let status = {
+            let handshake = self.handshake
+                .as_mut()
+                .expect("Fed PushDriver after completion");
+            poll_until_stalled(handshake)
+        };
+
+        let mut buffers = lock(&self.buffers);
+        let send = buffers.outgoing.split_off(0);
+        let status = match status {
+            Ready(Ok((outcome, _))) => {
+                self.handshake = None;
+                DriverStatus::Done {
+                    outcome,
+                    leftover: buffers.incoming.drain(..).collect(),
+                }
+            }
+            Pending => DriverStatus::Pending,
+            Ready(Err((err, _))) => {
+                self.handshake = None;
+                DriverStatus::Failed(err)
+            }
+        };
+        DriverOutput { send, status }
+    }
+}
+
+impl<H> PushDriver<H> {
+    /// Whether the handshake has completed or failed, after which `feed` must not be called
+    /// anymore.
+    pub fn is_finished(&self) -> bool {
+        self.handshake.is_none()
+    }
+}
+
+impl<H> fmt::Debug for PushDriver<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let buffers = lock(&self.buffers);
+        f.debug_struct("PushDriver")
+            .field("finished", &self.is_finished())
+            .field("buffered", &buffers.incoming.len())
+            .finish()
+    }
+}
+
+/// The stream of a handshake driven by a `PushDriver`. Reads return the bytes passed to
+/// `PushDriver::feed`, writes are returned from it. Created by `PushDriver::new` only.
+pub struct PushStream(Arc<Mutex<Buffers>>);
+
+#[derive(Default)]
+struct Buffers {
+    incoming: VecDeque<u8>,
+    outgoing: Vec<u8>,
+}
+
+// The buffers are only ever modified by single, non-panicking operations.
+fn lock(buffers: &Mutex<Buffers>) -> MutexGuard<Buffers> {
+    buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl AsyncRead for PushStream {
+    // Pending if nothing has been fed, the driver polls again on the next `feed`.
+    fn poll_read(self: Pin<&mut Self>,
+                 _: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let mut buffers = lock(&self.0);
+        if buffers.incoming.is_empty() {
+            return Pending;
+        }
+        let len = min(buf.len(), buffers.incoming.len());
+        for (byte, fed) in buf.iter_mut().zip(buffers.incoming.drain(..len)) {
+            *byte = fed;
+        }
+        Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for PushStream {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        lock(&self.0).outgoing.extend_from_slice(buf);
+        Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        Ready(Ok(()))
+    }
+}
+
+impl fmt::Debug for PushStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PushStream").finish()
+    }
+}
+
+// Polls the handshake until it completes or waits for more bytes. A handshake that wakes
+// its own task (e.g. to yield with `fair`) is polled again right away.
+fn poll_until_stalled<H: Handshake>
+    (handshake: &mut H)
+     -> Poll<Result<(Outcome, H::Stream), (HandshakeError, H::Stream)>> {
+    struct FlagWake(AtomicBool);
+
+    impl Wake for FlagWake {
+        fn wake(self: Arc<FlagWake>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(FlagWake(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        flag.0.store(false, Ordering::SeqCst);
+        match handshake.poll_handshake(&mut cx) {
+            Pending if flag.0.load(Ordering::SeqCst) => {}
+            poll => return poll,
+        }
+    }
+}
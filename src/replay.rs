@@ -0,0 +1,125 @@
+//! Detect replayed handshakes by the ephemeral key of the client.
+//!
+//! A replayed msg1 (captured and re-sent by a middlebox or an attacker) verifies just like
+//! the original, and the handshake only fails at msg3 with an ordinary crypto error. Honest
+//! clients use a fresh ephemeral keypair for every handshake, so an ephemeral key seen twice
+//! indicates a replay. A `ReplayCache` remembers the ephemeral keys of recent valid msg1s:
+//!
+//! ```rust,ignore
+//! // remember up to 100000 keys for ten minutes each
+//! let cache = ReplayCache::new(100000, Duration::from_secs(600));
+//! let acceptor = Acceptor::new(...).replay_cache(cache);
+//! ```
+//!
+//! Handshakes whose msg1 repeats a remembered key fail with
+//! `HandshakeError::ReplayedEphemeral`, see `Acceptor::replay_cache`. The cache holds at
+//! most `capacity` keys, evicting the least recently seen ones first, and forgets keys after
+//! `ttl`, so replays older than that go undetected.
+
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::box_;
+
+use sync::Mutex;
+
+/// A bounded set of recently seen client ephemeral keys, see the module documentation.
+#[derive(Debug)]
+pub struct ReplayCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    // When and as which sighting each remembered key was last seen.
+    seen: HashMap<[u8; box_::PUBLICKEYBYTES], (Instant, u64)>,
+    // Sightings in the order in which they happened. A key seen again is pushed again, and
+    // its older sightings are skipped once they reach the front.
+    order: VecDeque<([u8; box_::PUBLICKEYBYTES], Instant, u64)>,
+    // The number of the next sighting. Unlike the times, these are unique, so an older
+    // sighting of a key is never mistaken for its latest one.
+    next: u64,
+}
+
+impl ReplayCache {
+    /// Creates a cache that remembers up to `capacity` keys, each for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> ReplayCache {
+        ReplayCache {
+            capacity,
+            ttl,
+            inner: Mutex::new(Inner {
+                                  seen: HashMap::new(),
+                                  order: VecDeque::new(),
+                                  next: 0,
+                              }),
+        }
+    }
+
+    /// The number of keys currently remembered.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().seen.len()
+    }
+
+    /// Whether no keys are currently remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Remembers `key`, and returns whether it was already remembered.
+    pub(crate) fn check(&self, key: &[u8; box_::PUBLICKEYBYTES]) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    pub(crate) fn check_at(&self, key: &[u8; box_::PUBLICKEYBYTES], now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        // Tasks take the time before waiting for the lock, so a task can arrive with a time
+        // earlier than that of the last sighting. Keep the sightings ordered by time anyway.
+        let now = match inner.order.back() {
+            Some(&(_, last, _)) => max(now, last),
+            None => now,
+        };
+        inner.expire(now, self.ttl);
+
+        let sighting = inner.next;
+        inner.next += 1;
+        let replayed = inner.seen.insert(*key, (now, sighting)).is_some();
+        inner.order.push_back((*key, now, sighting));
+
+        while inner.seen.len() > self.capacity {
+            inner.pop_oldest();
+        }
+        // Bound the skipped sightings of keys that were seen again.
+        if inner.order.len() > 2 * self.capacity + 1 {
+            let mut order: Vec<_> = inner.seen
+                .iter()
+                .map(|(key, &(seen, sighting))| (*key, seen, sighting))
+                .collect();
+            order.sort_by_key(|&(_, _, sighting)| sighting);
+            inner.order = order.into_iter().collect();
+        }
+
+        replayed
+    }
+}
+
+impl Inner {
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some(&(_, seen, _)) = self.order.front() {
+            if now.duration_since(seen) < ttl {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((key, _, sighting)) = self.order.pop_front() {
+            if self.seen.get(&key).map(|&(_, latest)| latest) == Some(sighting) {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
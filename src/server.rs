@@ -2,27 +2,48 @@
 
 use std::{error, io, fmt};
 use std::error::Error;
-use std::io::ErrorKind::{WriteZero, UnexpectedEof};
+use std::io::ErrorKind::{WriteZero, UnexpectedEof, Interrupted, InvalidData};
+use std::cmp::min;
+use std::convert::Infallible;
+use std::future::{self, Future};
 use std::marker::PhantomData;
-use std::mem::uninitialized;
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "crypto-pool")]
+use std::mem;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant, SystemTime};
 
 use sodiumoxide::crypto::{box_, sign};
 use sodiumoxide::utils::memzero;
-use futures_core::{Poll, Future, Never};
-use futures_core::Async::{Ready, Pending};
-use futures_core::task::Context;
-use futures_core::future::{FutureResult, ok};
+use futures_core::future::TryFuture;
 use futures_io::{AsyncRead, AsyncWrite};
 
 use crypto::*;
+use {BoxedHandshake, Handshake, HandshakePhase};
+use options::HandshakeOptions;
+use pre_auth::PRE_AUTH_BYTES;
 use errors::*;
+use replay::ReplayCache;
+use throttle::Throttle;
+use observer::RejectionLog;
+use acceptor::accept_all_error;
+use timer::{self, Timer};
+#[cfg(feature = "crypto-pool")]
+use crypto_pool::{self, Job};
+#[cfg(feature = "insecure-key-schedule-trace")]
+use key_schedule;
 
 /// Performs the server side of a handshake.
-pub struct ServerHandshaker<'a, S>(ServerHandshakerWithFilter<'a,
-                                                               S,
-                                                               fn(&sign::PublicKey)
-                                                                  -> FutureResult<bool, Never>,
-                                                               FutureResult<bool, Never>>);
+///
+/// Polling the handshaker again after it resolved or failed does not panic, it stays pending
+/// forever like a fused future, and `Handshake::phase` returns `HandshakePhase::Finished`.
+///
+/// The handshaker is a `Future` for `Unpin` streams only, since it resolves to the stream. For
+/// `!Unpin` streams, pin the handshaker and drive it with `poll_pinned` instead.
+pub struct ServerHandshaker<'a, S>(ServerHandshakerWithFilter<'a, S, ConstFilter, ConstFuture>);
 
 impl<'a, S: AsyncRead + AsyncWrite> ServerHandshaker<'a, S> {
     /// Creates a new ServerHandshakerWithFilter to accept a connection from a
@@ -43,37 +64,158 @@ impl<'a, S: AsyncRead + AsyncWrite> ServerHandshaker<'a, S> {
                                                          &server_ephemeral_pk,
                                                          &server_ephemeral_sk))
     }
+
+    /// Boxes this handshaker into a `BoxedHandshake`. See `ClientHandshaker::boxed` for
+    /// details, including the cost of the allocation.
+    pub fn boxed(self) -> BoxedHandshake<'a, S>
+        where S: Unpin + 'a
+    {
+        Box::pin(self)
+    }
+
+    /// Drives the handshake with the stream pinned in place, for `!Unpin` streams. See
+    /// `ClientHandshaker::poll_pinned` for details.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, HandshakeError>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }
+            .poll_pinned(cx)
+            .map_err(accept_all_error)
+    }
+
+    /// Returns the stream, pinned in place. See `ClientHandshaker::poll_pinned` for details.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.get_pin_mut()
+    }
+}
+
+impl<'a, S> ServerHandshaker<'a, S> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details, the server reports a fingerprint of
+    /// the client's longterm public key once it has been verified.
+    pub fn log_summary(&self) -> String {
+        self.0.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.0.progress()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize) -> ServerHandshaker<'a, S> {
+        (self.0).0.driver.core.options.zero_read_tolerance = n;
+        self
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> ServerHandshaker<'a, S> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> ServerHandshaker<'a, S> {
+        (self.0).0.driver.core.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions) -> ServerHandshaker<'a, S> {
+        (self.0).0.driver.core.options = options;
+        self
+    }
+
+    /// Feeds `prefix` to the handshake before reading from the stream, for bytes that have
+    /// already been read from the connection, e.g. by `sniff::classify_first_bytes`. They are
+    /// verified exactly like bytes read from the stream. Call this before polling.
+    ///
+    /// The prefix may contain at most what the client sends before receiving msg4, i.e. msg1,
+    /// a pre-authentication and msg3. If bytes remain after msg3, the handshake fails with an
+    /// error of kind `InvalidData`.
+    pub fn with_buffered_prefix(mut self, prefix: &[u8]) -> ServerHandshaker<'a, S> {
+        (self.0).0.buffer_prefix(prefix);
+        self
+    }
+
+    /// Feeds an already read msg1 to the handshake, see `with_buffered_prefix`.
+    pub fn with_prefilled_msg1(self, msg1: [u8; MSG1_BYTES]) -> ServerHandshaker<'a, S> {
+        self.with_buffered_prefix(&msg1)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
-impl<'a, S: AsyncRead + AsyncWrite> Future for ServerHandshaker<'a, S> {
-    type Item = (Outcome, S);
-    type Error = (HandshakeError, S);
-
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        match self.0.poll(cx) {
-            Ok(foo) => Ok(foo),
-            Err((err, stream)) => {
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Future for ServerHandshaker<'a, S> {
+    type Output = Result<(Outcome, S), (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map_err(|(err, stream)| {
                 let new_err = match err {
                     FilteringHandshakeError::IoError(io_err) => io_err.into(),
                     FilteringHandshakeError::FilterError(_) => unreachable!(),
                     FilteringHandshakeError::CryptoError => HandshakeError::CryptoError,
-                    FilteringHandshakeError::Rejected => unreachable!(),
+                    FilteringHandshakeError::Rejected(_) => unreachable!(),
+                    FilteringHandshakeError::WeakSharedSecret => HandshakeError::WeakSharedSecret,
+                    FilteringHandshakeError::AuthorizerTimeout => {
+                        HandshakeError::AuthorizerTimeout
+                    }
+                    FilteringHandshakeError::PreAuthFailed => HandshakeError::PreAuthFailed,
+                    FilteringHandshakeError::ReplayedEphemeral => HandshakeError::ReplayedEphemeral,
+                    FilteringHandshakeError::UnexpectedClient { expected, actual } => {
+                        HandshakeError::UnexpectedClient { expected, actual }
+                    }
+                    FilteringHandshakeError::Throttled { retry_after } => {
+                        HandshakeError::Throttled { retry_after }
+                    }
                 };
 
-                Err((new_err, stream))
-            }
-        }
+                (new_err, stream)
+            })
+    }
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> Handshake for ServerHandshaker<'a, S> {
+    type Stream = S;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        Pin::new(self).poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        (self.0).0.phase()
+    }
+
+    fn abort(&mut self) -> Option<S> {
+        (self.0).0.abort()
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        (self.0).0.peer_pk()
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        (self.0).0.rtt_estimate()
     }
 }
 
 /// Performs the server side of a handshake. This copies the keys so that it isn't constrainted by
 /// their lifetime.
 pub struct OwningServerHandshaker<S>(OwningServerHandshakerWithFilter<S,
-                                                                       fn(&sign::PublicKey)
-                                                                          -> FutureResult<bool,
-                                                                                           Never>,
-                                                                       FutureResult<bool, Never>>);
+                                                                       ConstFilter,
+                                                                       ConstFuture>);
 
 impl<S: AsyncRead + AsyncWrite> OwningServerHandshaker<S> {
     /// Creates a new ServerHandshakerWithFilter to accept a connection from a
@@ -94,32 +236,183 @@ impl<S: AsyncRead + AsyncWrite> OwningServerHandshaker<S> {
                                                                      server_ephemeral_pk,
                                                                      server_ephemeral_sk))
     }
+
+    /// Creates a new OwningServerHandshaker which performs the key agreements involving its
+    /// ephemeral secret key via `key_agreement`, e.g. inside an HSM, so that the secret key
+    /// never needs to be in memory. `SoftwareKeyAgreement` is the default implementation.
+    pub fn with_key_agreement(stream: S,
+                              network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                              server_longterm_pk: sign::PublicKey,
+                              server_longterm_sk: sign::SecretKey,
+                              key_agreement: Box<EphemeralKeyAgreement + Send>)
+                              -> OwningServerHandshaker<S> {
+        let filter_fn: ConstFilter = const_async_true;
+        let inner = OwningServerHandshakerWithFilter::with_key_agreement(stream,
+                                                                         filter_fn,
+                                                                         network_identifier,
+                                                                         server_longterm_pk,
+                                                                         server_longterm_sk,
+                                                                         key_agreement);
+        OwningServerHandshaker(inner)
+    }
+
+    /// Boxes this handshaker into a `BoxedHandshake`. See `ClientHandshaker::boxed` for
+    /// details, including the cost of the allocation.
+    pub fn boxed<'a>(self) -> BoxedHandshake<'a, S>
+        where S: Unpin + 'a
+    {
+        Box::pin(self)
+    }
+
+    /// Drives the handshake with the stream pinned in place, for `!Unpin` streams. See
+    /// `ClientHandshaker::poll_pinned` for details.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, HandshakeError>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }
+            .poll_pinned(cx)
+            .map_err(accept_all_error)
+    }
+
+    /// Returns the stream, pinned in place. See `ClientHandshaker::poll_pinned` for details.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.get_pin_mut()
+    }
+}
+
+impl<S> OwningServerHandshaker<S> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details, the server reports a fingerprint of
+    /// the client's longterm public key once it has been verified.
+    pub fn log_summary(&self) -> String {
+        self.0.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.0.progress()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize) -> OwningServerHandshaker<S> {
+        self.0.inner.driver.core.options.zero_read_tolerance = n;
+        self
+    }
+
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    /// Returns the ephemeral secret key used by this handshake.
+    ///
+    /// **Insecure, for audits and tests only.** See
+    /// `OwningClientHandshaker::insecure_ephemeral_secret_key`.
+    pub fn insecure_ephemeral_secret_key(&self) -> &box_::SecretKey {
+        self.0.insecure_ephemeral_secret_key()
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> OwningServerHandshaker<S> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize) -> OwningServerHandshaker<S> {
+        self.0.inner.driver.core.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions) -> OwningServerHandshaker<S> {
+        self.0.inner.driver.core.options = options;
+        self
+    }
+
+    /// Feeds `prefix` to the handshake before reading from the stream. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_buffered_prefix(mut self, prefix: &[u8]) -> OwningServerHandshaker<S> {
+        self.0.inner.buffer_prefix(prefix);
+        self
+    }
+
+    /// Feeds an already read msg1 to the handshake. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_prefilled_msg1(self, msg1: [u8; MSG1_BYTES]) -> OwningServerHandshaker<S> {
+        self.with_buffered_prefix(&msg1)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
-impl<S: AsyncRead + AsyncWrite> Future for OwningServerHandshaker<S> {
-    type Item = (Outcome, S);
-    type Error = (HandshakeError, S);
-
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        match self.0.poll(cx) {
-            Ok(foo) => Ok(foo),
-            Err((err, stream)) => {
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for OwningServerHandshaker<S> {
+    type Output = Result<(Outcome, S), (HandshakeError, S)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map_err(|(err, stream)| {
                 let new_err = match err {
                     FilteringHandshakeError::IoError(io_err) => io_err.into(),
                     FilteringHandshakeError::FilterError(_) => unreachable!(),
                     FilteringHandshakeError::CryptoError => HandshakeError::CryptoError,
-                    FilteringHandshakeError::Rejected => unreachable!(),
+                    FilteringHandshakeError::Rejected(_) => unreachable!(),
+                    FilteringHandshakeError::WeakSharedSecret => HandshakeError::WeakSharedSecret,
+                    FilteringHandshakeError::AuthorizerTimeout => {
+                        HandshakeError::AuthorizerTimeout
+                    }
+                    FilteringHandshakeError::PreAuthFailed => HandshakeError::PreAuthFailed,
+                    FilteringHandshakeError::ReplayedEphemeral => HandshakeError::ReplayedEphemeral,
+                    FilteringHandshakeError::UnexpectedClient { expected, actual } => {
+                        HandshakeError::UnexpectedClient { expected, actual }
+                    }
+                    FilteringHandshakeError::Throttled { retry_after } => {
+                        HandshakeError::Throttled { retry_after }
+                    }
                 };
 
-                Err((new_err, stream))
-            }
-        }
+                (new_err, stream)
+            })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake for OwningServerHandshaker<S> {
+    type Stream = S;
+
+    fn poll_handshake(&mut self,
+                      cx: &mut Context)
+                      -> Poll<Result<(Outcome, S), (HandshakeError, S)>> {
+        Pin::new(self).poll(cx)
+    }
+
+    fn phase(&self) -> HandshakePhase {
+        self.0.inner.phase()
+    }
+
+    fn abort(&mut self) -> Option<S> {
+        self.0.inner.abort()
+    }
+
+    fn peer_pk(&self) -> Option<sign::PublicKey> {
+        self.0.inner.peer_pk()
+    }
+
+    fn rtt_estimate(&self) -> Option<Duration> {
+        self.0.inner.rtt_estimate()
     }
 }
 
-fn const_async_true(_: &sign::PublicKey) -> FutureResult<bool, Never> {
-    ok(true)
+// The filter of the handshakers that accept every client.
+pub(crate) type ConstFilter = fn(&sign::PublicKey) -> ConstFuture;
+pub(crate) type ConstFuture = future::Ready<Result<bool, Infallible>>;
+
+pub(crate) fn const_async_true(_: &sign::PublicKey) -> ConstFuture {
+    future::ready(Ok(true))
 }
 
 /// Performs the server side of a handshake. Allows filtering clients based on
@@ -129,14 +422,14 @@ pub struct ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool>(UnsafeServerHa
 impl<'a, S, FilterFn, AsyncBool> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool>
     where S: AsyncRead + AsyncWrite,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
     /// Creates a new ServerHandshakerWithFilter to accept a connection from a
     /// client which knows the server's public key and uses the right app key
     /// over the given `stream`.
     ///
     /// Once the client has revealed its longterm public key, `filter_fn` is
-    /// invoked. If the returned `AsyncBool` resolves to `Ok(Ready(false))`,
+    /// invoked. If the returned `AsyncBool` resolves to `Ok(false)`,
     /// the handshake is aborted.
     pub fn new(stream: S,
                filter_fn: FilterFn,
@@ -155,19 +448,134 @@ impl<'a, S, FilterFn, AsyncBool> ServerHandshakerWithFilter<'a, S, FilterFn, Asy
                                                                          server_ephemeral_sk),
                                    PhantomData)
     }
+
+    /// Drives the handshake with the stream pinned in place, for `!Unpin` streams. See
+    /// `ClientHandshaker::poll_pinned` for details.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.poll_pinned(cx)
+    }
+
+    /// Returns the stream, pinned in place. See `ClientHandshaker::poll_pinned` for details.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.get_pin_mut()
+    }
+}
+
+impl<'a, S, FilterFn, AsyncBool> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details, the server reports a fingerprint of
+    /// the client's longterm public key once it has been verified.
+    pub fn log_summary(&self) -> String {
+        self.0.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.0.progress()
+    }
+
+    /// How far the handshake has progressed. While the filter function or the future it
+    /// returned decides about the client, this is `HandshakePhase::Filter`.
+    pub fn phase(&self) -> HandshakePhase {
+        self.0.phase()
+    }
+
+    /// An estimate of the round trip time to the client, see `Handshake::rtt_estimate`.
+    pub fn rtt_estimate(&self) -> Option<Duration> {
+        self.0.rtt_estimate()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize)
+                               -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.0.driver.core.options.zero_read_tolerance = n;
+        self
+    }
+
+    /// Fail the handshake with `FilteringHandshakeError::AuthorizerTimeout` if the filter
+    /// future does not resolve within `timeout` after the filter function was invoked. The
+    /// filter future is then dropped without being polled again, and the stream is returned
+    /// so that the connection can be closed, which the client sees as a rejection.
+    ///
+    /// The timeout only applies to the filter, not to reading or writing messages. If the
+    /// filter is still pending when first polled, the task is woken at the deadline by a
+    /// single timer thread shared by all handshakes, so that this works independently of any
+    /// runtime. The deadline is removed from that thread once the filter has decided or the
+    /// handshaker is dropped.
+    pub fn filter_timeout(mut self, timeout: Duration)
+                          -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.0.driver.core.options.filter_timeout = Some(timeout);
+        self
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize)
+                       -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.0.driver.core.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions)
+                   -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.0.driver.core.options = options;
+        self
+    }
+
+    /// Records the clients that this handshake rejects after verifying them in `log`, be it
+    /// because of the filter function or because of the options, see `RejectionLog`.
+    pub fn rejection_log<L>(mut self, log: L)
+                            -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool>
+        where L: RejectionLog + Send + Sync + 'static
+    {
+        self.0.driver.rejection_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Feeds `prefix` to the handshake before reading from the stream. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_buffered_prefix(mut self, prefix: &[u8])
+                                -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.0.buffer_prefix(prefix);
+        self
+    }
+
+    /// Feeds an already read msg1 to the handshake. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_prefilled_msg1(self, msg1: [u8; MSG1_BYTES])
+                               -> ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+        self.with_buffered_prefix(&msg1)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
 impl<'a, S, FilterFn, AsyncBool> Future for ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool>
-    where S: AsyncRead + AsyncWrite,
+    where S: AsyncRead + AsyncWrite + Unpin,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
-    type Item = (Outcome, S);
-    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+    type Output = Result<(Outcome, S), (FilteringHandshakeError<AsyncBool::Error>, S)>;
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        self.0.poll(cx)
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.0) }.poll(cx)
     }
 }
 
@@ -175,25 +583,22 @@ impl<'a, S, FilterFn, AsyncBool> Future for ServerHandshakerWithFilter<'a, S, Fi
 /// their longterm public key. This copies the keys so that it isn't constrainted by
 /// their lifetime.
 pub struct OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-    network_identifier: Box<[u8; NETWORK_IDENTIFIER_BYTES]>,
-    server_longterm_pk: Box<sign::PublicKey>,
-    server_longterm_sk: Box<sign::SecretKey>,
-    server_ephemeral_pk: Box<box_::PublicKey>,
-    server_ephemeral_sk: Box<box_::SecretKey>,
     inner: UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>,
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    server_ephemeral_sk: box_::SecretKey,
 }
 
 impl<S, FilterFn, AsyncBool> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>
     where S: AsyncRead + AsyncWrite,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
     /// Creates a new OwningServerHandshakerWithFilter to accept a connection from a
     /// client which knows the server's public key and uses the right app key
     /// over the given `stream`.
     ///
     /// Once the client has revealed its longterm public key, `filter_fn` is
-    /// invoked. If the returned `AsyncBool` resolves to `Ok(Ready(false))`,
+    /// invoked. If the returned `AsyncBool` resolves to `Ok(false)`,
     /// the handshake is aborted.
     pub fn new(stream: S,
                filter_fn: FilterFn,
@@ -203,305 +608,1163 @@ impl<S, FilterFn, AsyncBool> OwningServerHandshakerWithFilter<S, FilterFn, Async
                server_ephemeral_pk: box_::PublicKey,
                server_ephemeral_sk: box_::SecretKey)
                -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-        let network_identifier = Box::new(network_identifier.clone());
-        let server_longterm_pk = Box::new(server_longterm_pk.clone());
-        let server_longterm_sk = Box::new(server_longterm_sk.clone());
-        let server_ephemeral_pk = Box::new(server_ephemeral_pk.clone());
-        let server_ephemeral_sk = Box::new(server_ephemeral_sk.clone());
-
         OwningServerHandshakerWithFilter {
             inner: UnsafeServerHandshakerWithFilter::new(stream,
                                                          filter_fn,
-                                                         network_identifier.as_ref(),
-                                                         server_longterm_pk.as_ref(),
-                                                         server_longterm_sk.as_ref(),
-                                                         server_ephemeral_pk.as_ref(),
-                                                         server_ephemeral_sk.as_ref()),
-            network_identifier,
-            server_longterm_pk,
-            server_longterm_sk,
-            server_ephemeral_pk,
+                                                         &network_identifier,
+                                                         &server_longterm_pk,
+                                                         &server_longterm_sk,
+                                                         &server_ephemeral_pk,
+                                                         &server_ephemeral_sk),
+            #[cfg(feature = "insecure-ephemeral-audit")]
             server_ephemeral_sk,
         }
     }
+
+    /// Creates a new OwningServerHandshakerWithFilter which performs the key agreements
+    /// involving its ephemeral secret key via `key_agreement`. See
+    /// `OwningServerHandshaker::with_key_agreement` for details.
+    pub fn with_key_agreement(stream: S,
+                              filter_fn: FilterFn,
+                              network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                              server_longterm_pk: sign::PublicKey,
+                              server_longterm_sk: sign::SecretKey,
+                              key_agreement: Box<EphemeralKeyAgreement + Send>)
+                              -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        // The ephemeral secret key is never accessed, all uses go through `key_agreement`.
+        let unused_sk = box_::SecretKey([0; box_::SECRETKEYBYTES]);
+        let mut handshaker = OwningServerHandshakerWithFilter::new(stream,
+                                                                   filter_fn,
+                                                                   network_identifier,
+                                                                   server_longterm_pk,
+                                                                   server_longterm_sk,
+                                                                   key_agreement.public_key(),
+                                                                   unused_sk);
+        handshaker.inner.driver.key_agreement = Some(key_agreement);
+        handshaker
+    }
+
+    /// Drives the handshake with the stream pinned in place, for `!Unpin` streams. See
+    /// `ClientHandshaker::poll_pinned` for details.
+    pub fn poll_pinned(self: Pin<&mut Self>,
+                       cx: &mut Context)
+                       -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.inner) }.poll_pinned(cx)
+    }
+
+    /// Returns the stream, pinned in place. See `ClientHandshaker::poll_pinned` for details.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.inner) }.get_pin_mut()
+    }
+}
+
+impl<S, FilterFn, AsyncBool> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+    /// Stops the handshake and returns the underlying stream. See
+    /// `ClientHandshaker::into_inner` for details.
+    pub fn into_inner(self) -> S {
+        self.inner.into_inner()
+    }
+
+    /// Returns a one-line summary of the progress of the handshake, for logging. See
+    /// `ClientHandshaker::log_summary` for details, the server reports a fingerprint of
+    /// the client's longterm public key once it has been verified.
+    pub fn log_summary(&self) -> String {
+        self.inner.log_summary()
+    }
+
+    /// Returns how far the handshake has progressed, from 0.0 to 1.0. See
+    /// `ClientHandshaker::progress` for details.
+    pub fn progress(&self) -> f32 {
+        self.inner.progress()
+    }
+
+    /// How far the handshake has progressed. While the filter function or the future it
+    /// returned decides about the client, this is `HandshakePhase::Filter`.
+    pub fn phase(&self) -> HandshakePhase {
+        self.inner.phase()
+    }
+
+    /// An estimate of the round trip time to the client, see `Handshake::rtt_estimate`.
+    pub fn rtt_estimate(&self) -> Option<Duration> {
+        self.inner.rtt_estimate()
+    }
+
+    /// Retry up to `n` consecutive zero-length reads before treating them as the end
+    /// of the stream. See `ClientHandshaker::zero_read_tolerance` for details.
+    pub fn zero_read_tolerance(mut self, n: usize)
+                               -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.inner.driver.core.options.zero_read_tolerance = n;
+        self
+    }
+
+    #[cfg(feature = "insecure-ephemeral-audit")]
+    /// Returns the ephemeral secret key used by this handshake.
+    ///
+    /// **Insecure, for audits and tests only.** See
+    /// `OwningClientHandshaker::insecure_ephemeral_secret_key`.
+    pub fn insecure_ephemeral_secret_key(&self) -> &box_::SecretKey {
+        &self.server_ephemeral_sk
+    }
+
+    /// Fail the handshake if the filter future does not resolve within `timeout`. See
+    /// `ServerHandshakerWithFilter::filter_timeout` for details.
+    pub fn filter_timeout(mut self, timeout: Duration)
+                          -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.inner.driver.core.options.filter_timeout = Some(timeout);
+        self
+    }
+
+    /// Yield to other tasks during the handshake. See `ClientHandshaker::fair` for details.
+    pub fn fair(self) -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.fair_budget(FAIR_BUDGET)
+    }
+
+    /// Like `fair`, but yields after every `n` state transitions. `0` disables yielding.
+    pub fn fair_budget(mut self, n: usize)
+                       -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.inner.driver.core.options.fair_budget = n;
+        self
+    }
+
+    /// Sets all options at once, replacing any previously set ones.
+    pub fn options(mut self, options: HandshakeOptions)
+                   -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.inner.driver.core.options = options;
+        self
+    }
+
+    /// Records the clients that this handshake rejects after verifying them in `log`. See
+    /// `ServerHandshakerWithFilter::rejection_log` for details.
+    pub fn rejection_log<L>(mut self, log: L)
+                            -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>
+        where L: RejectionLog + Send + Sync + 'static
+    {
+        self.inner.driver.rejection_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Feeds `prefix` to the handshake before reading from the stream. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_buffered_prefix(mut self, prefix: &[u8])
+                                -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.inner.buffer_prefix(prefix);
+        self
+    }
+
+    /// Feeds an already read msg1 to the handshake. See
+    /// `ServerHandshaker::with_buffered_prefix` for details.
+    pub fn with_prefilled_msg1(self, msg1: [u8; MSG1_BYTES])
+                               -> OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+        self.with_buffered_prefix(&msg1)
+    }
 }
 
 /// Future implementation to asynchronously drive a handshake.
 impl<S, FilterFn, AsyncBool> Future for OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool>
-    where S: AsyncRead + AsyncWrite,
+    where S: AsyncRead + AsyncWrite + Unpin,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
-    type Item = (Outcome, S);
-    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+    type Output = Result<(Outcome, S), (FilteringHandshakeError<AsyncBool::Error>, S)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The inner handshaker is pinned whenever this one is.
+        unsafe { self.map_unchecked_mut(|handshaker| &mut handshaker.inner) }.poll(cx)
+    }
+}
+
+impl<'a, S> fmt::Debug for ServerHandshaker<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (self.0).0.fmt_redacted("ServerHandshaker", f)
+    }
+}
+
+impl<S> fmt::Debug for OwningServerHandshaker<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.inner.fmt_redacted("OwningServerHandshaker", f)
+    }
+}
+
+impl<'a, S, FilterFn, AsyncBool> fmt::Debug
+    for ServerHandshakerWithFilter<'a, S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_redacted("ServerHandshakerWithFilter", f)
+    }
+}
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        self.inner.poll(cx)
+impl<S, FilterFn, AsyncBool> fmt::Debug
+    for OwningServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt_redacted("OwningServerHandshakerWithFilter", f)
     }
 }
 
-// Performs the server side of a handshake. Allows filtering clients based on
-// their longterm public key.
-struct UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-    stream: Option<S>,
+// Performs the server side of a handshake, by driving a `Core` over the stream. Allows
+// filtering clients based on their longterm public key.
+//
+// The stream is structurally pinned, just like in `UnsafeClientHandshaker`: nothing moves it
+// out of a pinned handshaker. The handshaker is only `Unpin` if `S` is, everything that takes
+// the stream out through `&mut self` (`Future::poll`, `abort`, `stream_take`) is only reached
+// with `S: Unpin`, `into_inner` consumes the handshaker, and no `Drop` impl touches the stream.
+pub(crate) struct UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B = Inline> {
+    stream: Option<S>, // `None` once the stream has been handed out
+    finished: bool, // whether the handshake resolved or failed with the stream pinned in place
+    pub(crate) driver: ServerDriver<FilterFn, AsyncBool, B>,
+}
+
+// Everything of an `UnsafeServerHandshakerWithFilter` but the stream, which it is handed on
+// every poll. The filter future is polled in place, so the driver never moves while the
+// handshaker is pinned.
+pub(crate) struct ServerDriver<FilterFn, AsyncBool, B> {
     filter: Option<FilterStuff<FilterFn, AsyncBool>>,
+    bulk: B, // the crypto state and the message buffer
+    pub(crate) core: Core, // the progress of the handshake and its options
+    verified_at: Option<SystemTime>, // when msg3 was verified, reported if the client is rejected
+    client_pk: Option<sign::PublicKey>, // the verified client, kept when the bulk is released
+    filter_deadline: Option<Instant>, // when the filter function times out, if it has a timeout
+    filter_timer: Option<Timer>, // wakes the task at the filter deadline
+    zero_reads: usize, // number of consecutive zero-length reads so far
+    retries: usize, // number of consecutive retryable errors so far
+    transitions: usize, // state transitions during the current poll
+    key_agreement: Option<Box<EphemeralKeyAgreement + Send>>, // replaces the ephemeral secret key if set
+    defer_longterm_keys: bool, // whether to wait for `provide_longterm_keys` after msg1
+    pub(crate) replay_cache: Option<Arc<ReplayCache>>, // remembers the client ephemeral keys
+    pub(crate) throttle: Option<Arc<Throttle>>, // blocks clients that reconnect too often
+    pub(crate) rejection_log: Option<Arc<RejectionLog + Send + Sync>>, // records rejected clients
+    msg2_flushed_at: Option<Instant>, // for `rtt_estimate`
+    msg3_received_at: Option<Instant>, // when the first byte of msg3 was read
+    prefix: Vec<u8>, // already read bytes to process before reading from the stream
+    #[cfg(feature = "crypto-pool")]
+    job: Option<ServerJob>, // a crypto step running on the pool
+}
+
+// The parts of a server handshaker that make up most of its size.
+pub(crate) struct Bulk {
     server: Server,
-    state: State,
     data: [u8; MSG3_BYTES], // used to hold and cache the results of `server.create_server_challenge` and `server.create_server_ack`, and any data read from the client
-    offset: usize, // offset into the data array at which to read/write
 }
 
 // Zero buffered handshake data on dropping.
-impl<S, FilterFn, AsyncBool> Drop for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
+impl Drop for Bulk {
     fn drop(&mut self) {
         memzero(&mut self.data);
     }
 }
 
-impl<S, FilterFn, AsyncBool> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>
+// Where a server handshaker keeps its `Bulk`.
+pub(crate) trait Storage: DerefMut<Target = Bulk> {
+    fn new(bulk: Bulk) -> Self;
+
+    // Called once the handshake has completed, the bulk is not accessed afterwards.
+    fn release(&mut self);
+}
+
+// Keeps the bulk inside the handshaker, which avoids an allocation per handshake.
+pub(crate) struct Inline(Bulk);
+
+impl Deref for Inline {
+    type Target = Bulk;
+
+    fn deref(&self) -> &Bulk {
+        &self.0
+    }
+}
+
+impl DerefMut for Inline {
+    fn deref_mut(&mut self) -> &mut Bulk {
+        &mut self.0
+    }
+}
+
+impl Storage for Inline {
+    fn new(bulk: Bulk) -> Inline {
+        Inline(bulk)
+    }
+
+    fn release(&mut self) {
+        memzero(&mut self.0.data);
+    }
+}
+
+// Keeps the bulk in a single heap allocation, which is zeroed and freed as soon as the
+// handshake completes. This keeps the handshaker, and any future that embeds it, small,
+// and bounds the memory of completed but not yet dropped handshakes.
+pub(crate) struct Compact(Option<Box<Bulk>>);
+
+impl Deref for Compact {
+    type Target = Bulk;
+
+    fn deref(&self) -> &Bulk {
+        self.0.as_ref().expect("Accessed the state of a completed ServerHandshaker")
+    }
+}
+
+impl DerefMut for Compact {
+    fn deref_mut(&mut self) -> &mut Bulk {
+        self.0.as_mut().expect("Accessed the state of a completed ServerHandshaker")
+    }
+}
+
+impl Storage for Compact {
+    fn new(bulk: Bulk) -> Compact {
+        Compact(Some(Box::new(bulk)))
+    }
+
+    fn release(&mut self) {
+        self.0 = None;
+    }
+}
+
+// A cpu-bound step of the server, performed after reading msg3 or filtering the client.
+type CryptoStep = fn(&mut Server,
+                     &mut [u8; MSG3_BYTES],
+                     Option<&(EphemeralKeyAgreement + Send)>,
+                     &HandshakeOptions)
+                     -> bool;
+
+// The bulk moves to the pool while a step runs there.
+#[cfg(feature = "crypto-pool")]
+type ServerJob = Job<(Box<Bulk>, Option<Box<EphemeralKeyAgreement + Send>>, bool)>;
+
+// Verifies msg1 and any pre-authentication.
+fn verify_msg1(bulk: &mut Bulk, options: &HandshakeOptions) -> Result<(), HandshakeError> {
+    if let Some(pre_auth) = options.pre_auth {
+        let (msg1, rest) = bulk.data.split_at(MSG1_BYTES);
+        if !pre_auth.verify(unsafe { &*(msg1.as_ptr() as *const [u8; MSG1_BYTES]) },
+                            unsafe { &*(rest.as_ptr() as *const [u8; PRE_AUTH_BYTES]) }) {
+            return Err(HandshakeError::PreAuthFailed);
+        }
+    }
+
+    let msg1 = unsafe { &*(&bulk.data as *const [u8; MSG3_BYTES] as *const [u8; MSG1_BYTES]) };
+    if !bulk.server.verify_msg1(msg1) {
+        #[cfg(feature = "insecure-key-schedule-trace")]
+        key_schedule::report(&bulk.server.trace_msg1(msg1));
+        return Err(HandshakeError::CryptoError);
+    }
+    if bulk.server.ephemeral_keys_match() {
+        return Err(HandshakeError::WeakSharedSecret);
+    }
+    Ok(())
+}
+
+// Verifies msg3.
+fn verify_msg3(server: &mut Server,
+               data: &mut [u8; MSG3_BYTES],
+               key_agreement: Option<&(EphemeralKeyAgreement + Send)>,
+               _: &HandshakeOptions)
+               -> bool {
+    let ok = match key_agreement {
+        Some(key_agreement) => server.verify_msg3_with(data, key_agreement),
+        None => server.verify_msg3(data),
+    };
+    #[cfg(feature = "insecure-key-schedule-trace")]
+    {
+        if !ok {
+            let key_agreement = key_agreement.map(|k| k as &EphemeralKeyAgreement);
+            key_schedule::report(&server.trace_msg3(data, key_agreement));
+        }
+    }
+    ok
+}
+
+// Creates msg4.
+fn create_msg4(server: &mut Server,
+               data: &mut [u8; MSG3_BYTES],
+               _: Option<&(EphemeralKeyAgreement + Send)>,
+               options: &HandshakeOptions)
+               -> bool {
+    let msg4 = unsafe { &mut *(data as *mut [u8; MSG3_BYTES] as *mut [u8; MSG4_BYTES]) };
+    match options.aad {
+        Some(ref aad) => server.create_msg4_with_aad(msg4, aad),
+        None => server.create_msg4(msg4),
+    }
+    true
+}
+
+// The errors of a `Core` as errors of a filtering server. The core only fails on invalid
+// messages from the client.
+fn filtering_error<FilterErr>(err: HandshakeError) -> FilteringHandshakeError<FilterErr> {
+    match err {
+        HandshakeError::PreAuthFailed => FilteringHandshakeError::PreAuthFailed,
+        HandshakeError::WeakSharedSecret => FilteringHandshakeError::WeakSharedSecret,
+        _ => FilteringHandshakeError::CryptoError,
+    }
+}
+
+// The progress of a server handshake, which works on a `Bulk` that is kept elsewhere. Both
+// `ServerHandshakeMachine` and the futures handshakers are built on it, the latter check the
+// replay cache, wait for deferred longterm keys and filter the client between its steps.
+pub(crate) struct Core {
+    state: State,
+    offset: usize, // offset into the data array at which to read or write
+    pub(crate) options: HandshakeOptions,
+    failed: bool,
+}
+
+impl Core {
+    fn new() -> Core {
+        Core {
+            state: ReadMsg1,
+            offset: 0,
+            options: HandshakeOptions::default(),
+            failed: false,
+        }
+    }
+
+    // How many bytes of the message from the client are still missing, `0` while not
+    // reading. The client may already send msg3 while msg2 is being flushed.
+    fn read_len(&self) -> usize {
+        if self.failed {
+            return 0;
+        }
+        match self.state {
+            ReadMsg1 => MSG1_BYTES + self.options.pre_auth_bytes() - self.offset,
+            FlushMsg2 | ReadMsg3 => MSG3_BYTES - self.offset,
+            _ => 0,
+        }
+    }
+
+    // The part of the data that the rest of the message from the client is read into.
+    fn read_buf<'b>(&self, bulk: &'b mut Bulk) -> &'b mut [u8] {
+        let end = self.offset + self.read_len();
+        &mut bulk.data[self.offset..end]
+    }
+
+    // How many bytes of the message to the client are still to be written, `0` while not
+    // writing.
+    fn write_len(&self) -> usize {
+        if self.failed {
+            return 0;
+        }
+        match self.state {
+            WriteMsg2 => MSG2_BYTES - self.offset,
+            WriteMsg4 => MSG4_BYTES - self.offset,
+            _ => 0,
+        }
+    }
+
+    // The rest of the message to the client, `None` while not writing.
+    fn write_buf<'b>(&self, bulk: &'b Bulk) -> Option<&'b [u8]> {
+        match self.state {
+            WriteMsg2 | WriteMsg4 if !self.failed => {
+                Some(&bulk.data[self.offset..self.offset + self.write_len()])
+            }
+            _ => None,
+        }
+    }
+
+    // Marks `n` bytes of the message to the client as written.
+    fn advance_write(&mut self, n: usize) {
+        let len = self.write_len();
+        assert!(n <= len,
+                "Advanced a ServerHandshakeMachine past the bytes it wants to write");
+        self.offset += n;
+        if n == len && n != 0 {
+            self.offset = 0;
+            self.state = match self.state {
+                WriteMsg2 => FlushMsg2,
+                _ => FlushMsg4,
+            };
+        }
+    }
+
+    // Marks msg2 as flushed. The handshake is complete once msg4 has been flushed, so that
+    // state is never left.
+    fn flushed(&mut self) {
+        if let FlushMsg2 = self.state {
+            self.state = ReadMsg3;
+        }
+    }
+
+    // Whether msg4 has been written.
+    fn finished(&self) -> bool {
+        match self.state {
+            FlushMsg4 => true,
+            _ => false,
+        }
+    }
+
+    // Verifies msg1 once it has been read.
+    fn verify_msg1(&mut self, bulk: &mut Bulk) -> Result<(), HandshakeError> {
+        let result = verify_msg1(bulk, &self.options);
+        self.failed = result.is_err();
+        result
+    }
+
+    // Waits for the longterm keys instead of creating msg2 right away.
+    fn await_keys(&mut self) {
+        self.offset = 0;
+        self.state = AwaitKeys;
+    }
+
+    fn prepare_msg2(&mut self, bulk: &mut Bulk) {
+        self.offset = 0;
+        self.state = WriteMsg2;
+        bulk.server
+            .create_msg2(unsafe {
+                             &mut *(&mut bulk.data as *mut [u8; MSG3_BYTES] as
+                                    *mut [u8; MSG2_BYTES])
+                         });
+    }
+
+    // Moves on to filtering the client after `verify_msg3`, `ok` is false if msg3 was
+    // invalid.
+    fn msg3_verified(&mut self, ok: bool) -> Result<(), HandshakeError> {
+        if !ok {
+            self.failed = true;
+            return Err(HandshakeError::CryptoError);
+        }
+        self.offset = 0;
+        self.state = FilterClient;
+        Ok(())
+    }
+
+    // Moves on to writing msg4 once the client has been accepted, msg4 is created by
+    // `create_msg4`.
+    fn accepted(&mut self) {
+        self.state = WriteMsg4;
+    }
+
+    // The phase as seen by the futures handshakers, which flush the stream themselves.
+    fn phase(&self) -> HandshakePhase {
+        match self.state {
+            ReadMsg1 | AwaitKeys => HandshakePhase::Msg1,
+            WriteMsg2 | FlushMsg2 => HandshakePhase::Msg2,
+            ReadMsg3 => HandshakePhase::Msg3,
+            FilterClient => HandshakePhase::Filter,
+            WriteMsg4 | FlushMsg4 => HandshakePhase::Msg4,
+        }
+    }
+}
+
+impl<S, FilterFn, AsyncBool, B: Storage> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B> {
+    pub(crate) fn into_inner(mut self) -> S {
+        self.stream.take().expect("Took the stream of ServerHandshaker after completion")
+    }
+
+    // Queues bytes to be read before reading from the stream.
+    pub(crate) fn buffer_prefix(&mut self, prefix: &[u8]) {
+        self.driver.prefix.extend_from_slice(prefix);
+    }
+
+    // Access to the stream before the handshake has started.
+    pub(crate) fn stream_mut(&mut self) -> &mut S {
+        self.stream.as_mut().expect("Accessed the stream of ServerHandshaker after completion")
+    }
+
+    pub(crate) fn phase(&self) -> HandshakePhase {
+        if self.stream.is_none() || self.finished {
+            return HandshakePhase::Finished;
+        }
+        self.driver.core.phase()
+    }
+
+    // The longterm public key of the client, once msg3 has been verified.
+    pub(crate) fn peer_pk(&self) -> Option<sign::PublicKey> {
+        self.driver.client_pk.clone()
+    }
+
+    // See `Handshake::rtt_estimate`.
+    pub(crate) fn rtt_estimate(&self) -> Option<Duration> {
+        match (self.driver.msg2_flushed_at, self.driver.msg3_received_at) {
+            (Some(flushed), Some(received)) => Some(received.duration_since(flushed)),
+            _ => None,
+        }
+    }
+
+    // Stops the handshake, see `Handshake::abort`.
+    pub(crate) fn abort(&mut self) -> Option<S> {
+        self.stream.take()
+    }
+
+    pub(crate) fn progress(&self) -> f32 {
+        let core = &self.driver.core;
+        let offset = core.offset;
+        let done = match core.state {
+            ReadMsg1 => min(offset, MSG1_BYTES), // the offset includes any pre-authentication
+            AwaitKeys => MSG1_BYTES,
+            WriteMsg2 => MSG1_BYTES + offset,
+            FlushMsg2 => MSG1_BYTES + MSG2_BYTES,
+            ReadMsg3 => MSG1_BYTES + MSG2_BYTES + offset,
+            FilterClient => MSG1_BYTES + MSG2_BYTES + MSG3_BYTES,
+            WriteMsg4 => MSG1_BYTES + MSG2_BYTES + MSG3_BYTES + offset,
+            FlushMsg4 => HANDSHAKE_TOTAL_BYTES,
+        };
+        done as f32 / HANDSHAKE_TOTAL_BYTES as f32
+    }
+
+    pub(crate) fn log_summary(&self) -> String {
+        let core = &self.driver.core;
+        let len = match core.state {
+            ReadMsg1 => Some(MSG1_BYTES + core.options.pre_auth_bytes()),
+            WriteMsg2 => Some(MSG2_BYTES),
+            ReadMsg3 => Some(MSG3_BYTES),
+            WriteMsg4 => Some(MSG4_BYTES),
+            AwaitKeys | FlushMsg2 | FilterClient | FlushMsg4 => None,
+        };
+        let offset = match len {
+            Some(len) => format!(" offset={}/{}", core.offset, len),
+            None => String::new(),
+        };
+        let client = match self.driver.client_pk {
+            Some(ref pk) => fingerprint(&pk.0),
+            None => "unknown".to_string(),
+        };
+
+        format!("shs-server state={:?}{} client={}", core.state, offset, client)
+    }
+
+    // Shows only the progress and the verified client, never the keys or the buffered
+    // handshake data.
+    fn fmt_redacted(&self, name: &str, f: &mut fmt::Formatter) -> fmt::Result {
+        let client = match self.driver.client_pk {
+            Some(ref pk) => fingerprint(&pk.0),
+            None => "unknown".to_string(),
+        };
+
+        f.debug_struct(name)
+            .field("state", &self.driver.core.state)
+            .field("offset", &self.driver.core.offset)
+            .field("options", &self.driver.core.options)
+            .field("client", &client)
+            .finish()
+    }
+
+    // Takes the stream to fail before the handshake has started.
+    pub(crate) fn stream_take(&mut self) -> S {
+        self.stream.take().expect("Took the stream of ServerHandshaker after completion")
+    }
+
+    // Makes the handshaker stop after msg1 has been verified, returning `Pending` without
+    // waking the task until `provide_longterm_keys` is called. The longterm keys passed to
+    // `new` are never used.
+    pub(crate) fn defer_longterm_keys(&mut self) {
+        self.driver.defer_longterm_keys = true;
+    }
+
+    // Whether the handshaker waits for `provide_longterm_keys`.
+    pub(crate) fn awaiting_longterm_keys(&self) -> bool {
+        match self.driver.core.state {
+            AwaitKeys => true,
+            _ => false,
+        }
+    }
+
+    // Sets the longterm keys after `defer_longterm_keys`, and prepares msg2. The server
+    // copies the keys.
+    pub(crate) fn provide_longterm_keys(&mut self,
+                                        server_longterm_pk: &sign::PublicKey,
+                                        server_longterm_sk: &sign::SecretKey) {
+        assert!(self.awaiting_longterm_keys(),
+                "Provided longterm keys to a ServerHandshaker that does not wait for them");
+        let driver = &mut self.driver;
+        driver.bulk.server.replace_longterm_keys(server_longterm_pk, server_longterm_sk);
+        driver.core.prepare_msg2(&mut driver.bulk);
+    }
+}
+
+impl<S, FilterFn, AsyncBool, B: Storage> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B>
     where S: AsyncRead + AsyncWrite,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
     /// Creates a new ServerHandshakerWithFilter to accept a connection from a
     /// client which knows the server's public key and uses the right app key
     /// over the given `stream`.
     ///
     /// Once the client has revealed its longterm public key, `filter_fn` is
-    /// invoked. If the returned `AsyncBool` resolves to `Ok(Ready(false))`,
-    /// the handshake is aborted.
+    /// invoked. If the returned `AsyncBool` resolves to `Ok(false)`,
+    /// the handshake is aborted. The server copies the keys.
     pub fn new(stream: S,
                filter_fn: FilterFn,
-               network_identifier: *const [u8; NETWORK_IDENTIFIER_BYTES],
-               server_longterm_pk: *const sign::PublicKey,
-               server_longterm_sk: *const sign::SecretKey,
-               server_ephemeral_pk: *const box_::PublicKey,
-               server_ephemeral_sk: *const box_::SecretKey)
-               -> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool> {
-        unsafe {
-            UnsafeServerHandshakerWithFilter {
-                stream: Some(stream),
+               network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+               server_longterm_pk: &sign::PublicKey,
+               server_longterm_sk: &sign::SecretKey,
+               server_ephemeral_pk: &box_::PublicKey,
+               server_ephemeral_sk: &box_::SecretKey)
+               -> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B> {
+        UnsafeServerHandshakerWithFilter {
+            stream: Some(stream),
+            finished: false,
+            driver: ServerDriver {
                 filter: Some(FilterFun(filter_fn)),
-                server: Server::new(network_identifier,
-                                    &(*server_longterm_pk).0,
-                                    &(*server_longterm_sk).0,
-                                    &(*server_ephemeral_pk).0,
-                                    &(*server_ephemeral_sk).0),
-                state: ReadMsg1,
-                data: [0; MSG3_BYTES],
-                offset: 0,
-            }
+                bulk: B::new(Bulk {
+                                 server: Server::from_keys(network_identifier,
+                                                           server_longterm_pk,
+                                                           server_longterm_sk,
+                                                           server_ephemeral_pk,
+                                                           server_ephemeral_sk),
+                                 data: [0; MSG3_BYTES],
+                             }),
+                core: Core::new(),
+                verified_at: None,
+                client_pk: None,
+                filter_deadline: None,
+                filter_timer: None,
+                zero_reads: 0,
+                retries: 0,
+                transitions: 0,
+                key_agreement: None,
+                defer_longterm_keys: false,
+                replay_cache: None,
+                throttle: None,
+                rejection_log: None,
+                msg2_flushed_at: None,
+                msg3_received_at: None,
+                prefix: Vec::new(),
+                #[cfg(feature = "crypto-pool")]
+                job: None,
+            },
         }
     }
 }
 
 /// Future implementation to asynchronously drive a handshake.
-impl<S, FilterFn, AsyncBool> Future for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool>
-    where S: AsyncRead + AsyncWrite,
+impl<S, FilterFn, AsyncBool, B: Storage> Future for UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B>
+    where S: AsyncRead + AsyncWrite + Unpin,
           FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
-          AsyncBool: Future<Item = bool>
+          AsyncBool: TryFuture<Ok = bool>
 {
-    type Item = (Outcome, S);
-    type Error = (FilteringHandshakeError<AsyncBool::Error>, S);
+    type Output = Result<(Outcome, S), (FilteringHandshakeError<AsyncBool::Error>, S)>;
 
-    fn poll(&mut self, cx: &mut Context) -> Poll<Self::Item, Self::Error> {
-        let mut stream = self.stream
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // The stream is the only thing that is ever moved out of a pinned handshaker, and it is
+        // `Unpin`. The driver, and with it the filter future, stays in place.
+        let this = unsafe { self.get_unchecked_mut() };
+        // Once the handshake has resolved or failed, the stream has been handed out, and this
+        // stays pending forever instead.
+        if this.finished {
+            return Pending;
+        }
+        let result = match this.stream {
+            Some(ref mut stream) => this.driver.poll(Pin::new(stream), cx),
+            None => return Pending,
+        };
+        let result = match result {
+            Ready(result) => result,
+            Pending => return Pending,
+        };
+        let stream = this.stream
             .take()
-            .expect("Polled ServerHandshaker after completion");
+            .expect("Completed ServerHandshaker without a stream");
+        match result {
+            Ok(outcome) => Ready(Ok((outcome, stream))),
+            Err(e) => Ready(Err((e, stream))),
+        }
+    }
+}
 
-        match self.state {
-            ReadMsg1 => {
-                while self.offset < MSG1_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG1_BYTES]) {
-                        Ok(Ready(read)) => {
-                            if read == 0 {
-                                return Err((io::Error::new(UnexpectedEof, "failed to read msg1")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += read;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
-                }
+impl<S, FilterFn, AsyncBool, B: Storage> UnsafeServerHandshakerWithFilter<S, FilterFn, AsyncBool, B>
+    where S: AsyncRead + AsyncWrite,
+          FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: TryFuture<Ok = bool>
+{
+    // Drives the handshake with the stream pinned in place, see `ClientHandshaker::poll_pinned`.
+    // Once the handshake has resolved or failed, this stays pending forever.
+    pub(crate) fn poll_pinned(self: Pin<&mut Self>,
+                              cx: &mut Context)
+                              -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>> {
+        // Neither the stream nor the driver is ever moved out of a pinned handshaker, see
+        // `UnsafeServerHandshakerWithFilter`.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.finished {
+            return Pending;
+        }
+        let result = match this.stream {
+            Some(ref mut stream) => this.driver.poll(unsafe { Pin::new_unchecked(stream) }, cx),
+            None => return Pending,
+        };
+        if result.is_ready() {
+            this.finished = true;
+        }
+        result
+    }
 
-                if !self.server
-                        .verify_msg1(unsafe {
-                                         &*(&self.data as *const [u8; MSG3_BYTES] as
-                                            *const [u8; MSG1_BYTES])
-                                     }) {
-                    return Err((FilteringHandshakeError::CryptoError, stream));
-                }
+    // The stream, pinned in place, see `ClientHandshaker::get_pin_mut`.
+    pub(crate) fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut S> {
+        // The stream is structurally pinned, see `UnsafeServerHandshakerWithFilter`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let stream = this.stream
+            .as_mut()
+            .expect("Accessed the stream of ServerHandshaker after it was handed out");
+        unsafe { Pin::new_unchecked(stream) }
+    }
+}
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = WriteMsg2;
-                self.server
-                    .create_msg2(unsafe {
-                                     &mut *(&mut self.data as *mut [u8; MSG3_BYTES] as
-                                            *mut [u8; MSG2_BYTES])
-                                 });
-                return self.poll(cx);
+impl<FilterFn, AsyncBool, B: Storage> ServerDriver<FilterFn, AsyncBool, B>
+    where FilterFn: FnOnce(&sign::PublicKey) -> AsyncBool,
+          AsyncBool: TryFuture<Ok = bool>
+{
+    // Drives the handshake over `stream`. The driver must not move after this has been
+    // called, since it polls the filter future in place.
+    fn poll<S>(&mut self,
+               stream: Pin<&mut S>,
+               cx: &mut Context)
+               -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.transitions = 0;
+        let result = self.step(stream, cx);
+        if result.is_ready() {
+            self.bulk.release();
+        }
+        result
+    }
+
+    // Moves queued prefix bytes into the message that is being read, at most up to its end.
+    fn take_prefix(&mut self) {
+        let len = min(self.prefix.len(), self.core.read_len());
+        self.core.read_buf(&mut self.bulk)[..len].copy_from_slice(&self.prefix[..len]);
+        self.prefix.drain(..len);
+        self.core.offset += len;
+    }
+
+    // Moves on to the next state, unless the fairness budget of this poll is used up, in
+    // which case the task is woken and yields.
+    fn transition<S>(&mut self,
+                     stream: Pin<&mut S>,
+                     cx: &mut Context)
+                     -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.transitions += 1;
+        let fair_budget = self.core.options.fair_budget;
+        if fair_budget != 0 && self.transitions >= fair_budget {
+            cx.waker().wake_by_ref();
+            return Pending;
+        }
+        self.step(stream, cx)
+    }
+
+    // Performs a crypto step, on the crypto pool if the options say so.
+    fn crypto_step<S>(&mut self,
+                      stream: Pin<&mut S>,
+                      cx: &mut Context,
+                      step: CryptoStep)
+                      -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        #[cfg(feature = "crypto-pool")]
+        {
+            if self.core.options.offload_crypto && self.bulk.server.owns_keys() {
+                // The server and the data move to the pool, only a placeholder and zeroes stay
+                // behind until the job returns them.
+                let mut offloaded = Box::new(Bulk {
+                                                 server: mem::replace(&mut self.bulk.server,
+                                                                      Server::placeholder()),
+                                                 data: self.bulk.data,
+                                             });
+                memzero(&mut self.bulk.data);
+                let key_agreement = self.key_agreement.take();
+                let options = self.core.options;
+                self.job = Some(crypto_pool::spawn(move || {
+                    let ok = {
+                        let bulk = &mut *offloaded;
+                        step(&mut bulk.server,
+                             &mut bulk.data,
+                             key_agreement.as_ref().map(|k| &**k),
+                             &options)
+                    };
+                    (offloaded, key_agreement, ok)
+                }));
+                return self.step(stream, cx);
             }
+        }
 
-            WriteMsg2 => {
-                while self.offset < MSG2_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG2_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((io::Error::new(WriteZero, "failed to write msg2")
-                                                .into(),
-                                            stream));
-                            }
-                            self.offset += written;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
-                        }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
+        let ok = {
+            let bulk = &mut *self.bulk;
+            step(&mut bulk.server,
+                 &mut bulk.data,
+                 self.key_agreement.as_ref().map(|k| &**k),
+                 &self.core.options)
+        };
+        self.finish_crypto(stream, cx, ok)
+    }
+
+    // Continues the handshake after a crypto step, `ok` is false if msg3 was invalid.
+    fn finish_crypto<S>(&mut self,
+                        stream: Pin<&mut S>,
+                        cx: &mut Context,
+                        ok: bool)
+                        -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        if let ReadMsg3 = self.core.state {
+            self.core.msg3_verified(ok).map_err(filtering_error)?;
+            self.verified_at = Some(SystemTime::now());
+            let client = sign::PublicKey(unsafe { self.bulk.server.client_longterm_pub() });
+            self.client_pk = Some(client);
+            if let Some(expected) = self.core.options.expected_client {
+                if client != expected {
+                    self.reject(RejectionReason::UnexpectedClient);
+                    return Ready(Err(FilteringHandshakeError::UnexpectedClient {
+                                         expected,
+                                         actual: client,
+                                     }));
                 }
+            }
+            let throttled = self.throttle.as_ref().map(|throttle| throttle.check(&client));
+            if let Some(Err(retry_after)) = throttled {
+                self.reject(RejectionReason::Throttled);
+                return Ready(Err(FilteringHandshakeError::Throttled { retry_after }));
+            }
+            self.filter_deadline = self.core
+                .options
+                .filter_timeout
+                .map(|timeout| Instant::now() + timeout);
+
+            let filter_fn =
+                match self.filter
+                          .take()
+                          .expect("Attempted to poll ServerHandshaker after completion") {
+                    FilterFun(f) => f,
+                    FilterFuture(_) => unreachable!(),
+                };
+
+            self.filter =
+                Some(FilterFuture(filter_fn(&sign::PublicKey(unsafe {
+                                             self.bulk.server.client_longterm_pub()
+                                         }))));
+        }
+
+        self.transition(stream, cx)
+    }
+
+    // Describes the verified client, which is rejected for `reason`, and records it in the
+    // rejection log.
+    fn reject(&self, reason: RejectionReason) -> RejectedClient {
+        let rejected = RejectedClient {
+            longterm_pk: sign::PublicKey(unsafe { self.bulk.server.client_longterm_pub() }),
+            ephemeral_pk: box_::PublicKey(unsafe { self.bulk.server.client_ephemeral_pub() }),
+            verified_at: self.verified_at.expect("Client was rejected before msg3 was verified"),
+            rejected_at: SystemTime::now(),
+            reason,
+        };
+        if let Some(ref log) = self.rejection_log {
+            log.record(&rejected);
+        }
+        rejected
+    }
+
+    // Continues the handshake once msg1 has been read.
+    fn after_msg1<S>(&mut self,
+                     stream: Pin<&mut S>,
+                     cx: &mut Context)
+                     -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        self.core.verify_msg1(&mut self.bulk).map_err(filtering_error)?;
+
+        if let Some(ref cache) = self.replay_cache {
+            if cache.check(&unsafe { self.bulk.server.client_ephemeral_pub() }) {
+                return Ready(Err(FilteringHandshakeError::ReplayedEphemeral));
+            }
+        }
+
+        if self.defer_longterm_keys {
+            self.core.await_keys();
+            return Pending;
+        }
+        self.core.prepare_msg2(&mut self.bulk);
+        self.transition(stream, cx)
+    }
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg2;
-                return self.poll(cx);
+    // Polls the filter future, which stays in place until it is dropped.
+    fn poll_filter(&mut self, cx: &mut Context) -> Poll<Result<bool, AsyncBool::Error>> {
+        match self.filter {
+            // The driver does not move while it is being polled, see `poll`, and the filter
+            // future is only ever dropped in place.
+            Some(FilterFuture(ref mut filter_future)) => {
+                unsafe { Pin::new_unchecked(filter_future) }.try_poll(cx)
             }
+            _ => unreachable!(),
+        }
+    }
 
-            FlushMsg2 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
-                    Ok(Pending) => {
-                        self.stream = Some(stream);
-                        return Ok(Pending);
+    // Drives the state machine as far as possible.
+    fn step<S>(&mut self,
+               mut stream: Pin<&mut S>,
+               cx: &mut Context)
+               -> Poll<Result<Outcome, FilteringHandshakeError<AsyncBool::Error>>>
+        where S: AsyncRead + AsyncWrite
+    {
+        #[cfg(feature = "crypto-pool")]
+        {
+            if let Some(mut job) = self.job.take() {
+                match job.poll(cx) {
+                    Ready((mut offloaded, key_agreement, ok)) => {
+                        mem::swap(&mut self.bulk.server, &mut offloaded.server);
+                        self.bulk.data = offloaded.data;
+                        self.key_agreement = key_agreement;
+                        return self.finish_crypto(stream, cx, ok);
+                    }
+                    Pending => {
+                        self.job = Some(job);
+                        return Pending;
                     }
-                    Err(e) => return Err((e.into(), stream)),
                 }
-
-                self.stream = Some(stream);
-                self.state = ReadMsg3;
-                return self.poll(cx);
             }
+        }
 
-            ReadMsg3 => {
-                while self.offset < MSG3_BYTES {
-                    match stream.poll_read(cx, &mut self.data[self.offset..MSG3_BYTES]) {
-                        Ok(Ready(read)) => {
+        match self.core.state {
+            ReadMsg1 | ReadMsg3 => {
+                self.take_prefix();
+                let what = match self.core.state {
+                    ReadMsg1 => "failed to read msg1",
+                    _ => {
+                        if !self.prefix.is_empty() {
+                            return Ready(Err(io::Error::new(InvalidData,
+                                                            "buffered prefix extends past msg3")
+                                                 .into()));
+                        }
+                        "failed to read msg3"
+                    }
+                };
+                loop {
+                    let (len, result) = {
+                        let buf = self.core.read_buf(&mut self.bulk);
+                        if buf.is_empty() {
+                            break;
+                        }
+                        (buf.len(), stream.as_mut().poll_read(cx, buf))
+                    };
+                    match result {
+                        Ready(Ok(read)) => {
                             if read == 0 {
-                                return Err((io::Error::new(UnexpectedEof, "failed to read msg3")
-                                                .into(),
-                                            stream));
+                                if self.zero_reads < self.core.options.zero_read_tolerance {
+                                    self.zero_reads += 1;
+                                    cx.waker().wake_by_ref();
+                                    return Pending;
+                                }
+                                return Ready(Err(io::Error::new(UnexpectedEof, what).into()));
                             }
-                            self.offset += read;
+                            self.zero_reads = 0;
+                            self.retries = 0;
+                            if read > len {
+                                return Ready(Err(overlong_read().into()));
+                            }
+                            if let (&ReadMsg3, 0) = (&self.core.state, self.core.offset) {
+                                self.msg3_received_at = Some(Instant::now());
+                            }
+                            self.core.offset += read;
                         }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
+                        Pending => return Pending,
+                        Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                            self.retries += 1;
+                            if e.kind() != Interrupted {
+                                cx.waker().wake_by_ref();
+                                return Pending;
+                            }
                         }
-                        Err(e) => return Err((e.into(), stream)),
+                        Ready(Err(e)) => return Ready(Err(e.into())),
                     }
                 }
 
-                if !self.server.verify_msg3(&self.data) {
-                    return Err((FilteringHandshakeError::CryptoError, stream));
+                match self.core.state {
+                    ReadMsg1 => self.after_msg1(stream, cx),
+                    _ => self.crypto_step(stream, cx, verify_msg3),
                 }
+            }
 
-                let filter_fn =
-                    match self.filter
-                              .take()
-                              .expect("Attempted to poll ServerHandshaker after completion") {
-                        FilterFun(f) => f,
-                        FilterFuture(_) => unreachable!(),
-                    };
-
-                self.filter =
-                    Some(FilterFuture(filter_fn(&sign::PublicKey(unsafe {
-                                                 self.server.client_longterm_pub()
-                                             }))));
-
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FilterClient;
-                return self.poll(cx);
+            AwaitKeys => {
+                // Woken by whoever provides the keys.
+                Pending
             }
 
-            FilterClient => {
-                let mut filter_future =
-                    match self.filter
-                              .take()
-                              .expect("Attempted to poll ServerHandshaker after completion") {
-                        FilterFun(_) => unreachable!(),
-                        FilterFuture(f) => f,
+            WriteMsg2 | WriteMsg4 => {
+                let what = match self.core.state {
+                    WriteMsg2 => "failed to write msg2",
+                    _ => "failed to write msg4",
+                };
+                loop {
+                    let (len, result) = match self.core.write_buf(&self.bulk) {
+                        Some(bytes) => (bytes.len(), stream.as_mut().poll_write(cx, bytes)),
+                        None => break,
                     };
-
-                match filter_future.poll(cx) {
-                    Err(err) => return Err((FilteringHandshakeError::FilterError(err), stream)),
-                    Ok(Pending) => {
-                        self.filter = Some(FilterFuture(filter_future));
-                        self.stream = Some(stream);
-                        return Ok(Pending);
-                    }
-                    Ok(Ready(is_authorized)) => {
-                        if !is_authorized {
-                            return Err((FilteringHandshakeError::Rejected, stream));
+                    match result {
+                        Ready(Ok(written)) => {
+                            if written == 0 {
+                                return Ready(Err(io::Error::new(WriteZero, what).into()));
+                            }
+                            if written > len {
+                                return Ready(Err(overlong_write().into()));
+                            }
+                            self.retries = 0;
+                            self.core.advance_write(written);
+                        }
+                        Pending => return Pending,
+                        Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                            self.retries += 1;
+                            if e.kind() != Interrupted {
+                                cx.waker().wake_by_ref();
+                                return Pending;
+                            }
                         }
+                        Ready(Err(e)) => return Ready(Err(e.into())),
+                    }
+                }
 
-                        self.stream = Some(stream);
-                        self.state = WriteMsg4;
-                        self.server
-                            .create_msg4(unsafe {
-                                             &mut *(&mut self.data as *mut [u8; MSG3_BYTES] as
-                                                    *mut [u8; MSG4_BYTES])
-                                         });
+                self.transition(stream, cx)
+            }
 
-                        return self.poll(cx);
+            FlushMsg2 | FlushMsg4 => {
+                match stream.as_mut().poll_flush(cx) {
+                    Ready(Ok(())) => self.retries = 0,
+                    Pending => return Pending,
+                    Ready(Err(ref e)) if is_retryable(e) && self.retries < RETRY_LIMIT => {
+                        self.retries += 1;
+                        cx.waker().wake_by_ref();
+                        return Pending;
                     }
+                    Ready(Err(e)) => return Ready(Err(e.into())),
+                }
+
+                if self.core.finished() {
+                    return Ready(Ok(self.bulk.server.outcome()));
                 }
+                self.msg2_flushed_at = Some(Instant::now());
+                self.core.flushed();
+                self.transition(stream, cx)
             }
 
-            WriteMsg4 => {
-                while self.offset < MSG4_BYTES {
-                    match stream.poll_write(cx, &self.data[self.offset..MSG4_BYTES]) {
-                        Ok(Ready(written)) => {
-                            if written == 0 {
-                                return Err((io::Error::new(WriteZero, "failed to write msg4")
-                                                .into(),
-                                            stream));
+            FilterClient => {
+                match self.poll_filter(cx) {
+                    Ready(Err(err)) => {
+                        self.filter = None;
+                        self.filter_timer = None;
+                        Ready(Err(FilteringHandshakeError::FilterError(err)))
+                    }
+                    Pending => {
+                        if let Some(deadline) = self.filter_deadline {
+                            if Instant::now() >= deadline {
+                                // The abandoned filter future is dropped here.
+                                self.filter = None;
+                                self.filter_timer = None;
+                                return Ready(Err(FilteringHandshakeError::AuthorizerTimeout));
+                            }
+                            if self.filter_timer.is_none() {
+                                self.filter_timer = Some(timer::wake_at(deadline,
+                                                                        cx.waker().clone()));
                             }
-                            self.offset += written;
-                        }
-                        Ok(Pending) => {
-                            self.stream = Some(stream);
-                            return Ok(Pending);
                         }
-                        Err(e) => return Err((e.into(), stream)),
-                    }
-                }
 
-                self.stream = Some(stream);
-                self.offset = 0;
-                self.state = FlushMsg4;
-                return self.poll(cx);
-            }
+                        Pending
+                    }
+                    Ready(Ok(is_authorized)) => {
+                        self.filter = None;
+                        self.filter_timer = None;
+                        if !is_authorized {
+                            let rejected = self.reject(RejectionReason::Filtered);
+                            return Ready(Err(FilteringHandshakeError::Rejected(rejected)));
+                        }
 
-            FlushMsg4 => {
-                match stream.poll_flush(cx) {
-                    Ok(Ready(())) => {}
-                    Ok(Pending) => {
-                        self.stream = Some(stream);
-                        return Ok(Pending);
+                        self.core.accepted();
+                        self.crypto_step(stream, cx, create_msg4)
                     }
-                    Err(e) => return Err((e.into(), stream)),
                 }
-
-                let mut outcome = unsafe { uninitialized() };
-                self.server.outcome(&mut outcome);
-                return Ok(Ready((outcome, stream)));
             }
         }
     }
@@ -550,9 +1813,155 @@ impl<FilterErr: error::Error> error::Error for ServerHandshakeError<FilterErr> {
     }
 }
 
-// State for the future state machine.
+/// The server side of a handshake as a state machine that performs no io, see
+/// `ClientHandshakeMachine` for how to drive it. The machine accepts any client that knows
+/// the server's public key and the app key, the driver can check the client's longterm
+/// public key in the outcome. The futures handshakers are built on the same state machine.
+///
+/// Only the `pre_auth` and `aad` options affect the machine. Timeouts, zero-length reads and
+/// retrying errors are up to the driver.
+pub struct ServerHandshakeMachine<'a> {
+    core: Core,
+    bulk: Bulk, // the crypto state and the message buffer
+    handed_over: bool, // whether the outcome has left the machine
+    _keys: PhantomData<&'a u8>,
+}
+
+impl<'a> ServerHandshakeMachine<'a> {
+    /// Creates a machine to accept a client which knows the server's public key and uses
+    /// the right app key. See `ServerHandshaker::new` for the arguments.
+    pub fn new(network_identifier: &'a [u8; NETWORK_IDENTIFIER_BYTES],
+               server_longterm_pk: &'a sign::PublicKey,
+               server_longterm_sk: &'a sign::SecretKey,
+               server_ephemeral_pk: &'a box_::PublicKey,
+               server_ephemeral_sk: &'a box_::SecretKey)
+               -> ServerHandshakeMachine<'a> {
+        ServerHandshakeMachine {
+            core: Core::new(),
+            bulk: Bulk {
+                server: Server::from_keys(network_identifier,
+                                          server_longterm_pk,
+                                          server_longterm_sk,
+                                          server_ephemeral_pk,
+                                          server_ephemeral_sk),
+                data: [0; MSG3_BYTES],
+            },
+            handed_over: false,
+            _keys: PhantomData,
+        }
+    }
+
+    /// Sets all options at once, replacing any previously set ones. Must be called before
+    /// anything has been read.
+    pub fn options(mut self, options: HandshakeOptions) -> ServerHandshakeMachine<'a> {
+        assert!(self.core.offset == 0,
+                "Set the options of a ServerHandshakeMachine after reading msg1");
+        self.core.options = options;
+        self
+    }
+
+    /// The bytes to send to the client next, or `None` if the machine waits for the client
+    /// (or has completed or failed).
+    pub fn wants_write(&self) -> Option<&[u8]> {
+        self.core.write_buf(&self.bulk)
+    }
+
+    /// Marks the first `n` bytes returned by `wants_write` as written.
+    ///
+    /// Panics if `n` is larger than the number of bytes returned by `wants_write`.
+    pub fn advance_write(&mut self, n: usize) {
+        self.core.advance_write(n)
+    }
+
+    /// Feeds bytes received from the client to the machine, and returns how many of them it
+    /// consumed. The machine consumes at most the rest of the message it is reading, and
+    /// nothing while it wants to write. The consumed bytes are zeroed in `buf`.
+    ///
+    /// Returns an error if a message from the client can not be verified.
+    ///
+    /// Panics if the machine has already failed.
+    pub fn wants_read(&mut self, buf: &mut [u8]) -> Result<usize, HandshakeError> {
+        assert!(!self.core.failed, "Fed ServerHandshakeMachine after failure");
+        // The driver flushes before it reads.
+        self.core.flushed();
+        let consumed = min(buf.len(), self.core.read_len());
+        self.core.read_buf(&mut self.bulk)[..consumed].copy_from_slice(&buf[..consumed]);
+        memzero(&mut buf[..consumed]);
+        self.core.offset += consumed;
+        if consumed == 0 || self.core.read_len() != 0 {
+            return Ok(consumed);
+        }
+
+        match self.core.state {
+            ReadMsg1 => {
+                self.core.verify_msg1(&mut self.bulk)?;
+                self.core.prepare_msg2(&mut self.bulk);
+            }
+            _ => {
+                let ok = verify_msg3(&mut self.bulk.server,
+                                     &mut self.bulk.data,
+                                     None,
+                                     &self.core.options);
+                self.core.msg3_verified(ok)?;
+                self.core.accepted();
+                create_msg4(&mut self.bulk.server,
+                            &mut self.bulk.data,
+                            None,
+                            &self.core.options);
+            }
+        }
+        Ok(consumed)
+    }
+
+    /// How many bytes `wants_read` consumes at most, i.e. the rest of the message the machine
+    /// is reading. Drivers that must not read past the handshake can limit their reads to
+    /// this.
+    pub fn wants_read_len(&self) -> usize {
+        self.core.read_len()
+    }
+
+    /// Hands over the outcome of the handshake once msg4 has been written. Returns `None`
+    /// before, and on every later call: the keys leave the machine exactly once.
+    pub fn is_finished(&mut self) -> Option<Outcome> {
+        if !self.core.finished() || self.handed_over {
+            return None;
+        }
+        self.handed_over = true;
+        Some(self.bulk.server.outcome())
+    }
+
+    /// How far the handshake has progressed. `Finished` once the machine has completed or
+    /// failed.
+    pub fn phase(&self) -> HandshakePhase {
+        if self.core.failed || self.core.finished() {
+            return HandshakePhase::Finished;
+        }
+        match self.core.state {
+            // The driver flushes on its own, the machine is already reading msg3.
+            FlushMsg2 => HandshakePhase::Msg3,
+            _ => self.core.phase(),
+        }
+    }
+}
+
+// Shows only the progress, never the keys or the buffered handshake data.
+impl<'a> fmt::Debug for ServerHandshakeMachine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ServerHandshakeMachine")
+            .field("state", &self.core.state)
+            .field("offset", &self.core.offset)
+            .field("phase", &self.phase())
+            .finish()
+    }
+}
+
+// State of a handshake. The futures handshakers poll the flush of the stream in the `Flush`
+// states. To other drivers, which flush on their own, the machine is already reading msg3 in
+// `FlushMsg2`, and done in `FlushMsg4`.
+#[derive(Debug)]
 enum State {
     ReadMsg1,
+    AwaitKeys,
     WriteMsg2,
     FlushMsg2,
     ReadMsg3,
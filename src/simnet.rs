@@ -0,0 +1,459 @@
+//! Simulate slow and unreliable networks in memory, driven by a virtual clock so that tests
+//! of adverse conditions run instantly.
+//!
+//! A `VirtualClock` only advances when told to. `sim_net` creates a pair of connected
+//! `SimStream`s, each direction of which is shaped by a `LinkConfig`: a latency drawn from a
+//! `Latency` distribution, a bandwidth cap, and a connection drop at a byte offset.
+//! `VirtualClock::run` polls a future to completion, advancing the clock to the next timer
+//! whenever the future waits, so a handshake over a link with 500ms of latency completes in
+//! microseconds of real time:
+//!
+//! ```rust,ignore
+//! let clock = VirtualClock::new();
+//! let slow = LinkConfig::new().latency(Latency::Fixed(Duration::from_millis(500)));
+//! let (client_stream, server_stream) = sim_net(&clock, slow, slow);
+//! let client = WithDeadline::with_clock(factory.start(client_stream, server_pk),
+//!                                       clock.now() + Duration::from_secs(3),
+//!                                       Arc::new(clock.clone()));
+//! clock.run(client.join(acceptor.accept(server_stream)));
+//! assert_eq!(clock.elapsed(), Duration::from_secs(2));
+//! ```
+//!
+//! Bytes are delivered in order, like over TCP. Writes complete once the link has the
+//! capacity to transmit them, so with different bandwidth caps per direction, the writes of
+//! the two sides complete in a different order than they were issued.
+//!
+//! This module is only available with the `test-util` feature.
+
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind::ConnectionReset;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
+use std::task::Poll::{Ready, Pending};
+use std::time::{Duration, Instant};
+
+use futures_io::{AsyncRead, AsyncWrite, Error};
+use rand_core::RngCore;
+
+use deadline::Clock;
+use testutil::SeededRng;
+
+/// A clock that only advances via `advance` (or `run`), starting at the real time of its
+/// creation. Clones share the same time.
+#[derive(Clone)]
+pub struct VirtualClock(Arc<Mutex<ClockState>>);
+
+struct ClockState {
+    start: Instant,
+    now: Instant,
+    timers: Vec<(Instant, Waker)>,
+}
+
+impl VirtualClock {
+    /// Creates a clock, which stands still until it is advanced.
+    pub fn new() -> VirtualClock {
+        let now = Instant::now();
+        VirtualClock(Arc::new(Mutex::new(ClockState {
+                                             start: now,
+                                             now,
+                                             timers: Vec::new(),
+                                         })))
+    }
+
+    /// How far the clock has been advanced since its creation.
+    pub fn elapsed(&self) -> Duration {
+        let state = lock(&self.0);
+        state.now - state.start
+    }
+
+    /// Advances the clock by `duration`, and wakes all tasks whose timers have expired.
+    pub fn advance(&self, duration: Duration) {
+        let expired: Vec<Waker> = {
+            let mut state = lock(&self.0);
+            state.now += duration;
+            let now = state.now;
+            let (expired, pending): (Vec<_>, Vec<_>) =
+                state.timers.drain(..).partition(|&(at, _)| at <= now);
+            state.timers = pending;
+            expired.into_iter().map(|(_, waker)| waker).collect()
+        };
+        // Outside the lock, a woken task may set a new timer right away.
+        for waker in expired {
+            waker.wake();
+        }
+    }
+
+    // The instant of the earliest pending timer.
+    fn next_timer(&self) -> Option<Instant> {
+        lock(&self.0).timers.iter().map(|&(at, _)| at).min()
+    }
+
+    /// Polls `future` to completion on the current thread. Whenever it waits without having
+    /// woken itself, the clock is advanced to the earliest timer.
+    ///
+    /// Panics if the future waits while no timer is pending, since it would wait forever.
+    pub fn run<F: Future + Unpin>(&self, mut future: F) -> F::Output {
+        struct FlagWake(AtomicBool);
+
+        impl Wake for FlagWake {
+            fn wake(self: Arc<FlagWake>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let flag = Arc::new(FlagWake(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            flag.0.store(false, Ordering::SeqCst);
+            if let Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return output;
+            }
+            if flag.0.load(Ordering::SeqCst) {
+                continue;
+            }
+            match self.next_timer() {
+                Some(at) => {
+                    let now = self.now();
+                    self.advance(at - now);
+                }
+                None => panic!("VirtualClock::run: the future waits, but no timer is pending"),
+            }
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        lock(&self.0).now
+    }
+
+    fn wake_at(&self, at: Instant, waker: Waker) {
+        let mut state = lock(&self.0);
+        if at <= state.now {
+            drop(state);
+            waker.wake();
+        } else {
+            state.timers.push((at, waker));
+        }
+    }
+}
+
+impl fmt::Debug for VirtualClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualClock").field("elapsed", &self.elapsed()).finish()
+    }
+}
+
+/// The distribution of the latency of a link, sampled once per write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latency {
+    /// Every write takes the same time.
+    Fixed(Duration),
+    /// The latency is uniformly distributed between the two bounds (inclusive).
+    Uniform(Duration, Duration),
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut SeededRng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(low, high) => {
+                let (low, high) = (duration_nanos(low), duration_nanos(high.max(low)));
+                nanos_duration(low + rng.next_u64() % (high - low + 1))
+            }
+        }
+    }
+}
+
+/// How one direction of a `sim_net` behaves. The default is a perfect link: no latency, no
+/// bandwidth cap, and no drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkConfig {
+    latency: Latency,
+    bandwidth: Option<u64>,
+    drop: Option<(u64, u32)>, // offset and probability in millionths
+    seed: [u8; 32],
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            latency: Latency::Fixed(Duration::from_secs(0)),
+            bandwidth: None,
+            drop: None,
+            seed: [0; 32],
+        }
+    }
+}
+
+impl LinkConfig {
+    /// Creates the configuration of a perfect link.
+    pub fn new() -> LinkConfig {
+        LinkConfig::default()
+    }
+
+    /// Delays the delivery of every write by a latency drawn from `latency`.
+    pub fn latency(mut self, latency: Latency) -> LinkConfig {
+        self.latency = latency;
+        self
+    }
+
+    /// Transmits at most `bytes_per_second`. Writes wait until the previous ones have been
+    /// transmitted, and are delivered a latency after their transmission completed.
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> LinkConfig {
+        self.bandwidth = Some(bytes_per_second.max(1));
+        self
+    }
+
+    /// With the given `probability` (between 0 and 1), drops the connection once `offset`
+    /// bytes have been written in this direction. Bytes before the offset are still
+    /// delivered, after that reads and writes of both sides fail with `ConnectionReset`.
+    pub fn drop_at(mut self, offset: u64, probability: f64) -> LinkConfig {
+        let millionths = (probability.max(0.0).min(1.0) * 1_000_000.0) as u32;
+        self.drop = Some((offset, millionths));
+        self
+    }
+
+    /// Seeds the randomness of the latency and the drop, see `testutil::SeededRng`. Links
+    /// with the same configuration and seed behave identically.
+    pub fn seed(mut self, seed: [u8; 32]) -> LinkConfig {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Creates two connected `SimStream`s on `clock`. Bytes written to the first one arrive at
+/// the second one as configured by `a_to_b`, the other direction as configured by `b_to_a`.
+pub fn sim_net(clock: &VirtualClock,
+               a_to_b: LinkConfig,
+               b_to_a: LinkConfig)
+               -> (SimStream, SimStream) {
+    let a_to_b = Arc::new(Mutex::new(Link::new(a_to_b, clock.now())));
+    let b_to_a = Arc::new(Mutex::new(Link::new(b_to_a, clock.now())));
+    let dropped = Arc::new(AtomicBool::new(false));
+
+    (SimStream {
+         clock: clock.clone(),
+         outgoing: a_to_b.clone(),
+         incoming: b_to_a.clone(),
+         dropped: dropped.clone(),
+     },
+     SimStream {
+         clock: clock.clone(),
+         outgoing: b_to_a,
+         incoming: a_to_b,
+         dropped,
+     })
+}
+
+/// One endpoint of a simulated connection, created by `sim_net`.
+pub struct SimStream {
+    clock: VirtualClock,
+    outgoing: Arc<Mutex<Link>>,
+    incoming: Arc<Mutex<Link>>,
+    dropped: Arc<AtomicBool>, // shared by both endpoints
+}
+
+// One direction of a connection.
+struct Link {
+    config: LinkConfig,
+    rng: SeededRng,
+    in_flight: VecDeque<(Instant, Vec<u8>)>, // chunks with the instant they arrive
+    offset: usize, // offset into the first chunk at which to continue reading
+    free_at: Instant, // when the previous writes have been transmitted
+    written: u64,
+    drop_at: Option<u64>, // the offset at which the connection drops, if it does
+    closed: bool, // whether the writing side has been closed or dropped
+    reader: Option<Waker>, // the reading task, waiting for a chunk to be sent
+}
+
+impl Link {
+    fn new(config: LinkConfig, now: Instant) -> Link {
+        let mut rng = SeededRng::new(config.seed);
+        let drop_at = config
+            .drop
+            .and_then(|(offset, millionths)| if rng.next_u32() % 1_000_000 < millionths {
+                          Some(offset)
+                      } else {
+                          None
+                      });
+        Link {
+            config,
+            rng,
+            in_flight: VecDeque::new(),
+            offset: 0,
+            free_at: now,
+            written: 0,
+            drop_at,
+            closed: false,
+            reader: None,
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn duration_nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn nanos_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+fn reset() -> Error {
+    Error::new(ConnectionReset, "dropped by SimNet")
+}
+
+impl SimStream {
+    /// Whether the connection has been dropped, see `LinkConfig::drop_at`.
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    // Drops the connection, and wakes the readers of both sides so they notice. Must not be
+    // called while holding the lock of a link.
+    fn drop_connection(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+        lock(&self.outgoing).wake_reader();
+        lock(&self.incoming).wake_reader();
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let now = self.clock.now();
+        let mut link = lock(&self.incoming);
+        let arrives_at = match link.in_flight.front() {
+            Some(&(arrives_at, _)) => arrives_at,
+            None if self.is_dropped() => return Ready(Err(reset())),
+            None if link.closed => return Ready(Ok(0)),
+            None => {
+                link.reader = Some(cx.waker().clone());
+                return Pending;
+            }
+        };
+        if arrives_at > now {
+            self.clock.wake_at(arrives_at, cx.waker().clone());
+            return Pending;
+        }
+
+        let offset = link.offset;
+        let (len, exhausted) = {
+            let chunk = &link.in_flight[0].1;
+            let len = min(buf.len(), chunk.len() - offset);
+            buf[..len].copy_from_slice(&chunk[offset..offset + len]);
+            (len, offset + len == chunk.len())
+        };
+        if exhausted {
+            link.in_flight.pop_front();
+            link.offset = 0;
+        } else {
+            link.offset += len;
+        }
+        Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        if self.is_dropped() {
+            return Ready(Err(reset()));
+        }
+        let now = self.clock.now();
+        let mut link = lock(&self.outgoing);
+        if link.free_at > now {
+            self.clock.wake_at(link.free_at, cx.waker().clone());
+            return Pending;
+        }
+
+        let mut len = buf.len() as u64;
+        if let Some(drop_at) = link.drop_at {
+            if link.written >= drop_at {
+                drop(link);
+                self.drop_connection();
+                return Ready(Err(reset()));
+            }
+            len = min(len, drop_at - link.written);
+        }
+        if len == 0 {
+            return Ready(Ok(0));
+        }
+
+        let transmission = match link.config.bandwidth {
+            Some(bandwidth) => nanos_duration(len * 1_000_000_000 / bandwidth),
+            None => Duration::from_secs(0),
+        };
+        let latency = {
+            let Link { ref config, ref mut rng, .. } = *link;
+            config.latency.sample(rng)
+        };
+        // In order: a chunk never overtakes the previous one.
+        let previous = link.in_flight.back().map(|&(arrives_at, _)| arrives_at);
+        let arrives_at = match previous {
+            Some(previous) => max(previous, now + transmission + latency),
+            None => now + transmission + latency,
+        };
+        link.free_at = now + transmission;
+        link.written += len;
+        link.in_flight.push_back((arrives_at, buf[..len as usize].to_vec()));
+        link.wake_reader();
+        if link.drop_at == Some(link.written) {
+            // The bytes up to the offset are still delivered, but nothing after them.
+            drop(link);
+            self.drop_connection();
+        }
+        Ready(Ok(len as usize))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        if self.is_dropped() {
+            Ready(Err(reset()))
+        } else {
+            Ready(Ok(()))
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        let mut link = lock(&self.outgoing);
+        link.closed = true;
+        link.wake_reader();
+        Ready(Ok(()))
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut link = lock(&self.outgoing);
+        link.closed = true;
+        link.wake_reader();
+    }
+}
+
+impl fmt::Debug for SimStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimStream")
+            .field("dropped", &self.is_dropped())
+            .finish()
+    }
+}
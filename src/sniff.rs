@@ -0,0 +1,144 @@
+//! Guess the protocol of a connection from its first bytes, to serve secret-handshake
+//! and other protocols on the same port.
+//!
+//! Read (at least) `MSG1_BYTES` bytes from a new connection and pass them to
+//! `classify_first_bytes`. If the guess is `ProtocolGuess::Shs`, wrap the bytes and the
+//! connection in a `Prefixed` stream and hand that to a server handshaker (e.g. via
+//! `Acceptor::accept`), so that the handshaker reads the already consumed msg1 first.
+//! Alternatively, pass the bytes to `ServerHandshaker::with_buffered_prefix`, which keeps
+//! the type of the stream unchanged.
+//!
+//! A valid msg1 can only be produced by someone who knows the network identifier, so
+//! other protocols are never mistaken for secret-handshake. The checks for TLS and HTTP
+//! are heuristics only.
+
+use std::cmp::min;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::Ready;
+
+use sodiumoxide::crypto::auth;
+use futures_io::{AsyncRead, AsyncWrite, Error};
+
+use crypto::{MSG1_BYTES, NETWORK_IDENTIFIER_BYTES};
+
+/// The protocol a connection most likely uses, as guessed by `classify_first_bytes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolGuess {
+    /// The bytes start with a valid msg1 for the network identifier at the given index.
+    Shs(usize),
+    /// The bytes look like a TLS ClientHello.
+    Tls,
+    /// The bytes look like an HTTP request.
+    Http,
+    /// None of the above.
+    Unknown,
+}
+
+const HTTP_METHODS: [&[u8]; 9] = [b"GET ",
+                                  b"HEAD ",
+                                  b"POST ",
+                                  b"PUT ",
+                                  b"DELETE ",
+                                  b"CONNECT ",
+                                  b"OPTIONS ",
+                                  b"TRACE ",
+                                  b"PATCH "];
+
+/// Guesses the protocol of a connection from the first `bytes` received on it.
+///
+/// The bytes are only classified as `ProtocolGuess::Shs` if they contain at least
+/// `MSG1_BYTES` bytes, the first `MSG1_BYTES` of which verify as a msg1 for one of the
+/// given `network_identifiers`. This check does not depend on any keys of the server.
+pub fn classify_first_bytes(bytes: &[u8],
+                            network_identifiers: &[[u8; NETWORK_IDENTIFIER_BYTES]])
+                            -> ProtocolGuess {
+    if bytes.len() >= MSG1_BYTES {
+        for (i, network_identifier) in network_identifiers.iter().enumerate() {
+            if verify_msg1(&bytes[..MSG1_BYTES], network_identifier) {
+                return ProtocolGuess::Shs(i);
+            }
+        }
+    }
+
+    // TLS record of type handshake (0x16), protocol version 3.x, containing a ClientHello (0x01)
+    if bytes.len() >= 6 && bytes[0] == 0x16 && bytes[1] == 0x03 && bytes[2] <= 0x04 &&
+       bytes[5] == 0x01 {
+        return ProtocolGuess::Tls;
+    }
+
+    if HTTP_METHODS.iter().any(|method| bytes.starts_with(method)) {
+        return ProtocolGuess::Http;
+    }
+
+    ProtocolGuess::Unknown
+}
+
+// msg1 is the hmac of the client's ephemeral public key (keyed with the network identifier),
+// followed by that public key.
+fn verify_msg1(msg1: &[u8], network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES]) -> bool {
+    let mut tag = [0; auth::TAGBYTES];
+    tag.copy_from_slice(&msg1[..auth::TAGBYTES]);
+
+    auth::verify(&auth::Tag(tag),
+                 &msg1[auth::TAGBYTES..],
+                 &auth::Key(*network_identifier))
+}
+
+/// A stream that yields some already read bytes before reading from the wrapped stream.
+/// Writes go directly to the wrapped stream.
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    offset: usize,
+    stream: S,
+}
+
+impl<S> Prefixed<S> {
+    /// Creates a new Prefixed stream, which yields `prefix` before the data of `stream`.
+    pub fn new(prefix: Vec<u8>, stream: S) -> Prefixed<S> {
+        Prefixed {
+            prefix,
+            offset: 0,
+            stream,
+        }
+    }
+
+    /// Returns the wrapped stream. Any prefix data that has not been read yet is lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let this = &mut *self;
+        if this.offset < this.prefix.len() {
+            let len = min(buf.len(), this.prefix.len() - this.offset);
+            buf[..len].copy_from_slice(&this.prefix[this.offset..this.offset + len]);
+            this.offset += len;
+            return Ready(Ok(len));
+        }
+
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
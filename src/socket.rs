@@ -0,0 +1,267 @@
+//! Configure tcp sockets before handshaking over them.
+//!
+//! The handshake consists of four small messages, each of which waits for the previous one,
+//! so Nagle's algorithm can delay it noticeably, and long-lived connections benefit from tcp
+//! keepalive. `SocketOptions` collects these settings, `connect_tcp` applies them to dialed
+//! sockets and `NonblockingListener::socket_options` to accepted ones:
+//!
+//! ```rust,ignore
+//! let options = SocketOptions::new().nodelay(true).keepalive(Duration::from_secs(60));
+//! let stream = connect_tcp(addr, &options)?;
+//! let listener = NonblockingListener::new(TcpListener::bind(addr)?)?.socket_options(options);
+//! ```
+//!
+//! Setting the keepalive interval and binding before connecting are only supported on unix.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use listener::NonblockingStream;
+
+/// Options applied to tcp sockets before the handshake starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SocketOptions {
+    /// Whether to disable Nagle's algorithm (`TCP_NODELAY`). Defaults to `false`.
+    pub nodelay: bool,
+    /// If set, enables tcp keepalive, with probes starting after the connection has been
+    /// idle for the given duration. Defaults to `None`, which leaves keepalive untouched.
+    pub keepalive: Option<Duration>,
+    /// The local address to bind dialed sockets to. Ignored for accepted sockets. Defaults to
+    /// `None`, which lets the operating system choose.
+    pub bind_addr: Option<SocketAddr>,
+    /// Whether failing to apply an option is fatal. If not, the failure is reported by
+    /// `apply` but the socket is still used. Defaults to `false`.
+    pub strict: bool,
+}
+
+impl SocketOptions {
+    /// Creates options that leave all settings of the sockets untouched.
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+
+    /// Sets whether to disable Nagle's algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enables tcp keepalive after the given idle time.
+    pub fn keepalive(mut self, idle: Duration) -> SocketOptions {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Binds dialed sockets to the given local address.
+    pub fn bind_addr(mut self, addr: SocketAddr) -> SocketOptions {
+        self.bind_addr = Some(addr);
+        self
+    }
+
+    /// Sets whether failing to apply an option is fatal.
+    pub fn strict(mut self, strict: bool) -> SocketOptions {
+        self.strict = strict;
+        self
+    }
+
+    /// Applies `nodelay` and `keepalive` to the given socket. Attempts all options, and
+    /// returns the failures.
+    pub fn apply(&self, stream: &TcpStream) -> Vec<SocketOptionError> {
+        let mut failures = Vec::new();
+        if self.nodelay {
+            if let Err(err) = stream.set_nodelay(true) {
+                failures.push(SocketOptionError {
+                                  option: "nodelay",
+                                  err,
+                              });
+            }
+        }
+        if let Some(idle) = self.keepalive {
+            if let Err(err) = sys::set_keepalive(stream, idle) {
+                failures.push(SocketOptionError {
+                                  option: "keepalive",
+                                  err,
+                              });
+            }
+        }
+        failures
+    }
+
+    // Applies the options, failing with the first failure if they are strict.
+    pub(crate) fn apply_checked(&self, stream: &TcpStream) -> Result<(), SocketOptionError> {
+        match self.apply(stream).into_iter().next() {
+            Some(failure) if self.strict => Err(failure),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Connects to `addr`, binding to `options.bind_addr` first if set, applies the `options`,
+/// and puts the socket into nonblocking mode for the handshake.
+///
+/// Connecting blocks the calling thread. Fails if connecting or binding fails, or if an
+/// option can not be applied and the options are strict. Can be used as the `connect`
+/// function of `known_peers::connect_any` by wrapping the result into a future.
+pub fn connect_tcp(addr: SocketAddr,
+                   options: &SocketOptions)
+                   -> Result<NonblockingStream<TcpStream>, SocketOptionError> {
+    let stream = match options.bind_addr {
+        Some(local) => sys::connect_from(local, addr),
+        None => TcpStream::connect(addr),
+    };
+    let stream = stream.map_err(|err| SocketOptionError {
+                                    option: "connect",
+                                    err,
+                                })?;
+    options.apply_checked(&stream)?;
+    NonblockingStream::new(stream).map_err(|err| SocketOptionError {
+                                               option: "nonblocking",
+                                               err,
+                                           })
+}
+
+/// A socket option that could not be applied, or a failure to connect.
+#[derive(Debug)]
+pub struct SocketOptionError {
+    /// The option, or `"connect"` if connecting failed.
+    pub option: &'static str,
+    /// The error.
+    pub err: io::Error,
+}
+
+impl Display for SocketOptionError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Socket option error: {}: {}", self.option, self.err)
+    }
+}
+
+impl Error for SocketOptionError {
+    fn description(&self) -> &str {
+        "could not set up the socket"
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        Some(&self.err)
+    }
+}
+
+impl From<SocketOptionError> for io::Error {
+    fn from(err: SocketOptionError) -> io::Error {
+        err.err
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::time::Duration;
+
+    use libc;
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPALIVE;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    const KEEPALIVE_IDLE: libc::c_int = libc::TCP_KEEPIDLE;
+
+    pub fn set_keepalive(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+        let fd = stream.as_raw_fd();
+        set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        let secs = idle.as_secs().max(1).min(libc::c_int::max_value() as u64);
+        set_int_option(fd, libc::IPPROTO_TCP, KEEPALIVE_IDLE, secs as libc::c_int)
+    }
+
+    fn set_int_option(fd: RawFd,
+                      level: libc::c_int,
+                      option: libc::c_int,
+                      value: libc::c_int)
+                      -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(fd,
+                             level,
+                             option,
+                             &value as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    // Creates a socket bound to `local` and connects it to `remote`.
+    pub fn connect_from(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStream> {
+        let family = match remote {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = unsafe { libc::socket(family, libc::SOCK_STREAM, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owns the descriptor from here on, so that it is closed on errors.
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        with_sockaddr(local, |addr, len| unsafe { libc::bind(fd, addr, len) })?;
+        with_sockaddr(remote, |addr, len| unsafe { libc::connect(fd, addr, len) })?;
+        Ok(stream)
+    }
+
+    // Calls `f` with the C representation of `addr`, turning its result into an io result.
+    fn with_sockaddr<F>(addr: SocketAddr, f: F) -> io::Result<()>
+        where F: FnOnce(*const libc::sockaddr, libc::socklen_t) -> libc::c_int
+    {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        if f(&storage as *const _ as *const libc::sockaddr, len as libc::socklen_t) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    pub fn set_keepalive(_: &TcpStream, _: Duration) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "keepalive is only supported on unix"))
+    }
+
+    pub fn connect_from(_: SocketAddr, _: SocketAddr) -> io::Result<TcpStream> {
+        Err(io::Error::new(io::ErrorKind::Other, "binding is only supported on unix"))
+    }
+}
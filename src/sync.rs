@@ -0,0 +1,8 @@
+//! The synchronization primitives guarding state that is shared between handshakes. When
+//! testing with `--cfg loom`, these are the ones of loom, so that the loom tests can explore
+//! all interleavings of the tasks that access the state.
+
+#[cfg(not(all(test, loom)))]
+pub(crate) use std::sync::Mutex;
+#[cfg(all(test, loom))]
+pub(crate) use loom::sync::Mutex;
@@ -0,0 +1,198 @@
+//! Perform handshakes over blocking `std::io` streams, without a futures executor.
+//!
+//! `client_side` and `server_side` drive a `ClientHandshakeMachine` or
+//! `ServerHandshakeMachine` with blocking reads and writes on the calling thread:
+//!
+//! ```rust,ignore
+//! let mut stream = TcpStream::connect("127.0.0.1:8008")?;
+//! let outcome = sync_io::client_side(&mut stream, &network_identifier, &pk, &sk, &eph_pk,
+//!                                    &eph_sk, &server_pk)?;
+//! ```
+//!
+//! Short reads and writes are continued, and `Interrupted` errors are retried. A read of
+//! zero bytes fails with `UnexpectedEof` and a write of zero bytes with `WriteZero`, like
+//! with the asynchronous handshakers. Set timeouts on the stream (e.g.
+//! `TcpStream::set_read_timeout`) to bound how long a handshake may block. Nothing is read
+//! past the last handshake message, so the stream can be used for the encrypted channel
+//! afterwards.
+
+use std::io::{self, Read, Write};
+use std::io::ErrorKind::{WriteZero, UnexpectedEof, Interrupted};
+
+use sodiumoxide::crypto::{box_, sign};
+use sodiumoxide::utils::memzero;
+
+use crypto::*;
+use errors::{HandshakeError, overlong_read, overlong_write};
+use {ClientHandshakeMachine, ServerHandshakeMachine, HandshakePhase};
+
+/// Performs the client side of a handshake over `stream`, and returns its outcome. See
+/// `ClientHandshaker::new` for the arguments.
+pub fn client_side<S: Read + Write>(stream: &mut S,
+                                    network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                                    client_longterm_pk: &sign::PublicKey,
+                                    client_longterm_sk: &sign::SecretKey,
+                                    client_ephemeral_pk: &box_::PublicKey,
+                                    client_ephemeral_sk: &box_::SecretKey,
+                                    server_longterm_pk: &sign::PublicKey)
+                                    -> Result<Outcome, HandshakeError> {
+    let mut machine = ClientHandshakeMachine::new(network_identifier,
+                                                  client_longterm_pk,
+                                                  client_longterm_sk,
+                                                  client_ephemeral_pk,
+                                                  client_ephemeral_sk,
+                                                  server_longterm_pk);
+    drive(stream, &mut machine)
+}
+
+/// Performs the server side of a handshake over `stream`, accepting any client, and
+/// returns its outcome. See `ServerHandshaker::new` for the arguments.
+pub fn server_side<S: Read + Write>(stream: &mut S,
+                                    network_identifier: &[u8; NETWORK_IDENTIFIER_BYTES],
+                                    server_longterm_pk: &sign::PublicKey,
+                                    server_longterm_sk: &sign::SecretKey,
+                                    server_ephemeral_pk: &box_::PublicKey,
+                                    server_ephemeral_sk: &box_::SecretKey)
+                                    -> Result<Outcome, HandshakeError> {
+    let mut machine = ServerHandshakeMachine::new(network_identifier,
+                                                  server_longterm_pk,
+                                                  server_longterm_sk,
+                                                  server_ephemeral_pk,
+                                                  server_ephemeral_sk);
+    drive(stream, &mut machine)
+}
+
+// The methods of `ClientHandshakeMachine` and `ServerHandshakeMachine` used by `drive`.
+pub(crate) trait Machine {
+    fn wants_write(&self) -> Option<&[u8]>;
+    fn advance_write(&mut self, n: usize);
+    fn wants_read(&mut self, buf: &mut [u8]) -> Result<usize, HandshakeError>;
+    fn wants_read_len(&self) -> usize;
+    fn is_finished(&mut self) -> Option<Outcome>;
+    fn phase(&self) -> HandshakePhase;
+}
+
+macro_rules! impl_machine {
+    ($machine:ident) => {
+        impl<'a> Machine for $machine<'a> {
+            fn wants_write(&self) -> Option<&[u8]> {
+                $machine::wants_write(self)
+            }
+
+            fn advance_write(&mut self, n: usize) {
+                $machine::advance_write(self, n)
+            }
+
+            fn wants_read(&mut self, buf: &mut [u8]) -> Result<usize, HandshakeError> {
+                $machine::wants_read(self, buf)
+            }
+
+            fn wants_read_len(&self) -> usize {
+                $machine::wants_read_len(self)
+            }
+
+            fn is_finished(&mut self) -> Option<Outcome> {
+                $machine::is_finished(self)
+            }
+
+            fn phase(&self) -> HandshakePhase {
+                $machine::phase(self)
+            }
+        }
+    }
+}
+
+impl_machine!(ClientHandshakeMachine);
+impl_machine!(ServerHandshakeMachine);
+
+// Drives the machine with blocking reads and writes until it finishes. Reads never go past
+// the last handshake message, so the stream is left at the start of the encrypted channel.
+// The scratch buffer for the received messages is zeroed afterwards, whether the handshake
+// succeeded or not.
+pub(crate) fn drive<S: Read + Write, M: Machine>(stream: &mut S,
+                                                 machine: &mut M)
+                                                 -> Result<Outcome, HandshakeError> {
+    let mut buf = [0; MSG3_BYTES];
+    let result = drive_with(stream, machine, &mut buf);
+    memzero(&mut buf);
+    result
+}
+
+// Drives the machine, reading into `buf`.
+fn drive_with<S: Read + Write, M: Machine>(stream: &mut S,
+                                           machine: &mut M,
+                                           buf: &mut [u8; MSG3_BYTES])
+                                           -> Result<Outcome, HandshakeError> {
+    loop {
+        if let Some(outcome) = machine.is_finished() {
+            return Ok(outcome);
+        }
+
+        if machine.wants_write().is_some() {
+            while let Some(written) = write_some(stream, machine)? {
+                machine.advance_write(written);
+            }
+            flush(stream)?;
+            continue;
+        }
+
+        let len = machine.wants_read_len();
+        let read = read_some(stream, &mut buf[..len], machine.phase())?;
+        machine.wants_read(&mut buf[..read])?;
+    }
+}
+
+// Writes some of the bytes the machine wants to write, and returns how many. `None` if it
+// does not want to write anything.
+fn write_some<S: Write, M: Machine>(stream: &mut S, machine: &M) -> io::Result<Option<usize>> {
+    let bytes = match machine.wants_write() {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    loop {
+        match stream.write(bytes) {
+            Ok(0) => return Err(io::Error::new(WriteZero, failure("write", machine.phase()))),
+            Ok(written) if written > bytes.len() => return Err(overlong_write()),
+            Ok(written) => return Ok(Some(written)),
+            Err(ref err) if err.kind() == Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn flush<S: Write>(stream: &mut S) -> io::Result<()> {
+    loop {
+        match stream.flush() {
+            Err(ref err) if err.kind() == Interrupted => {}
+            result => return result,
+        }
+    }
+}
+
+// Reads some bytes of the message of the given phase into `buf`, and returns how many.
+// Fails with `UnexpectedEof` if the stream has ended.
+fn read_some<S: Read>(stream: &mut S,
+                      buf: &mut [u8],
+                      phase: HandshakePhase)
+                      -> io::Result<usize> {
+    loop {
+        match stream.read(buf) {
+            Ok(0) => return Err(io::Error::new(UnexpectedEof, failure("read", phase))),
+            Ok(read) if read > buf.len() => return Err(overlong_read()),
+            Ok(read) => return Ok(read),
+            Err(ref err) if err.kind() == Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// The message of a failed read or write, e.g. "failed to read msg2".
+fn failure(operation: &str, phase: HandshakePhase) -> String {
+    let msg = match phase {
+        HandshakePhase::Msg1 => "msg1",
+        HandshakePhase::Msg2 => "msg2",
+        HandshakePhase::Msg3 => "msg3",
+        _ => "msg4",
+    };
+    format!("failed to {} {}", operation, msg)
+}
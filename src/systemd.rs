@@ -0,0 +1,305 @@
+//! Accept connections on listeners inherited via systemd socket activation, see
+//! `Acceptor::incoming_from_listen_fds`.
+//!
+//! This module is only available on unix, with the `systemd` feature.
+//!
+//! With socket activation, systemd binds the listening sockets and passes them to the
+//! process as file descriptors starting at 3, announced by the `LISTEN_PID` and
+//! `LISTEN_FDS` environment variables:
+//!
+//! ```rust,ignore
+//! for handshakes in acceptor.incoming_from_listen_fds()? {
+//!     // drive each stream of handshakes like the one returned by `Acceptor::incoming`
+//! }
+//! ```
+//!
+//! Each inherited descriptor is checked to be a listening stream socket of the tcp or unix
+//! family, and marked close-on-exec. The environment variables are removed once the
+//! descriptors have been taken, so that child processes do not consider themselves
+//! activated.
+
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::mem;
+use std::net;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use libc;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use listener::{Listener, NonblockingListener, NonblockingStream, PeerInfo, Reset};
+
+/// The first inherited file descriptor, `SD_LISTEN_FDS_START`.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// A listener inherited from systemd.
+pub enum ActivatedListener {
+    /// A tcp listener.
+    Tcp(NonblockingListener<net::TcpListener>),
+    /// A unix socket listener.
+    Unix(NonblockingListener<UnixListener>),
+}
+
+impl Listener for ActivatedListener {
+    type Stream = ActivatedStream;
+
+    fn poll_accept(&mut self,
+                   cx: &mut Context)
+                   -> Poll<Result<(ActivatedStream, PeerInfo), io::Error>> {
+        match *self {
+            ActivatedListener::Tcp(ref mut listener) => {
+                listener.poll_accept(cx)
+                    .map_ok(|(stream, peer)| (ActivatedStream::Tcp(stream), peer))
+            }
+            ActivatedListener::Unix(ref mut listener) => {
+                listener.poll_accept(cx)
+                    .map_ok(|(stream, peer)| (ActivatedStream::Unix(stream), peer))
+            }
+        }
+    }
+}
+
+/// A connection accepted by an `ActivatedListener`.
+pub enum ActivatedStream {
+    /// A tcp connection.
+    Tcp(NonblockingStream<net::TcpStream>),
+    /// A unix socket connection.
+    Unix(NonblockingStream<UnixStream>),
+}
+
+impl AsyncRead for ActivatedStream {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<io::Result<usize>> {
+        match *self {
+            ActivatedStream::Tcp(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            ActivatedStream::Unix(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ActivatedStream {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        match *self {
+            ActivatedStream::Tcp(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+            ActivatedStream::Unix(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match *self {
+            ActivatedStream::Tcp(ref mut stream) => Pin::new(stream).poll_flush(cx),
+            ActivatedStream::Unix(ref mut stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match *self {
+            ActivatedStream::Tcp(ref mut stream) => Pin::new(stream).poll_close(cx),
+            ActivatedStream::Unix(ref mut stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+impl Reset for ActivatedStream {
+    fn reset_on_drop(&self) -> io::Result<()> {
+        match *self {
+            ActivatedStream::Tcp(ref stream) => stream.reset_on_drop(),
+            ActivatedStream::Unix(ref stream) => stream.reset_on_drop(),
+        }
+    }
+}
+
+/// Takes the listeners passed by systemd, see the module documentation.
+///
+/// Fails with `SystemdError::NotActivated` if the process was not socket activated. If any
+/// inherited descriptor is unsuitable, none of them are taken.
+pub fn listen_fds() -> Result<Vec<ActivatedListener>, SystemdError> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let listeners = listen_fds_from(pid.as_ref().map(String::as_str),
+                                    fds.as_ref().map(String::as_str),
+                                    LISTEN_FDS_START)?;
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    Ok(listeners)
+}
+
+// Takes the listeners announced by the given values of `LISTEN_PID` and `LISTEN_FDS`,
+// starting at the descriptor `start`.
+pub(crate) fn listen_fds_from(pid: Option<&str>,
+                              fds: Option<&str>,
+                              start: RawFd)
+                              -> Result<Vec<ActivatedListener>, SystemdError> {
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Err(SystemdError::NotActivated),
+    };
+    let pid: u32 = pid.parse()
+        .map_err(|_| SystemdError::InvalidEnvironment {
+                     name: "LISTEN_PID",
+                     value: pid.to_string(),
+                 })?;
+    let count: RawFd = fds.parse()
+        .map_err(|_| SystemdError::InvalidEnvironment {
+                     name: "LISTEN_FDS",
+                     value: fds.to_string(),
+                 })?;
+    if pid != unsafe { libc::getpid() } as u32 || count <= 0 {
+        return Err(SystemdError::NotActivated);
+    }
+
+    // Check all descriptors before taking ownership of any.
+    let mut kinds = Vec::with_capacity(count as usize);
+    for fd in start..start + count {
+        kinds.push(socket_kind(fd)?);
+    }
+
+    let mut listeners = Vec::with_capacity(kinds.len());
+    for (fd, unix) in (start..).zip(kinds) {
+        let listener = if unix {
+            let listener = unsafe { UnixListener::from_raw_fd(fd) };
+            NonblockingListener::new(listener).map(ActivatedListener::Unix)
+        } else {
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            NonblockingListener::new(listener).map(ActivatedListener::Tcp)
+        };
+        listeners.push(listener.map_err(|err| SystemdError::Io { fd, err })?);
+    }
+    Ok(listeners)
+}
+
+// Checks that `fd` is a listening stream socket and marks it close-on-exec. Returns whether
+// it is a unix socket rather than a tcp socket.
+fn socket_kind(fd: RawFd) -> Result<bool, SystemdError> {
+    let wrong = |reason| Err(SystemdError::WrongSocketType { fd, reason });
+    let io_error = || SystemdError::Io {
+        fd,
+        err: io::Error::last_os_error(),
+    };
+
+    unsafe {
+        let mut stat: libc::stat = mem::zeroed();
+        if libc::fstat(fd, &mut stat) != 0 {
+            return Err(io_error());
+        }
+        if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+            return wrong("not a socket");
+        }
+
+        if int_option(fd, libc::SO_TYPE).ok_or_else(io_error)? != libc::SOCK_STREAM {
+            return wrong("not a stream socket");
+        }
+        if int_option(fd, libc::SO_ACCEPTCONN).ok_or_else(io_error)? == 0 {
+            return wrong("not listening");
+        }
+
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd,
+                             &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                             &mut len) != 0 {
+            return Err(io_error());
+        }
+        let unix = match addr.ss_family as libc::c_int {
+            libc::AF_INET | libc::AF_INET6 => false,
+            libc::AF_UNIX => true,
+            _ => return wrong("neither a tcp nor a unix socket"),
+        };
+
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) != 0 {
+            return Err(io_error());
+        }
+
+        Ok(unix)
+    }
+}
+
+// Reads an integer socket option of the `SOL_SOCKET` level.
+unsafe fn int_option(fd: RawFd, option: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if libc::getsockopt(fd,
+                        libc::SOL_SOCKET,
+                        option,
+                        &mut value as *mut libc::c_int as *mut libc::c_void,
+                        &mut len) != 0 {
+        return None;
+    }
+    Some(value)
+}
+
+/// Errors that can occur when taking the listeners passed by systemd.
+#[derive(Debug)]
+pub enum SystemdError {
+    /// The process was not socket activated: `LISTEN_PID` or `LISTEN_FDS` is not set,
+    /// `LISTEN_PID` is the id of another process, or `LISTEN_FDS` is zero.
+    NotActivated,
+    /// `LISTEN_PID` or `LISTEN_FDS` is not a number.
+    InvalidEnvironment {
+        /// The name of the variable.
+        name: &'static str,
+        /// Its value.
+        value: String,
+    },
+    /// The process was socket activated, but an inherited descriptor is not a listening
+    /// tcp or unix stream socket.
+    WrongSocketType {
+        /// The descriptor.
+        fd: RawFd,
+        /// What is wrong with it.
+        reason: &'static str,
+    },
+    /// Inspecting or setting up an inherited descriptor failed.
+    Io {
+        /// The descriptor.
+        fd: RawFd,
+        /// The error.
+        err: io::Error,
+    },
+}
+
+impl Display for SystemdError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SystemdError::NotActivated => write!(f, "Systemd error: not socket activated"),
+            SystemdError::InvalidEnvironment { name, ref value } => {
+                write!(f, "Systemd error: {} {:?} is not a number", name, value)
+            }
+            SystemdError::WrongSocketType { fd, reason } => {
+                write!(f, "Systemd error: fd {}: {}", fd, reason)
+            }
+            SystemdError::Io { fd, ref err } => write!(f, "Systemd error: fd {}: {}", fd, err),
+        }
+    }
+}
+
+impl Error for SystemdError {
+    fn description(&self) -> &str {
+        match *self {
+            SystemdError::NotActivated => "not socket activated",
+            SystemdError::InvalidEnvironment { .. } => "invalid socket activation variable",
+            SystemdError::WrongSocketType { reason, .. } => reason,
+            SystemdError::Io { .. } => "could not set up an inherited descriptor",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SystemdError::Io { ref err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! In-process transports for performing handshakes without sockets.
+//!
+//! With the `test-util` feature, this also provides `FailingStream`, which injects an io
+//! error at an exact position of a stream, and `deterministic_rng`, which makes the
+//! ephemeral keys of handshakes reproducible.
+
+use std::cmp::min;
+#[cfg(feature = "test-util")]
+use std::io::ErrorKind;
+use std::io::ErrorKind::BrokenPipe;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::task::Poll::{Ready, Pending};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite, Error};
+use futures_channel::mpsc::{channel, Sender, Receiver};
+#[cfg(feature = "test-util")]
+use rand_core::{self, RngCore, CryptoRng};
+#[cfg(feature = "test-util")]
+use sodiumoxide::crypto::hash::sha256;
+
+#[cfg(feature = "test-util")]
+use crypto::EphemeralRng;
+
+/// One endpoint of an in-process byte stream, created by `channel_pair`.
+///
+/// Each write is sent to the other endpoint as a single chunk over a
+/// `futures_channel::mpsc` channel. Reading returns `Pending` while the channel is
+/// empty, and signals the end of the stream once the other endpoint has been closed
+/// or dropped.
+pub struct ChannelStream {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>, // the last received chunk
+    offset: usize, // offset into the chunk at which to continue reading
+}
+
+/// Creates two connected `ChannelStream`s. Bytes written to one of them can be read
+/// from the other.
+pub fn channel_pair() -> (ChannelStream, ChannelStream) {
+    let (sender_a, receiver_a) = channel(0);
+    let (sender_b, receiver_b) = channel(0);
+
+    (ChannelStream::new(sender_a, receiver_b), ChannelStream::new(sender_b, receiver_a))
+}
+
+impl ChannelStream {
+    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>) -> ChannelStream {
+        ChannelStream {
+            sender,
+            receiver,
+            chunk: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let this = &mut *self;
+        while this.offset >= this.chunk.len() {
+            match Pin::new(&mut this.receiver).poll_next(cx) {
+                Ready(Some(chunk)) => {
+                    this.chunk = chunk;
+                    this.offset = 0;
+                }
+                Ready(None) => return Ready(Ok(0)),
+                Pending => return Pending,
+            }
+        }
+
+        let len = min(buf.len(), this.chunk.len() - this.offset);
+        buf[..len].copy_from_slice(&this.chunk[this.offset..this.offset + len]);
+        this.offset += len;
+        Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        if buf.is_empty() {
+            return Ready(Ok(0));
+        }
+
+        match self.sender.poll_ready(cx) {
+            Ready(Ok(())) => {}
+            Ready(Err(_)) => {
+                return Ready(Err(Error::new(BrokenPipe, "the other endpoint was dropped")))
+            }
+            Pending => return Pending,
+        }
+
+        match self.sender.start_send(buf.to_vec()) {
+            Ok(()) => Ready(Ok(buf.len())),
+            Err(_) => Ready(Err(Error::new(BrokenPipe, "the other endpoint was dropped"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+        self.sender.close_channel();
+        Ready(Ok(()))
+    }
+}
+
+/// The direction in which a `FailingStream` fails.
+#[cfg(feature = "test-util")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Fail a read.
+    Read,
+    /// Fail a write.
+    Write,
+    /// Fail a flush. The offset counts the flushes rather than bytes.
+    Flush,
+}
+
+/// Wraps a stream and fails a single read or write with an error of a chosen kind, at an
+/// exact byte offset. Useful for exercising the error handling of handshakes, e.g. to fail
+/// the 10th byte of msg3 written by a client:
+///
+/// ```rust,ignore
+/// FailingStream::new(stream, Direction::Write, MSG1_BYTES + 9, ErrorKind::BrokenPipe)
+/// ```
+///
+/// The offset counts the bytes transferred in the given direction since the start of the
+/// stream, or the completed flushes for `Direction::Flush`. Reads or writes are shortened so that they stop right before the offset, the
+/// next one then fails. All other calls are passed to the wrapped stream, including those
+/// after the failure, so retryable kinds like `Interrupted` can be injected as well.
+///
+/// This is only available with the `test-util` feature.
+#[cfg(feature = "test-util")]
+pub struct FailingStream<S> {
+    inner: S,
+    direction: Direction,
+    offset: usize,
+    kind: ErrorKind,
+    transferred: usize, // bytes transferred in `direction` so far
+    failed: bool,
+}
+
+#[cfg(feature = "test-util")]
+impl<S> FailingStream<S> {
+    /// Wraps `inner`, failing with `kind` when reaching `offset` in `direction`.
+    pub fn new(inner: S, direction: Direction, offset: usize, kind: ErrorKind) -> FailingStream<S> {
+        FailingStream {
+            inner,
+            direction,
+            offset,
+            kind,
+            transferred: 0,
+            failed: false,
+        }
+    }
+
+    /// Whether the error has been injected yet.
+    pub fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Returns the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // How many bytes a call in `direction` with a buffer of `len` bytes may transfer, or an
+    // error if it has to fail.
+    fn limit(&mut self, direction: Direction, len: usize) -> Result<usize, Error> {
+        if direction != self.direction || self.failed {
+            return Ok(len);
+        }
+        if self.transferred == self.offset && len > 0 {
+            self.failed = true;
+            return Err(Error::new(self.kind, "injected by FailingStream"));
+        }
+        Ok(min(len, self.offset - self.transferred))
+    }
+
+    fn record(&mut self, direction: Direction, len: usize) {
+        if direction == self.direction {
+            self.transferred += len;
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl<S: AsyncRead + Unpin> AsyncRead for FailingStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, Error>> {
+        let len = self.limit(Direction::Read, buf.len())?;
+        match Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len]) {
+            Ready(Ok(read)) => {
+                self.record(Direction::Read, read);
+                Ready(Ok(read))
+            }
+            other => other,
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for FailingStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, Error>> {
+        let len = self.limit(Direction::Write, buf.len())?;
+        match Pin::new(&mut self.inner).poll_write(cx, &buf[..len]) {
+            Ready(Ok(written)) => {
+                self.record(Direction::Write, written);
+                Ready(Ok(written))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        self.limit(Direction::Flush, 1)?;
+        match Pin::new(&mut self.inner).poll_flush(cx) {
+            Ready(Ok(())) => {
+                self.record(Direction::Flush, 1);
+                Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// A deterministic random number generator, the output of which only depends on its seed:
+/// the concatenation of `sha256(seed || counter)` for counter = 0, 1, 2, ... (as little
+/// endian u64).
+///
+/// **Insecure, for tests only.** Anyone who knows the seed can compute all of its output.
+#[cfg(feature = "test-util")]
+pub struct SeededRng {
+    seed: [u8; 32],
+    counter: u64,
+    block: [u8; sha256::DIGESTBYTES],
+    offset: usize, // offset into the block at which to continue
+}
+
+#[cfg(feature = "test-util")]
+impl SeededRng {
+    /// Creates a generator with the given `seed`.
+    pub fn new(seed: [u8; 32]) -> SeededRng {
+        SeededRng {
+            seed,
+            counter: 0,
+            block: [0; sha256::DIGESTBYTES],
+            offset: sha256::DIGESTBYTES,
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for byte in dest.iter_mut() {
+            if self.offset == self.block.len() {
+                let mut input = [0; 40];
+                input[..32].copy_from_slice(&self.seed);
+                input[32..].copy_from_slice(&self.counter.to_le_bytes());
+                self.block = sha256::hash(&input).0;
+                self.counter += 1;
+                self.offset = 0;
+            }
+            *byte = self.block[self.offset];
+            self.offset += 1;
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl CryptoRng for SeededRng {}
+
+/// Returns an `EphemeralRng` backed by a `SeededRng` with the given `seed`. Passing it to
+/// e.g. `Acceptor::with_rng` makes the ephemeral keys, and thus all messages of the
+/// handshakes, reproducible across runs.
+///
+/// **Insecure, for tests only.**
+#[cfg(feature = "test-util")]
+pub fn deterministic_rng(seed: [u8; 32]) -> EphemeralRng {
+    EphemeralRng::new(SeededRng::new(seed))
+}
@@ -0,0 +1,204 @@
+//! Throttle clients that reconnect too often, keyed by their longterm public key.
+//!
+//! A misconfigured peer behind a NAT, or a buggy reconnect loop, can complete a full
+//! handshake many times per second. Every one of these costs the server four scalar
+//! multiplications, and the filter function or authorizer runs again each time. A `Throttle`
+//! remembers when each client last authenticated, and blocks clients that come back too
+//! soon, for a window that doubles with every further attempt:
+//!
+//! ```rust,ignore
+//! // track up to 10000 clients, back off from one second up to ten minutes
+//! let throttle = Throttle::new(10000, Duration::from_secs(1), Duration::from_secs(600));
+//! let acceptor = Acceptor::new(...).throttle(throttle);
+//! ```
+//!
+//! A client that authenticates less than `base` after its previous attempt, or while it is
+//! blocked, is struck: the handshake fails with `HandshakeError::Throttled` before the filter
+//! function runs and before msg4 is sent, and the client is blocked for `base`, `2 * base`,
+//! `4 * base`, ... up to `max`, depending on its number of strikes. Every full window the
+//! client stays quiet after its block ended forgives one strike. The application can strike
+//! clients that authenticated but were rejected later on with `Acceptor::reject_client`.
+//!
+//! Since the client is only known once msg3 has been verified, a throttled client still costs
+//! the crypto of a handshake, but nothing beyond it. The throttle holds at most `capacity`
+//! clients, forgetting the least recently seen ones first.
+
+use std::cmp::{max, min};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use sodiumoxide::crypto::sign;
+
+use sync::Mutex;
+
+// Strikes beyond this do not lengthen the window any further, and keep the decay bounded.
+const MAX_STRIKES: u32 = 32;
+
+/// Per-client exponential backoff, see the module documentation.
+#[derive(Debug)]
+pub struct Throttle {
+    capacity: usize,
+    base: Duration,
+    max: Duration,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    // The backoff of each remembered client, and its latest sighting.
+    clients: HashMap<[u8; sign::PUBLICKEYBYTES], (Backoff, u64)>,
+    // Sightings in the order in which they happened, as in `ReplayCache`.
+    order: VecDeque<([u8; sign::PUBLICKEYBYTES], u64)>,
+    // The number of the next sighting.
+    next: u64,
+}
+
+#[derive(Debug)]
+struct Backoff {
+    strikes: u32,
+    last_seen: Option<Instant>,
+    blocked_until: Instant,
+}
+
+impl Throttle {
+    /// Creates a throttle that tracks up to `capacity` clients, blocking them for `base` on
+    /// their first strike and for at most `max`.
+    pub fn new(capacity: usize, base: Duration, max: Duration) -> Throttle {
+        Throttle {
+            capacity,
+            base,
+            max,
+            inner: Mutex::new(Inner {
+                                  clients: HashMap::new(),
+                                  order: VecDeque::new(),
+                                  next: 0,
+                              }),
+        }
+    }
+
+    /// The number of clients currently tracked.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().clients.len()
+    }
+
+    /// Whether no clients are currently tracked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Records an authentication of `client`, and returns how long it stays blocked if it came
+    // back too soon.
+    pub(crate) fn check(&self, client: &sign::PublicKey) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    pub(crate) fn check_at(&self, client: &sign::PublicKey, now: Instant) -> Result<(), Duration> {
+        let (base, max_window) = (self.base, self.max);
+        self.update(client, now, |backoff, now| {
+            let too_soon = match backoff.last_seen {
+                Some(last_seen) => now.duration_since(last_seen) < base,
+                None => false,
+            };
+            backoff.last_seen = Some(now);
+
+            if now < backoff.blocked_until || too_soon {
+                Err(backoff.strike(now, base, max_window))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    // Strikes `client` without recording an authentication, and returns how long it stays
+    // blocked.
+    pub(crate) fn reject(&self, client: &sign::PublicKey) -> Duration {
+        self.reject_at(client, Instant::now())
+    }
+
+    pub(crate) fn reject_at(&self, client: &sign::PublicKey, now: Instant) -> Duration {
+        let (base, max_window) = (self.base, self.max);
+        self.update(client, now, |backoff, now| backoff.strike(now, base, max_window))
+    }
+
+    fn update<T, F>(&self, client: &sign::PublicKey, now: Instant, f: F) -> T
+        where F: FnOnce(&mut Backoff, Instant) -> T
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let sighting = inner.next;
+        inner.next += 1;
+
+        let ret = {
+            let entry = inner.clients
+                .entry(client.0)
+                .or_insert_with(|| {
+                                    (Backoff {
+                                         strikes: 0,
+                                         last_seen: None,
+                                         blocked_until: now,
+                                     },
+                                     sighting)
+                                });
+            entry.1 = sighting;
+            let backoff = &mut entry.0;
+            // Tasks take the time before waiting for the lock, so a task can arrive with a
+            // time earlier than that of the last sighting.
+            let now = max(now, backoff.last_seen.unwrap_or(now));
+            backoff.decay(now, self.base, self.max);
+            f(backoff, now)
+        };
+        inner.order.push_back((client.0, sighting));
+
+        while inner.clients.len() > self.capacity {
+            inner.pop_oldest();
+        }
+        // Bound the skipped sightings of clients that were seen again.
+        if inner.order.len() > 2 * self.capacity + 1 {
+            let mut order: Vec<_> = inner.clients
+                .iter()
+                .map(|(key, &(_, sighting))| (*key, sighting))
+                .collect();
+            order.sort_by_key(|&(_, sighting)| sighting);
+            inner.order = order.into_iter().collect();
+        }
+
+        ret
+    }
+}
+
+impl Inner {
+    fn pop_oldest(&mut self) {
+        if let Some((key, sighting)) = self.order.pop_front() {
+            if self.clients.get(&key).map(|&(_, latest)| latest) == Some(sighting) {
+                self.clients.remove(&key);
+            }
+        }
+    }
+}
+
+impl Backoff {
+    // Forgives one strike for every full window the client stayed quiet after its block.
+    fn decay(&mut self, now: Instant, base: Duration, max_window: Duration) {
+        while self.strikes > 0 {
+            let window = window(self.strikes, base, max_window);
+            if now < self.blocked_until + window {
+                break;
+            }
+            self.blocked_until += window;
+            self.strikes -= 1;
+        }
+    }
+
+    fn strike(&mut self, now: Instant, base: Duration, max_window: Duration) -> Duration {
+        self.strikes = min(self.strikes + 1, MAX_STRIKES);
+        let window = window(self.strikes, base, max_window);
+        self.blocked_until = now + window;
+        window
+    }
+}
+
+// `base * 2^(strikes - 1)`, but at most `max_window`.
+fn window(strikes: u32, base: Duration, max_window: Duration) -> Duration {
+    1u32.checked_shl(strikes - 1)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(max_window, |window| min(window, max_window))
+}
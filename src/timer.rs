@@ -0,0 +1,108 @@
+//! A single thread that wakes tasks at their deadlines, for everything in this crate that needs
+//! a timer without depending on the timer of some specific runtime.
+//!
+//! The thread is started when the first timer is set, and sleeps until the earliest pending
+//! deadline. Dropping a `Timer` removes its deadline, so the timers of handshakes that finished
+//! or were dropped before their deadline are gone right away.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::task::Waker;
+use std::thread;
+use std::time::Instant;
+
+// The pending timers, once the timer thread has been started.
+static TIMERS: Mutex<Option<Timers>> = Mutex::new(None);
+// Notified whenever a timer is set that is due before all others.
+static EARLIER: Condvar = Condvar::new();
+
+struct Timers {
+    wakers: BTreeMap<(Instant, u64), Waker>, // by deadline, then by the order they were set in
+    next_id: u64,
+}
+
+// A pending deadline, removed when dropped.
+pub(crate) struct Timer {
+    key: (Instant, u64),
+}
+
+impl Timer {
+    #[cfg(feature = "loadtest")]
+    pub(crate) fn deadline(&self) -> Instant {
+        self.key.0
+    }
+
+    // Keeps the deadline when the timer is dropped, for callers that can not hold on to it.
+    pub(crate) fn detach(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(ref mut timers) = *lock(&TIMERS) {
+            timers.wakers.remove(&self.key);
+        }
+    }
+}
+
+// Wakes `waker` once `at` has been reached, unless the returned timer is dropped before.
+pub(crate) fn wake_at(at: Instant, waker: Waker) -> Timer {
+    let mut timers = lock(&TIMERS);
+    let timers = timers.get_or_insert_with(|| {
+        thread::Builder::new()
+            .name("shs-timer".to_string())
+            .spawn(run)
+            .expect("failed to start the timer thread");
+        Timers {
+            wakers: BTreeMap::new(),
+            next_id: 0,
+        }
+    });
+
+    let key = (at, timers.next_id);
+    timers.next_id += 1;
+    let earliest = match timers.wakers.keys().next() {
+        Some(first) => key < *first,
+        None => true,
+    };
+    timers.wakers.insert(key, waker);
+    if earliest {
+        EARLIER.notify_one();
+    }
+    Timer { key }
+}
+
+fn run() {
+    let mut timers = lock(&TIMERS);
+    loop {
+        let next = match *timers {
+            Some(ref timers) => timers.wakers.keys().next().cloned(),
+            None => None,
+        };
+        let now = Instant::now();
+        timers = match next {
+            Some(key) if key.0 <= now => {
+                let waker = timers.as_mut().and_then(|timers| timers.wakers.remove(&key));
+                // Wakers may run arbitrary code, including setting timers.
+                drop(timers);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                lock(&TIMERS)
+            }
+            Some(key) => {
+                EARLIER.wait_timeout(timers, key.0 - now)
+                    .map(|(timers, _)| timers)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner().0)
+            }
+            None => EARLIER.wait(timers).unwrap_or_else(|poisoned| poisoned.into_inner()),
+        };
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // The timers are consistent whenever the mutex is released, even after a panic.
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,550 @@
+//! Golden transcripts, for keeping the bytes on the wire stable across refactors.
+//!
+//! A `Transcript` records a handshake between a client and a server with fixed keys: the
+//! keys themselves, the exact bytes each side sent, and the outcome each side computed.
+//! `replay_against_client` and `replay_against_server` run one side of the handshake
+//! against the recorded bytes of the other side, and report whether it still sends the
+//! same bytes and computes the same outcome.
+//!
+//! Transcripts are stored as fixtures via `to_fixture` and `from_fixture`. An intentional
+//! change to the protocol invalidates all fixtures, they then need to be recorded again
+//! with `Transcript::record`.
+//!
+//! This module is only available with the `test-util` feature.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::task::Poll::{Ready, Pending};
+
+use sodiumoxide::crypto::{box_, sign};
+use futures_io::{AsyncRead, AsyncWrite, Error as IoError};
+
+use crypto::{Outcome, NETWORK_IDENTIFIER_BYTES, MSG1_BYTES, MSG2_BYTES};
+use errors::HandshakeError;
+use client::OwningClientHandshaker;
+use server::OwningServerHandshaker;
+use testutil::channel_pair;
+use wire::{Msg1, Msg2, Msg3, Msg4, ParseError};
+use keyfile::decode_hex;
+
+// Handshakes over in-memory streams complete in a handful of polls, more than this many
+// means that they are stuck.
+const MAX_POLLS: usize = 1000;
+
+/// Wraps a stream and records all bytes read from and written to it.
+pub struct TranscriptRecorder<S> {
+    inner: S,
+    sent: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl<S> TranscriptRecorder<S> {
+    /// Starts recording the bytes transmitted over `inner`.
+    pub fn new(inner: S) -> TranscriptRecorder<S> {
+        TranscriptRecorder {
+            inner,
+            sent: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// All bytes written to the stream so far.
+    pub fn sent(&self) -> &[u8] {
+        &self.sent
+    }
+
+    /// All bytes read from the stream so far.
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Stops recording and returns the wrapped stream.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptRecorder<S> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, IoError>> {
+        match Pin::new(&mut self.inner).poll_read(cx, buf)? {
+            Ready(read) => {
+                self.received.extend_from_slice(&buf[..read]);
+                Ready(Ok(read))
+            }
+            Pending => Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptRecorder<S> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  cx: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, IoError>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf)? {
+            Ready(written) => {
+                self.sent.extend_from_slice(&buf[..written]);
+                Ready(Ok(written))
+            }
+            Pending => Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// A recorded handshake: the keys of both sides, the bytes each side sent, and the
+/// outcome each side computed.
+///
+/// Outcomes are stored as their encryption key, encryption nonce, decryption key,
+/// decryption nonce and peer longterm public key, concatenated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transcript {
+    /// The network identifier used by both sides.
+    pub network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+    /// The longterm keypair of the client.
+    pub client_longterm: (sign::PublicKey, sign::SecretKey),
+    /// The ephemeral keypair of the client.
+    pub client_ephemeral: (box_::PublicKey, box_::SecretKey),
+    /// The longterm keypair of the server.
+    pub server_longterm: (sign::PublicKey, sign::SecretKey),
+    /// The ephemeral keypair of the server.
+    pub server_ephemeral: (box_::PublicKey, box_::SecretKey),
+    /// All bytes the client sent, i.e. msg1 followed by msg3.
+    pub client_sent: Vec<u8>,
+    /// All bytes the server sent, i.e. msg2 followed by msg4.
+    pub server_sent: Vec<u8>,
+    /// The outcome computed by the client.
+    pub client_outcome: Vec<u8>,
+    /// The outcome computed by the server.
+    pub server_outcome: Vec<u8>,
+}
+
+impl Transcript {
+    /// Performs a handshake with the given keys and records it.
+    ///
+    /// Panics if the handshake fails.
+    pub fn record(network_identifier: [u8; NETWORK_IDENTIFIER_BYTES],
+                  client_longterm: (sign::PublicKey, sign::SecretKey),
+                  client_ephemeral: (box_::PublicKey, box_::SecretKey),
+                  server_longterm: (sign::PublicKey, sign::SecretKey),
+                  server_ephemeral: (box_::PublicKey, box_::SecretKey))
+                  -> Transcript {
+        let (client_stream, server_stream) = channel_pair();
+
+        let mut client = OwningClientHandshaker::new(TranscriptRecorder::new(client_stream),
+                                                     network_identifier,
+                                                     client_longterm.0,
+                                                     client_longterm.1.clone(),
+                                                     client_ephemeral.0,
+                                                     client_ephemeral.1.clone(),
+                                                     server_longterm.0);
+        let mut server = OwningServerHandshaker::new(TranscriptRecorder::new(server_stream),
+                                                     network_identifier,
+                                                     server_longterm.0,
+                                                     server_longterm.1.clone(),
+                                                     server_ephemeral.0,
+                                                     server_ephemeral.1.clone());
+
+        // Both handshakes run on this thread, so poll them in turns until both are done.
+        let mut client_done = None;
+        let mut server_done = None;
+        for _ in 0..MAX_POLLS {
+            if client_done.is_none() {
+                if let Ready(done) = poll_once(&mut client) {
+                    client_done = Some(done.map_err(|(err, _)| err)
+                                           .expect("the client handshake failed"));
+                }
+            }
+            if server_done.is_none() {
+                if let Ready(done) = poll_once(&mut server) {
+                    server_done = Some(done.map_err(|(err, _)| err)
+                                           .expect("the server handshake failed"));
+                }
+            }
+            if client_done.is_some() && server_done.is_some() {
+                break;
+            }
+        }
+        let (client_outcome, client_stream) = client_done.expect("the client handshake stalled");
+        let (server_outcome, server_stream) = server_done.expect("the server handshake stalled");
+
+        Transcript {
+            network_identifier,
+            client_longterm,
+            client_ephemeral,
+            server_longterm,
+            server_ephemeral,
+            client_sent: client_stream.sent().to_vec(),
+            server_sent: server_stream.sent().to_vec(),
+            client_outcome: outcome_bytes(&client_outcome),
+            server_outcome: outcome_bytes(&server_outcome),
+        }
+    }
+
+    /// The msg1 sent by the client. Fails if the client sent too few bytes.
+    pub fn msg1(&self) -> Result<Msg1, ParseError> {
+        Msg1::parse(message(&self.client_sent, 0, MSG1_BYTES))
+    }
+
+    /// The msg2 sent by the server. Fails if the server sent too few bytes.
+    pub fn msg2(&self) -> Result<Msg2, ParseError> {
+        Msg2::parse(message(&self.server_sent, 0, MSG2_BYTES))
+    }
+
+    /// The msg3 sent by the client. Fails if the client did not send exactly msg1 and msg3.
+    pub fn msg3(&self) -> Result<Msg3, ParseError> {
+        Msg3::parse(message(&self.client_sent, MSG1_BYTES, self.client_sent.len()))
+    }
+
+    /// The msg4 sent by the server. Fails if the server did not send exactly msg2 and msg4.
+    pub fn msg4(&self) -> Result<Msg4, ParseError> {
+        Msg4::parse(message(&self.server_sent, MSG2_BYTES, self.server_sent.len()))
+    }
+
+    /// Serializes the transcript into a fixture: one line per field, consisting of the name
+    /// of the field and its hex encoded value.
+    pub fn to_fixture(&self) -> String {
+        let fields: [(&str, &[u8]); 13] =
+            [("network_identifier", &self.network_identifier),
+             ("client_longterm_pk", &(self.client_longterm.0).0),
+             ("client_longterm_sk", &(self.client_longterm.1).0),
+             ("client_ephemeral_pk", &(self.client_ephemeral.0).0),
+             ("client_ephemeral_sk", &(self.client_ephemeral.1).0),
+             ("server_longterm_pk", &(self.server_longterm.0).0),
+             ("server_longterm_sk", &(self.server_longterm.1).0),
+             ("server_ephemeral_pk", &(self.server_ephemeral.0).0),
+             ("server_ephemeral_sk", &(self.server_ephemeral.1).0),
+             ("client_sent", &self.client_sent),
+             ("server_sent", &self.server_sent),
+             ("client_outcome", &self.client_outcome),
+             ("server_outcome", &self.server_outcome)];
+
+        let mut fixture = String::from("# secret-handshake transcript v1\n");
+        for &(name, value) in fields.iter() {
+            fixture.push_str(name);
+            fixture.push(' ');
+            for byte in value {
+                fixture.push_str(&format!("{:02x}", byte));
+            }
+            fixture.push('\n');
+        }
+        fixture
+    }
+
+    /// Parses a fixture created by `to_fixture`. Empty lines and lines starting with `#` are
+    /// ignored.
+    pub fn from_fixture(fixture: &str) -> Result<Transcript, InvalidFixture> {
+        let mut fields: Vec<(&str, Vec<u8>)> = Vec::new();
+        for line in fixture.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let name = parts.next().unwrap_or("");
+            let value = decode_hex(parts.next().unwrap_or("").trim())
+                .ok_or(InvalidFixture::InvalidValue(name.to_string()))?;
+            fields.push((name, value));
+        }
+
+        let field = |name: &str| -> Result<&[u8], InvalidFixture> {
+            fields
+                .iter()
+                .find(|&&(field_name, _)| field_name == name)
+                .map(|&(_, ref value)| &value[..])
+                .ok_or(InvalidFixture::MissingField(name.to_string()))
+        };
+        let array = |name: &str, len: usize| -> Result<&[u8], InvalidFixture> {
+            let value = field(name)?;
+            if value.len() == len {
+                Ok(value)
+            } else {
+                Err(InvalidFixture::InvalidValue(name.to_string()))
+            }
+        };
+        let sign_keypair = |prefix: &str| -> Result<(sign::PublicKey, sign::SecretKey), InvalidFixture> {
+            let pk = array(&format!("{}_pk", prefix), sign::PUBLICKEYBYTES)?;
+            let sk = array(&format!("{}_sk", prefix), sign::SECRETKEYBYTES)?;
+            Ok((sign::PublicKey::from_slice(pk).unwrap(),
+                sign::SecretKey::from_slice(sk).unwrap()))
+        };
+        let box_keypair = |prefix: &str| -> Result<(box_::PublicKey, box_::SecretKey), InvalidFixture> {
+            let pk = array(&format!("{}_pk", prefix), box_::PUBLICKEYBYTES)?;
+            let sk = array(&format!("{}_sk", prefix), box_::SECRETKEYBYTES)?;
+            Ok((box_::PublicKey::from_slice(pk).unwrap(),
+                box_::SecretKey::from_slice(sk).unwrap()))
+        };
+
+        let mut network_identifier = [0; NETWORK_IDENTIFIER_BYTES];
+        network_identifier
+            .copy_from_slice(array("network_identifier", NETWORK_IDENTIFIER_BYTES)?);
+
+        Ok(Transcript {
+               network_identifier,
+               client_longterm: sign_keypair("client_longterm")?,
+               client_ephemeral: box_keypair("client_ephemeral")?,
+               server_longterm: sign_keypair("server_longterm")?,
+               server_ephemeral: box_keypair("server_ephemeral")?,
+               client_sent: field("client_sent")?.to_vec(),
+               server_sent: field("server_sent")?.to_vec(),
+               client_outcome: field("client_outcome")?.to_vec(),
+               server_outcome: field("server_outcome")?.to_vec(),
+           })
+    }
+}
+
+/// Runs the client side of the handshake of the transcript against the recorded bytes of
+/// the server, and checks that the client sends the recorded bytes and computes the
+/// recorded outcome.
+pub fn replay_against_client(transcript: &Transcript) -> Result<(), ReplayMismatch> {
+    let stream = ReplayStream::new(&transcript.server_sent);
+    let client = OwningClientHandshaker::new(stream,
+                                             transcript.network_identifier,
+                                             transcript.client_longterm.0,
+                                             transcript.client_longterm.1.clone(),
+                                             transcript.client_ephemeral.0,
+                                             transcript.client_ephemeral.1.clone(),
+                                             transcript.server_longterm.0);
+
+    replay(client, &transcript.client_sent, &transcript.client_outcome)
+}
+
+/// Runs the server side of the handshake of the transcript against the recorded bytes of
+/// the client, and checks that the server sends the recorded bytes and computes the
+/// recorded outcome.
+pub fn replay_against_server(transcript: &Transcript) -> Result<(), ReplayMismatch> {
+    let stream = ReplayStream::new(&transcript.client_sent);
+    let server = OwningServerHandshaker::new(stream,
+                                             transcript.network_identifier,
+                                             transcript.server_longterm.0,
+                                             transcript.server_longterm.1.clone(),
+                                             transcript.server_ephemeral.0,
+                                             transcript.server_ephemeral.1.clone());
+
+    replay(server, &transcript.server_sent, &transcript.server_outcome)
+}
+
+fn replay<'a, F>(mut handshaker: F,
+                 expected_sent: &[u8],
+                 expected_outcome: &[u8])
+                 -> Result<(), ReplayMismatch>
+    where F: Future<Output = Result<(Outcome, ReplayStream<'a>),
+                                    (HandshakeError, ReplayStream<'a>)>> + Unpin
+{
+    for _ in 0..MAX_POLLS {
+        let (outcome, stream) = match poll_once(&mut handshaker) {
+            Ready(Ok(done)) => done,
+            Pending => continue,
+            Ready(Err((err, stream))) => {
+                // Sending different bytes than recorded explains why the peer's recorded
+                // bytes were not accepted, so report that rather than the failure.
+                if !expected_sent.starts_with(&stream.sent) {
+                    return Err(ReplayMismatch::Sent {
+                                   expected: expected_sent.to_vec(),
+                                   actual: stream.sent,
+                               });
+                }
+                return Err(ReplayMismatch::Failed(err));
+            }
+        };
+
+        if stream.sent != expected_sent {
+            return Err(ReplayMismatch::Sent {
+                           expected: expected_sent.to_vec(),
+                           actual: stream.sent,
+                       });
+        }
+        let actual_outcome = outcome_bytes(&outcome);
+        if actual_outcome != expected_outcome {
+            return Err(ReplayMismatch::Outcome {
+                           expected: expected_outcome.to_vec(),
+                           actual: actual_outcome,
+                       });
+        }
+        return Ok(());
+    }
+
+    panic!("the replayed handshake stalled")
+}
+
+// A stream that reads the recorded bytes of the peer, and records all bytes written to it.
+// It is never pending.
+struct ReplayStream<'a> {
+    to_read: &'a [u8],
+    sent: Vec<u8>,
+}
+
+impl<'a> ReplayStream<'a> {
+    fn new(to_read: &'a [u8]) -> ReplayStream<'a> {
+        ReplayStream {
+            to_read,
+            sent: Vec::new(),
+        }
+    }
+}
+
+impl<'a> AsyncRead for ReplayStream<'a> {
+    fn poll_read(mut self: Pin<&mut Self>,
+                 _: &mut Context,
+                 buf: &mut [u8])
+                 -> Poll<Result<usize, IoError>> {
+        let len = if buf.len() < self.to_read.len() {
+            buf.len()
+        } else {
+            self.to_read.len()
+        };
+        buf[..len].copy_from_slice(&self.to_read[..len]);
+        self.to_read = &self.to_read[len..];
+        Ready(Ok(len))
+    }
+}
+
+impl<'a> AsyncWrite for ReplayStream<'a> {
+    fn poll_write(mut self: Pin<&mut Self>,
+                  _: &mut Context,
+                  buf: &[u8])
+                  -> Poll<Result<usize, IoError>> {
+        self.sent.extend_from_slice(buf);
+        Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), IoError>> {
+        Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), IoError>> {
+        Ready(Ok(()))
+    }
+}
+
+// Polls the future once, on the current thread and without an executor. Wakeups are
+// ignored, the callers keep polling until the future completes.
+fn poll_once<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+    struct NoopWake;
+
+    impl Wake for NoopWake {
+        fn wake(self: Arc<NoopWake>) {}
+    }
+
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+    Pin::new(future).poll(&mut cx)
+}
+
+// The bytes from `start` to `end`, or as many of them as were sent.
+fn message(sent: &[u8], start: usize, end: usize) -> &[u8] {
+    let end = if end < sent.len() { end } else { sent.len() };
+    let start = if start < end { start } else { end };
+    &sent[start..end]
+}
+
+fn outcome_bytes(outcome: &Outcome) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&outcome.encryption_key().0);
+    bytes.extend_from_slice(&outcome.encryption_nonce().0);
+    bytes.extend_from_slice(&outcome.decryption_key().0);
+    bytes.extend_from_slice(&outcome.decryption_nonce().0);
+    bytes.extend_from_slice(&outcome.peer_longterm_pk().0);
+    bytes
+}
+
+/// A fixture could not be parsed by `Transcript::from_fixture`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidFixture {
+    /// The fixture lacks the field of the given name.
+    MissingField(String),
+    /// The field of the given name is not valid hex, or has the wrong length.
+    InvalidValue(String),
+}
+
+impl Display for InvalidFixture {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            InvalidFixture::MissingField(ref name) => {
+                write!(f, "Invalid fixture: missing field {}", name)
+            }
+            InvalidFixture::InvalidValue(ref name) => {
+                write!(f, "Invalid fixture: invalid value for field {}", name)
+            }
+        }
+    }
+}
+
+impl Error for InvalidFixture {
+    fn description(&self) -> &str {
+        match *self {
+            InvalidFixture::MissingField(_) => "missing field",
+            InvalidFixture::InvalidValue(_) => "invalid value",
+        }
+    }
+}
+
+/// How a replayed handshake differs from its transcript.
+#[derive(Debug)]
+pub enum ReplayMismatch {
+    /// The handshake failed on the recorded bytes of the peer.
+    Failed(HandshakeError),
+    /// The replayed side sent different bytes than recorded.
+    Sent {
+        /// The recorded bytes.
+        expected: Vec<u8>,
+        /// The bytes sent during the replay.
+        actual: Vec<u8>,
+    },
+    /// The replayed side computed a different outcome than recorded.
+    Outcome {
+        /// The recorded outcome.
+        expected: Vec<u8>,
+        /// The outcome computed during the replay.
+        actual: Vec<u8>,
+    },
+}
+
+impl Display for ReplayMismatch {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ReplayMismatch::Failed(ref err) => {
+                write!(f, "Replay mismatch: the handshake failed: {}", err)
+            }
+            ReplayMismatch::Sent { ref expected, ref actual } => {
+                let offset = first_difference(expected, actual);
+                write!(f, "Replay mismatch: sent bytes differ at offset {}", offset)
+            }
+            ReplayMismatch::Outcome { ref expected, ref actual } => {
+                let offset = first_difference(expected, actual);
+                write!(f, "Replay mismatch: outcomes differ at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for ReplayMismatch {
+    fn description(&self) -> &str {
+        match *self {
+            ReplayMismatch::Failed(_) => "the handshake failed",
+            ReplayMismatch::Sent { .. } => "sent bytes differ",
+            ReplayMismatch::Outcome { .. } => "outcomes differ",
+        }
+    }
+}
+
+fn first_difference(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .unwrap_or_else(|| if a.len() < b.len() { a.len() } else { b.len() })
+}
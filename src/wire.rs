@@ -0,0 +1,132 @@
+//! The four handshake messages as typed structs, for tooling that inspects or constructs
+//! them: `parse` splits the bytes of a message into its fields, and `encode` reassembles
+//! them.
+//!
+//! Neither involves any keys, so parsing does not verify anything: every byte string of the
+//! right length parses, whether or not it is a valid message. msg3 and msg4 are encrypted,
+//! their fields are the authenticator and the ciphertext of the secretbox.
+//!
+//! The layouts follow the [protocol guide](https://ssbc.github.io/scuttlebutt-protocol-guide/#handshake),
+//! using its notation (`K` the network identifier, `a` and `b` the ephemeral keys of client
+//! and server, `A` and `B` their longterm keys):
+//!
+//! - msg1 (`MSG1_BYTES`): `hmac_K(a_p)` (32 bytes), `a_p` (32 bytes)
+//! - msg2 (`MSG2_BYTES`): `hmac_K(b_p)` (32 bytes), `b_p` (32 bytes)
+//! - msg3 (`MSG3_BYTES`): `secretbox_{hash(K | a * b | a * B)}(H)` with
+//!   `H = sign_A(K | B_p | hash(a * b)) | A_p`: the authenticator (16 bytes), followed by
+//!   the encrypted signature (64 bytes) and the encrypted `A_p` (32 bytes)
+//! - msg4 (`MSG4_BYTES`): `secretbox_{hash(K | a * b | a * B | A * b)}(S)` with
+//!   `S = sign_B(K | H | hash(a * b))`: the authenticator (16 bytes), followed by the
+//!   encrypted signature (64 bytes). With `HandshakeOptions::aad`, `hash(aad)` is appended
+//!   to the signed message.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+use sodiumoxide::crypto::{auth, box_, secretbox};
+
+use crypto::{MSG1_BYTES, MSG2_BYTES, MSG3_BYTES, MSG4_BYTES};
+
+/// msg1, sent by the client: its ephemeral public key, authenticated with the network
+/// identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msg1 {
+    /// `hmac_K(a_p)`.
+    pub hmac: [u8; auth::TAGBYTES],
+    /// `a_p`, the ephemeral public key of the client.
+    pub ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+}
+
+/// msg2, sent by the server: its ephemeral public key, authenticated with the network
+/// identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msg2 {
+    /// `hmac_K(b_p)`.
+    pub hmac: [u8; auth::TAGBYTES],
+    /// `b_p`, the ephemeral public key of the server.
+    pub ephemeral_pk: [u8; box_::PUBLICKEYBYTES],
+}
+
+/// msg3, sent by the client: its encrypted signature and longterm public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msg3 {
+    /// The authenticator of the secretbox.
+    pub mac: [u8; secretbox::MACBYTES],
+    /// The encrypted `H`, i.e. the signature of the client followed by its longterm public
+    /// key.
+    pub ciphertext: [u8; MSG3_BYTES - secretbox::MACBYTES],
+}
+
+/// msg4, sent by the server: its encrypted signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msg4 {
+    /// The authenticator of the secretbox.
+    pub mac: [u8; secretbox::MACBYTES],
+    /// The encrypted signature of the server.
+    pub ciphertext: [u8; MSG4_BYTES - secretbox::MACBYTES],
+}
+
+// Implements `parse` and `encode` for a message consisting of two fields.
+macro_rules! two_fields {
+    ($msg:ident, $len:ident, $first:ident: $first_len:path, $second:ident) => {
+        impl $msg {
+            /// Splits the bytes of the message into its fields. Fails only if `bytes` does not
+            /// have the length of the message, see the module documentation.
+            pub fn parse(bytes: &[u8]) -> Result<$msg, ParseError> {
+                if bytes.len() != $len {
+                    return Err(ParseError {
+                                   expected: $len,
+                                   actual: bytes.len(),
+                               });
+                }
+
+                let mut msg = $msg {
+                    $first: [0; $first_len],
+                    $second: [0; $len - $first_len],
+                };
+                let (first, second) = bytes.split_at($first_len);
+                msg.$first.copy_from_slice(first);
+                msg.$second.copy_from_slice(second);
+                Ok(msg)
+            }
+
+            /// Reassembles the bytes of the message from its fields.
+            pub fn encode(&self) -> [u8; $len] {
+                let mut bytes = [0; $len];
+                let (first, second) = bytes.split_at_mut($first_len);
+                first.copy_from_slice(&self.$first);
+                second.copy_from_slice(&self.$second);
+                bytes
+            }
+        }
+    }
+}
+
+two_fields!(Msg1, MSG1_BYTES, hmac: auth::TAGBYTES, ephemeral_pk);
+two_fields!(Msg2, MSG2_BYTES, hmac: auth::TAGBYTES, ephemeral_pk);
+two_fields!(Msg3, MSG3_BYTES, mac: secretbox::MACBYTES, ciphertext);
+two_fields!(Msg4, MSG4_BYTES, mac: secretbox::MACBYTES, ciphertext);
+
+/// A message could not be parsed because it has the wrong length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    /// The length of the message.
+    pub expected: usize,
+    /// The length of the parsed bytes.
+    pub actual: usize,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f,
+               "Parse error: expected {} bytes, got {}",
+               self.expected,
+               self.actual)
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        "the message has the wrong length"
+    }
+}
@@ -0,0 +1,148 @@
+//! Runs full handshakes through both the `crypto` module and the reference
+//! implementation in `reference`, and checks that they agree byte for byte.
+
+extern crate sodiumoxide;
+extern crate secret_handshake;
+
+mod reference;
+
+use sodiumoxide::crypto::{auth, box_, sign};
+use secret_handshake::crypto::*;
+
+use reference::ReferenceOutcome;
+
+// Panics with the first differing offset if `actual` and `expected` differ.
+fn assert_same(msg: &str, actual: &[u8], expected: &[u8]) {
+    assert_eq!(actual.len(), expected.len(), "{} has the wrong length", msg);
+    if let Some(offset) = actual.iter().zip(expected).position(|(a, b)| a != b) {
+        panic!("{} differs from the reference at offset {}: {:?} vs {:?}",
+               msg,
+               offset,
+               actual,
+               expected);
+    }
+}
+
+fn to_reference(outcome: &Outcome) -> ReferenceOutcome {
+    ReferenceOutcome {
+        encryption_key: outcome.encryption_key(),
+        encryption_nonce: outcome.encryption_nonce(),
+        decryption_key: outcome.decryption_key(),
+        decryption_nonce: outcome.decryption_nonce(),
+        peer_longterm_pk: outcome.peer_longterm_pk(),
+    }
+}
+
+// Performs a handshake with the given keys and compares every message and both outcomes.
+fn differential_handshake(app: &[u8; NETWORK_IDENTIFIER_BYTES],
+                          client_pk: &sign::PublicKey,
+                          client_sk: &sign::SecretKey,
+                          client_eph_pk: &box_::PublicKey,
+                          client_eph_sk: &box_::SecretKey,
+                          server_pk: &sign::PublicKey,
+                          server_sk: &sign::SecretKey,
+                          server_eph_pk: &box_::PublicKey,
+                          server_eph_sk: &box_::SecretKey) {
+    let mut client = Client::from_keys(app,
+                                       client_pk,
+                                       client_sk,
+                                       client_eph_pk,
+                                       client_eph_sk,
+                                       server_pk);
+    let mut server = Server::from_keys(app,
+                                       server_pk,
+                                       server_sk,
+                                       server_eph_pk,
+                                       server_eph_sk);
+
+    let mut msg1 = [0; MSG1_BYTES];
+    client.create_msg1(&mut msg1);
+    assert_same("msg1", &msg1, &reference::msg1(app, client_eph_pk));
+    assert!(server.verify_msg1(&msg1));
+    assert_eq!(reference::verify_msg1(app, &msg1), Some(*client_eph_pk));
+
+    let mut msg2 = [0; MSG2_BYTES];
+    server.create_msg2(&mut msg2);
+    assert_same("msg2", &msg2, &reference::msg2(app, server_eph_pk));
+    assert!(client.verify_msg2(&msg2));
+    assert_eq!(reference::verify_msg2(app, &msg2), Some(*server_eph_pk));
+
+    let mut msg3 = [0; MSG3_BYTES];
+    assert_eq!(client.create_msg3(&mut msg3), 0);
+    assert_same("msg3",
+                &msg3,
+                &reference::msg3(app,
+                                 client_pk,
+                                 client_sk,
+                                 client_eph_sk,
+                                 server_pk,
+                                 server_eph_pk));
+    assert!(server.verify_msg3(&msg3));
+    let (verified_client_pk, hello) =
+        reference::verify_msg3(app, server_pk, server_sk, server_eph_sk, client_eph_pk, &msg3)
+            .expect("reference rejected msg3");
+    assert_eq!(verified_client_pk, *client_pk);
+
+    let mut msg4 = [0; MSG4_BYTES];
+    server.create_msg4(&mut msg4);
+    assert_same("msg4",
+                &msg4,
+                &reference::msg4(app,
+                                 server_sk,
+                                 server_eph_sk,
+                                 client_pk,
+                                 client_eph_pk,
+                                 &hello));
+    assert!(client.verify_msg4(&msg4));
+    assert!(reference::verify_msg4(app,
+                                   client_pk,
+                                   client_sk,
+                                   client_eph_sk,
+                                   server_pk,
+                                   server_eph_pk,
+                                   &msg4));
+
+    let client_outcome = client.outcome();
+    assert_eq!(to_reference(&client_outcome),
+               reference::client_outcome(app,
+                                         client_pk,
+                                         client_sk,
+                                         client_eph_pk,
+                                         client_eph_sk,
+                                         server_pk,
+                                         server_eph_pk));
+
+    let server_outcome = server.outcome();
+    assert_eq!(to_reference(&server_outcome),
+               reference::server_outcome(app,
+                                         server_pk,
+                                         server_sk,
+                                         server_eph_pk,
+                                         server_eph_sk,
+                                         client_pk,
+                                         client_eph_pk));
+}
+
+#[test]
+// The crypto module and the reference implementation agree for random keys.
+fn differential_random_keys() {
+    sodiumoxide::init();
+
+    for _ in 0..64 {
+        let app = auth::gen_key().0;
+        let (client_pk, client_sk) = sign::gen_keypair();
+        let (client_eph_pk, client_eph_sk) = box_::gen_keypair();
+        let (server_pk, server_sk) = sign::gen_keypair();
+        let (server_eph_pk, server_eph_sk) = box_::gen_keypair();
+
+        differential_handshake(&app,
+                               &client_pk,
+                               &client_sk,
+                               &client_eph_pk,
+                               &client_eph_sk,
+                               &server_pk,
+                               &server_sk,
+                               &server_eph_pk,
+                               &server_eph_sk);
+    }
+}
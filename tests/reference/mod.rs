@@ -0,0 +1,266 @@
+//! A deliberately simple and slow reimplementation of the shs1 message construction
+//! and verification, used to cross-check the `crypto` module of this crate.
+//!
+//! Every function takes all of its inputs explicitly and recomputes all intermediate
+//! values, closely following the notation of the protocol description:
+//!
+//! - `K`: the network identifier
+//! - `A_p`, `A_s`: the client's longterm keys, `a_p`, `a_s` its ephemeral keys
+//! - `B_p`, `B_s`: the server's longterm keys, `b_p`, `b_s` its ephemeral keys
+//!
+//! The primitives themselves are those of libsodium, only the way they are composed
+//! is reimplemented here.
+
+#![allow(dead_code)]
+
+use std::os::raw::c_int;
+
+use sodiumoxide::crypto::{auth, box_, sign, secretbox};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::scalarmult::{scalarmult, Scalar, GroupElement};
+
+// Not exposed by sodiumoxide, but linked anyways.
+extern "C" {
+    fn crypto_sign_ed25519_pk_to_curve25519(curve25519_pk: *mut [u8; 32],
+                                            ed25519_pk: *const [u8; 32])
+                                            -> c_int;
+    fn crypto_sign_ed25519_sk_to_curve25519(curve25519_sk: *mut [u8; 32],
+                                            ed25519_sk: *const [u8; 64])
+                                            -> c_int;
+}
+
+/// The keys and nonces resulting from a handshake.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReferenceOutcome {
+    pub encryption_key: secretbox::Key,
+    pub encryption_nonce: secretbox::Nonce,
+    pub decryption_key: secretbox::Key,
+    pub decryption_nonce: secretbox::Nonce,
+    pub peer_longterm_pk: sign::PublicKey,
+}
+
+fn curve_pk(pk: &sign::PublicKey) -> GroupElement {
+    let mut curve = [0; 32];
+    assert_eq!(unsafe { crypto_sign_ed25519_pk_to_curve25519(&mut curve, &pk.0) }, 0);
+    GroupElement(curve)
+}
+
+fn curve_sk(sk: &sign::SecretKey) -> Scalar {
+    let mut curve = [0; 32];
+    assert_eq!(unsafe { crypto_sign_ed25519_sk_to_curve25519(&mut curve, &sk.0) }, 0);
+    Scalar(curve)
+}
+
+fn dh(sk: &Scalar, pk: &GroupElement) -> [u8; 32] {
+    scalarmult(sk, pk).expect("scalarmult with an all-zero group element").0
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for part in parts {
+        ret.extend_from_slice(part);
+    }
+    ret
+}
+
+fn hmac(app: &[u8; 32], msg: &[u8]) -> [u8; 32] {
+    auth::authenticate(msg, &auth::Key(*app)).0
+}
+
+fn nonce(app: &[u8; 32], eph_pk: &box_::PublicKey) -> secretbox::Nonce {
+    secretbox::Nonce::from_slice(&hmac(app, &eph_pk.0)[..secretbox::NONCEBYTES]).unwrap()
+}
+
+fn zero_nonce() -> secretbox::Nonce {
+    secretbox::Nonce([0; secretbox::NONCEBYTES])
+}
+
+// hmac_K(x_p) | x_p
+fn challenge(app: &[u8; 32], eph_pk: &box_::PublicKey) -> Vec<u8> {
+    concat(&[&hmac(app, &eph_pk.0), &eph_pk.0])
+}
+
+// Returns the ephemeral key contained in a valid challenge.
+fn verify_challenge(app: &[u8; 32], challenge: &[u8]) -> Option<box_::PublicKey> {
+    let tag = auth::Tag::from_slice(&challenge[..32]).unwrap();
+    if auth::verify(&tag, &challenge[32..], &auth::Key(*app)) {
+        box_::PublicKey::from_slice(&challenge[32..])
+    } else {
+        None
+    }
+}
+
+/// msg1 = hmac_K(a_p) | a_p
+pub fn msg1(app: &[u8; 32], client_eph_pk: &box_::PublicKey) -> Vec<u8> {
+    challenge(app, client_eph_pk)
+}
+
+/// Returns `a_p` if msg1 is valid.
+pub fn verify_msg1(app: &[u8; 32], msg1: &[u8]) -> Option<box_::PublicKey> {
+    verify_challenge(app, msg1)
+}
+
+/// msg2 = hmac_K(b_p) | b_p
+pub fn msg2(app: &[u8; 32], server_eph_pk: &box_::PublicKey) -> Vec<u8> {
+    challenge(app, server_eph_pk)
+}
+
+/// Returns `b_p` if msg2 is valid.
+pub fn verify_msg2(app: &[u8; 32], msg2: &[u8]) -> Option<box_::PublicKey> {
+    verify_challenge(app, msg2)
+}
+
+/// H = sign_{A_s}(K | B_p | hash(a_s * b_p)) | A_p
+///
+/// msg3 = secretbox_{hash(K | a_s * b_p | a_s * B_p)}(H)
+pub fn msg3(app: &[u8; 32],
+            client_pk: &sign::PublicKey,
+            client_sk: &sign::SecretKey,
+            client_eph_sk: &box_::SecretKey,
+            server_pk: &sign::PublicKey,
+            server_eph_pk: &box_::PublicKey)
+            -> Vec<u8> {
+    let eph_sk = Scalar(client_eph_sk.0);
+    let ab = dh(&eph_sk, &GroupElement(server_eph_pk.0));
+    let a_b = dh(&eph_sk, &curve_pk(server_pk));
+
+    let signed = concat(&[app, &server_pk.0, &sha256::hash(&ab).0]);
+    let hello = concat(&[&sign::sign_detached(&signed, client_sk).0, &client_pk.0]);
+
+    let key = secretbox::Key(sha256::hash(&concat(&[app, &ab, &a_b])).0);
+    secretbox::seal(&hello, &zero_nonce(), &key)
+}
+
+/// Returns `A_p` and `H` if msg3 is valid.
+pub fn verify_msg3(app: &[u8; 32],
+                   server_pk: &sign::PublicKey,
+                   server_sk: &sign::SecretKey,
+                   server_eph_sk: &box_::SecretKey,
+                   client_eph_pk: &box_::PublicKey,
+                   msg3: &[u8])
+                   -> Option<(sign::PublicKey, Vec<u8>)> {
+    let ab = dh(&Scalar(server_eph_sk.0), &GroupElement(client_eph_pk.0));
+    let a_b = dh(&curve_sk(server_sk), &GroupElement(client_eph_pk.0));
+
+    let key = secretbox::Key(sha256::hash(&concat(&[app, &ab, &a_b])).0);
+    let hello = match secretbox::open(msg3, &zero_nonce(), &key) {
+        Ok(hello) => hello,
+        Err(()) => return None,
+    };
+
+    let sig = sign::Signature::from_slice(&hello[..64]).unwrap();
+    let client_pk = sign::PublicKey::from_slice(&hello[64..]).unwrap();
+    let signed = concat(&[app, &server_pk.0, &sha256::hash(&ab).0]);
+
+    if sign::verify_detached(&sig, &signed, &client_pk) {
+        Some((client_pk, hello))
+    } else {
+        None
+    }
+}
+
+// hash(K | a_s * b_p | a_s * B_p | A_s * b_p), computed from either side's secrets.
+fn box_secret(app: &[u8; 32], ab: &[u8; 32], a_b: &[u8; 32], ab_: &[u8; 32]) -> [u8; 32] {
+    sha256::hash(&concat(&[app, ab, a_b, ab_])).0
+}
+
+/// msg4 = secretbox_{hash(K | b_s * a_p | B_s * a_p | b_s * A_p)}(sign_{B_s}(K | H | hash(b_s * a_p)))
+pub fn msg4(app: &[u8; 32],
+            server_sk: &sign::SecretKey,
+            server_eph_sk: &box_::SecretKey,
+            client_pk: &sign::PublicKey,
+            client_eph_pk: &box_::PublicKey,
+            hello: &[u8])
+            -> Vec<u8> {
+    let eph_sk = Scalar(server_eph_sk.0);
+    let ab = dh(&eph_sk, &GroupElement(client_eph_pk.0));
+    let a_b = dh(&curve_sk(server_sk), &GroupElement(client_eph_pk.0));
+    let ab_ = dh(&eph_sk, &curve_pk(client_pk));
+
+    let signed = concat(&[app, hello, &sha256::hash(&ab).0]);
+    let sig = sign::sign_detached(&signed, server_sk);
+
+    let key = secretbox::Key(box_secret(app, &ab, &a_b, &ab_));
+    secretbox::seal(&sig.0, &zero_nonce(), &key)
+}
+
+/// Returns whether msg4 is valid.
+pub fn verify_msg4(app: &[u8; 32],
+                   client_pk: &sign::PublicKey,
+                   client_sk: &sign::SecretKey,
+                   client_eph_sk: &box_::SecretKey,
+                   server_pk: &sign::PublicKey,
+                   server_eph_pk: &box_::PublicKey,
+                   msg4: &[u8])
+                   -> bool {
+    let eph_sk = Scalar(client_eph_sk.0);
+    let ab = dh(&eph_sk, &GroupElement(server_eph_pk.0));
+    let a_b = dh(&eph_sk, &curve_pk(server_pk));
+    let ab_ = dh(&curve_sk(client_sk), &GroupElement(server_eph_pk.0));
+
+    let key = secretbox::Key(box_secret(app, &ab, &a_b, &ab_));
+    let sig = match secretbox::open(msg4, &zero_nonce(), &key) {
+        Ok(sig) => sign::Signature::from_slice(&sig).unwrap(),
+        Err(()) => return false,
+    };
+
+    let signed_hello = concat(&[app, &server_pk.0, &sha256::hash(&ab).0]);
+    let hello = concat(&[&sign::sign_detached(&signed_hello, client_sk).0, &client_pk.0]);
+    let signed = concat(&[app, &hello, &sha256::hash(&ab).0]);
+
+    sign::verify_detached(&sig, &signed, server_pk)
+}
+
+// hash(hash(hash(K | a_s * b_p | a_s * B_p | A_s * b_p)) | X_p)
+fn session_key(box_secret: &[u8; 32], pk: &sign::PublicKey) -> secretbox::Key {
+    let hashed = sha256::hash(box_secret).0;
+    secretbox::Key(sha256::hash(&concat(&[&hashed, &pk.0])).0)
+}
+
+/// The outcome of a successful handshake, from the client's perspective.
+pub fn client_outcome(app: &[u8; 32],
+                      client_pk: &sign::PublicKey,
+                      client_sk: &sign::SecretKey,
+                      client_eph_pk: &box_::PublicKey,
+                      client_eph_sk: &box_::SecretKey,
+                      server_pk: &sign::PublicKey,
+                      server_eph_pk: &box_::PublicKey)
+                      -> ReferenceOutcome {
+    let eph_sk = Scalar(client_eph_sk.0);
+    let ab = dh(&eph_sk, &GroupElement(server_eph_pk.0));
+    let a_b = dh(&eph_sk, &curve_pk(server_pk));
+    let ab_ = dh(&curve_sk(client_sk), &GroupElement(server_eph_pk.0));
+    let box_secret = box_secret(app, &ab, &a_b, &ab_);
+
+    ReferenceOutcome {
+        encryption_key: session_key(&box_secret, server_pk),
+        encryption_nonce: nonce(app, server_eph_pk),
+        decryption_key: session_key(&box_secret, client_pk),
+        decryption_nonce: nonce(app, client_eph_pk),
+        peer_longterm_pk: server_pk.clone(),
+    }
+}
+
+/// The outcome of a successful handshake, from the server's perspective.
+pub fn server_outcome(app: &[u8; 32],
+                      server_pk: &sign::PublicKey,
+                      server_sk: &sign::SecretKey,
+                      server_eph_pk: &box_::PublicKey,
+                      server_eph_sk: &box_::SecretKey,
+                      client_pk: &sign::PublicKey,
+                      client_eph_pk: &box_::PublicKey)
+                      -> ReferenceOutcome {
+    let eph_sk = Scalar(server_eph_sk.0);
+    let ab = dh(&eph_sk, &GroupElement(client_eph_pk.0));
+    let a_b = dh(&curve_sk(server_sk), &GroupElement(client_eph_pk.0));
+    let ab_ = dh(&eph_sk, &curve_pk(client_pk));
+    let box_secret = box_secret(app, &ab, &a_b, &ab_);
+
+    ReferenceOutcome {
+        encryption_key: session_key(&box_secret, client_pk),
+        encryption_nonce: nonce(app, client_eph_pk),
+        decryption_key: session_key(&box_secret, server_pk),
+        decryption_nonce: nonce(app, server_eph_pk),
+        peer_longterm_pk: client_pk.clone(),
+    }
+}